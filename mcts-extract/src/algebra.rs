@@ -0,0 +1,124 @@
+//! The operations the search needs on the values it backpropagates, so that
+//! it can work with values other than a single [`Utility`].
+
+use std::{cmp::Ordering, fmt::Debug};
+
+use crate::Utility;
+
+/// A type of value that search tree statistics can be accumulated in, and
+/// that [`EgraphAlgebraCost`](crate::EgraphAlgebraCost) scores assignments
+/// with.
+///
+/// As with [`Utility`], greater values are better. The search averages values
+/// by summing them, each scaled by its weight, and scaling the sum by the
+/// reciprocal of the total weight. It only ever compares values with
+/// [`compare`](Self::compare), so an algebra can order its values however it
+/// likes: tuples, for example, are ordered lexicographically, so that
+/// `(errors, size)` prefers fewer errors however large the term.
+///
+/// Some features of the search do arithmetic on values beyond these
+/// operations: the exploration term of the selection policy, first-play
+/// urgency, priors, the cost ceiling and target utility, the confidence gate,
+/// and [`MctsConfig::epsilon`](crate::MctsConfig::epsilon). They rely on
+/// [`as_utility`](Self::as_utility) and [`from_utility`](Self::from_utility),
+/// and are ignored for algebras that are not scalar. Selection policies see
+/// the values of non-scalar algebras by rank instead.
+pub trait CostAlgebra: Copy + Debug + PartialEq {
+    /// The value of an empty sum.
+    fn zero() -> Self;
+
+    /// The sum of `self` and `other`.
+    fn add(self, other: Self) -> Self;
+
+    /// `self` multiplied by the non-negative `factor`.
+    fn scale(self, factor: f32) -> Self;
+
+    /// Compare `self` to `other`, where greater values are better.
+    fn compare(&self, other: &Self) -> Ordering;
+
+    /// This value as a single utility, if the algebra is scalar. The default
+    /// returns `None`.
+    fn as_utility(&self) -> Option<Utility> {
+        None
+    }
+
+    /// The value corresponding to `utility`, if the algebra is scalar. The
+    /// default returns `None`.
+    fn from_utility(utility: Utility) -> Option<Self> {
+        let _ = utility;
+        None
+    }
+}
+
+impl CostAlgebra for Utility {
+    fn zero() -> Self {
+        Utility::default()
+    }
+
+    fn add(self, other: Self) -> Self {
+        self + other
+    }
+
+    fn scale(self, factor: f32) -> Self {
+        self * factor
+    }
+
+    fn compare(&self, other: &Self) -> Ordering {
+        self.cmp(other)
+    }
+
+    fn as_utility(&self) -> Option<Utility> {
+        Some(*self)
+    }
+
+    fn from_utility(utility: Utility) -> Option<Self> {
+        Some(utility)
+    }
+}
+
+/// Implement [`CostAlgebra`] for tuples, ordered lexicographically.
+macro_rules! lexicographic {
+    ($($t:ident $i:tt),+) => {
+        impl<$($t: CostAlgebra),+> CostAlgebra for ($($t,)+) {
+            fn zero() -> Self {
+                ($($t::zero(),)+)
+            }
+
+            fn add(self, other: Self) -> Self {
+                ($(self.$i.add(other.$i),)+)
+            }
+
+            fn scale(self, factor: f32) -> Self {
+                ($(self.$i.scale(factor),)+)
+            }
+
+            fn compare(&self, other: &Self) -> Ordering {
+                Ordering::Equal$(.then_with(|| self.$i.compare(&other.$i)))+
+            }
+        }
+    };
+}
+
+lexicographic!(A 0, B 1);
+lexicographic!(A 0, B 1, C 2);
+lexicographic!(A 0, B 1, C 2, D 3);
+
+/// The rank of each of `values` among the others, from 0.0 for a value worse
+/// than every other to 1.0 for one better than every other. Ties count as
+/// half a win.
+pub(crate) fn ranks<U: CostAlgebra>(values: &[U]) -> impl Iterator<Item = Utility> + '_ {
+    let others = values.len().saturating_sub(1).max(1) as f32;
+    values.iter().map(move |value| {
+        let wins = values
+            .iter()
+            .map(|other| match value.compare(other) {
+                Ordering::Greater => 1.0,
+                Ordering::Equal => 0.5,
+                Ordering::Less => 0.0,
+            })
+            .sum::<f32>()
+            // Every value ties with itself.
+            - 0.5;
+        Utility::new(wins / others).unwrap()
+    })
+}
@@ -0,0 +1,448 @@
+//! Static analyses over the portion of an egraph reachable from a root class.
+//!
+//! These do not depend on a cost model, and are meant to help users size a
+//! search before running it.
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap, VecDeque},
+    fmt,
+    sync::Arc,
+};
+
+use fxhash::{FxHashMap, FxHashSet};
+
+use crate::{Assignment, Egraph, EgraphNodeCost, Utility};
+
+/// Facts about the part of an egraph reachable from a root that the search
+/// would otherwise work out for itself: how many classes are reachable, and
+/// optionally lower bounds on the cost of each class.
+///
+/// Callers that already know these, for example from saturating the egraph,
+/// can build an `Analysis` with [`new`](Self::new) and
+/// [`with_cost_bounds`](Self::with_cost_bounds) and hand it to the search with
+/// [`SearchSession::use_analysis`](crate::SearchSession::use_analysis) or
+/// [`mcts_extract_with_analysis`](crate::mcts_extract_with_analysis). The
+/// search trusts it: bounds that are too high make it prune terms it should
+/// not.
+pub struct Analysis<E: Egraph> {
+    n_reachable: usize,
+    bounds: Option<Arc<CostBounds<E>>>,
+}
+
+impl<E: Egraph> fmt::Debug for Analysis<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Analysis")
+            .field("n_reachable", &self.n_reachable)
+            .field("has_cost_bounds", &self.bounds.is_some())
+            .finish()
+    }
+}
+
+impl<E: Egraph> Analysis<E> {
+    /// An analysis for a root from which exactly `n_reachable` classes can be
+    /// reached, including the root itself.
+    ///
+    /// The search trusts this count: it cuts short any random sample that
+    /// would assign more classes than this, and gives up on the search as
+    /// non-terminating if it makes more than twice as many decisions.
+    pub fn new(n_reachable: usize) -> Self {
+        Self {
+            n_reachable,
+            bounds: None,
+        }
+    }
+
+    /// Count the classes reachable from `root`.
+    pub fn compute(egraph: &E, root: E::ClassId) -> Self {
+        Self::new(reachable_class_order(egraph, root).len())
+    }
+
+    /// Add lower bounds on costs, which let the search prune partial
+    /// assignments that cannot beat
+    /// [`MctsConfig::cost_ceiling`](crate::MctsConfig::cost_ceiling).
+    ///
+    /// `node_costs` gives the cost of each node, `class_bounds` a lower bound
+    /// on the cost of whichever node is assigned to each class (usually the
+    /// cost of its cheapest member), and `root_bound` a lower bound on the
+    /// cost of a complete assignment. Missing nodes and classes cost nothing.
+    /// See [`EgraphNodeCost`] for what the costs must satisfy.
+    pub fn with_cost_bounds(
+        mut self,
+        node_costs: impl IntoIterator<Item = (E::NodeId, Utility)>,
+        class_bounds: impl IntoIterator<Item = (E::ClassId, Utility)>,
+        root_bound: Utility,
+    ) -> Self {
+        self.bounds = Some(Arc::new(CostBounds {
+            node_costs: node_costs.into_iter().collect(),
+            class_bounds: class_bounds.into_iter().collect(),
+            root_bound,
+        }));
+        self
+    }
+
+    /// The number of classes reachable from the root.
+    pub fn n_reachable(&self) -> usize {
+        self.n_reachable
+    }
+
+    pub(crate) fn cost_bounds(&self) -> Option<Arc<CostBounds<E>>> {
+        self.bounds.clone()
+    }
+}
+
+impl<E: EgraphNodeCost> Analysis<E> {
+    /// Count the classes reachable from `root`, and compute the cost bounds
+    /// [`SearchSession::prune_with_node_costs`](crate::SearchSession::prune_with_node_costs)
+    /// would use.
+    pub fn compute_with_costs(egraph: &E, root: E::ClassId) -> Self {
+        let bounds = CostBounds::compute(egraph, root.clone());
+        Self {
+            n_reachable: reachable_class_order(egraph, root).len(),
+            bounds: Some(Arc::new(bounds)),
+        }
+    }
+}
+
+/// Summary statistics describing how hard an extraction problem is.
+#[derive(Clone, Debug, Default)]
+pub struct ProblemStats {
+    /// The number of classes reachable from the root (including the root).
+    pub reachable_classes: usize,
+    /// The number of e-nodes contained in the reachable classes.
+    pub reachable_nodes: usize,
+    /// A histogram mapping class size to the number of reachable classes with
+    /// that many members.
+    pub class_sizes: BTreeMap<usize, usize>,
+    /// The base-10 logarithm of the product of all reachable class sizes.
+    ///
+    /// This is an upper bound on the number of distinct assignments that the
+    /// search could consider; many of them will not be reachable together, or
+    /// will contain cycles. Empty classes are ignored.
+    pub log10_search_space: f64,
+}
+
+/// Compute [`ProblemStats`] for the classes reachable from `root`.
+///
+/// This is cheap relative to running a search, and can be used to pick
+/// parameters like `playouts_per_round`.
+pub fn analyze<E: Egraph>(egraph: &E, root: E::ClassId) -> ProblemStats {
+    let mut stats = ProblemStats::default();
+    for class in reachable_class_order(egraph, root) {
+        let size = egraph.members(&class).count();
+        stats.reachable_classes += 1;
+        stats.reachable_nodes += size;
+        *stats.class_sizes.entry(size).or_default() += 1;
+        if size > 0 {
+            stats.log10_search_space += (size as f64).log10();
+        }
+    }
+    stats
+}
+
+/// Return the classes reachable from `root` by following the children of any
+/// member, not just those of a particular assignment.
+///
+/// The result includes `root`. Opaque classes are included but not explored,
+/// and cycles are followed only once. The egraph is only explored from
+/// `root`, since [`Egraph`] has no way to list every class.
+pub fn reachable_classes<E: Egraph>(egraph: &E, root: E::ClassId) -> FxHashSet<E::ClassId> {
+    reachable_class_order(egraph, root).into_iter().collect()
+}
+
+/// Like [`reachable_classes`], but in breadth-first order.
+pub(crate) fn reachable_class_order<E: Egraph>(egraph: &E, root: E::ClassId) -> Vec<E::ClassId> {
+    // This mirrors the to_visit queue in `ExtractionState`, but follows every
+    // member of a class rather than the assigned one.
+    let mut seen = FxHashSet::default();
+    let mut to_visit = VecDeque::new();
+    let mut res = Vec::new();
+    seen.insert(root.clone());
+    to_visit.push_back(root);
+    while let Some(class) = to_visit.pop_front() {
+        // Opaque classes are leaves.
+        let members = (!egraph.is_opaque(&class)).then(|| egraph.members(&class));
+        for node in members.into_iter().flatten() {
+            for child in egraph.children(node) {
+                if seen.insert(child.clone()) {
+                    to_visit.push_back(child.clone());
+                }
+            }
+        }
+        res.push(class);
+    }
+    res
+}
+
+/// Return the classes reachable from `root` that have an acyclic extraction.
+///
+/// A class is extractable if one of its members has only extractable
+/// children. This is computed bottom-up in time linear in the size of the
+/// reachable egraph, and does not depend on a cost model. The egraph is only
+/// explored from `root`, since [`Egraph`] has no way to list every class.
+pub fn extractable_classes<E: Egraph>(egraph: &E, root: E::ClassId) -> FxHashSet<E::ClassId> {
+    extraction_ranks(egraph, root).into_keys().collect()
+}
+
+/// Compute, for every class reachable from `root` that has an acyclic
+/// extraction, the order in which a bottom-up traversal proves it extractable.
+///
+/// Every class in the result has at least one member whose children all have
+/// strictly lower ranks, so picking such a member for every class always
+/// yields an acyclic assignment. Classes missing from the result cannot be
+/// extracted.
+pub(crate) fn extraction_ranks<E: Egraph>(
+    egraph: &E,
+    root: E::ClassId,
+) -> FxHashMap<E::ClassId, usize> {
+    // For each member node: its class and the number of distinct child classes
+    // that have not been proven extractable yet.
+    let mut nodes = Vec::<(E::ClassId, usize)>::new();
+    // For each class, the indexes in `nodes` of the nodes that depend on it.
+    let mut parents = FxHashMap::<E::ClassId, Vec<usize>>::default();
+    let mut ready = Vec::new();
+    for class in reachable_class_order(egraph, root) {
+        if egraph.is_opaque(&class) {
+            // Opaque classes are always extractable, as if they had a single
+            // member with no children.
+            ready.push(nodes.len());
+            nodes.push((class, 0));
+            continue;
+        }
+        for node in egraph.members(&class) {
+            let ix = nodes.len();
+            let mut n_children = 0;
+            for child in egraph.children(node) {
+                let class_parents = parents.entry(child.clone()).or_default();
+                // Only count each distinct child class once.
+                if class_parents.last() != Some(&ix) {
+                    class_parents.push(ix);
+                    n_children += 1;
+                }
+            }
+            if n_children == 0 {
+                ready.push(ix);
+            }
+            nodes.push((class.clone(), n_children));
+        }
+    }
+
+    let mut ranks = FxHashMap::default();
+    while let Some(ix) = ready.pop() {
+        let class = &nodes[ix].0;
+        if ranks.contains_key(class) {
+            continue;
+        }
+        let class = class.clone();
+        ranks.insert(class.clone(), ranks.len());
+        for &parent in parents.get(&class).into_iter().flatten() {
+            nodes[parent].1 -= 1;
+            if nodes[parent].1 == 0 {
+                ready.push(parent);
+            }
+        }
+    }
+    ranks
+}
+
+/// Extract the term rooted at `root` with the lowest tree cost, exactly and
+/// without searching.
+///
+/// The tree cost of a term is the sum of [`EgraphNodeCost::node_cost`] over
+/// every node of the term written out as a tree, so a class used in several
+/// places is paid for each time; opaque classes cost their
+/// [`opaque_cost`](crate::EgraphTotalCost::opaque_cost) per use. This is the
+/// cost model that bottom-up dynamic programming optimizes exactly. The
+/// result never contains a cycle, even if the egraph does.
+///
+/// Extraction under DAG cost, where each class is paid for once, is NP-hard,
+/// and this is only a heuristic for it: the returned term may miss sharing
+/// that a cheaper DAG would exploit. It is still a fast baseline to compare
+/// the search against. Returns `None` if the root has no acyclic extraction.
+pub fn optimal_dag_extract<E: EgraphNodeCost>(
+    egraph: &E,
+    root: E::ClassId,
+) -> Option<Assignment<E>> {
+    // A node's tree cost is at least that of each of its children, so, as in
+    // Dijkstra's algorithm, the cheapest node whose children are all settled
+    // can settle its class. This is `extraction_ranks` in order of cost
+    // rather than depth.
+    struct Candidate<E: Egraph> {
+        class: E::ClassId,
+        /// `None` for an opaque class.
+        node: Option<E::NodeId>,
+        /// The node's cost plus that of its settled children.
+        cost: Utility,
+        /// The number of children, counted with multiplicity, whose classes
+        /// are not settled yet.
+        unresolved: usize,
+    }
+    let mut candidates = Vec::<Candidate<E>>::new();
+    // For each class, the indexes in `candidates` of the nodes that use it,
+    // once per use.
+    let mut parents = FxHashMap::<E::ClassId, Vec<usize>>::default();
+    let mut ready = BinaryHeap::new();
+    for class in reachable_class_order(egraph, root.clone()) {
+        if egraph.is_opaque(&class) {
+            let cost = egraph.opaque_cost(&class);
+            ready.push(Reverse((cost, candidates.len())));
+            candidates.push(Candidate {
+                class,
+                node: None,
+                cost,
+                unresolved: 0,
+            });
+            continue;
+        }
+        for node in egraph.members(&class) {
+            let ix = candidates.len();
+            let mut unresolved = 0;
+            for child in egraph.children(node) {
+                parents.entry(child.clone()).or_default().push(ix);
+                unresolved += 1;
+            }
+            let cost = egraph.node_cost(node);
+            if unresolved == 0 {
+                ready.push(Reverse((cost, ix)));
+            }
+            candidates.push(Candidate {
+                class: class.clone(),
+                node: Some(node.clone()),
+                cost,
+                unresolved,
+            });
+        }
+    }
+
+    let mut settled = FxHashMap::<E::ClassId, Option<E::NodeId>>::default();
+    while let Some(Reverse((cost, ix))) = ready.pop() {
+        let class = &candidates[ix].class;
+        if settled.contains_key(class) {
+            continue;
+        }
+        let class = class.clone();
+        settled.insert(class.clone(), candidates[ix].node.clone());
+        for &parent in parents.get(&class).into_iter().flatten() {
+            let candidate = &mut candidates[parent];
+            candidate.cost += cost;
+            candidate.unresolved -= 1;
+            if candidate.unresolved == 0 {
+                ready.push(Reverse((candidate.cost, parent)));
+            }
+        }
+    }
+
+    // Every settled node's children were settled before it, so following
+    // the settled nodes down from the root cannot loop.
+    let mut assign = Assignment::<E>::default();
+    let mut to_visit = vec![root];
+    while let Some(class) = to_visit.pop() {
+        if assign.contains_key(&class) {
+            continue;
+        }
+        let Some(node) = settled.get(&class)?.clone() else {
+            continue;
+        };
+        to_visit.extend(egraph.children(&node).cloned());
+        assign.insert(class, node);
+    }
+    Some(assign)
+}
+
+/// Lower bounds on the cost of the classes reachable from a root, used to
+/// prune partial assignments that cannot beat a cost ceiling.
+pub(crate) struct CostBounds<E: Egraph> {
+    node_costs: FxHashMap<E::NodeId, Utility>,
+    class_bounds: FxHashMap<E::ClassId, Utility>,
+    root_bound: Utility,
+}
+
+impl<E: EgraphNodeCost> CostBounds<E> {
+    /// Compute bounds for every class reachable from `root`.
+    ///
+    /// The bound for a class is the cost of its cheapest member. We do not
+    /// add in the cost of the member's children: they may be shared with the
+    /// rest of the term, and an assignment only pays for each class once.
+    pub(crate) fn compute(egraph: &E, root: E::ClassId) -> Self {
+        let mut node_costs = FxHashMap::default();
+        let mut class_bounds = FxHashMap::default();
+        let classes = reachable_class_order(egraph, root.clone());
+        for class in classes.iter().cloned() {
+            if egraph.is_opaque(&class) {
+                class_bounds.insert(class.clone(), egraph.opaque_cost(&class));
+                continue;
+            }
+            let mut bound = None::<Utility>;
+            for node in egraph.members(&class) {
+                let cost = egraph.node_cost(node);
+                node_costs.insert(node.clone(), cost);
+                bound = Some(bound.map_or(cost, |bound| bound.min(cost)));
+            }
+            // Empty classes cannot be extracted at all; the search handles
+            // them separately.
+            class_bounds.insert(class, bound.unwrap_or_default());
+        }
+        let root_bound = path_bound(egraph, root, classes, &node_costs, &class_bounds);
+        Self {
+            node_costs,
+            class_bounds,
+            root_bound,
+        }
+    }
+}
+
+/// A lower bound on the cost of any acyclic assignment rooted at `root`.
+///
+/// A term rooted at a node costs at least as much as the node plus any one of
+/// the subterms below it, since an acyclic term cannot reuse the node inside
+/// its own subterms. We tighten the cheapest-node bound for each class along
+/// those lines, visiting classes bottom-up where possible. Any bound we read
+/// is itself a valid lower bound, so cycles only make the result looser.
+fn path_bound<E: Egraph>(
+    egraph: &E,
+    root: E::ClassId,
+    mut classes: Vec<E::ClassId>,
+    node_costs: &FxHashMap<E::NodeId, Utility>,
+    class_bounds: &FxHashMap<E::ClassId, Utility>,
+) -> Utility {
+    let ranks = extraction_ranks(egraph, root.clone());
+    classes.sort_by_key(|class| ranks.get(class).copied().unwrap_or(usize::MAX));
+    let mut bounds = class_bounds.clone();
+    for class in classes {
+        if egraph.is_opaque(&class) {
+            continue;
+        }
+        let tightened = egraph
+            .members(&class)
+            .map(|node| {
+                let below = egraph
+                    .children(node)
+                    .map(|child| bounds[child])
+                    .max()
+                    .unwrap_or_default();
+                node_costs[node] + below
+            })
+            .min();
+        if let Some(tightened) = tightened {
+            let bound = bounds.get_mut(&class).unwrap();
+            *bound = (*bound).max(tightened);
+        }
+    }
+    bounds[&root]
+}
+
+impl<E: Egraph> CostBounds<E> {
+    pub(crate) fn node_cost(&self, node: &E::NodeId) -> Utility {
+        self.node_costs.get(node).copied().unwrap_or_default()
+    }
+
+    /// A lower bound on the cost of assigning any node to `class`.
+    pub(crate) fn class_bound(&self, class: &E::ClassId) -> Utility {
+        self.class_bounds.get(class).copied().unwrap_or_default()
+    }
+
+    /// A lower bound on the cost of any complete assignment.
+    pub(crate) fn root_bound(&self) -> Utility {
+        self.root_bound
+    }
+}
@@ -0,0 +1,101 @@
+//! Extraction that cooperates with an async runtime.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use crate::{Assignment, EgraphTotalCost, MctsConfig, SearchSession, StepOutcome};
+
+/// A flag for cancelling a [`mcts_extract_async`] search from elsewhere.
+///
+/// This is a handle on the search's [`MctsConfig::cancel`] flag, so the
+/// search stops before its next playout, even in the middle of a round.
+/// Clones share the same flag.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask every search holding this token to stop before its next playout.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Like [`mcts_extract`](crate::mcts_extract), but yield to the async runtime
+/// after each round of playouts, and stop early once `cancel` is cancelled.
+///
+/// The search itself is the same as the synchronous one; it runs
+/// [`MctsConfig::playouts_per_round`] playouts between yield points, so that
+/// setting bounds how long the executor is blocked. `cancel` replaces
+/// [`MctsConfig::cancel`], and a cancelled search returns the best complete
+/// assignment it has scored so far, if any. Like
+/// [`mcts_extract`](crate::mcts_extract), a search that makes far more
+/// decisions than the egraph could need is stopped, and returns `None`.
+pub async fn mcts_extract_async<E: EgraphTotalCost>(
+    egraph: &E,
+    root: E::ClassId,
+    config: MctsConfig,
+    cancel: &CancelToken,
+) -> Option<Assignment<E>> {
+    let has_ceiling = config.cost_ceiling.is_some();
+    let config = MctsConfig {
+        cancel: Some(cancel.0.clone()),
+        ..config
+    };
+    let mut session = SearchSession::new(egraph, root, config);
+    let limit = session.decision_limit();
+    let mut decisions = 0;
+    loop {
+        match session.step() {
+            StepOutcome::Committed { .. } => decisions += 1,
+            // Breakpoints are decided by the search, as if there were none.
+            StepOutcome::Breakpoint { .. } => {
+                if session.commit_best_root().is_some() {
+                    decisions += 1;
+                }
+            }
+            // With a cost ceiling, only the assignments offered along the
+            // way are known to beat it.
+            StepOutcome::Done if !has_ceiling => return session.complete_assignment().cloned(),
+            StepOutcome::Done | StepOutcome::Failed | StepOutcome::Stopped => {
+                return session.best_assignment().cloned()
+            }
+        }
+        if decisions > limit {
+            return None;
+        }
+        YieldNow(false).await;
+    }
+}
+
+/// A future that is pending the first time it is polled, so that the runtime
+/// can run other tasks before it resumes.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
@@ -6,13 +6,13 @@
 
 use std::cmp;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub(crate) struct BacktrackQueue<T> {
     data: Vec<T>,
     front: usize,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub(crate) struct QueueSnapshot {
     front: usize,
     back: usize,
@@ -52,6 +52,12 @@ impl<T: Clone> BacktrackQueue<T> {
         }
     }
 
+    /// Remove every element, keeping the allocation.
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.front = 0;
+    }
+
     pub fn restore(&mut self, snap: &QueueSnapshot) {
         self.front = snap.front;
         self.data.truncate(snap.back);
@@ -0,0 +1,128 @@
+//! Choice-optimization problems that do not come from an egraph library.
+
+use crate::{Assignment, Egraph, EgraphNodeCost, EgraphTotalCost, Utility};
+
+/// One of the alternatives for a position of a [`ChoiceGraph`].
+#[derive(Debug)]
+struct Choice<T> {
+    value: T,
+    cost: Utility,
+    children: Vec<usize>,
+}
+
+/// An egraph built by hand from a term with alternatives at each position,
+/// such as an AST whose nodes each have several possible lowerings.
+///
+/// Each position of the term is a class, and each alternative for it is a
+/// node whose children are the positions of its operands. Positions and
+/// choices are numbered in the order they are added, and those numbers are
+/// the class and node ids. The cost of an assignment is the sum of the costs
+/// of its choices; a position shared by several parents is only paid for
+/// once.
+#[derive(Debug)]
+pub struct ChoiceGraph<T> {
+    positions: Vec<Vec<usize>>,
+    choices: Vec<Choice<T>>,
+}
+
+impl<T> ChoiceGraph<T> {
+    /// An empty graph.
+    pub fn new() -> Self {
+        Self {
+            positions: Vec::new(),
+            choices: Vec::new(),
+        }
+    }
+
+    /// Add a position with no alternatives yet, and return its id.
+    pub fn add_position(&mut self) -> usize {
+        self.positions.push(Vec::new());
+        self.positions.len() - 1
+    }
+
+    /// Add `value` as an alternative for `position`, costing `cost` and with
+    /// operands at the positions in `children`, and return its id.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `cost` is negative, or if `position` or one of `children`
+    /// has not been added.
+    pub fn add_choice(
+        &mut self,
+        position: usize,
+        value: T,
+        cost: Utility,
+        children: impl IntoIterator<Item = usize>,
+    ) -> usize {
+        assert!(cost >= Utility::default(), "costs must be non-negative");
+        let children = children.into_iter().collect::<Vec<_>>();
+        assert!(
+            children.iter().all(|child| *child < self.positions.len()),
+            "children must be added before the choices that refer to them"
+        );
+        let id = self.choices.len();
+        self.choices.push(Choice {
+            value,
+            cost,
+            children,
+        });
+        self.positions[position].push(id);
+        id
+    }
+
+    /// The value of the choice `choice`.
+    pub fn value(&self, choice: usize) -> &T {
+        &self.choices[choice].value
+    }
+
+    /// The number of positions in the graph.
+    pub fn n_positions(&self) -> usize {
+        self.positions.len()
+    }
+}
+
+impl<T> Default for ChoiceGraph<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Egraph for ChoiceGraph<T> {
+    type ClassId = usize;
+    type NodeId = usize;
+
+    fn children(&self, id: &Self::NodeId) -> impl Iterator<Item = &Self::ClassId> {
+        self.choices[*id].children.iter()
+    }
+
+    fn members(&self, id: &Self::ClassId) -> impl Iterator<Item = &Self::NodeId> {
+        self.positions[*id].iter()
+    }
+
+    fn members_slice(&self, id: &Self::ClassId) -> Option<&[Self::NodeId]> {
+        Some(&self.positions[*id])
+    }
+
+    fn ordered_children(&self, id: &Self::NodeId) -> Option<&[Self::ClassId]> {
+        Some(&self.choices[*id].children)
+    }
+}
+
+impl<T> EgraphTotalCost for ChoiceGraph<T> {
+    type CostCtx = ();
+
+    fn precompute(&self, _: &[Self::ClassId]) {}
+
+    fn assignment_utility(&self, _: &(), assignment: &Assignment<Self>) -> Utility {
+        -assignment
+            .values()
+            .map(|choice| self.choices[*choice].cost)
+            .sum::<Utility>()
+    }
+}
+
+impl<T> EgraphNodeCost for ChoiceGraph<T> {
+    fn node_cost(&self, node: &Self::NodeId) -> Utility {
+        self.choices[*node].cost
+    }
+}
@@ -0,0 +1,129 @@
+//! Sources of the random choices made during a search.
+
+use std::fmt::Debug;
+
+use rand::Rng;
+
+/// A source of random choices between the members of a class.
+pub(crate) trait Choose<C> {
+    /// Pick an index below `n`, for one of the `n` members of `class`.
+    fn choose(&mut self, class: &C, n: usize) -> usize;
+
+    /// Pick an index into `weights`, for one of the members of `class`, with
+    /// probability proportional to its weight. The weights must be positive.
+    fn choose_weighted(&mut self, class: &C, weights: &[f32]) -> usize;
+}
+
+impl<C, R: Rng> Choose<C> for R {
+    fn choose(&mut self, _: &C, n: usize) -> usize {
+        self.gen_range(0..n)
+    }
+
+    fn choose_weighted(&mut self, _: &C, weights: &[f32]) -> usize {
+        weighted_index(self, weights)
+    }
+}
+
+/// Pick an index into `weights` with probability proportional to its weight.
+fn weighted_index(rng: &mut (impl Rng + ?Sized), weights: &[f32]) -> usize {
+    let mut target = rng.gen_range(0.0..weights.iter().sum::<f32>());
+    for (i, weight) in weights.iter().enumerate() {
+        if target < *weight {
+            return i;
+        }
+        target -= weight;
+    }
+    // Rounding can leave a sliver past the last weight.
+    weights.len() - 1
+}
+
+/// A source of random choices that can record the choices it makes, or
+/// replay choices recorded earlier instead of making new ones.
+pub(crate) struct ChoiceLog<C, R> {
+    rng: R,
+    mode: ChoiceMode,
+    log: Vec<(C, usize)>,
+    /// The index in `log` of the next choice to replay.
+    next: usize,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum ChoiceMode {
+    Random,
+    Record,
+    Replay,
+}
+
+impl<C, R> ChoiceLog<C, R> {
+    pub(crate) fn new(rng: R) -> Self {
+        Self {
+            rng,
+            mode: ChoiceMode::Random,
+            log: Vec::new(),
+            next: 0,
+        }
+    }
+
+    /// Record every choice made from now on.
+    pub(crate) fn record(&mut self) {
+        self.mode = ChoiceMode::Record;
+        self.log.clear();
+    }
+
+    /// Make the choices in `log`, in order, instead of random ones.
+    pub(crate) fn replay(&mut self, log: Vec<(C, usize)>) {
+        self.mode = ChoiceMode::Replay;
+        self.log = log;
+        self.next = 0;
+    }
+
+    /// The choices recorded or replayed so far.
+    pub(crate) fn log(&self) -> &[(C, usize)] {
+        match self.mode {
+            ChoiceMode::Replay => &self.log[..self.next],
+            ChoiceMode::Random | ChoiceMode::Record => &self.log,
+        }
+    }
+}
+
+impl<C: Clone + Eq + Debug, R> ChoiceLog<C, R> {
+    /// Make a choice between the `n` members of `class` with `choose`, or
+    /// take it from the log when replaying.
+    pub(crate) fn decide(
+        &mut self,
+        class: &C,
+        n: usize,
+        choose: impl FnOnce(&mut R) -> usize,
+    ) -> usize {
+        match self.mode {
+            ChoiceMode::Random => choose(&mut self.rng),
+            ChoiceMode::Record => {
+                let choice = choose(&mut self.rng);
+                self.log.push((class.clone(), choice));
+                choice
+            }
+            ChoiceMode::Replay => {
+                let Some((recorded, choice)) = self.log.get(self.next) else {
+                    panic!("ran out of recorded choices at class {class:?}");
+                };
+                assert!(
+                    recorded == class && *choice < n,
+                    "replay diverged from the recording: expected a choice for {recorded:?}, \
+                     got one for {class:?} with {n} members"
+                );
+                self.next += 1;
+                *choice
+            }
+        }
+    }
+}
+
+impl<C: Clone + Eq + Debug, R: Rng> Choose<C> for ChoiceLog<C, R> {
+    fn choose(&mut self, class: &C, n: usize) -> usize {
+        self.decide(class, n, |rng| rng.gen_range(0..n))
+    }
+
+    fn choose_weighted(&mut self, class: &C, weights: &[f32]) -> usize {
+        self.decide(class, weights.len(), |rng| weighted_index(rng, weights))
+    }
+}
@@ -0,0 +1,182 @@
+//! Cost models that can be put on top of an egraph's structure.
+
+use std::fmt;
+
+use fxhash::FxHashMap;
+
+use crate::{Assignment, Egraph, EgraphNodeCost, EgraphTotalCost, Utility, UTILITY_LIMIT};
+
+/// The length of the critical path through the term extracted by
+/// `assignment`: the largest sum of `latency` along any chain of nodes from a
+/// parent down to one of its descendants.
+///
+/// A node's depth is its latency plus the largest depth among its children;
+/// classes missing from the assignment, such as opaque classes, have depth
+/// zero. A subterm shared by several parents is computed once and adds to the
+/// depth of each of them, so shared subterms lengthen paths rather than being
+/// summed. Returns `None` if the assigned nodes form a cycle, which no complete
+/// assignment does.
+pub fn assignment_depth<E: Egraph + ?Sized>(
+    egraph: &E,
+    assignment: &Assignment<E>,
+    latency: impl Fn(&E::NodeId) -> Utility,
+) -> Option<Utility> {
+    // `None` marks a class whose depth is being computed, i.e. that is on the
+    // current path.
+    let mut depths = FxHashMap::<&E::ClassId, Option<Utility>>::default();
+    let mut max_depth = Utility::default();
+    for start in assignment.keys() {
+        if depths.contains_key(start) {
+            continue;
+        }
+        // An explicit stack, so that deep terms cannot overflow the call
+        // stack. Each class is pushed once to enter it, and once more to
+        // finish it after its children.
+        let mut stack = vec![(start, false)];
+        while let Some((class, children_done)) = stack.pop() {
+            let node = &assignment[class];
+            if children_done {
+                let below = egraph
+                    .children(node)
+                    .filter_map(|child| depths.get(child).copied().flatten())
+                    .max()
+                    .unwrap_or_default();
+                let depth = latency(node) + below;
+                max_depth = max_depth.max(depth);
+                depths.insert(class, Some(depth));
+                continue;
+            }
+            match depths.get(class) {
+                Some(Some(_)) => continue,
+                Some(None) => return None,
+                None => {}
+            }
+            depths.insert(class, None);
+            stack.push((class, true));
+            for child in egraph.children(node) {
+                match depths.get(child) {
+                    Some(None) => return None,
+                    Some(Some(_)) => {}
+                    None if assignment.contains_key(child) => stack.push((child, false)),
+                    None => {}
+                }
+            }
+        }
+    }
+    Some(max_depth)
+}
+
+/// The expected cost of the term extracted by `assignment`, when each node
+/// only succeeds with probability [`Egraph::node_weight`] and is retried until
+/// it does: the sum over the assigned nodes of their cost divided by their
+/// weight.
+///
+/// This is the same as the sum of the node costs when every weight is 1.
+/// Egraphs whose nodes are probabilistic can use it as their
+/// [`assignment_utility`](EgraphTotalCost::assignment_utility), negated; the
+/// node costs then bound it as [`EgraphNodeCost`] requires, since weights are
+/// at most 1.
+///
+/// # Panics
+///
+/// Panics if an assigned node's weight is not positive and finite.
+pub fn expected_cost<E: EgraphNodeCost + ?Sized>(
+    egraph: &E,
+    assignment: &Assignment<E>,
+) -> Utility {
+    assignment
+        .values()
+        .map(|node| {
+            let weight = egraph.node_weight(node);
+            assert!(
+                weight > 0.0 && weight.is_finite(),
+                "node weights must be positive and finite, got {weight}"
+            );
+            egraph.node_cost(node) / Utility::new(weight).unwrap()
+        })
+        .sum()
+}
+
+/// An egraph whose assignments are scored by their critical path (see
+/// [`assignment_depth`]) rather than by the total cost of their nodes.
+///
+/// This suits latency-oriented scheduling, where independent operations run
+/// in parallel: the search then maximizes the negated depth, so it prefers
+/// wide terms to deep ones even when they have more nodes. Cyclic assignments
+/// score `-UTILITY_LIMIT`.
+pub struct CriticalPathCost<'a, E: Egraph, F> {
+    egraph: &'a E,
+    latency: F,
+}
+
+impl<E: Egraph, F> fmt::Debug for CriticalPathCost<'_, E, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CriticalPathCost").finish_non_exhaustive()
+    }
+}
+
+impl<'a, E, F> CriticalPathCost<'a, E, F>
+where
+    E: Egraph,
+    F: Fn(&E::NodeId) -> Utility,
+{
+    /// Score assignments of `egraph` by their depth, where each node adds
+    /// `latency` to the paths through it.
+    pub fn new(egraph: &'a E, latency: F) -> Self {
+        Self { egraph, latency }
+    }
+}
+
+impl<E: Egraph, F> Egraph for CriticalPathCost<'_, E, F> {
+    type ClassId = E::ClassId;
+    type NodeId = E::NodeId;
+
+    fn children(&self, id: &Self::NodeId) -> impl Iterator<Item = &Self::ClassId> {
+        self.egraph.children(id)
+    }
+
+    fn members(&self, id: &Self::ClassId) -> impl Iterator<Item = &Self::NodeId> {
+        self.egraph.members(id)
+    }
+
+    fn members_slice(&self, id: &Self::ClassId) -> Option<&[Self::NodeId]> {
+        self.egraph.members_slice(id)
+    }
+
+    fn ordered_children(&self, id: &Self::NodeId) -> Option<&[Self::ClassId]> {
+        self.egraph.ordered_children(id)
+    }
+
+    fn topo_hint(&self) -> Option<Vec<Self::ClassId>> {
+        self.egraph.topo_hint()
+    }
+
+    fn is_opaque(&self, id: &Self::ClassId) -> bool {
+        self.egraph.is_opaque(id)
+    }
+
+    fn node_weight(&self, id: &Self::NodeId) -> f32 {
+        self.egraph.node_weight(id)
+    }
+
+    fn has_node_weights(&self) -> bool {
+        self.egraph.has_node_weights()
+    }
+}
+
+impl<E, F> EgraphTotalCost for CriticalPathCost<'_, E, F>
+where
+    E: Egraph,
+    F: Fn(&E::NodeId) -> Utility,
+{
+    type CostCtx = ();
+
+    fn precompute(&self, _: &[Self::ClassId]) {}
+
+    fn assignment_utility(&self, _: &(), assignment: &Assignment<Self>) -> Utility {
+        match assignment_depth(self, assignment, &self.latency) {
+            Some(depth) => -depth,
+            None => Utility::new(-UTILITY_LIMIT).unwrap(),
+        }
+    }
+}
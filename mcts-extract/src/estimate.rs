@@ -0,0 +1,296 @@
+//! Estimating the utility of partial assignments by sampling random complete
+//! assignments that extend them.
+
+use fxhash::FxHashMap;
+
+use crate::{
+    choices::Choose,
+    extraction_state::{random_completion, random_cost_estimate, ExtractionState, RolloutFailure},
+    finite_utility,
+    search_tree::{BestAssignment, DeadEnds, EstimateUtility, LeafEstimate, TreeNodeId},
+    CostAlgebra, Egraph, EgraphAlgebraCost, EgraphTotalCost, MctsConfig, SampleAggregation,
+    SearchObjective, Utility,
+};
+
+/// The default leaf estimator: score complete assignments exactly, and
+/// partial assignments by averaging over random completions.
+pub(crate) struct RolloutEstimator<E: EgraphTotalCost> {
+    /// The egraph's cost context for the search.
+    ctx: E::CostCtx,
+    n_samples: usize,
+    aggregation: SampleAggregation,
+    objective: SearchObjective,
+    weighted: bool,
+    cost_ceiling: Option<Utility>,
+    prune_by_best: bool,
+    /// The choices to share between siblings, if enabled.
+    siblings: Option<SiblingChoices<E>>,
+    /// The rollouts that could not be completed so far.
+    dead_ends: DeadEnds,
+    /// The number of terms scored by estimates so far.
+    utility_calls: usize,
+}
+
+/// The random choices made by the rollouts for the children of one search
+/// tree node.
+///
+/// Siblings only differ in the node they assign to one class, so the rest of
+/// a rollout for one of them is usually a valid rollout for the others too.
+/// The `i`th sample for each sibling makes the same choices wherever it can,
+/// so only the classes the earlier siblings never reached need new random
+/// choices. This also means the siblings are compared on the same
+/// completions, rather than on independent ones.
+struct SiblingChoices<E: Egraph> {
+    parent: Option<TreeNodeId>,
+    /// The node picked for each class, for each sample.
+    samples: Vec<FxHashMap<E::ClassId, E::NodeId>>,
+}
+
+impl<E: EgraphTotalCost> RolloutEstimator<E> {
+    pub(crate) fn new(config: &MctsConfig, ctx: E::CostCtx) -> Self {
+        Self {
+            ctx,
+            n_samples: config.terms_to_sample,
+            aggregation: config.sample_aggregation,
+            objective: config.objective,
+            weighted: config.weighted_backprop && config.objective == SearchObjective::Utility,
+            cost_ceiling: config.cost_ceiling,
+            prune_by_best: config.prune_rollouts_by_best,
+            siblings: config.share_sibling_rollouts.then(|| SiblingChoices {
+                parent: None,
+                samples: (0..config.terms_to_sample)
+                    .map(|_| FxHashMap::default())
+                    .collect(),
+            }),
+            dead_ends: DeadEnds::default(),
+            utility_calls: 0,
+        }
+    }
+
+    pub(crate) fn ctx(&self) -> &E::CostCtx {
+        &self.ctx
+    }
+
+    pub(crate) fn dead_ends(&self) -> &DeadEnds {
+        &self.dead_ends
+    }
+
+    /// The utilities of `n` random completions of `state`, in the order they
+    /// were sampled, with `None` for completions that failed or could not
+    /// beat the cost ceiling.
+    pub(crate) fn sample_utilities(
+        &self,
+        eg: &E,
+        state: &mut ExtractionState<E>,
+        n: usize,
+        choices: &mut dyn Choose<E::ClassId>,
+    ) -> Vec<Option<Utility>> {
+        (0..n)
+            .map(|_| {
+                random_cost_estimate(
+                    eg,
+                    &self.ctx,
+                    state,
+                    choices,
+                    self.cost_ceiling,
+                    None,
+                    |_, _| {},
+                )
+                .ok()
+            })
+            .collect()
+    }
+}
+
+impl<E: EgraphTotalCost> EstimateUtility<E> for RolloutEstimator<E> {
+    fn set_parent(&mut self, parent: Option<TreeNodeId>) {
+        if let Some(siblings) = &mut self.siblings {
+            if siblings.parent != parent || parent.is_none() {
+                siblings.parent = parent;
+                siblings.samples.iter_mut().for_each(FxHashMap::clear);
+            }
+        }
+    }
+
+    fn utility_calls(&self) -> usize {
+        self.utility_calls
+    }
+
+    fn estimate(
+        &mut self,
+        partial_assign: &mut ExtractionState<E>,
+        eg: &E,
+        best: &mut BestAssignment<E>,
+        choices: &mut dyn Choose<E::ClassId>,
+    ) -> LeafEstimate {
+        // Greedy samples are all the same.
+        let n_samples = if partial_assign.greedy_rollouts() {
+            1
+        } else {
+            self.n_samples
+        };
+        if let Some(assign) = partial_assign.complete_assignment() {
+            let util = eg.total_utility(&self.ctx, assign);
+            self.utility_calls += 1;
+            best.offer(eg, assign, util);
+            let util = finite_utility(util);
+            match self.objective {
+                SearchObjective::Utility if self.weighted => LeafEstimate {
+                    utility: util,
+                    weight: Utility::new(n_samples as f32).unwrap(),
+                },
+                SearchObjective::Utility => LeafEstimate::new(util),
+                SearchObjective::CompleteExtraction => {
+                    LeafEstimate::new(Utility::new(1.0).unwrap())
+                }
+            }
+        } else {
+            let prune_by_best = self.prune_by_best && partial_assign.has_cost_bounds();
+            let mut util = Utility::default();
+            // The best and worst of the samples that count towards the
+            // estimate.
+            let mut extremes: Option<(Utility, Utility)> = None;
+            let mut successes = 0;
+            for i in 0..n_samples {
+                // Samples that cannot beat the best assignment so far are
+                // abandoned just like those that cannot beat the ceiling.
+                let cost_ceiling = match best.utility() {
+                    Some(best) if prune_by_best => Some(
+                        self.cost_ceiling
+                            .map_or(-best, |ceiling| ceiling.min(-best)),
+                    ),
+                    _ => self.cost_ceiling,
+                };
+                let sample = random_cost_estimate(
+                    eg,
+                    &self.ctx,
+                    partial_assign,
+                    choices,
+                    cost_ceiling,
+                    self.siblings
+                        .as_mut()
+                        .map(|siblings| &mut siblings.samples[i]),
+                    |assign, util| best.offer(eg, assign, util),
+                );
+                // Every sample that is not cut short gets scored, even if it
+                // leaves classes out.
+                successes += usize::from(sample.is_ok());
+                self.utility_calls += usize::from(sample.is_ok());
+                if let Err(RolloutFailure::Stuck(dead_end)) = sample {
+                    self.dead_ends.add(dead_end);
+                }
+                let score = match self.objective {
+                    // If we fail to extract, count that run as 0 utility.
+                    // XXX: This probably isn't the best way to handle this! We
+                    // should revisit later. It'd be better to resample here but
+                    // just bail if we fail to extract after 10*n samples or
+                    // some such.
+                    //
+                    // With a cost ceiling, we know a failed run is no better
+                    // than the ceiling, so we score it as exactly that. Runs
+                    // abandoned because they could not beat the best
+                    // assignment are scored the same way, or as the cost
+                    // bound they reached if there is no ceiling: scoring them
+                    // as the best assignment would rank children whose runs
+                    // keep getting abandoned above children whose runs
+                    // complete.
+                    SearchObjective::Utility => match (sample, self.cost_ceiling) {
+                        (Ok(util), _) => util,
+                        (Err(_), Some(ceiling)) => -ceiling,
+                        (Err(RolloutFailure::Abandoned { cost_bound }), None) => -cost_bound,
+                        (Err(RolloutFailure::Stuck(_) | RolloutFailure::Truncated), None) => {
+                            Utility::default()
+                        }
+                    },
+                    SearchObjective::CompleteExtraction => {
+                        Utility::new(if sample.is_ok() { 1.0 } else { 0.0 }).unwrap()
+                    }
+                };
+                util += score;
+                if !self.weighted || sample.is_ok() {
+                    extremes = Some(match extremes {
+                        Some((best, worst)) => (best.max(score), worst.min(score)),
+                        None => (score, score),
+                    });
+                }
+            }
+            let extreme = match (self.aggregation, extremes) {
+                (SampleAggregation::MeanOfSamples, _) | (_, None) => None,
+                (SampleAggregation::MinOfSamples, Some((best, _))) => Some(best),
+                (SampleAggregation::MaxOfSamples, Some((_, worst))) => Some(worst),
+            };
+            if !self.weighted {
+                LeafEstimate::new(
+                    extreme.unwrap_or_else(|| util / Utility::new(n_samples as f32).unwrap()),
+                )
+            } else if successes == 0 {
+                LeafEstimate {
+                    utility: Utility::default(),
+                    weight: Utility::default(),
+                }
+            } else {
+                // Failed samples contributed nothing to `util`.
+                let successes = Utility::new(successes as f32).unwrap();
+                LeafEstimate {
+                    utility: extreme.unwrap_or(util / successes),
+                    weight: successes,
+                }
+            }
+        }
+    }
+}
+
+/// The leaf estimator for egraphs scored in a [`CostAlgebra`]: score complete
+/// assignments exactly, and partial assignments by the mean value of the
+/// random completions that succeed.
+pub(crate) struct AlgebraRolloutEstimator {
+    n_samples: usize,
+}
+
+impl AlgebraRolloutEstimator {
+    pub(crate) fn new(n_samples: usize) -> Self {
+        Self { n_samples }
+    }
+}
+
+impl<E: EgraphAlgebraCost> EstimateUtility<E, E::Value> for AlgebraRolloutEstimator {
+    fn estimate(
+        &mut self,
+        partial_assign: &mut ExtractionState<E>,
+        eg: &E,
+        best: &mut BestAssignment<E, E::Value>,
+        choices: &mut dyn Choose<E::ClassId>,
+    ) -> LeafEstimate<E::Value> {
+        if let Some(assign) = partial_assign.complete_assignment() {
+            let value = eg.assignment_value(assign);
+            best.offer(eg, assign, value);
+            return LeafEstimate::new(value);
+        }
+        let mut total = E::Value::zero();
+        let mut successes = 0;
+        for _ in 0..self.n_samples {
+            // Without an empty-class penalty, samples never leave classes out.
+            let sample = random_completion(eg, partial_assign, choices, None, None, |assign, _| {
+                let value = eg.assignment_value(assign);
+                best.offer(eg, assign, value);
+                value
+            });
+            if let Ok(value) = sample {
+                total = total.add(value);
+                successes += 1;
+            }
+        }
+        // There is no value to score failed samples as, so they count for
+        // nothing.
+        if successes == 0 {
+            return LeafEstimate {
+                utility: E::Value::zero(),
+                weight: Utility::default(),
+            };
+        }
+        LeafEstimate {
+            utility: total.scale(1.0 / successes as f32),
+            weight: Utility::new(successes as f32).unwrap(),
+        }
+    }
+}
@@ -10,15 +10,15 @@
 //! from the root class. Top-down extraction has a number of downsides, however:
 //!
 //!  * Bottom-up extraction can prune cycles more easily: one only starts
-//!  processing an e-node once there is a valid extraction for all of its
-//!  children. Top-down extraction can't do this, and as a result it can
-//!  traverse unfruitful paths (though ideally MCTS will prune these for us).
+//!    processing an e-node once there is a valid extraction for all of its
+//!    children. Top-down extraction can't do this, and as a result it can
+//!    traverse unfruitful paths (though ideally MCTS will prune these for us).
 //!
 //!  * Top-down extraction is probably worse for greedy extraction where each
-//!  e-node has known cost. For bottom-up, we can choose between the cost of
-//!  entire subtrees of a term, whereas top-down can only look at the node cost
-//!  on its own. This crate is focused on cases where we are only interested in
-//!  computing whole-term costs anyways, so this downside won't concern us here.
+//!    e-node has known cost. For bottom-up, we can choose between the cost of
+//!    entire subtrees of a term, whereas top-down can only look at the node cost
+//!    on its own. This crate is focused on cases where we are only interested in
+//!    computing whole-term costs anyways, so this downside won't concern us here.
 //!
 //!  * Handling cycles "lazily" ends up being much more complicated.
 //!
@@ -28,90 +28,629 @@
 //! metadata about the e-graph:
 //!
 //!   * The current partial assignment of nodes to classes. This only includes
-//!   nodes whose children are also assigned.
+//!     nodes whose children are also assigned.
 //!   * The pending state, which include:
 //!     - A provisional assignment of nodes to classes. This (logically)
-//!     contains nodes whose dependencies may not be satisfied yet. We use a
-//!     scheme similar to two-watch literals in SAT solvers to track
-//!     dependencies efficiently here. Once all dependencies are satisfied, the
-//!     assignment here is added to the main assignment.
+//!       contains nodes whose dependencies may not be satisfied yet. We use a
+//!       scheme similar to two-watch literals in SAT solvers to track
+//!       dependencies efficiently here. Once all dependencies are satisfied, the
+//!       assignment here is added to the main assignment.
 //!     - A queue of classes to visit.
 //!   * A stack of snapshots of the state, which we use to backtrack when we
 //!     finish an MCTS playout or when we finish extracting random terms to
 //!     estimate cost. Many of the data-structures here include quirks that make
 //!     backtracking easier.
 //!
+//! Callers can also mark some classes as optional. Optional classes are
+//! assigned like any other if the search reaches them while mandatory classes
+//! are still pending; afterwards, any optional classes left in the queue are
+//! skipped. A skipped class becomes a "hole": it counts as resolved for the
+//! nodes that depend on it, but is not part of the assignment. Optional
+//! classes with no members are always skipped.
+//!
+//! Opaque classes (see [`Egraph::is_opaque`]) are simpler still: they are
+//! never queued at all, and nodes do not wait on them.
+//!
+//! Callers can also limit how many nodes of each kind the assignment uses.
+//! The state counts the provisionally assigned nodes against each limit, and
+//! reports which members of a class still fit; it is up to the caller to only
+//! assign those.
+//!
 //! The resulting scheme naturally handles cycles, because a cyclic assignment
 //! will not be able to resolve all of its dependencies. We check for this case
 //! when generating a complete assignment. A potential optimization would be to
 //! add an "occurrs check" that filters out any potential assignments that would
 //! introduce a cycle.
-use fxhash::FxHashSet;
-use indexmap::IndexMap;
-use rand::Rng;
+use std::{
+    cmp,
+    hash::{BuildHasher, Hash},
+    sync::Arc,
+};
+
+use fxhash::{FxBuildHasher, FxHashMap, FxHashSet};
+use indexmap::{IndexMap, IndexSet};
 use smallvec::SmallVec;
 
 use crate::{
+    analysis::CostBounds,
     backtrack_queue::{BacktrackQueue, QueueSnapshot},
-    Assignment, Egraph, EgraphTotalCost, Utility,
+    choices::Choose,
+    finite_utility,
+    implications::Implications,
+    quotas::OpQuotas,
+    Assignment, Egraph, EgraphTotalCost, RolloutPolicy, Utility,
 };
 
+/// Maps whose capacity exceeds this many times their length (or
+/// [`MIN_RETAINED_CAPACITY`]) after backtracking are shrunk, so that one
+/// unusually deep rollout does not hold on to its peak memory for the rest of
+/// the search. They keep half that much room to grow.
+const SHRINK_RATIO: usize = 4;
+
+/// The maps are only checked for spare capacity once every this many
+/// backtracks, so that searches whose rollouts alternate between deep and
+/// shallow ones do not keep reallocating.
+pub(crate) const SHRINK_INTERVAL: u32 = 64;
+
+/// Maps are never shrunk below this capacity.
+const MIN_RETAINED_CAPACITY: usize = 64;
+
+/// Why a random extraction did not produce a complete assignment.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum RolloutFailure {
+    /// The extraction could no longer beat the cost ceiling.
+    Abandoned {
+        /// The lower bound on the extraction's cost that reached the ceiling.
+        cost_bound: Utility,
+    },
+    /// The extraction could not be completed.
+    Stuck(DeadEnd),
+    /// The extraction assigned as many classes as it is allowed to (see
+    /// [`MctsConfig::rollout_max_depth`](crate::MctsConfig::rollout_max_depth)),
+    /// or as there are reachable classes, without finishing.
+    Truncated,
+}
+
+/// Why an extraction could not be completed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DeadEnd {
+    /// It reached a mandatory class with no members it could use.
+    EmptyClass,
+    /// Every class was assigned, but some of the assigned nodes depend on
+    /// themselves through a cycle.
+    Cycle,
+}
+
 /// Given an egraph that can estimate the utility of an assignment, simulate
 /// a random extraction given the partial extraion in `state` and return its
-/// cost. Returns `None` is random extraction fails.
+/// cost, or the reason random extraction failed.
+///
+/// The extraction is abandoned as soon as it cannot beat `cost_ceiling`, and
+/// truncated once it has assigned the state's rollout depth limit of classes.
+/// `on_complete` is called with each complete assignment that is scored.
+///
+/// The extraction fails at a mandatory class it has no members for, unless the
+/// state's empty-class penalty lets it leave the class out. It then deducts the
+/// penalty from the utility of the assignment with the class missing, and
+/// does not pass that assignment to `on_complete`, since it is not a term.
+///
+/// With `reuse`, the extraction picks the node recorded there for a class
+/// instead of a random one whenever it can, and records the random choices
+/// it does make.
 pub(crate) fn random_cost_estimate<E: EgraphTotalCost>(
+    egraph: &E,
+    ctx: &E::CostCtx,
+    state: &mut ExtractionState<E>,
+    g: &mut (impl Choose<E::ClassId> + ?Sized),
+    cost_ceiling: Option<Utility>,
+    reuse: Option<&mut FxHashMap<E::ClassId, E::NodeId>>,
+    mut on_complete: impl FnMut(&Assignment<E>, Utility),
+) -> Result<Utility, RolloutFailure> {
+    random_completion(egraph, state, g, cost_ceiling, reuse, |assign, penalty| {
+        let util = egraph.total_utility(ctx, assign);
+        let Some(penalty) = penalty else {
+            on_complete(assign, util);
+            return finite_utility(util);
+        };
+        // The assignment has holes in mandatory classes, so it only stands for
+        // the terms it could have been.
+        finite_utility(util - penalty)
+    })
+}
+
+/// Simulate a random extraction given the partial extraction in `state`, as
+/// [`random_cost_estimate`] does, and return what `score` makes of the
+/// result, or the reason random extraction failed.
+///
+/// `score` is passed the assignment and the total penalty for the mandatory
+/// classes it leaves out, if it leaves any out.
+pub(crate) fn random_completion<E: Egraph, T>(
     egraph: &E,
     state: &mut ExtractionState<E>,
-    g: &mut impl Rng,
-) -> Option<Utility> {
+    g: &mut (impl Choose<E::ClassId> + ?Sized),
+    cost_ceiling: Option<Utility>,
+    mut reuse: Option<&mut FxHashMap<E::ClassId, E::NodeId>>,
+    score: impl FnOnce(&Assignment<E>, Option<Utility>) -> T,
+) -> Result<T, RolloutFailure> {
     // Push a snapshot so we can hand the state back like we got it.
     state.push_snapshot();
-    let res = || -> Option<Utility> {
+    let res = || -> Result<T, RolloutFailure> {
         // Scratch space to use for repeated allocations of enodes.
         let mut scratch = Vec::new();
+        let mut weights = Vec::new();
+        let weighted = egraph.has_node_weights();
+        let greedy = state.greedy_rollouts;
+        // Classes skipped as optional count towards the depth too, and there
+        // are only so many reachable classes to either assign or skip.
+        let n_visited = state.pending.provisional_assign.len() + state.pending.holes.len();
+        let max_depth = [
+            state.rollout_max_depth,
+            state.max_assigned.map(|max| max.saturating_sub(n_visited)),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let mut depth = 0;
+        // The total penalty for the classes left out for lack of members, if
+        // any were.
+        let mut penalty: Option<Utility> = None;
         while let Some(handle) = state.start_next_assign() {
-            scratch.extend(egraph.members(handle.class()));
-            if scratch.is_empty() {
-                return None;
+            if max_depth.is_some_and(|max| depth >= max) {
+                return Err(RolloutFailure::Truncated);
+            }
+            depth += 1;
+            let reused = reuse
+                .as_deref()
+                .and_then(|reuse| reuse.get(handle.class()))
+                .filter(|node| handle.allows(node));
+            if let Some(node) = reused {
+                handle.assign(node.clone(), egraph);
+                if state.cannot_beat(cost_ceiling) {
+                    return Err(RolloutFailure::Abandoned {
+                        cost_bound: state.cost_lower_bound,
+                    });
+                }
+                continue;
+            }
+            let policy = handle.rollout_policy();
+            let choice = match egraph.members_slice(handle.class()) {
+                Some(members)
+                    if !handle.has_constraints()
+                        && policy == RolloutPolicy::Uniform
+                        && !weighted
+                        && !greedy =>
+                {
+                    pick_random(handle.class(), members, g).cloned()
+                }
+                _ => {
+                    scratch.extend(
+                        egraph
+                            .members(handle.class())
+                            .filter(|node| handle.allows(node)),
+                    );
+                    let choice = match policy {
+                        _ if scratch.len() <= 1 => scratch.first(),
+                        _ if greedy => {
+                            // `min_by_key` would take the last of several
+                            // equally cheap members.
+                            scratch.iter().reduce(|cheapest, node| {
+                                if handle.node_cost(node) < handle.node_cost(cheapest) {
+                                    node
+                                } else {
+                                    cheapest
+                                }
+                            })
+                        }
+                        RolloutPolicy::Uniform if !weighted => {
+                            pick_random(handle.class(), &scratch, g)
+                        }
+                        RolloutPolicy::Uniform | RolloutPolicy::PreferResolved => {
+                            let prefer_resolved = policy == RolloutPolicy::PreferResolved;
+                            weights.extend(scratch.iter().map(|node| {
+                                let weight = egraph.node_weight(node);
+                                if prefer_resolved {
+                                    weight / (1 + handle.new_classes(node, egraph)) as f32
+                                } else {
+                                    weight
+                                }
+                            }));
+                            let choice = g.choose_weighted(handle.class(), &weights);
+                            weights.clear();
+                            Some(&scratch[choice])
+                        }
+                    }
+                    .map(|node| (*node).clone());
+                    scratch.clear();
+                    choice
+                }
+            };
+            let Some(node) = choice else {
+                if handle.is_optional() {
+                    handle.skip();
+                    continue;
+                }
+                let Some(cost) = handle.empty_class_penalty() else {
+                    return Err(RolloutFailure::Stuck(DeadEnd::EmptyClass));
+                };
+                *penalty.get_or_insert_default() += cost;
+                handle.leave_out();
+                continue;
+            };
+            if let Some(reuse) = reuse.as_deref_mut() {
+                reuse.insert(handle.class().clone(), node.clone());
+            }
+            handle.assign(node, egraph);
+            if state.cannot_beat(cost_ceiling) {
+                return Err(RolloutFailure::Abandoned {
+                    cost_bound: state.cost_lower_bound,
+                });
             }
-            let choice = g.gen_range(0..scratch.len());
-            handle.assign(scratch[choice].clone(), egraph);
-            scratch.clear();
         }
-        Some(egraph.assignment_utility(state.complete_assignment()?))
+        let assign = state
+            .complete_assignment()
+            .ok_or(RolloutFailure::Stuck(DeadEnd::Cycle))?;
+        Ok(score(assign, penalty))
     }();
     state.reset(egraph);
     state.pop_snapshot();
     res
 }
 
+/// Pick a random member of `class` from `members`, without making a choice
+/// if there is only one.
+fn pick_random<'a, C, T>(
+    class: &C,
+    members: &'a [T],
+    g: &mut (impl Choose<C> + ?Sized),
+) -> Option<&'a T> {
+    match members.len() {
+        0 => None,
+        1 => Some(&members[0]),
+        n => Some(&members[g.choose(class, n)]),
+    }
+}
+
+/// A provisionally assigned node that is still waiting for some of its
+/// children to be assigned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WatchedNode<C, N> {
+    /// The class the node is provisionally assigned to.
+    pub class: C,
+    /// The provisionally assigned node.
+    pub node: N,
+    /// The child class whose assignment will next be checked for this node.
+    pub watching: C,
+    /// The number of distinct child classes that are not assigned yet,
+    /// including `watching`.
+    pub unresolved: usize,
+}
+
+/// Extraction states left over from finished searches, for new searches to
+/// start from instead of allocating their own.
+///
+/// Searches that extract many roots one after another can share a pool, so
+/// that only as many states are allocated as there are searches in flight.
+pub(crate) struct StatePool<E: Egraph> {
+    free: Vec<ExtractionState<E>>,
+    /// The number of states the pool has had to allocate.
+    allocated: usize,
+}
+
+impl<E: Egraph> Default for StatePool<E> {
+    fn default() -> Self {
+        Self {
+            free: Vec::new(),
+            allocated: 0,
+        }
+    }
+}
+
+impl<E: Egraph> StatePool<E> {
+    /// A state for a search rooted at `root`, as if created by
+    /// [`ExtractionState::new`].
+    pub(crate) fn take(&mut self, root: E::ClassId) -> ExtractionState<E> {
+        match self.free.pop() {
+            Some(mut state) => {
+                state.recycle(root);
+                state
+            }
+            None => {
+                self.allocated += 1;
+                ExtractionState::new(root)
+            }
+        }
+    }
+
+    /// Return `state` to the pool once its search is done with it.
+    pub(crate) fn put(&mut self, state: ExtractionState<E>) {
+        self.free.push(state);
+    }
+
+    /// The number of states the pool has had to allocate.
+    #[cfg(test)]
+    pub(crate) fn allocated(&self) -> usize {
+        self.allocated
+    }
+}
+
+/// Gives the cost of leaving out a class that has no usable members, or `None`
+/// if the class must not be left out.
+pub(crate) type EmptyClassPenalty<E> =
+    Arc<dyn Fn(&<E as Egraph>::ClassId) -> Option<Utility> + Send + Sync>;
+
 pub(crate) struct ExtractionState<E: Egraph> {
     assign: Assignment<E>,
     pending: PendingState<E>,
     snapshots: Vec<StateSnapshot>,
+    /// Classes that may be left unassigned.
+    optional: FxHashSet<E::ClassId>,
+    /// Node costs, if we are tracking a lower bound on the cost of the
+    /// assignment.
+    bounds: Option<Arc<CostBounds<E>>>,
+    /// The cost of the provisionally assigned nodes, plus the cost bounds for
+    /// the mandatory classes left to visit.
+    cost_lower_bound: Utility,
+    /// Limits on the kinds of nodes that may be assigned, if any.
+    quotas: Option<Arc<OpQuotas<E>>>,
+    /// The number of provisionally assigned nodes counted against each of the
+    /// `quotas`.
+    quota_usage: Vec<usize>,
+    /// Assignments that other assignments require, if any.
+    implications: Option<Arc<Implications<E>>>,
+    /// The cost of leaving out a class that a random extraction has no
+    /// members for, if it may.
+    empty_class_penalty: Option<EmptyClassPenalty<E>>,
+    /// How random extractions pick between the members of a class.
+    rollout_policy: RolloutPolicy,
+    /// Whether random extractions instead always pick the member with the
+    /// lowest cost in `bounds`.
+    greedy_rollouts: bool,
+    /// The most classes a random extraction may assign.
+    rollout_max_depth: Option<usize>,
+    /// The most classes the state can assign or skip on a well-formed egraph,
+    /// i.e. the number of classes reachable from the root, if known. Random
+    /// extractions that would go past it are cut short.
+    max_assigned: Option<usize>,
+    /// The position of each class in the egraph's topological order hint, if
+    /// it has one (see [`Egraph::topo_hint`]).
+    visit_order: Option<Arc<FxHashMap<E::ClassId, usize>>>,
+    /// The number of calls to [`reset`](Self::reset) left before the maps
+    /// are next checked for spare capacity.
+    resets_until_shrink: u32,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct StateSnapshot {
     assign_len: usize,
     pending: PendingStateSnapshot,
+    cost_lower_bound: Utility,
 }
 
 impl<E: Egraph> ExtractionState<E> {
     pub(crate) fn new(root: E::ClassId) -> Self {
+        Self::with_optional(root, Default::default())
+    }
+
+    /// Create a state where the classes in `optional` may be left unassigned.
+    pub(crate) fn with_optional(root: E::ClassId, optional: FxHashSet<E::ClassId>) -> Self {
         let mut res = Self {
             assign: Default::default(),
             pending: Default::default(),
             snapshots: Default::default(),
+            optional,
+            bounds: None,
+            cost_lower_bound: Utility::default(),
+            quotas: None,
+            implications: None,
+            empty_class_penalty: None,
+            quota_usage: Vec::new(),
+            rollout_policy: RolloutPolicy::Uniform,
+            greedy_rollouts: false,
+            rollout_max_depth: None,
+            max_assigned: None,
+            visit_order: None,
+            resets_until_shrink: SHRINK_INTERVAL,
         };
-        res.pending.push_to_visit(root);
+        res.pending.push_to_visit(root, &res.optional);
         res.push_snapshot();
         res
     }
+
+    /// Start over from `root` with the settings of a state created by
+    /// [`new`](Self::new), while keeping the allocations.
+    fn recycle(&mut self, root: E::ClassId) {
+        self.optional.clear();
+        self.bounds = None;
+        self.quotas = None;
+        self.quota_usage.clear();
+        self.implications = None;
+        self.empty_class_penalty = None;
+        self.rollout_policy = RolloutPolicy::Uniform;
+        self.greedy_rollouts = false;
+        self.rollout_max_depth = None;
+        self.max_assigned = None;
+        self.visit_order = None;
+        self.clear_and_seed(root);
+    }
+
+    /// Start over from `root`, as if the state had just been created, while
+    /// keeping its allocations, optional classes, cost bounds, quotas,
+    /// implications, rollout settings and visit order.
+    pub(crate) fn clear_and_seed(&mut self, root: E::ClassId) {
+        self.assign.clear();
+        self.snapshots.clear();
+        self.pending.clear();
+        self.quota_usage.iter_mut().for_each(|usage| *usage = 0);
+        self.pending.push_to_visit(root, &self.optional);
+        self.cost_lower_bound = self
+            .pending
+            .to_visit
+            .iter()
+            .map(|class| self.class_bound(class))
+            .sum();
+        self.push_snapshot();
+    }
+
+    /// Let the classes in `optional` be left unassigned, starting over from
+    /// `root`.
+    pub(crate) fn set_optional(&mut self, root: E::ClassId, optional: FxHashSet<E::ClassId>) {
+        self.optional = optional;
+        self.clear_and_seed(root);
+    }
+
+    /// Start tracking a lower bound on the cost of the assignment.
+    ///
+    /// This must be called before anything is assigned.
+    pub(crate) fn set_cost_bounds(&mut self, bounds: Arc<CostBounds<E>>) {
+        assert!(
+            self.pending.provisional_assign.is_empty(),
+            "cost bounds must be set before assigning any classes"
+        );
+        self.bounds = Some(bounds);
+        self.cost_lower_bound = self
+            .pending
+            .to_visit
+            .iter()
+            .map(|class| self.class_bound(class))
+            .sum();
+        self.snapshots.clear();
+        self.push_snapshot();
+    }
+
+    /// A lower bound on the cost of every completion of the current
+    /// assignment, if we are tracking one.
+    pub(crate) fn cost_lower_bound(&self) -> Option<Utility> {
+        self.bounds.as_ref().map(|_| self.cost_lower_bound)
+    }
+
+    /// Whether we are tracking a lower bound on the cost of the assignment.
+    pub(crate) fn has_cost_bounds(&self) -> bool {
+        self.bounds.is_some()
+    }
+
+    /// Only allow nodes to be assigned while they fit within `quotas`.
+    ///
+    /// This must be called before anything is assigned.
+    pub(crate) fn set_quotas(&mut self, quotas: Arc<OpQuotas<E>>) {
+        assert!(
+            self.pending.provisional_assign.is_empty(),
+            "quotas must be set before assigning any classes"
+        );
+        self.quota_usage = vec![0; quotas.n_limits()];
+        self.quotas = Some(quotas);
+    }
+
+    /// Only allow assignments that assign `then` whenever they assign `when`.
+    ///
+    /// This must be called before anything is assigned.
+    pub(crate) fn add_implication(
+        &mut self,
+        when: (E::ClassId, E::NodeId),
+        then: (E::ClassId, E::NodeId),
+    ) {
+        assert!(
+            self.pending.provisional_assign.is_empty(),
+            "implications must be added before assigning any classes"
+        );
+        Arc::make_mut(self.implications.get_or_insert_default()).add(when, then);
+    }
+
+    /// Whether `node` may be assigned to the next class to visit: whether it
+    /// would keep the assignment within its quotas, and is consistent with
+    /// its implications.
+    ///
+    /// This is always true if there are neither quotas nor implications.
+    pub(crate) fn allows(&self, node: &E::NodeId) -> bool {
+        let within_quota = self.quotas.as_ref().is_none_or(|quotas| {
+            quotas
+                .slot(node)
+                .is_none_or(|slot| self.quota_usage[slot] < quotas.limit(slot))
+        });
+        within_quota
+            && self
+                .implications
+                .as_ref()
+                .zip(self.pending.to_visit.front())
+                .is_none_or(|(implications, class)| {
+                    implications.allows(&self.pending.provisional_assign, class, node)
+                })
+    }
+
+    /// Let random extractions leave out classes with no usable members, at the
+    /// cost `penalty` gives them.
+    pub(crate) fn set_empty_class_penalty(&mut self, penalty: EmptyClassPenalty<E>) {
+        self.empty_class_penalty = Some(penalty);
+    }
+
+    /// Have random extractions pick between the members of a class according
+    /// to `policy`.
+    pub(crate) fn set_rollout_policy(&mut self, policy: RolloutPolicy) {
+        self.rollout_policy = policy;
+    }
+
+    /// Have random extractions always pick the member of a class with the
+    /// lowest cost in `bounds`, taking the first of any that tie, rather than
+    /// a random one.
+    pub(crate) fn set_greedy_rollouts(&mut self, bounds: Arc<CostBounds<E>>) {
+        self.set_cost_bounds(bounds);
+        self.greedy_rollouts = true;
+    }
+
+    pub(crate) fn greedy_rollouts(&self) -> bool {
+        self.greedy_rollouts
+    }
+
+    /// Truncate random extractions once they have assigned `max_depth`
+    /// classes.
+    pub(crate) fn set_rollout_max_depth(&mut self, max_depth: Option<usize>) {
+        self.rollout_max_depth = max_depth;
+    }
+
+    /// Cut random extractions short once the state has assigned or skipped
+    /// `max_assigned` classes and more are left to assign.
+    ///
+    /// Only an egraph with more reachable classes than `max_assigned` can get
+    /// that far, so this guards against egraphs that do not match the analysis
+    /// the search was given.
+    pub(crate) fn set_max_assigned(&mut self, max_assigned: usize) {
+        self.max_assigned = Some(max_assigned);
+    }
+
+    pub(crate) fn max_assigned(&self) -> Option<usize> {
+        self.max_assigned
+    }
+
+    /// Visit the new classes added by each node in the order given by
+    /// `visit_order`, which maps classes to their positions.
+    pub(crate) fn set_visit_order(&mut self, visit_order: Arc<FxHashMap<E::ClassId, usize>>) {
+        self.visit_order = Some(visit_order);
+    }
+
+    pub(crate) fn cost_bounds(&self) -> Option<Arc<CostBounds<E>>> {
+        self.bounds.clone()
+    }
+
+    /// Whether every completion of the current assignment is known to cost at
+    /// least `cost_ceiling`.
+    ///
+    /// This is always false if we are not tracking cost bounds.
+    pub(crate) fn cannot_beat(&self, cost_ceiling: Option<Utility>) -> bool {
+        self.bounds.is_some()
+            && cost_ceiling.is_some_and(|ceiling| self.cost_lower_bound >= ceiling)
+    }
+
+    /// The cost bound for a class that may need to be assigned.
+    fn class_bound(&self, class: &E::ClassId) -> Utility {
+        match &self.bounds {
+            Some(bounds) if !self.optional.contains(class) => bounds.class_bound(class),
+            _ => Utility::default(),
+        }
+    }
     pub(crate) fn push_snapshot(&mut self) {
         self.snapshots.push(StateSnapshot {
             assign_len: self.assign.len(),
             pending: self.pending.save_snapshot(),
+            cost_lower_bound: self.cost_lower_bound,
         });
     }
 
@@ -120,12 +659,96 @@ impl<E: Egraph> ExtractionState<E> {
             return;
         };
         self.assign.truncate(snapshot.assign_len);
+        if let Some(quotas) = &self.quotas {
+            let undone = self
+                .pending
+                .provisional_assign
+                .values()
+                .skip(snapshot.pending.assign_len);
+            for slot in undone.filter_map(|node| quotas.slot(node)) {
+                self.quota_usage[slot] -= 1;
+            }
+        }
+        self.cost_lower_bound = snapshot.cost_lower_bound;
         self.pending
-            .restore(&snapshot.pending, &mut self.assign, egraph);
+            .restore(&snapshot.pending, &mut self.assign, &self.optional, egraph);
+        self.resets_until_shrink -= 1;
+        if self.resets_until_shrink == 0 {
+            self.resets_until_shrink = SHRINK_INTERVAL;
+            shrink_excess(&mut self.assign);
+            shrink_excess(&mut self.pending.provisional_assign);
+            shrink_excess(&mut self.pending.deps.data);
+        }
+    }
+
+    /// The total capacity of the maps that grow with the assignment.
+    #[cfg(test)]
+    pub(crate) fn capacity(&self) -> usize {
+        self.assign.capacity()
+            + self.pending.provisional_assign.capacity()
+            + self.pending.deps.data.capacity()
     }
     pub(crate) fn pop_snapshot(&mut self) {
         self.snapshots.pop();
     }
+    /// The nodes that have been provisionally assigned, but are still waiting
+    /// for some of their children.
+    ///
+    /// The assignment can only be completed once this is empty. A node that
+    /// stays here after every class has been visited is part of a cycle.
+    pub(crate) fn pending_nodes(&self) -> Vec<WatchedNode<E::ClassId, E::NodeId>> {
+        let unresolved =
+            |dep: &E::ClassId| !self.assign.contains_key(dep) && !self.pending.holes.contains(dep);
+        self.pending
+            .deps
+            .data
+            .iter()
+            .flat_map(|(watching, listeners)| {
+                listeners.iter().map(move |pending| WatchedNode {
+                    class: pending.class.clone(),
+                    node: pending.node.clone(),
+                    watching: watching.clone(),
+                    unresolved: pending
+                        .deps
+                        .iter()
+                        .enumerate()
+                        .filter(|(i, dep)| unresolved(dep) && !pending.deps[..*i].contains(dep))
+                        .count(),
+                })
+            })
+            .collect()
+    }
+
+    /// The number of classes assigned so far, including those whose nodes are
+    /// still waiting for some of their children.
+    pub(crate) fn n_assigned(&self) -> usize {
+        self.pending.provisional_assign.len()
+    }
+
+    /// The number of unassigned classes reachable from the classes left to
+    /// visit, by following the children of any member.
+    ///
+    /// This bounds how many more classes an assignment can take: assigning a
+    /// class only ever shrinks it.
+    pub(crate) fn n_reachable_unassigned(&self, egraph: &E) -> usize {
+        let mut seen = self.pending.to_visit_set.clone();
+        let mut to_visit: Vec<_> = seen.iter().cloned().collect();
+        while let Some(class) = to_visit.pop() {
+            for node in egraph.members(&class) {
+                for child in egraph.children(node) {
+                    if !egraph.is_opaque(child)
+                        && !self.pending.provisional_assign.contains_key(child)
+                        && !self.pending.holes.contains(child)
+                        && seen.insert(child.clone())
+                    {
+                        to_visit.push(child.clone());
+                    }
+                }
+            }
+        }
+        seen.len()
+    }
+
     pub(crate) fn complete_assignment(&self) -> Option<&Assignment<E>> {
         if self.pending.n_remaining == 0 && self.pending.to_visit_set.is_empty() {
             Some(&self.assign)
@@ -134,26 +757,50 @@ impl<E: Egraph> ExtractionState<E> {
         }
     }
     fn provisional_assign(&mut self, class: E::ClassId, node: E::NodeId, egraph: &E) {
-        self.pending.to_visit_set.remove(&class);
         self.pending
             .provisional_assign
             .insert(class.clone(), node.clone());
+        if let Some(bounds) = &self.bounds {
+            self.cost_lower_bound += bounds.node_cost(&node);
+        }
+        if let Some(slot) = self.quotas.as_ref().and_then(|quotas| quotas.slot(&node)) {
+            self.quota_usage[slot] += 1;
+        }
         self.pending.n_remaining += 1;
         self.pending.n_remaining -= self.pending.deps.track_pending_assignment(
             node.clone(),
             class,
             &mut self.assign,
-            egraph.children(&node).cloned(),
+            &self.pending.holes,
+            egraph
+                .children(&node)
+                .filter(|child| !egraph.is_opaque(child))
+                .cloned(),
         );
-        for child in egraph
+        let mut children: SmallVec<[&E::ClassId; 4]> = egraph
             .children(&node)
-            .filter(|x| !self.assign.contains_key(*x))
-        {
-            self.pending.push_to_visit(child.clone());
+            .filter(|x| !self.assign.contains_key(*x) && !egraph.is_opaque(x))
+            .collect();
+        if let Some(order) = &self.visit_order {
+            children.sort_by_key(|child| order.get(*child).copied().unwrap_or(usize::MAX));
+        }
+        for child in children {
+            if self.pending.push_to_visit(child.clone(), &self.optional) {
+                self.cost_lower_bound += self.class_bound(child);
+            }
         }
     }
 
-    pub(crate) fn start_next_assign(&mut self) -> Option<AssignHandle<E>> {
+    /// Start assigning the next pending class.
+    ///
+    /// Once only optional classes are left to visit, they are all skipped and
+    /// this returns `None`.
+    pub(crate) fn start_next_assign(&mut self) -> Option<AssignHandle<'_, E>> {
+        if self.pending.n_mandatory_to_visit == 0 {
+            while !self.pending.to_visit_set.is_empty() {
+                self.pending.skip_next(&mut self.assign, &self.optional);
+            }
+        }
         let next = self.pending.to_visit.front()?;
         assert!(
             self.pending.to_visit_set.contains(next),
@@ -176,21 +823,64 @@ struct PendingState<E: Egraph> {
     /// A queue of classes to visit, along with a set to prevent duplicates.
     to_visit: BacktrackQueue<E::ClassId>,
     to_visit_set: FxHashSet<E::ClassId>,
+    /// The number of mandatory classes in `to_visit`.
+    n_mandatory_to_visit: usize,
+    /// Optional classes that were skipped. These count as resolved, but do not
+    /// appear in any assignment.
+    holes: IndexSet<E::ClassId, FxBuildHasher>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct PendingStateSnapshot {
     assign_len: usize,
     n_remaining: usize,
     to_visit: QueueSnapshot,
+    holes_len: usize,
 }
 
 impl<E: Egraph> PendingState<E> {
-    fn push_to_visit(&mut self, class: E::ClassId) {
-        if !self.provisional_assign.contains_key(&class) && self.to_visit_set.insert(class.clone())
+    /// Queue `class` to be visited, returning false if it was already queued
+    /// or assigned.
+    fn push_to_visit(&mut self, class: E::ClassId, optional: &FxHashSet<E::ClassId>) -> bool {
+        if !self.provisional_assign.contains_key(&class)
+            && !self.holes.contains(&class)
+            && self.to_visit_set.insert(class.clone())
         {
+            if !optional.contains(&class) {
+                self.n_mandatory_to_visit += 1;
+            }
             self.to_visit.push_back(class);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Forget every assignment and class to visit, keeping the allocations.
+    fn clear(&mut self) {
+        self.provisional_assign.clear();
+        self.n_remaining = 0;
+        self.deps.clear();
+        self.to_visit.clear();
+        self.to_visit_set.clear();
+        self.n_mandatory_to_visit = 0;
+        self.holes.clear();
+    }
+
+    fn pop_to_visit(&mut self, optional: &FxHashSet<E::ClassId>) -> E::ClassId {
+        let class = self.to_visit.pop_front().unwrap();
+        self.to_visit_set.remove(&class);
+        if !optional.contains(&class) {
+            self.n_mandatory_to_visit -= 1;
         }
+        class
+    }
+
+    /// Leave the next class to visit unassigned.
+    fn skip_next(&mut self, assign: &mut Assignment<E>, optional: &FxHashSet<E::ClassId>) {
+        let class = self.pop_to_visit(optional);
+        self.holes.insert(class.clone());
+        self.n_remaining -= self.deps.resolve_dep(class, assign, &self.holes);
     }
 
     fn save_snapshot(&self) -> PendingStateSnapshot {
@@ -198,6 +888,7 @@ impl<E: Egraph> PendingState<E> {
             assign_len: self.provisional_assign.len(),
             n_remaining: self.n_remaining,
             to_visit: self.to_visit.snapshot(),
+            holes_len: self.holes.len(),
         }
     }
 
@@ -205,13 +896,19 @@ impl<E: Egraph> PendingState<E> {
         &mut self,
         snapshot: &PendingStateSnapshot,
         full_assign: &mut Assignment<E>,
+        optional: &FxHashSet<E::ClassId>,
         egraph: &E,
     ) {
         self.provisional_assign.truncate(snapshot.assign_len);
         self.n_remaining = snapshot.n_remaining;
+        self.holes.truncate(snapshot.holes_len);
         self.to_visit.restore(&snapshot.to_visit);
         self.to_visit_set.clear();
+        self.n_mandatory_to_visit = 0;
         for entry in self.to_visit.iter() {
+            if !optional.contains(entry) {
+                self.n_mandatory_to_visit += 1;
+            }
             self.to_visit_set.insert(entry.clone());
         }
         self.deps.clear();
@@ -224,7 +921,11 @@ impl<E: Egraph> PendingState<E> {
                     node.clone(),
                     class.clone(),
                     full_assign,
-                    egraph.children(node).cloned(),
+                    &self.holes,
+                    egraph
+                        .children(node)
+                        .filter(|child| !egraph.is_opaque(child))
+                        .cloned(),
                 ),
                 0
             );
@@ -232,6 +933,14 @@ impl<E: Egraph> PendingState<E> {
     }
 }
 
+/// Release most of `map`'s spare capacity if it has far more than it needs.
+fn shrink_excess<K: Hash + Eq, V, S: BuildHasher>(map: &mut IndexMap<K, V, S>) {
+    let retained = cmp::max(map.len(), MIN_RETAINED_CAPACITY);
+    if map.capacity() > SHRINK_RATIO * retained {
+        map.shrink_to(SHRINK_RATIO / 2 * retained);
+    }
+}
+
 pub(crate) struct AssignHandle<'a, E: Egraph> {
     state: &'a mut ExtractionState<E>,
 }
@@ -241,9 +950,66 @@ impl<E: Egraph> AssignHandle<'_, E> {
         self.state.pending.to_visit.front().unwrap()
     }
     pub(crate) fn assign(self, node: E::NodeId, egraph: &E) {
-        let class = self.state.pending.to_visit.pop_front().unwrap();
+        let class = self.state.pending.pop_to_visit(&self.state.optional);
+        self.state.cost_lower_bound -= self.state.class_bound(&class);
         self.state.provisional_assign(class, node, egraph);
     }
+    /// Whether the class's member `node` fits within the assignment's quotas
+    /// and implications.
+    pub(crate) fn allows(&self, node: &E::NodeId) -> bool {
+        self.state.allows(node)
+    }
+    /// Whether some members of the class may not fit within the assignment's
+    /// quotas or implications.
+    pub(crate) fn has_constraints(&self) -> bool {
+        self.state.quotas.is_some() || self.state.implications.is_some()
+    }
+    pub(crate) fn rollout_policy(&self) -> RolloutPolicy {
+        self.state.rollout_policy
+    }
+    /// The cost of the class's member `node`, or zero without node costs.
+    pub(crate) fn node_cost(&self, node: &E::NodeId) -> Utility {
+        self.state
+            .bounds
+            .as_ref()
+            .map_or(Utility::default(), |bounds| bounds.node_cost(node))
+    }
+    /// The number of children of `node` that assigning it would add to the
+    /// classes left to visit.
+    pub(crate) fn new_classes(&self, node: &E::NodeId, egraph: &E) -> usize {
+        let pending = &self.state.pending;
+        egraph
+            .children(node)
+            .filter(|child| {
+                !egraph.is_opaque(child)
+                    && !pending.provisional_assign.contains_key(*child)
+                    && !pending.holes.contains(*child)
+                    && !pending.to_visit_set.contains(*child)
+            })
+            .count()
+    }
+    /// Whether the class may be left unassigned.
+    pub(crate) fn is_optional(&self) -> bool {
+        self.state.optional.contains(self.class())
+    }
+    /// Leave the (optional) class unassigned.
+    pub(crate) fn skip(self) {
+        debug_assert!(self.is_optional(), "skipping mandatory class");
+        let state = self.state;
+        state.pending.skip_next(&mut state.assign, &state.optional);
+    }
+    /// The cost of leaving the class out of a random extraction, if it may
+    /// be left out.
+    pub(crate) fn empty_class_penalty(&self) -> Option<Utility> {
+        self.state.empty_class_penalty.as_ref()?(self.class())
+    }
+    /// Leave the class unassigned even though it is mandatory, leaving a hole
+    /// in the assignment.
+    pub(crate) fn leave_out(self) {
+        let state = self.state;
+        state.cost_lower_bound -= state.class_bound(state.pending.to_visit.front().unwrap());
+        state.pending.skip_next(&mut state.assign, &state.optional);
+    }
 }
 
 struct PendingNode<E: Egraph> {
@@ -260,22 +1026,42 @@ impl<E: Egraph> Deps<E> {
     fn clear(&mut self) {
         self.data.clear();
     }
-    fn resolve_dep(&mut self, class: E::ClassId, assign: &mut Assignment<E>) -> usize {
+    fn resolve_dep(
+        &mut self,
+        class: E::ClassId,
+        assign: &mut Assignment<E>,
+        holes: &IndexSet<E::ClassId, FxBuildHasher>,
+    ) -> usize {
         // look at all pending nodes listening on the newly-resolved class.
+        //
+        // NB: resolving one class can resolve a long chain of others, so we
+        // use an explicit stack of the pending nodes left to look at rather
+        // than recursing. This visits nodes in the same order as a recursive
+        // depth-first traversal would.
         let mut assigned = 0;
-        let Some(pending) = self.data.swap_remove(&class) else {
-            return assigned;
-        };
-        for mut pending in pending {
-            pending.deps.retain(|dep| !assign.contains_key(dep));
+        let mut stack = Vec::new();
+        stack.extend(self.data.swap_remove(&class).map(IntoIterator::into_iter));
+        while let Some(listeners) = stack.last_mut() {
+            let Some(mut pending) = listeners.next() else {
+                stack.pop();
+                continue;
+            };
+            pending
+                .deps
+                .retain(|dep| !assign.contains_key(dep) && !holes.contains(dep));
             if let Some(first) = pending.deps.first() {
                 // Start watching another unresolved dependency.
                 self.data.entry(first.clone()).or_default().push(pending);
             } else {
                 // This was the last pending dependency for this node, so we can
-                // safely assign it.
+                // safely assign it, then look at the nodes listening on it.
                 assign.insert(pending.class.clone(), pending.node);
-                assigned += self.resolve_dep(pending.class, assign) + 1;
+                assigned += 1;
+                stack.extend(
+                    self.data
+                        .swap_remove(&pending.class)
+                        .map(IntoIterator::into_iter),
+                );
             }
         }
         assigned
@@ -286,16 +1072,17 @@ impl<E: Egraph> Deps<E> {
         node: E::NodeId,
         class: E::ClassId,
         assign: &mut Assignment<E>,
+        holes: &IndexSet<E::ClassId, FxBuildHasher>,
         deps: impl Iterator<Item = E::ClassId>,
     ) -> usize {
         let deps = deps
-            .filter(|x| !assign.contains_key(x))
+            .filter(|x| !assign.contains_key(x) && !holes.contains(x))
             .collect::<SmallVec<[_; 2]>>();
         let Some(dep) = deps.first() else {
             // No pending dependencies! Make the final assignment to the node
             // and update any other provisional assignments that depend on it.
             assign.insert(class.clone(), node);
-            return self.resolve_dep(class, assign) + 1;
+            return self.resolve_dep(class, assign, holes) + 1;
         };
         self.data.entry(dep.clone()).or_default().push(PendingNode {
             node: node.clone(),
@@ -306,6 +1093,64 @@ impl<E: Egraph> Deps<E> {
     }
 }
 
+// NB: we implement Clone by hand for the types below to avoid deriving an
+// `E: Clone` bound; only the id types need to be cloneable.
+
+impl<E: Egraph> Clone for ExtractionState<E> {
+    fn clone(&self) -> Self {
+        Self {
+            assign: self.assign.clone(),
+            pending: self.pending.clone(),
+            snapshots: self.snapshots.clone(),
+            optional: self.optional.clone(),
+            bounds: self.bounds.clone(),
+            cost_lower_bound: self.cost_lower_bound,
+            quotas: self.quotas.clone(),
+            quota_usage: self.quota_usage.clone(),
+            implications: self.implications.clone(),
+            empty_class_penalty: self.empty_class_penalty.clone(),
+            rollout_policy: self.rollout_policy,
+            greedy_rollouts: self.greedy_rollouts,
+            rollout_max_depth: self.rollout_max_depth,
+            max_assigned: self.max_assigned,
+            visit_order: self.visit_order.clone(),
+            resets_until_shrink: self.resets_until_shrink,
+        }
+    }
+}
+
+impl<E: Egraph> Clone for PendingState<E> {
+    fn clone(&self) -> Self {
+        Self {
+            provisional_assign: self.provisional_assign.clone(),
+            n_remaining: self.n_remaining,
+            deps: self.deps.clone(),
+            to_visit: self.to_visit.clone(),
+            to_visit_set: self.to_visit_set.clone(),
+            n_mandatory_to_visit: self.n_mandatory_to_visit,
+            holes: self.holes.clone(),
+        }
+    }
+}
+
+impl<E: Egraph> Clone for PendingNode<E> {
+    fn clone(&self) -> Self {
+        Self {
+            node: self.node.clone(),
+            class: self.class.clone(),
+            deps: self.deps.clone(),
+        }
+    }
+}
+
+impl<E: Egraph> Clone for Deps<E> {
+    fn clone(&self) -> Self {
+        Self {
+            data: self.data.clone(),
+        }
+    }
+}
+
 impl<E: Egraph> Default for PendingState<E> {
     fn default() -> Self {
         Self {
@@ -314,6 +1159,8 @@ impl<E: Egraph> Default for PendingState<E> {
             to_visit: Default::default(),
             to_visit_set: Default::default(),
             n_remaining: 0,
+            n_mandatory_to_visit: 0,
+            holes: Default::default(),
         }
     }
 }
@@ -0,0 +1,170 @@
+//! Extracting several roots at once, as a single term.
+
+use fxhash::FxHashMap;
+
+use crate::{Assignment, Egraph, EgraphTotalCost, Utility};
+
+/// A class of a [`Forest`]: either the synthetic root, or a class of the
+/// underlying egraph.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum ForestClass<C> {
+    Root,
+    Class(C),
+}
+
+/// A node of a [`Forest`]: either the single member of the synthetic root,
+/// or a node of the underlying egraph.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum ForestNode<N> {
+    Root,
+    Node(N),
+}
+
+/// An egraph with an extra root class, whose only node has each of a set of
+/// classes of an underlying egraph as its children.
+///
+/// Extracting the extra root extracts a term for each of the roots at once.
+/// Because an assignment picks one node per class, subterms shared between the
+/// roots appear in it once, and the underlying cost model sees the whole
+/// forest in a single call.
+///
+/// The ids of the underlying egraph have to be wrapped, and
+/// [`Egraph::children`] hands out references to them, so the part of the
+/// underlying egraph reachable from the roots is copied up front.
+pub(crate) struct Forest<'a, E: Egraph> {
+    egraph: &'a E,
+    /// The roots, as classes of the underlying egraph.
+    class_roots: Vec<E::ClassId>,
+    roots: Vec<ForestClass<E::ClassId>>,
+    root_members: [ForestNode<E::NodeId>; 1],
+    members: FxHashMap<E::ClassId, Vec<ForestNode<E::NodeId>>>,
+    children: FxHashMap<E::NodeId, Vec<ForestClass<E::ClassId>>>,
+}
+
+impl<'a, E: Egraph> Forest<'a, E> {
+    pub(crate) fn new(egraph: &'a E, roots: impl IntoIterator<Item = E::ClassId>) -> Self {
+        let roots = roots.into_iter().collect::<Vec<_>>();
+        let mut members = FxHashMap::default();
+        let mut children = FxHashMap::default();
+        let mut to_visit = roots.clone();
+        while let Some(class) = to_visit.pop() {
+            if members.contains_key(&class) || egraph.is_opaque(&class) {
+                continue;
+            }
+            let nodes = egraph.members(&class).cloned().collect::<Vec<_>>();
+            for node in &nodes {
+                if children.contains_key(node) {
+                    continue;
+                }
+                let node_children = egraph.children(node).cloned().collect::<Vec<_>>();
+                to_visit.extend(node_children.iter().cloned());
+                children.insert(
+                    node.clone(),
+                    node_children.into_iter().map(ForestClass::Class).collect(),
+                );
+            }
+            members.insert(class, nodes.into_iter().map(ForestNode::Node).collect());
+        }
+        Self {
+            egraph,
+            roots: roots.iter().cloned().map(ForestClass::Class).collect(),
+            class_roots: roots,
+            root_members: [ForestNode::Root],
+            members,
+            children,
+        }
+    }
+
+    /// The assignment for the underlying egraph that `assignment` describes.
+    pub(crate) fn unwrap_assignment(&self, assignment: &Assignment<Self>) -> Assignment<E> {
+        assignment
+            .iter()
+            .filter_map(|(class, node)| match (class, node) {
+                (ForestClass::Class(class), ForestNode::Node(node)) => {
+                    Some((class.clone(), node.clone()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+impl<E: Egraph> Egraph for Forest<'_, E> {
+    type ClassId = ForestClass<E::ClassId>;
+    type NodeId = ForestNode<E::NodeId>;
+
+    fn children(&self, id: &Self::NodeId) -> impl Iterator<Item = &Self::ClassId> {
+        match id {
+            ForestNode::Root => self.roots.iter(),
+            ForestNode::Node(node) => self.children[node].iter(),
+        }
+    }
+
+    fn members(&self, id: &Self::ClassId) -> impl Iterator<Item = &Self::NodeId> {
+        self.members_slice(id).unwrap().iter()
+    }
+
+    fn members_slice(&self, id: &Self::ClassId) -> Option<&[Self::NodeId]> {
+        Some(match id {
+            ForestClass::Root => &self.root_members,
+            ForestClass::Class(class) => self.members.get(class).map_or(&[], Vec::as_slice),
+        })
+    }
+
+    fn ordered_children(&self, id: &Self::NodeId) -> Option<&[Self::ClassId]> {
+        Some(match id {
+            ForestNode::Root => &self.roots,
+            ForestNode::Node(node) => &self.children[node],
+        })
+    }
+
+    fn is_opaque(&self, id: &Self::ClassId) -> bool {
+        match id {
+            ForestClass::Root => false,
+            ForestClass::Class(class) => self.egraph.is_opaque(class),
+        }
+    }
+
+    fn node_weight(&self, id: &Self::NodeId) -> f32 {
+        match id {
+            ForestNode::Root => 1.0,
+            ForestNode::Node(node) => self.egraph.node_weight(node),
+        }
+    }
+
+    fn has_node_weights(&self) -> bool {
+        self.egraph.has_node_weights()
+    }
+
+    fn topo_hint(&self) -> Option<Vec<Self::ClassId>> {
+        let mut hint: Vec<_> = self
+            .egraph
+            .topo_hint()?
+            .into_iter()
+            .map(ForestClass::Class)
+            .collect();
+        hint.push(ForestClass::Root);
+        Some(hint)
+    }
+}
+
+impl<E: EgraphTotalCost> EgraphTotalCost for Forest<'_, E> {
+    type CostCtx = E::CostCtx;
+
+    /// The underlying egraph's context, for all of the roots.
+    fn precompute(&self, _: &[Self::ClassId]) -> Self::CostCtx {
+        self.egraph.precompute(&self.class_roots)
+    }
+
+    fn assignment_utility(&self, ctx: &Self::CostCtx, assignment: &Assignment<Self>) -> Utility {
+        self.egraph
+            .assignment_utility(ctx, &self.unwrap_assignment(assignment))
+    }
+
+    fn opaque_cost(&self, class: &Self::ClassId) -> Utility {
+        match class {
+            ForestClass::Root => Utility::default(),
+            ForestClass::Class(class) => self.egraph.opaque_cost(class),
+        }
+    }
+}
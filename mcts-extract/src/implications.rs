@@ -0,0 +1,78 @@
+//! Requirements that assigning one node to a class places on another class.
+
+use fxhash::FxHashMap;
+
+use crate::{Assignment, Egraph};
+
+/// A set of implications of the form "if `class` is assigned `node`, then
+/// `required_class` must be assigned `required_node`".
+///
+/// Implications are checked against the assignment as it stands, whichever
+/// of the two classes is assigned first, so they need no state of their own.
+pub(crate) struct Implications<E: Egraph> {
+    /// For each assignment of a node to a class, the assignments it requires.
+    requires: FxHashMap<Decision<E>, Vec<Decision<E>>>,
+    /// For each class, the assignments that require something of it.
+    required_of: FxHashMap<E::ClassId, Vec<Decision<E>>>,
+}
+
+/// The assignment of a node to a class.
+type Decision<E> = (<E as Egraph>::ClassId, <E as Egraph>::NodeId);
+
+impl<E: Egraph> Default for Implications<E> {
+    fn default() -> Self {
+        Self {
+            requires: Default::default(),
+            required_of: Default::default(),
+        }
+    }
+}
+
+impl<E: Egraph> Clone for Implications<E> {
+    fn clone(&self) -> Self {
+        Self {
+            requires: self.requires.clone(),
+            required_of: self.required_of.clone(),
+        }
+    }
+}
+
+impl<E: Egraph> Implications<E> {
+    /// Require `then` to be assigned whenever `when` is.
+    pub(crate) fn add(&mut self, when: Decision<E>, then: Decision<E>) {
+        self.required_of
+            .entry(then.0.clone())
+            .or_default()
+            .push(when.clone());
+        self.requires.entry(when).or_default().push(then);
+    }
+
+    /// Whether assigning `node` to `class` is consistent with the rest of
+    /// `assign`: every assignment it requires has not been contradicted, and
+    /// it meets what the assignments made so far require of `class`.
+    pub(crate) fn allows(
+        &self,
+        assign: &Assignment<E>,
+        class: &E::ClassId,
+        node: &E::NodeId,
+    ) -> bool {
+        let required = |when: &Decision<E>| self.requires.get(when).into_iter().flatten();
+        let meets_own = required(&(class.clone(), node.clone())).all(|(other, required)| {
+            let assigned = if other == class {
+                Some(node)
+            } else {
+                assign.get(other)
+            };
+            assigned.is_none_or(|assigned| assigned == required)
+        });
+        meets_own
+            && self
+                .required_of
+                .get(class)
+                .into_iter()
+                .flatten()
+                .filter(|(when_class, when_node)| assign.get(when_class) == Some(when_node))
+                .flat_map(required)
+                .all(|(other, required)| other != class || required == node)
+    }
+}
@@ -1,40 +1,630 @@
 //! A library for performing egraph extraction using Monte-Carlo Tree Search.
+#![warn(missing_debug_implementations)]
 
-use std::{fmt::Debug, hash::Hash};
+use std::{
+    cmp::Ordering,
+    fmt::{self, Debug},
+    hash::{Hash, Hasher},
+    sync::{atomic::AtomicBool, Arc},
+};
 
-use extraction_state::{random_cost_estimate, ExtractionState};
-use fxhash::FxBuildHasher;
+use analysis::extraction_ranks;
+use estimate::AlgebraRolloutEstimator;
+use extraction_state::{ExtractionState, StatePool};
+use forest::{Forest, ForestClass};
+use fxhash::{FxBuildHasher, FxHashMap, FxHashSet, FxHasher};
 use indexmap::IndexMap;
 use ordered_float::NotNan;
-use rand::thread_rng;
+use rand::{rngs::StdRng, thread_rng, Rng, RngCore, SeedableRng};
 use search_tree::SearchTree;
+use smallvec::SmallVec;
 
+pub(crate) mod algebra;
+pub(crate) mod analysis;
+#[cfg(feature = "async")]
+pub(crate) mod async_extract;
 pub(crate) mod backtrack_queue;
+pub(crate) mod choice_graph;
+pub(crate) mod choices;
+pub(crate) mod cost;
+pub(crate) mod estimate;
 pub(crate) mod extraction_state;
+pub(crate) mod forest;
+pub(crate) mod implications;
+#[cfg(test)]
+mod model_check;
+pub(crate) mod penalties;
+pub(crate) mod quotas;
+pub(crate) mod random_egraph;
 pub(crate) mod search_tree;
+pub(crate) mod selection;
+pub(crate) mod session;
+#[cfg(test)]
 pub(crate) mod simple_egraph;
+pub(crate) mod termination;
 #[cfg(test)]
 mod tests;
+pub(crate) mod timing;
+#[cfg(feature = "trace")]
+pub(crate) mod trace;
+
+pub use algebra::CostAlgebra;
+pub use analysis::{
+    analyze, extractable_classes, optimal_dag_extract, reachable_classes, Analysis, ProblemStats,
+};
+#[cfg(feature = "async")]
+pub use async_extract::{mcts_extract_async, CancelToken};
+pub use choice_graph::ChoiceGraph;
+pub use cost::{assignment_depth, expected_cost, CriticalPathCost};
+pub use extraction_state::{DeadEnd, WatchedNode};
+pub use penalties::{Materialized, PatternPenalties, PatternPenalty, SoftPenalties};
+pub use random_egraph::gen_random_egraph;
+pub use search_tree::{CandidateStats, DeadEnds, SearchProgress, SearchStats, StepOutcome};
+pub use selection::{ChildStat, ExplorationSchedule, ParentStat, SelectionPolicy, Ucb1};
+pub use session::SearchSession;
+pub use termination::{
+    And, Deadline, MaxMemoryBytes, MaxPlayouts, MaxRounds, NoImprovement, Or, TargetUtility,
+    TerminationPolicy,
+};
+pub use timing::{Clock, SearchTimings, StdClock};
+#[cfg(feature = "trace")]
+pub use trace::{DecisionRecord, PlayoutRecord, TraceRecorder};
 
 /// Tuning params for the search.
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct MctsConfig {
     /// The number of playouts to run per node in the egraph.
     pub playouts_per_round: usize,
 
     /// The number of terms to sample when estimating the utility of partial
     /// assignments.
+    ///
+    /// Leaves whose assignment is already complete are scored exactly, without
+    /// sampling; [`SearchStats`] counts how many leaves were scored each way.
     pub terms_to_sample: usize,
+
+    /// How the samples for a partial assignment are combined into its
+    /// estimate. See [`SampleAggregation`].
+    pub sample_aggregation: SampleAggregation,
+
+    /// An approximate cap, in bytes, on the memory used by the search tree.
+    ///
+    /// Once the tree grows past this size the search stops and returns the
+    /// best complete assignment it has seen so far (if any). The search
+    /// checks between rounds (see [`MaxMemoryBytes`]).
+    pub max_memory_bytes: Option<usize>,
+
+    /// A flag for cancelling the search from another thread.
+    ///
+    /// The search checks the flag before every playout, with a relaxed atomic
+    /// load, so it stops even in the middle of a round. This is also how
+    /// `mcts_extract_async` is cancelled. Once it is set, the search stops as
+    /// if it had hit one of its limits: [`SearchSession::step`] returns
+    /// [`StepOutcome::Stopped`], and running the search to completion returns
+    /// the best complete assignment it has seen so far (if any). Clones of the
+    /// config share the flag.
+    pub cancel: Option<Arc<AtomicBool>>,
+
+    /// The most rounds of playouts to run (see [`MaxRounds`]).
+    ///
+    /// The search runs a round for every decision it makes, so extracting an
+    /// enormous term can take a very long time. Once this many rounds have
+    /// run, [`SearchSession::step`] returns [`StepOutcome::Stopped`] and
+    /// running the search to completion fails with
+    /// [`ExtractError::BudgetExhausted`]; the decisions made until then are
+    /// left in [`SearchSession::decisions`]. `None`, the default, sets no
+    /// limit.
+    pub max_rounds: Option<usize>,
+
+    /// What the search is trying to maximize.
+    pub objective: SearchObjective,
+
+    /// Utilities that differ by at most this much are considered equal when
+    /// committing to a node or tracking the best assignment seen so far.
+    ///
+    /// Ties are broken deterministically: nodes with equal visit counts and
+    /// utilities are committed in the tree's iteration order, and the earliest
+    /// assignment seen is kept as the best, unless
+    /// [`tiebreak`](Self::tiebreak) says otherwise.
+    pub epsilon: f32,
+
+    /// How to choose between assignments of equal utility. See
+    /// [`Tiebreak`].
+    pub tiebreak: Tiebreak,
+
+    /// Weight each leaf estimate by the number of random samples that
+    /// produced a complete assignment, rather than counting every estimate
+    /// equally.
+    ///
+    /// With this enabled, a node's value is the mean utility over all of the
+    /// successful samples taken beneath it, so estimates backed by few
+    /// successful samples carry less weight. Leaves with a complete assignment
+    /// are evaluated exactly and count as much as `terms_to_sample` successful
+    /// samples. This has no effect with [`SearchObjective::CompleteExtraction`],
+    /// where failed samples are themselves informative.
+    pub weighted_backprop: bool,
+
+    /// Share search tree nodes between every occurrence of the same decision.
+    ///
+    /// By default, the search tree records statistics for assigning a node to
+    /// a class separately for each sequence of decisions leading up to it.
+    /// With this set, all decisions assigning the same node to the same class
+    /// pool their statistics, no matter where in the tree they occur. This
+    /// makes the tree much smaller and lets it learn faster when a class's
+    /// best node does not depend on the rest of the term, but it changes the
+    /// semantics of the search: the statistics for a decision no longer
+    /// describe a single partial assignment.
+    pub transposition_table: bool,
+
+    /// The most search tree nodes to create for any one class before
+    /// decisions for it start sharing nodes.
+    ///
+    /// Without [`transposition_table`](Self::transposition_table), the tree
+    /// has a separate node for every sequence of decisions leading to a
+    /// class, so a class reached along many paths can take up a lot of the
+    /// tree. Once a class has this many nodes, assigning it a node it has
+    /// been assigned before reuses the first tree node for that decision, as
+    /// a transposition table would. A class thus ends up with at most this
+    /// many nodes plus one per member. With a transposition table, classes
+    /// already have at most one node per member. `None`, the default, sets no
+    /// limit.
+    pub max_tree_nodes_per_class: Option<usize>,
+
+    /// How utilities from the leaves of the search tree are aggregated into
+    /// the value of the nodes above them.
+    pub backup: Backup,
+
+    /// Commit to a decision early once its most-visited choice has received
+    /// more than this fraction of the visits to its siblings.
+    ///
+    /// `playouts_per_round` remains the upper bound on the playouts spent on
+    /// each decision; with a threshold set, the round ends as soon as the
+    /// decision becomes lopsided enough, which saves most of the budget on
+    /// "obvious" decisions such as classes with a single member. Lower
+    /// thresholds commit sooner but are more easily swayed by early noise.
+    /// Values of 1.0 or more never end a round early.
+    pub decisiveness_threshold: Option<f32>,
+
+    /// Run extra playouts before committing to a decision whose two best
+    /// choices cannot be told apart yet.
+    ///
+    /// This is the opposite of
+    /// [`decisiveness_threshold`](Self::decisiveness_threshold): rather than
+    /// ending rounds early for easy decisions, it extends them for close ones.
+    /// See [`ConfidenceGate`]. `None`, the default, always commits after
+    /// `playouts_per_round` playouts.
+    pub confidence_gate: Option<ConfidenceGate>,
+
+    /// Which choice to commit to once a decision's playouts are done. See
+    /// [`FinalSelection`].
+    pub final_selection: FinalSelection,
+
+    /// Only return assignments whose cost (negated utility) is strictly below
+    /// this value.
+    ///
+    /// If nothing the search sees beats the ceiling, extraction returns
+    /// `None`. With node costs available (see
+    /// [`SearchSession::prune_with_node_costs`]), the search also abandons
+    /// descents and random samples once the cost of the nodes assigned so far,
+    /// plus the cheapest node of each class still to assign, reaches the
+    /// ceiling. When maximizing [`SearchObjective::Utility`], abandoned
+    /// descents and samples are scored as if they had met the ceiling exactly,
+    /// as are samples that fail for any other reason.
+    pub cost_ceiling: Option<Utility>,
+
+    /// Also abandon random samples once they cannot beat the best complete
+    /// assignment seen so far.
+    ///
+    /// This extends [`cost_ceiling`](Self::cost_ceiling) to the cost of the
+    /// best assignment found, for random samples only: a sample is abandoned
+    /// as soon as its cost bound reaches that cost, and scored as the
+    /// configured ceiling if there is one, or as the cost bound it reached
+    /// otherwise. This saves finishing samples that can only lose. It needs
+    /// node costs to bound partial assignments (see
+    /// [`SearchSession::prune_with_node_costs`]), and has no effect without
+    /// them. Off by default.
+    pub prune_rollouts_by_best: bool,
+
+    /// How many samples a prior from the egraph's node costs is worth.
+    ///
+    /// With node costs available (see
+    /// [`SearchSession::prune_with_node_costs`]), each new search tree node
+    /// starts out with a value of minus the lower bound on the cost of the
+    /// assignment it stands for, as if that many samples had already been
+    /// averaged into it. Before a node's own samples outweigh the prior, this
+    /// steers selection towards nodes that look cheap. The prior only applies
+    /// to [`Backup::Mean`]. The default of 0.0 disables it.
+    pub prior_strength: f32,
+
+    /// Let the random samples for the children of a search tree node reuse
+    /// each other's choices.
+    ///
+    /// When the search estimates the siblings of a node one after the other,
+    /// each of their samples picks the same node for a class as the
+    /// corresponding sample for an earlier sibling did, where it can. This
+    /// saves random choices at nodes with many children, and compares the
+    /// siblings on matching completions, at the price of less varied
+    /// samples. Off by default.
+    pub share_sibling_rollouts: bool,
+
+    /// How random samples pick between the members of a class. See
+    /// [`RolloutPolicy`].
+    pub rollout_policy: RolloutPolicy,
+
+    /// The most classes a random sample may assign before it is cut short.
+    ///
+    /// This bounds the cost of each sample on deep or cyclic egraphs, however
+    /// deep the search tree itself gets. Truncated samples count as failed
+    /// ones: when maximizing [`SearchObjective::Utility`] they are scored as
+    /// meeting the [`cost_ceiling`](Self::cost_ceiling) if one is set, and as
+    /// zero utility otherwise, and with
+    /// [`weighted_backprop`](Self::weighted_backprop) they carry no weight. A
+    /// tight limit therefore drags down the estimates of nodes whose
+    /// completions are large. `None`, the default, sets no limit.
+    pub rollout_max_depth: Option<usize>,
+
+    /// How much worse than its parent an unvisited child of a search tree
+    /// node is assumed to be.
+    ///
+    /// Before a child has been visited, its UCT score uses its parent's
+    /// current value minus this reduction in place of its own (this is known
+    /// as "first-play urgency"). The default of 0.0 assumes unvisited children
+    /// are as good as their parent, so the search tries each of them once it
+    /// stops finding improvements among the ones it has visited. Larger
+    /// values make the search focus on the children it has already visited.
+    /// The reduction is in units of utility, so it should be scaled to match
+    /// the egraph's utilities. It must not be NaN.
+    pub fpu_reduction: f32,
+
+    /// Scale up the exploration term of unvisited children by the fraction of
+    /// their class's members that are still unvisited, times this factor.
+    ///
+    /// UCT treats every unvisited child the same, no matter how many of its
+    /// siblings have not been tried yet, so at a wide class it can settle on
+    /// the first few members it visits. A positive boost makes the search
+    /// more eager to try new members while many remain, and fades out as the
+    /// class is covered. Large values make the search try every member once
+    /// before revisiting any of them. The default of 0.0 disables the boost.
+    pub expansion_boost: f32,
+
+    /// Vary the exploration constant with the number of visits to the node
+    /// being descended from. By default, the constant stays fixed.
+    pub exploration_schedule: Option<ExplorationSchedule>,
+
+    /// How playouts choose which member of a class to try next, among the
+    /// members the search has statistics for. Defaults to [`Ucb1`].
+    pub selection: Arc<dyn SelectionPolicy>,
+
+    /// How many children of a search tree node are created when a playout
+    /// first reaches it.
+    pub expansion_strategy: ExpansionStrategy,
+
+    /// The most children a playout may add to the search tree when it
+    /// expands a node with [`ExpansionStrategy::Full`].
+    ///
+    /// The members left out are added later, one at a time, by the playouts
+    /// that select them, as with [`ExpansionStrategy::Lazy`]. This smooths
+    /// the growth of the tree at very wide classes. `None`, the default, adds
+    /// a child for every member at once.
+    pub expand_per_visit: Option<usize>,
+
+    /// Stop the search once a round of playouts sees a complete assignment
+    /// with at least this utility, and return that assignment (see
+    /// [`TargetUtility`]).
+    ///
+    /// This is useful when the optimal utility is known ahead of time, or
+    /// when any assignment that is good enough will do.
+    pub target_utility: Option<Utility>,
+
+    /// Throw away the search tree below the current decision every this many
+    /// rounds.
+    ///
+    /// A long search can keep refining an early mistake, because the
+    /// statistics it has already gathered keep steering playouts towards it.
+    /// Restarting lets the search explore the rest of the decision afresh.
+    /// The best assignment seen so far, and the decisions already committed
+    /// to, are kept. `None`, the default, never restarts.
+    pub restart_interval: Option<u32>,
+
+    /// Keep the statistics for the choices at the current decision when
+    /// restarting (see [`restart_interval`](Self::restart_interval)), and
+    /// only throw away the tree below them.
+    pub restart_keeps_root_stats: bool,
+
+    /// Count the distinct complete assignments the search scores, and report
+    /// them in [`SearchStats::unique_assignments`].
+    ///
+    /// This hashes every complete assignment the search sees, so it is off by
+    /// default.
+    pub count_unique_assignments: bool,
+
+    /// Seed the search's random choices, which makes it deterministic.
+    ///
+    /// Seeded searches also let the selection policy break ties at random
+    /// (see [`SelectionPolicy::select_with_rng`]). By default, each search is
+    /// seeded from the operating system, and the selection policy breaks ties
+    /// deterministically.
+    pub seed: Option<u64>,
+
+    /// What to do when the egraph's cost function scores an assignment as
+    /// infinitely good or bad. See [`NonFiniteUtilities`].
+    pub non_finite_utilities: NonFiniteUtilities,
+}
+
+/// Ways of aggregating leaf utilities into the value of a search tree node.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Backup {
+    /// Use the mean utility of the leaves below a node. This is standard for
+    /// MCTS, and is robust to noisy utility estimates.
+    #[default]
+    Mean,
+    /// Use the highest utility seen below a node. When utilities are
+    /// deterministic, this can converge to the optimum much faster than
+    /// `Mean`, because a single good term is not averaged away by the poor
+    /// ones explored near it.
+    Max,
 }
 
+/// Ways of growing the search tree when a playout reaches a new node.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ExpansionStrategy {
+    /// Estimate the new node's utility, and only add children to it as later
+    /// playouts choose them. This keeps the tree small, and spends estimates
+    /// only on the members that selection favors.
+    #[default]
+    Lazy,
+    /// Add a child for every member of the next class at once, and estimate
+    /// each of them. The node's value is then the combination of its
+    /// children's estimates. This costs one estimate per member up front and
+    /// grows the tree faster, but gives selection a value for every member
+    /// right away, which can converge faster when classes are small.
+    Full,
+}
+
+/// When to keep searching a decision whose best choices are too close to call
+/// (see [`MctsConfig::confidence_gate`]).
+///
+/// After a round, the search looks at the two choices it would rank highest,
+/// and at a confidence interval around each of their mean utilities: the
+/// mean plus or minus `z` standard errors. While the intervals overlap, the
+/// search runs more playouts, each through whichever of the two has fewer
+/// visits, up to `max_playouts` extra playouts per decision.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ConfidenceGate {
+    /// The width of the confidence intervals, in standard errors. 1.96 gives
+    /// roughly 95% intervals for normally distributed utilities.
+    pub z: f32,
+    /// The most extra playouts to run for one decision.
+    pub max_playouts: usize,
+}
+
+/// How the search chooses between assignments whose utilities are equal to
+/// within [`MctsConfig::epsilon`] (see [`MctsConfig::tiebreak`]).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Tiebreak {
+    /// Keep the first of the tied assignments seen as the best, and commit to
+    /// tied choices in the tree's iteration order.
+    #[default]
+    FirstSeen,
+    /// Prefer the assignment whose DAG is shallowest: the one with the fewest
+    /// nodes along its longest path (see [`assignment_depth`]).
+    ///
+    /// A tied assignment replaces the best one if it is shallower. When
+    /// committing, the node that the best assignment so far picks for the
+    /// class is preferred over the most-visited one if their values tie, so
+    /// the extracted term follows the shallowest of the tied terms the search
+    /// has scored. This costs a depth computation for every new best
+    /// assignment and every tie.
+    ShallowestDag,
+}
+
+/// How the search picks the choice to commit to for a decision (see
+/// [`MctsConfig::final_selection`]).
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum FinalSelection {
+    /// Commit to the most-visited choice, breaking ties by value. Visit
+    /// counts are less noisy than values, but a choice that was explored
+    /// heavily before its value fell off can still come out on top.
+    #[default]
+    MostVisits,
+    /// Commit to the most-visited choice among those whose value is within
+    /// `epsilon` of the best value of any visited choice. Like
+    /// [`MctsConfig::epsilon`], `epsilon` only applies to scalar utilities;
+    /// otherwise, only the choices with the best value are considered.
+    MostVisitsNearBest { epsilon: f32 },
+}
+
+/// How the search handles utilities from
+/// [`EgraphTotalCost::assignment_utility`] that are not finite.
+///
+/// Cost functions must return finite utilities: the search sums and averages
+/// them, and a single infinity would otherwise turn its statistics into NaN.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum NonFiniteUtilities {
+    /// Clamp infinities to plus or minus [`UTILITY_LIMIT`], then carry on.
+    #[default]
+    Clamp,
+    /// Clamp them during the search, but fail it with
+    /// [`ExtractError::NonFiniteUtility`] once one has been seen.
+    /// [`SearchSession::step`] returns [`StepOutcome::Failed`] instead.
+    Reject,
+}
+
+/// How the [`terms_to_sample`](MctsConfig::terms_to_sample) random completions
+/// of a partial assignment are combined into an estimate of its utility.
+///
+/// The variants are named for the cost of the samples, which is their negated
+/// utility: `MinOfSamples` takes the cheapest sample, which has the highest
+/// utility. Failed samples take part with the score they are given otherwise
+/// (see [`MctsConfig::rollout_max_depth`]), except with
+/// [`MctsConfig::weighted_backprop`], where only successful samples count.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SampleAggregation {
+    /// Average the samples. This is the default.
+    #[default]
+    MeanOfSamples,
+    /// Take the best sample. This estimates how good the best completion of
+    /// a partial assignment is, rather than a typical one, which suits
+    /// egraphs where the completions vary a lot in size.
+    MinOfSamples,
+    /// Take the worst sample, for a pessimistic estimate.
+    MaxOfSamples,
+}
+
+/// How the random samples that estimate utilities pick between the members of
+/// a class.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum RolloutPolicy {
+    /// Pick each member with probability proportional to its
+    /// [`node_weight`](Egraph::node_weight), which is equal probability
+    /// unless the egraph weights its nodes.
+    #[default]
+    Uniform,
+    /// Favor members that add fewer new classes to the sample: a member whose
+    /// children add `n` classes that are neither assigned nor already waiting
+    /// to be is picked with its node weight times `1 / (1 + n)`.
+    ///
+    /// Members whose children are already taken care of close off the sample
+    /// sooner, which tends to make samples fail less often on egraphs with
+    /// many interdependent classes.
+    PreferResolved,
+}
+
+/// The quantity used to score the leaves of the search tree.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SearchObjective {
+    /// Maximize the utility of the extracted term.
+    #[default]
+    Utility,
+    /// Maximize the probability that a complete term can be extracted at all.
+    ///
+    /// Leaves are scored by the fraction of random rollouts from them that
+    /// produce a complete assignment, which steers the search away from
+    /// subtrees that are full of cycles or empty classes. This is useful for
+    /// egraphs where finding any valid extraction is hard.
+    CompleteExtraction,
+}
+
+impl Default for MctsConfig {
+    fn default() -> Self {
+        Self {
+            playouts_per_round: 16,
+            terms_to_sample: 4,
+            sample_aggregation: SampleAggregation::MeanOfSamples,
+            max_memory_bytes: None,
+            cancel: None,
+            max_rounds: None,
+            objective: SearchObjective::Utility,
+            epsilon: 0.0,
+            tiebreak: Tiebreak::FirstSeen,
+            weighted_backprop: false,
+            transposition_table: false,
+            max_tree_nodes_per_class: None,
+            backup: Backup::Mean,
+            decisiveness_threshold: None,
+            confidence_gate: None,
+            final_selection: FinalSelection::MostVisits,
+            cost_ceiling: None,
+            prune_rollouts_by_best: false,
+            prior_strength: 0.0,
+            share_sibling_rollouts: false,
+            rollout_policy: RolloutPolicy::Uniform,
+            rollout_max_depth: None,
+            fpu_reduction: 0.0,
+            expansion_boost: 0.0,
+            exploration_schedule: None,
+            selection: Arc::new(Ucb1),
+            expansion_strategy: ExpansionStrategy::Lazy,
+            expand_per_visit: None,
+            target_utility: None,
+            restart_interval: None,
+            restart_keeps_root_stats: false,
+            count_unique_assignments: false,
+            seed: None,
+            non_finite_utilities: NonFiniteUtilities::Clamp,
+        }
+    }
+}
+
+/// The ways that running a search to completion can fail.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExtractError {
+    /// The search did not find a complete assignment (that beats
+    /// [`MctsConfig::cost_ceiling`], if one is set).
+    NoAssignment,
+    /// The search made far more decisions than there are classes reachable
+    /// from the root, and was stopped.
+    ///
+    /// This never happens for a well-behaved [`Egraph`]; it points to an
+    /// implementation whose classes or children change during the search, or
+    /// to a bug in the search itself.
+    NonTerminating {
+        /// The number of decisions made before the search was stopped.
+        decisions: usize,
+    },
+    /// The search ran [`MctsConfig::max_rounds`] rounds without finishing.
+    BudgetExhausted {
+        /// The number of decisions made by then.
+        decisions: usize,
+    },
+    /// The cost function returned a utility that is not finite, and
+    /// [`MctsConfig::non_finite_utilities`] is
+    /// [`Reject`](NonFiniteUtilities::Reject).
+    NonFiniteUtility {
+        /// The first assignment that was scored that way, in `Debug` form.
+        assignment: String,
+    },
+}
+
+impl fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExtractError::NoAssignment => write!(f, "the search found no complete assignment"),
+            ExtractError::NonTerminating { decisions } => write!(
+                f,
+                "the search was stopped after {decisions} decisions, more than the egraph \
+                 could need"
+            ),
+            ExtractError::BudgetExhausted { decisions } => write!(
+                f,
+                "the search ran out of rounds after {decisions} decisions"
+            ),
+            ExtractError::NonFiniteUtility { assignment } => write!(
+                f,
+                "the cost function returned a utility that is not finite for {assignment}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ExtractError {}
+
 /// The type used for cost estimates for an egraph. In keeping with the MCTS
 /// literature, we use "utility" where lower-cost extractions will have higher
 /// utility.
 pub type Utility = NotNan<f32>;
 
+/// The magnitude that infinite utilities are clamped to (see
+/// [`NonFiniteUtilities`]). This leaves room to sum many clamped utilities
+/// without overflowing.
+pub const UTILITY_LIMIT: f32 = 1e30;
+
+/// `util`, clamped to plus or minus [`UTILITY_LIMIT`] if it is not finite.
+pub(crate) fn finite_utility(util: Utility) -> Utility {
+    if util.is_finite() {
+        util
+    } else if *util == f32::INFINITY {
+        Utility::new(UTILITY_LIMIT).unwrap()
+    } else {
+        Utility::new(-UTILITY_LIMIT).unwrap()
+    }
+}
+
 /// An assignment is a mapping from class ids to node ids.
 ///
-/// Assignments can be partial or complete.
+/// Assignments can be partial or complete. Comparing them with `==` compares
+/// them as maps, whatever order their pairs were inserted in (see
+/// [`assignments_equal`]), though iterating over them follows that order.
 pub type Assignment<E> = IndexMap<<E as Egraph>::ClassId, <E as Egraph>::NodeId, FxBuildHasher>;
 
 /// The core egraph specifications: Egraphs represent equivalence classes of
@@ -43,49 +633,709 @@ pub type Assignment<E> = IndexMap<<E as Egraph>::ClassId, <E as Egraph>::NodeId,
 pub trait Egraph {
     type NodeId: Clone + Hash + Eq + Debug;
     type ClassId: Clone + Hash + Eq + Debug;
+
+    /// The classes of the children of node `id`, in positional order.
+    ///
+    /// The search never reorders children, so cost functions for operators
+    /// whose operand order matters can rely on the order given here. A class
+    /// may appear more than once.
     fn children(&self, id: &Self::NodeId) -> impl Iterator<Item = &Self::ClassId>;
     fn members(&self, id: &Self::ClassId) -> impl Iterator<Item = &Self::NodeId>;
+
+    /// The members of class `id`, if the egraph stores them contiguously.
+    ///
+    /// This must list the same nodes in the same order as
+    /// [`members`](Self::members). When it is available, random rollouts pick
+    /// nodes from it directly instead of first collecting the members into a
+    /// buffer. The default returns `None`.
+    fn members_slice(&self, id: &Self::ClassId) -> Option<&[Self::NodeId]> {
+        let _ = id;
+        None
+    }
+
+    /// The children of node `id`, if the egraph stores them contiguously.
+    ///
+    /// This must list the same classes in the same order as
+    /// [`children`](Self::children). It lets cost functions index operands by
+    /// position. The default returns `None`.
+    fn ordered_children(&self, id: &Self::NodeId) -> Option<&[Self::ClassId]> {
+        let _ = id;
+        None
+    }
+
+    /// A topological order of the classes, if the egraph knows one: every
+    /// class should come after the classes its members' children belong to.
+    ///
+    /// When a node adds several new classes to visit, the search visits the
+    /// ones that come earliest in this order first, and classes missing from
+    /// it last. This only changes the order in which classes are visited, so
+    /// a wrong or partial hint can make the search slower but never changes
+    /// which terms are valid. The default returns `None`, which visits
+    /// children in order.
+    fn topo_hint(&self) -> Option<Vec<Self::ClassId>> {
+        None
+    }
+
+    /// Whether class `id` is provided from outside the egraph, and should not
+    /// be extracted.
+    ///
+    /// Opaque classes are leaves as far as extraction is concerned: they are
+    /// never assigned a node, their members are never looked at, and the
+    /// nodes that refer to them treat them as resolved. Their cost is given
+    /// by [`EgraphTotalCost::opaque_cost`]. The root class must not be
+    /// opaque; searches rooted at an opaque class panic. The default returns
+    /// `false`.
+    fn is_opaque(&self, id: &Self::ClassId) -> bool {
+        let _ = id;
+        false
+    }
+
+    /// The weight of node `id`, such as the probability that it computes the
+    /// right result in an approximate program.
+    ///
+    /// Random rollouts pick between the members of a class in proportion to
+    /// their weights, so that they sample likely terms more often, and
+    /// [`expected_cost`] charges each node its cost divided by its weight.
+    /// Weights must be positive. The default is 1 for every node.
+    ///
+    /// Rollouts only consult the weights if
+    /// [`has_node_weights`](Self::has_node_weights) says there are any.
+    fn node_weight(&self, id: &Self::NodeId) -> f32 {
+        let _ = id;
+        1.0
+    }
+
+    /// Whether any node has a [`node_weight`](Self::node_weight) other than 1.
+    ///
+    /// Egraphs that override `node_weight` must override this too. The
+    /// default returns `false`.
+    fn has_node_weights(&self) -> bool {
+        false
+    }
 }
 
 /// An Egraph that also has a means of estimating the total cost associated with
 /// an assignment.
 pub trait EgraphTotalCost: Egraph {
+    /// Data that is computed once per search, by
+    /// [`precompute`](Self::precompute), and passed to every call to
+    /// [`assignment_utility`](Self::assignment_utility).
+    ///
+    /// Cost models that need to walk the egraph from the root can build
+    /// indexes or a topological order here once, rather than on every call.
+    /// Use `()` if there is nothing to precompute.
+    ///
+    /// Contexts need not implement `Default`, since they may only make sense
+    /// for a particular root. This means there is no default for
+    /// [`precompute`](Self::precompute): implementations with `()` contexts
+    /// write `fn precompute(&self, _: &[Self::ClassId]) {}`.
+    type CostCtx;
+
+    /// Build the cost context for a search for terms rooted at `roots`.
+    ///
+    /// This is a single root, except for searches that extract several roots
+    /// at once (see [`mcts_extract_forest`]), whose assignments cover a term
+    /// for each of them.
+    fn precompute(&self, roots: &[Self::ClassId]) -> Self::CostCtx;
+
     /// The cost of the total assignment for the egraph.
     ///
-    /// If the assignment is not compelete, this method may panic.
-    fn assignment_utility(&self, assignment: &Assignment<Self>) -> Utility;
+    /// Opaque classes (see [`Egraph::is_opaque`]) never appear in
+    /// assignments, so they contribute nothing here; their costs are added by
+    /// [`total_utility`](Self::total_utility) from
+    /// [`opaque_cost`](Self::opaque_cost).
+    ///
+    /// If the assignment is not compelete, this method may panic, unless the
+    /// search leaves out empty classes (see
+    /// [`SearchSession::on_empty_class`]), in which case it must score
+    /// assignments with mandatory classes missing too. The result
+    /// must be finite; see [`MctsConfig::non_finite_utilities`] for what
+    /// happens otherwise.
+    fn assignment_utility(&self, ctx: &Self::CostCtx, assignment: &Assignment<Self>) -> Utility;
+
+    /// The fixed cost of the opaque class `class` (see [`Egraph::is_opaque`]).
+    ///
+    /// This must be non-negative. The default is zero.
+    fn opaque_cost(&self, class: &Self::ClassId) -> Utility {
+        let _ = class;
+        Utility::default()
+    }
+
+    /// The utility of `assignment`, including the costs of the opaque classes
+    /// it refers to.
+    ///
+    /// This is the utility that the search maximizes. Each opaque class is
+    /// paid for once, no matter how many nodes refer to it. Implementors
+    /// should not need to override this.
+    fn total_utility(&self, ctx: &Self::CostCtx, assignment: &Assignment<Self>) -> Utility {
+        let mut opaque = FxHashSet::default();
+        let opaque_cost: Utility = assignment_edges(self, assignment)
+            .filter(|(_, child)| self.is_opaque(child) && opaque.insert(*child))
+            .map(|(_, child)| self.opaque_cost(child))
+            .sum();
+        self.assignment_utility(ctx, assignment) - opaque_cost
+    }
+}
+
+/// An Egraph that scores assignments in a [`CostAlgebra`] rather than with a
+/// single [`Utility`], such as a tuple of keys compared lexicographically.
+///
+/// Extract from one with [`mcts_extract_in_algebra`].
+pub trait EgraphAlgebraCost: Egraph {
+    /// The values that assignments are scored with. Greater values are better.
+    type Value: CostAlgebra;
+
+    /// The value of the complete assignment `assignment`.
+    fn assignment_value(&self, assignment: &Assignment<Self>) -> Self::Value;
+}
+
+/// An Egraph whose nodes each have a fixed cost.
+///
+/// Node costs are used to bound the utility of partial assignments, which lets
+/// the search prune them (see [`SearchSession::prune_with_node_costs`]). In
+/// keeping with [`Utility`], the cost of an assignment is its negated utility.
+pub trait EgraphNodeCost: EgraphTotalCost {
+    /// The cost of including `node` in an assignment.
+    ///
+    /// Costs must be non-negative, and the cost of a complete assignment must
+    /// be at least the sum of the costs of its nodes.
+    fn node_cost(&self, node: &Self::NodeId) -> Utility;
+}
+
+/// An Egraph that charges for the edges of an extracted term, independent of
+/// the nodes at either end.
+///
+/// This is useful for modeling costs at the boundaries between classes, such
+/// as the cost of materializing a value in a register. Implementors will
+/// usually fold [`assignment_edge_cost`](Self::assignment_edge_cost) into
+/// their [`EgraphTotalCost::assignment_utility`].
+pub trait EgraphEdgeCost: Egraph {
+    /// The cost of `parent` referring to the class `child`.
+    fn edge_cost(&self, parent: &Self::NodeId, child: &Self::ClassId) -> Utility;
+
+    /// The sum of the costs of the edges in `assignment` (see
+    /// [`assignment_edges`]).
+    fn assignment_edge_cost(&self, assignment: &Assignment<Self>) -> Utility {
+        assignment_edges(self, assignment)
+            .map(|(parent, child)| self.edge_cost(parent, child))
+            .sum()
+    }
+}
+
+/// Iterate over the edges of the term extracted by `assignment`: each pair of
+/// an assigned node and one of its child classes.
+///
+/// Each node's edges are listed in the order of its children (see
+/// [`Egraph::children`]).
+/// An assignment describes a DAG, so each edge is listed once even when the
+/// parent's class is shared by several parts of the term. A node that refers
+/// to the same child class more than once only contributes one edge for it.
+/// Edges to classes missing from the assignment (e.g. optional classes that
+/// were left out) are included.
+pub fn assignment_edges<'a, E: Egraph + ?Sized>(
+    egraph: &'a E,
+    assignment: &'a Assignment<E>,
+) -> impl Iterator<Item = (&'a E::NodeId, &'a E::ClassId)> {
+    assignment.values().flat_map(move |node| {
+        let mut seen = SmallVec::<[&E::ClassId; 4]>::new();
+        egraph
+            .children(node)
+            .filter(move |child| {
+                let new = !seen.contains(child);
+                if new {
+                    seen.push(child);
+                }
+                new
+            })
+            .map(move |child| (node, child))
+    })
+}
+
+/// Hash the `(class, node)` pairs in `assignment`.
+///
+/// Unlike hashing the [`Assignment`] map directly, this does not depend on
+/// the order in which the pairs were inserted, so two assignments that make
+/// the same choices always hash equally. Each pair is hashed on its own and
+/// the results are summed, so the ids do not need to be ordered.
+///
+/// The hasher is not randomly seeded, so the hash is the same from one run to
+/// the next as long as the ids' [`Hash`] implementations are, which makes it
+/// usable as a cache key. Distinct assignments can collide, so caches should
+/// still compare the assignments themselves.
+pub fn assignment_hash<E: Egraph + ?Sized>(assignment: &Assignment<E>) -> u64 {
+    assignment
+        .iter()
+        .map(|pair| {
+            let mut hasher = FxHasher::default();
+            pair.hash(&mut hasher);
+            hasher.finish()
+        })
+        .fold(0, u64::wrapping_add)
+}
+
+/// Whether `a` and `b` assign the same node to each class, whatever order
+/// their pairs were inserted in.
+///
+/// This is the same as `a == b`, which compares [`Assignment`]s as maps; it is
+/// spelled out for callers who would rather not rely on that.
+pub fn assignments_equal<E: Egraph + ?Sized>(a: &Assignment<E>, b: &Assignment<E>) -> bool {
+    a == b
+}
+
+/// A total order on assignments that, like [`assignments_equal`], does not
+/// depend on the order in which their pairs were inserted.
+///
+/// Assignments are compared as lists of `(class, node)` pairs sorted by
+/// class, so they can be sorted and deduplicated deterministically.
+pub fn assignment_cmp<E>(a: &Assignment<E>, b: &Assignment<E>) -> Ordering
+where
+    E: Egraph + ?Sized,
+    E::ClassId: Ord,
+    E::NodeId: Ord,
+{
+    let mut a = a.iter().collect::<Vec<_>>();
+    let mut b = b.iter().collect::<Vec<_>>();
+    a.sort_unstable();
+    b.sort_unstable();
+    a.cmp(&b)
+}
+
+/// Build a value for the term that `assignment` extracts at `root`, bottom-up.
+///
+/// `build` is called with each assigned node and the values built for its
+/// children, in positional order (see [`Egraph::children`]). It is called
+/// once per class: a class shared by several parts of the term has its value
+/// cloned to each of them. Returns `None` if the term refers to a class the
+/// assignment leaves out, such as an opaque or optional class, or if the
+/// assigned nodes form a cycle.
+pub fn fold_assignment<E: Egraph + ?Sized, T: Clone>(
+    egraph: &E,
+    root: &E::ClassId,
+    assignment: &Assignment<E>,
+    mut build: impl FnMut(&E::NodeId, &[T]) -> T,
+) -> Option<T> {
+    fold_assigned_classes(egraph, root, assignment, |_, node, children| {
+        build(node, children)
+    })
+}
+
+/// The classes of the term that `assignment` extracts at `root`, with their
+/// assigned nodes, children before parents.
+///
+/// This is an order in which the term can be evaluated or emitted: every
+/// node comes after the nodes for all of its children. Each class appears
+/// once, however many parts of the term share it. Returns `None` under the
+/// same conditions as [`fold_assignment`]: if the term refers to a class the
+/// assignment leaves out, or if the assigned nodes form a cycle.
+pub fn assignment_topo_order<E: Egraph + ?Sized>(
+    egraph: &E,
+    root: &E::ClassId,
+    assignment: &Assignment<E>,
+) -> Option<Vec<(E::ClassId, E::NodeId)>> {
+    let mut order = Vec::new();
+    fold_assigned_classes(egraph, root, assignment, |class, node, _| {
+        order.push((class.clone(), node.clone()));
+    })?;
+    Some(order)
+}
+
+/// Like [`fold_assignment`], but also pass `build` the class of each node.
+fn fold_assigned_classes<E: Egraph + ?Sized, T: Clone>(
+    egraph: &E,
+    root: &E::ClassId,
+    assignment: &Assignment<E>,
+    mut build: impl FnMut(&E::ClassId, &E::NodeId, &[T]) -> T,
+) -> Option<T> {
+    // `None` marks a class whose value is being built, i.e. that is on the
+    // current path.
+    let mut values = FxHashMap::<&E::ClassId, Option<T>>::default();
+    let mut children = Vec::new();
+    // Each class is pushed once to enter it, and once more to build it after
+    // its children.
+    let mut stack = vec![(assignment.get_key_value(root)?.0, false)];
+    while let Some((class, children_done)) = stack.pop() {
+        let node = &assignment[class];
+        if children_done {
+            children.clear();
+            for child in egraph.children(node) {
+                children.push(values[child].clone()?);
+            }
+            values.insert(class, Some(build(class, node, &children)));
+            continue;
+        }
+        match values.get(class) {
+            Some(Some(_)) => continue,
+            Some(None) => return None,
+            None => {}
+        }
+        values.insert(class, None);
+        stack.push((class, true));
+        for child in egraph.children(node) {
+            let (child, _) = assignment.get_key_value(child)?;
+            match values.get(child) {
+                Some(None) => return None,
+                Some(Some(_)) => {}
+                None => stack.push((child, false)),
+            }
+        }
+    }
+    values.remove(root).flatten()
+}
+
+/// Quickly extract some valid assignment from an egraph, without regard to its
+/// cost.
+///
+/// This does not build a search tree: it computes which classes have acyclic
+/// extractions and then descends from the root, picking a random member for
+/// each class among those that cannot introduce a cycle. Returns `None` only if
+/// the root class has no acyclic extraction at all.
+pub fn extract_any<E: Egraph>(egraph: &E, root: E::ClassId) -> Option<Assignment<E>> {
+    let ranks = extraction_ranks(egraph, root.clone());
+    let mut rng = thread_rng();
+    let mut assign = Assignment::<E>::default();
+    let mut to_visit = vec![root];
+    let mut candidates = Vec::new();
+    while let Some(class) = to_visit.pop() {
+        if assign.contains_key(&class) || egraph.is_opaque(&class) {
+            continue;
+        }
+        let rank = *ranks.get(&class)?;
+        // Only consider nodes whose children were all proven extractable
+        // before this class: those are guaranteed not to lead back here.
+        candidates.extend(egraph.members(&class).filter(|node| {
+            egraph
+                .children(node)
+                .all(|child| ranks.get(child).is_some_and(|r| *r < rank))
+        }));
+        let node = candidates[rng.gen_range(0..candidates.len())].clone();
+        candidates.clear();
+        to_visit.extend(egraph.children(&node).cloned());
+        assign.insert(class, node);
+    }
+    Some(assign)
 }
 
 /// Extract an assignment from an egraph using Monte-Carlo Tree Search.
 ///
-/// Returns `None` if extraction fails.
+/// Returns `None` if extraction fails; [`mcts_extract_with_stats`] also says
+/// why, in [`SearchStats::failure`].
 pub fn mcts_extract<E: EgraphTotalCost>(
     egraph: &E,
     root: E::ClassId,
     config: MctsConfig,
 ) -> Option<Assignment<E>> {
-    let mut tree = SearchTree::<E>::new(root);
-    let n_samples = config.terms_to_sample;
-    let mut rng = thread_rng();
-    let mut searcher = tree.start_round(
-        |partial_assign: &mut ExtractionState<E>, eg: &E| -> Utility {
-            if let Some(assign) = partial_assign.complete_assignment() {
-                eg.assignment_utility(assign)
-            } else {
-                let mut util = Utility::default();
-                for _ in 0..n_samples {
-                    // If we fail to extract, count that run as 0 utility.
-                    // XXX: This probably isn't the best way to handle this! We
-                    // should revisit later. It'd be better to resample here but
-                    // just bail if we fail to extract after 10*n samples or
-                    // some such.
-                    util += random_cost_estimate(eg, partial_assign, &mut rng).unwrap_or_default();
-                }
-                util / Utility::new(n_samples as f32).unwrap()
-            }
-        },
+    mcts_extract_with_stats(egraph, root, config).0
+}
+
+/// Like [`mcts_extract`], but for an egraph that scores assignments in a
+/// [`CostAlgebra`] (see [`EgraphAlgebraCost`]). Returns the value of the
+/// extracted assignment along with it.
+///
+/// Partial assignments are estimated by the mean value of
+/// [`MctsConfig::terms_to_sample`] random completions, leaving out those that
+/// fail. The settings that only shape the estimates of [`mcts_extract`] are
+/// ignored: [`objective`](MctsConfig::objective),
+/// [`sample_aggregation`](MctsConfig::sample_aggregation),
+/// [`weighted_backprop`](MctsConfig::weighted_backprop),
+/// [`share_sibling_rollouts`](MctsConfig::share_sibling_rollouts) and
+/// [`prune_rollouts_by_best`](MctsConfig::prune_rollouts_by_best). So are the
+/// settings that do arithmetic on utilities, for algebras that are not scalar
+/// (see [`CostAlgebra`]).
+///
+/// # Panics
+///
+/// Panics if `root` is opaque (see [`Egraph::is_opaque`]), or for the
+/// invalid configurations that [`SearchSession::new`] panics on.
+pub fn mcts_extract_in_algebra<E: EgraphAlgebraCost>(
+    egraph: &E,
+    root: E::ClassId,
+    config: MctsConfig,
+) -> Result<(Assignment<E>, E::Value), ExtractError> {
+    assert!(
+        !egraph.is_opaque(&root),
+        "the root class {root:?} is opaque, so there is nothing to extract"
+    );
+    let estimator = AlgebraRolloutEstimator::new(config.terms_to_sample);
+    let state = ExtractionState::new(root.clone());
+    let mut search = SearchTree::<E, E::Value>::new(root).start_round_with(
+        config,
+        estimator,
         Utility::new(2.0f32.sqrt()).unwrap(),
+        state,
     );
+    if let Some(hint) = egraph.topo_hint() {
+        search.set_topo_hint(hint);
+    }
+    let settled = search.finish(egraph)?;
+    let assign = search.settled_assignment(settled)?;
+    Ok((assign.clone(), egraph.assignment_value(assign)))
+}
 
-    searcher.assign(&config, egraph)
+/// Like [`mcts_extract`], but use `analysis` rather than computing those
+/// facts during the search (see [`SearchSession::use_analysis`]).
+///
+/// An analysis that undercounts the reachable classes can make the search
+/// give up as non-terminating, so this reports why extraction failed.
+pub fn mcts_extract_with_analysis<E: EgraphTotalCost>(
+    egraph: &E,
+    root: E::ClassId,
+    config: MctsConfig,
+    analysis: Analysis<E>,
+) -> Result<Assignment<E>, ExtractError> {
+    let mut session = SearchSession::new(egraph, root, config);
+    session.use_analysis(analysis);
+    session.run()
+}
+
+/// Like [`mcts_extract`], but write the assignment into `out` instead of
+/// returning a new one, reusing its allocation.
+///
+/// `out` is cleared first. Returns whether extraction succeeded; on failure,
+/// `out` is left empty.
+pub fn mcts_extract_into<E: EgraphTotalCost>(
+    egraph: &E,
+    root: E::ClassId,
+    config: MctsConfig,
+    out: &mut Assignment<E>,
+) -> bool {
+    SearchSession::new(egraph, root, config)
+        .run_into(out)
+        .is_ok()
+}
+
+/// Like [`mcts_extract`], but lower the extracted term into a type of the
+/// caller's choosing with [`fold_assignment`], rather than returning the
+/// assignment.
+///
+/// Returns `None` if extraction fails, or if the term refers to a class the
+/// assignment leaves out.
+pub fn extract_into<E: EgraphTotalCost, T: Clone>(
+    egraph: &E,
+    root: E::ClassId,
+    config: MctsConfig,
+    build: impl FnMut(&E::NodeId, &[T]) -> T,
+) -> Option<T> {
+    let assign = mcts_extract(egraph, root.clone(), config)?;
+    fold_assignment(egraph, &root, &assign, build)
+}
+
+/// Extract terms for all of `roots` at once, as a single assignment.
+///
+/// Unlike extracting each root separately, this scores the combined
+/// assignment with one call to [`EgraphTotalCost::assignment_utility`], so a
+/// subterm shared between the roots is only paid for once (as long as the cost
+/// model counts each class once, as DAG costs do). This steers the search
+/// towards terms that share work across roots. The cost context is
+/// precomputed for all of the roots together.
+///
+/// Returns `None` if extraction fails, or if there are no roots.
+pub fn mcts_extract_forest<E: EgraphTotalCost>(
+    egraph: &E,
+    roots: impl IntoIterator<Item = E::ClassId>,
+    config: MctsConfig,
+) -> Option<Assignment<E>> {
+    let roots = roots.into_iter().collect::<Vec<_>>();
+    if roots.is_empty() {
+        return None;
+    }
+    let forest = Forest::new(egraph, roots);
+    let assign = mcts_extract(&forest, ForestClass::Root, config)?;
+    Some(forest.unwrap_assignment(&assign))
+}
+
+/// Extract up to `k` distinct terms rooted at `class`, with their utilities,
+/// best first.
+///
+/// `class` can be any class, not just the root of the whole egraph; the terms
+/// only cover the part of the egraph below it. The terms are the best distinct
+/// complete assignments scored while searching for the best term, so fewer
+/// than `k` are returned if the search scores fewer than that.
+pub fn mcts_extract_top_k<E: EgraphTotalCost>(
+    egraph: &E,
+    class: E::ClassId,
+    k: usize,
+    config: MctsConfig,
+) -> Vec<(Assignment<E>, Utility)> {
+    let mut session = SearchSession::new(egraph, class, config);
+    session.keep_top(k);
+    // The assignments scored along the way are worth returning even if the
+    // search fails to commit to one.
+    let _ = session.run();
+    session.top_assignments()
+}
+
+/// Run [`mcts_extract`] `n_restarts` times with different seeds and return the
+/// best assignment found, along with its utility.
+///
+/// The seed of each restart is derived from `seed`, overriding
+/// [`MctsConfig::seed`], so the result is reproducible. The restarts share the
+/// budget in `config`: each runs `n_restarts` times fewer
+/// [`playouts_per_round`](MctsConfig::playouts_per_round), but at least one,
+/// so that together they cost about as much as a single search. Ties go to
+/// the earliest restart. Returns `None` if every restart fails.
+pub fn mcts_extract_multistart<E: EgraphTotalCost>(
+    egraph: &E,
+    root: E::ClassId,
+    config: MctsConfig,
+    n_restarts: usize,
+    seed: u64,
+) -> Option<(Assignment<E>, Utility)> {
+    let config = MctsConfig {
+        playouts_per_round: (config.playouts_per_round / n_restarts.max(1)).max(1),
+        ..config
+    };
+    let seeds = StdRng::seed_from_u64(seed);
+    let mut best: Option<(Assignment<E>, Utility)> = None;
+    for (assign, utility) in seeded_runs(egraph, root, config, n_restarts, seeds).flatten() {
+        if best.as_ref().is_none_or(|(_, best)| utility > *best) {
+            best = Some((assign, utility));
+        }
+    }
+    best
+}
+
+/// The results of independent searches of the same egraph, as a statistical
+/// estimate of the best achievable utility. See [`estimate_optimal`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OptimalEstimate {
+    /// The utility of the assignment extracted by each run that succeeded, in
+    /// the order they ran.
+    pub utilities: Vec<Utility>,
+    /// The number of runs that failed to extract an assignment.
+    pub failed_runs: usize,
+}
+
+impl OptimalEstimate {
+    /// The highest utility any run achieved, which is the best lower bound on
+    /// the optimal utility the runs give. `None` if every run failed.
+    pub fn best(&self) -> Option<Utility> {
+        self.utilities.iter().copied().max()
+    }
+
+    /// The mean utility of the runs that succeeded.
+    pub fn mean(&self) -> Option<f64> {
+        let n = self.utilities.len();
+        (n > 0).then(|| {
+            self.utilities
+                .iter()
+                .map(|util| f64::from(util.into_inner()))
+                .sum::<f64>()
+                / n as f64
+        })
+    }
+
+    /// The sample variance of the utilities of the runs that succeeded, with
+    /// Bessel's correction. `None` with fewer than two of them.
+    pub fn variance(&self) -> Option<f64> {
+        let n = self.utilities.len();
+        let mean = self.mean().filter(|_| n > 1)?;
+        let squares = self
+            .utilities
+            .iter()
+            .map(|util| (f64::from(util.into_inner()) - mean).powi(2))
+            .sum::<f64>();
+        Some(squares / (n - 1) as f64)
+    }
+}
+
+/// Run `n_runs` independent searches of `egraph`, each with its own seed, and
+/// collect the utilities they extract.
+///
+/// With [`MctsConfig::seed`] set, the seeds of the runs are derived from it,
+/// so the estimate is reproducible; otherwise they are random. Every run gets
+/// the full budget in `config`.
+pub fn estimate_optimal<E: EgraphTotalCost>(
+    egraph: &E,
+    root: E::ClassId,
+    config: MctsConfig,
+    n_runs: usize,
+) -> OptimalEstimate {
+    let seeds = config
+        .seed
+        .map_or_else(StdRng::from_entropy, StdRng::seed_from_u64);
+    let mut estimate = OptimalEstimate::default();
+    for run in seeded_runs(egraph, root, config, n_runs, seeds) {
+        match run {
+            Some((_, utility)) => estimate.utilities.push(utility),
+            None => estimate.failed_runs += 1,
+        }
+    }
+    estimate
+}
+
+/// Run [`mcts_extract`] `n_runs` times, seeding each run with the next number
+/// from `seeds`, and yield each run's assignment and its utility, or `None` if
+/// the run failed.
+fn seeded_runs<'a, E: EgraphTotalCost>(
+    egraph: &'a E,
+    root: E::ClassId,
+    config: MctsConfig,
+    n_runs: usize,
+    mut seeds: StdRng,
+) -> impl Iterator<Item = Option<(Assignment<E>, Utility)>> + 'a {
+    let mut pool = StatePool::default();
+    (0..n_runs).map(move |_| {
+        let config = MctsConfig {
+            seed: Some(seeds.next_u64()),
+            ..config.clone()
+        };
+        let mut session = SearchSession::new_in(&mut pool, egraph, root.clone(), config);
+        let res = session.run().ok().map(|assign| {
+            let utility = session
+                .utility(Some(&assign))
+                .expect("assignment was supplied");
+            (assign, utility)
+        });
+        session.recycle(&mut pool);
+        res
+    })
+}
+
+/// Like [`mcts_extract`], but also return counters describing the search.
+pub fn mcts_extract_with_stats<E: EgraphTotalCost>(
+    egraph: &E,
+    root: E::ClassId,
+    config: MctsConfig,
+) -> (Option<Assignment<E>>, SearchStats) {
+    let mut session = SearchSession::new(egraph, root, config);
+    let res = session.run().ok();
+    (res, session.stats())
+}
+
+/// Extract a separate assignment for each of the `k` most promising nodes in
+/// the root class, with that node pinned as the root's choice.
+///
+/// A round of playouts at the root ranks its members by visit count (ties are
+/// broken by value). Each of the top `k` members is then pinned in turn, and
+/// the rest of the term is extracted by a search that starts from the ranking
+/// round's statistics. Each entry holds the pinned node, the extracted
+/// assignment (or `None` if that search failed), and the value the ranking
+/// round estimated for the node.
+pub fn extract_per_root_node<E: EgraphTotalCost>(
+    egraph: &E,
+    root: E::ClassId,
+    k: usize,
+    config: MctsConfig,
+) -> Vec<(E::NodeId, Option<Assignment<E>>, Utility)> {
+    let mut ranking = SearchSession::new(egraph, root.clone(), config.clone());
+    ranking.add_breakpoint(root.clone());
+    ranking.step();
+    let mut candidates = ranking.candidates();
+    let mut seen = FxHashSet::default();
+    candidates.retain(|c| seen.insert(c.node.clone()));
+    candidates.sort_by(|a, b| b.visits.cmp(&a.visits).then(b.value.cmp(&a.value)));
+    candidates.truncate(k);
+    let mut pool = StatePool::default();
+    candidates
+        .into_iter()
+        .map(|candidate| {
+            let mut session =
+                SearchSession::new_in(&mut pool, egraph, root.clone(), config.clone());
+            session.merge(&ranking);
+            session.choose(candidate.node.clone());
+            let assign = session.run().ok();
+            session.recycle(&mut pool);
+            (candidate.node, assign, candidate.value)
+        })
+        .collect()
 }
@@ -0,0 +1,166 @@
+//! Model-based tests that cross-check [`ExtractionState`] against a naive
+//! extractor that recomputes everything from scratch.
+//!
+//! `ExtractionState` resolves classes incrementally by watching one child of
+//! each pending node at a time, and has to undo all of that on `reset`. The
+//! model just keeps the provisional assignment in a list, and truncates it to
+//! backtrack.
+
+use fxhash::{FxHashMap, FxHashSet};
+use proptest::prelude::*;
+
+use crate::{extraction_state::ExtractionState, simple_egraph::SimpleEgraph, Egraph, Utility};
+
+/// One step of a random walk over an extraction.
+#[derive(Clone, Debug)]
+enum Op {
+    /// Assign the member with this index (modulo the class size) to the next
+    /// class.
+    Assign(usize),
+    PushSnapshot,
+    Reset,
+    PopSnapshot,
+}
+
+/// The naive extractor: a provisional assignment, in order, and the lengths
+/// it had at each snapshot.
+struct Model {
+    root: usize,
+    assigned: Vec<(usize, usize)>,
+    snapshots: Vec<usize>,
+}
+
+impl Model {
+    fn new(root: usize) -> Self {
+        // `ExtractionState` starts out with a snapshot of its empty state.
+        Self {
+            root,
+            assigned: Vec::new(),
+            snapshots: vec![0],
+        }
+    }
+
+    fn assignment(&self) -> FxHashMap<usize, usize> {
+        self.assigned.iter().copied().collect()
+    }
+
+    /// The classes reachable from the root that are not assigned yet.
+    fn to_visit(&self, egraph: &SimpleEgraph) -> FxHashSet<usize> {
+        let assign = self.assignment();
+        let mut to_visit: FxHashSet<usize> = assign
+            .values()
+            .flat_map(|node| egraph.children(node).copied())
+            .filter(|class| !assign.contains_key(class))
+            .collect();
+        if !assign.contains_key(&self.root) {
+            to_visit.insert(self.root);
+        }
+        to_visit
+    }
+
+    /// The assignment, if every class is assigned and no assigned node
+    /// depends on itself.
+    fn complete_assignment(&self, egraph: &SimpleEgraph) -> Option<FxHashMap<usize, usize>> {
+        if !self.to_visit(egraph).is_empty() {
+            return None;
+        }
+        let assign = self.assignment();
+        // Resolve classes bottom-up until nothing changes; whatever is left
+        // is on a cycle.
+        let mut resolved = FxHashSet::default();
+        loop {
+            let before = resolved.len();
+            for (class, node) in &assign {
+                if egraph.children(node).all(|child| resolved.contains(child)) {
+                    resolved.insert(*class);
+                }
+            }
+            if resolved.len() == before {
+                break;
+            }
+        }
+        (resolved.len() == assign.len()).then_some(assign)
+    }
+}
+
+/// Small egraphs, as the children of each member of each class.
+fn egraph_strategy() -> impl Strategy<Value = Vec<Vec<Vec<usize>>>> {
+    (1..6usize).prop_flat_map(|n_classes| {
+        prop::collection::vec(
+            prop::collection::vec(prop::collection::vec(0..n_classes, 0..3), 0..3),
+            n_classes,
+        )
+    })
+}
+
+fn build_egraph(classes: Vec<Vec<Vec<usize>>>) -> SimpleEgraph {
+    let mut egraph = SimpleEgraph {
+        nodes: Vec::new(),
+        classes: Vec::new(),
+        score_fn: Box::new(|_, _| Utility::default()),
+    };
+    for members in classes {
+        let ids = egraph.nodes.len()..egraph.nodes.len() + members.len();
+        egraph.classes.push(ids.collect());
+        egraph.nodes.extend(members);
+    }
+    egraph
+}
+
+fn op_strategy() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        4 => any::<usize>().prop_map(Op::Assign),
+        1 => Just(Op::PushSnapshot),
+        1 => Just(Op::Reset),
+        1 => Just(Op::PopSnapshot),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn extraction_state_matches_naive_model(
+        classes in egraph_strategy(),
+        ops in prop::collection::vec(op_strategy(), 0..40),
+    ) {
+        let egraph = build_egraph(classes);
+        let mut state = ExtractionState::new(0);
+        let mut model = Model::new(0);
+        for op in ops {
+            match op {
+                Op::Assign(choice) => {
+                    let to_visit = model.to_visit(&egraph);
+                    let Some(handle) = state.start_next_assign() else {
+                        prop_assert!(to_visit.is_empty());
+                        continue;
+                    };
+                    let class = *handle.class();
+                    prop_assert!(to_visit.contains(&class), "visiting {class}, not in {to_visit:?}");
+                    let members = &egraph.classes[class];
+                    if members.is_empty() {
+                        continue;
+                    }
+                    let node = members[choice % members.len()];
+                    handle.assign(node, &egraph);
+                    model.assigned.push((class, node));
+                }
+                Op::PushSnapshot => {
+                    state.push_snapshot();
+                    model.snapshots.push(model.assigned.len());
+                }
+                Op::Reset => {
+                    state.reset(&egraph);
+                    model.assigned.truncate(*model.snapshots.last().unwrap());
+                }
+                Op::PopSnapshot if model.snapshots.len() > 1 => {
+                    state.pop_snapshot();
+                    model.snapshots.pop();
+                }
+                Op::PopSnapshot => {}
+            }
+            let actual = state
+                .complete_assignment()
+                .map(|assign| assign.iter().map(|(c, n)| (*c, *n)).collect());
+            prop_assert_eq!(actual, model.complete_assignment(&egraph));
+        }
+    }
+}
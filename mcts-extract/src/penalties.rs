@@ -0,0 +1,364 @@
+//! Adapters that adjust the costs of an existing egraph.
+
+use std::{fmt, hash::Hash};
+
+use fxhash::{FxHashMap, FxHashSet};
+
+use crate::{assignment_edges, Assignment, Egraph, EgraphNodeCost, EgraphTotalCost, Utility};
+
+/// An egraph that charges extra for using particular nodes.
+///
+/// This discourages the search from using the penalized nodes without ruling
+/// them out: each penalty is a cost, subtracted from the utility of every
+/// assignment that uses its node. If the underlying egraph has node costs,
+/// each node's penalty is added to its cost, so cost bounds (see
+/// [`SearchSession::prune_with_node_costs`](crate::SearchSession::prune_with_node_costs))
+/// account for it.
+pub struct SoftPenalties<'a, E: Egraph> {
+    egraph: &'a E,
+    penalties: FxHashMap<E::NodeId, Utility>,
+}
+
+impl<E: Egraph> fmt::Debug for SoftPenalties<'_, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SoftPenalties")
+            .field("penalties", &self.penalties)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a, E: Egraph> SoftPenalties<'a, E> {
+    /// Penalize the nodes in `penalties` when extracting from `egraph`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a penalty is negative.
+    pub fn new(egraph: &'a E, penalties: FxHashMap<E::NodeId, Utility>) -> Self {
+        assert!(
+            penalties
+                .values()
+                .all(|penalty| *penalty >= Utility::default()),
+            "penalties must be non-negative"
+        );
+        Self { egraph, penalties }
+    }
+
+    fn penalty(&self, node: &E::NodeId) -> Utility {
+        self.penalties.get(node).copied().unwrap_or_default()
+    }
+}
+
+impl<E: Egraph> Egraph for SoftPenalties<'_, E> {
+    type ClassId = E::ClassId;
+    type NodeId = E::NodeId;
+
+    fn children(&self, id: &Self::NodeId) -> impl Iterator<Item = &Self::ClassId> {
+        self.egraph.children(id)
+    }
+
+    fn members(&self, id: &Self::ClassId) -> impl Iterator<Item = &Self::NodeId> {
+        self.egraph.members(id)
+    }
+
+    fn members_slice(&self, id: &Self::ClassId) -> Option<&[Self::NodeId]> {
+        self.egraph.members_slice(id)
+    }
+
+    fn ordered_children(&self, id: &Self::NodeId) -> Option<&[Self::ClassId]> {
+        self.egraph.ordered_children(id)
+    }
+
+    fn topo_hint(&self) -> Option<Vec<Self::ClassId>> {
+        self.egraph.topo_hint()
+    }
+
+    fn is_opaque(&self, id: &Self::ClassId) -> bool {
+        self.egraph.is_opaque(id)
+    }
+
+    fn node_weight(&self, id: &Self::NodeId) -> f32 {
+        self.egraph.node_weight(id)
+    }
+
+    fn has_node_weights(&self) -> bool {
+        self.egraph.has_node_weights()
+    }
+}
+
+impl<E: EgraphTotalCost> EgraphTotalCost for SoftPenalties<'_, E> {
+    type CostCtx = E::CostCtx;
+
+    fn precompute(&self, roots: &[Self::ClassId]) -> Self::CostCtx {
+        self.egraph.precompute(roots)
+    }
+
+    fn assignment_utility(&self, ctx: &Self::CostCtx, assignment: &Assignment<Self>) -> Utility {
+        let penalty: Utility = assignment.values().map(|node| self.penalty(node)).sum();
+        self.egraph.assignment_utility(ctx, assignment) - penalty
+    }
+
+    fn opaque_cost(&self, class: &Self::ClassId) -> Utility {
+        self.egraph.opaque_cost(class)
+    }
+}
+
+impl<E: EgraphNodeCost> EgraphNodeCost for SoftPenalties<'_, E> {
+    fn node_cost(&self, node: &Self::NodeId) -> Utility {
+        self.egraph.node_cost(node) + self.penalty(node)
+    }
+}
+
+/// A penalty for extracting a node whose operator is `child_op` as a child of
+/// a node whose operator is `parent_op`.
+#[derive(Clone, Debug)]
+pub struct PatternPenalty<Op> {
+    /// The operator of the parent node.
+    pub parent_op: Op,
+    /// The operator of the node assigned to one of the parent's children.
+    pub child_op: Op,
+    /// The cost added for each occurrence of the pattern. Must be
+    /// non-negative.
+    pub penalty: Utility,
+}
+
+/// An egraph that charges extra for particular combinations of parent and
+/// child operators in the extracted term.
+///
+/// Operators are whatever `node_op` maps each node to. For every edge of an
+/// assignment (see [`assignment_edges`]) whose parent and child nodes match
+/// a pattern, the pattern's penalty is subtracted from the assignment's
+/// utility. Like [`SoftPenalties`], this discourages the patterns without
+/// ruling them out. Node costs, if any, are passed through unchanged, since
+/// penalties only ever make an assignment more expensive.
+pub struct PatternPenalties<'a, E: Egraph, Op, F> {
+    egraph: &'a E,
+    node_op: F,
+    penalties: FxHashMap<(Op, Op), Utility>,
+}
+
+impl<E: Egraph, Op: fmt::Debug, F> fmt::Debug for PatternPenalties<'_, E, Op, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PatternPenalties")
+            .field("penalties", &self.penalties)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a, E, Op, F> PatternPenalties<'a, E, Op, F>
+where
+    E: Egraph,
+    Op: Hash + Eq,
+    F: Fn(&E::NodeId) -> Op,
+{
+    /// Apply `patterns` to extractions from `egraph`. Penalties for the same
+    /// pair of operators add up.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a penalty is negative.
+    pub fn new(
+        egraph: &'a E,
+        node_op: F,
+        patterns: impl IntoIterator<Item = PatternPenalty<Op>>,
+    ) -> Self {
+        let mut penalties = FxHashMap::<_, Utility>::default();
+        for pattern in patterns {
+            assert!(
+                pattern.penalty >= Utility::default(),
+                "penalties must be non-negative"
+            );
+            *penalties
+                .entry((pattern.parent_op, pattern.child_op))
+                .or_default() += pattern.penalty;
+        }
+        Self {
+            egraph,
+            node_op,
+            penalties,
+        }
+    }
+
+    /// The total penalty for the patterns that occur in `assignment`.
+    pub fn pattern_penalty(&self, assignment: &Assignment<E>) -> Utility {
+        assignment_edges(self.egraph, assignment)
+            .filter_map(|(parent, child)| {
+                let child = assignment.get(child)?;
+                let ops = ((self.node_op)(parent), (self.node_op)(child));
+                self.penalties.get(&ops).copied()
+            })
+            .sum()
+    }
+}
+
+impl<E: Egraph, Op, F> Egraph for PatternPenalties<'_, E, Op, F> {
+    type ClassId = E::ClassId;
+    type NodeId = E::NodeId;
+
+    fn children(&self, id: &Self::NodeId) -> impl Iterator<Item = &Self::ClassId> {
+        self.egraph.children(id)
+    }
+
+    fn members(&self, id: &Self::ClassId) -> impl Iterator<Item = &Self::NodeId> {
+        self.egraph.members(id)
+    }
+
+    fn members_slice(&self, id: &Self::ClassId) -> Option<&[Self::NodeId]> {
+        self.egraph.members_slice(id)
+    }
+
+    fn ordered_children(&self, id: &Self::NodeId) -> Option<&[Self::ClassId]> {
+        self.egraph.ordered_children(id)
+    }
+
+    fn topo_hint(&self) -> Option<Vec<Self::ClassId>> {
+        self.egraph.topo_hint()
+    }
+
+    fn is_opaque(&self, id: &Self::ClassId) -> bool {
+        self.egraph.is_opaque(id)
+    }
+
+    fn node_weight(&self, id: &Self::NodeId) -> f32 {
+        self.egraph.node_weight(id)
+    }
+
+    fn has_node_weights(&self) -> bool {
+        self.egraph.has_node_weights()
+    }
+}
+
+impl<E, Op, F> EgraphTotalCost for PatternPenalties<'_, E, Op, F>
+where
+    E: EgraphTotalCost,
+    Op: Hash + Eq,
+    F: Fn(&E::NodeId) -> Op,
+{
+    type CostCtx = E::CostCtx;
+
+    fn precompute(&self, roots: &[Self::ClassId]) -> Self::CostCtx {
+        self.egraph.precompute(roots)
+    }
+
+    fn assignment_utility(&self, ctx: &Self::CostCtx, assignment: &Assignment<Self>) -> Utility {
+        self.egraph.assignment_utility(ctx, assignment) - self.pattern_penalty(assignment)
+    }
+
+    fn opaque_cost(&self, class: &Self::ClassId) -> Utility {
+        self.egraph.opaque_cost(class)
+    }
+}
+
+impl<E, Op, F> EgraphNodeCost for PatternPenalties<'_, E, Op, F>
+where
+    E: EgraphNodeCost,
+    Op: Hash + Eq,
+    F: Fn(&E::NodeId) -> Op,
+{
+    fn node_cost(&self, node: &Self::NodeId) -> Utility {
+        self.egraph.node_cost(node)
+    }
+}
+
+/// An egraph in which some classes have already been built, e.g. by earlier
+/// extractions in an incremental compilation, and cost nothing to reuse.
+///
+/// Materialized classes are treated as opaque (see [`Egraph::is_opaque`]) with
+/// a cost of zero: extractions refer to them rather than extracting them
+/// again, and only pay for what they add. The underlying egraph's cost model
+/// must therefore accept assignments that leave the materialized classes out.
+/// Extracting several roots one after another, and calling
+/// [`materialize`](Self::materialize) with each result, models the total cost
+/// of building all of them.
+pub struct Materialized<'a, E: Egraph> {
+    egraph: &'a E,
+    classes: FxHashSet<E::ClassId>,
+}
+
+impl<E: Egraph> fmt::Debug for Materialized<'_, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Materialized")
+            .field("classes", &self.classes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a, E: Egraph> Materialized<'a, E> {
+    /// Treat the classes in `classes` as already built when extracting from
+    /// `egraph`. The root of an extraction must not be one of them.
+    pub fn new(egraph: &'a E, classes: FxHashSet<E::ClassId>) -> Self {
+        Self { egraph, classes }
+    }
+
+    /// Treat every class assigned by `assignment` as built from now on.
+    pub fn materialize(&mut self, assignment: &Assignment<E>) {
+        self.classes.extend(assignment.keys().cloned());
+    }
+
+    /// The classes treated as already built.
+    pub fn classes(&self) -> &FxHashSet<E::ClassId> {
+        &self.classes
+    }
+}
+
+impl<E: Egraph> Egraph for Materialized<'_, E> {
+    type ClassId = E::ClassId;
+    type NodeId = E::NodeId;
+
+    fn children(&self, id: &Self::NodeId) -> impl Iterator<Item = &Self::ClassId> {
+        self.egraph.children(id)
+    }
+
+    fn members(&self, id: &Self::ClassId) -> impl Iterator<Item = &Self::NodeId> {
+        self.egraph.members(id)
+    }
+
+    fn members_slice(&self, id: &Self::ClassId) -> Option<&[Self::NodeId]> {
+        self.egraph.members_slice(id)
+    }
+
+    fn ordered_children(&self, id: &Self::NodeId) -> Option<&[Self::ClassId]> {
+        self.egraph.ordered_children(id)
+    }
+
+    fn topo_hint(&self) -> Option<Vec<Self::ClassId>> {
+        self.egraph.topo_hint()
+    }
+
+    fn is_opaque(&self, id: &Self::ClassId) -> bool {
+        self.classes.contains(id) || self.egraph.is_opaque(id)
+    }
+
+    fn node_weight(&self, id: &Self::NodeId) -> f32 {
+        self.egraph.node_weight(id)
+    }
+
+    fn has_node_weights(&self) -> bool {
+        self.egraph.has_node_weights()
+    }
+}
+
+impl<E: EgraphTotalCost> EgraphTotalCost for Materialized<'_, E> {
+    type CostCtx = E::CostCtx;
+
+    fn precompute(&self, roots: &[Self::ClassId]) -> Self::CostCtx {
+        self.egraph.precompute(roots)
+    }
+
+    fn assignment_utility(&self, ctx: &Self::CostCtx, assignment: &Assignment<Self>) -> Utility {
+        self.egraph.assignment_utility(ctx, assignment)
+    }
+
+    fn opaque_cost(&self, class: &Self::ClassId) -> Utility {
+        if self.classes.contains(class) {
+            Utility::default()
+        } else {
+            self.egraph.opaque_cost(class)
+        }
+    }
+}
+
+impl<E: EgraphNodeCost> EgraphNodeCost for Materialized<'_, E> {
+    fn node_cost(&self, node: &Self::NodeId) -> Utility {
+        self.egraph.node_cost(node)
+    }
+}
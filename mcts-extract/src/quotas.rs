@@ -0,0 +1,65 @@
+//! Limits on how many nodes of each kind an extracted term may use.
+
+use std::hash::Hash;
+
+use fxhash::FxHashMap;
+
+use crate::{analysis::reachable_class_order, Egraph};
+
+/// Per-kind limits on the nodes in an assignment, for the nodes reachable from
+/// a root.
+///
+/// Each node is mapped to the index of the limit that covers it up front, so
+/// extraction only has to keep a count per limit.
+pub(crate) struct OpQuotas<E: Egraph> {
+    slots: FxHashMap<E::NodeId, usize>,
+    limits: Vec<usize>,
+}
+
+impl<E: Egraph> OpQuotas<E> {
+    /// Allow at most `limits[op]` of the nodes reachable from `root` whose kind,
+    /// according to `node_op`, is `op`. Kinds without a limit are unrestricted.
+    pub(crate) fn new<Op: Hash + Eq>(
+        egraph: &E,
+        root: E::ClassId,
+        node_op: impl Fn(&E::NodeId) -> Op,
+        limits: impl IntoIterator<Item = (Op, usize)>,
+    ) -> Self {
+        let mut slots_by_op = FxHashMap::default();
+        let mut slot_limits = Vec::new();
+        for (op, limit) in limits {
+            let slot = *slots_by_op.entry(op).or_insert_with(|| {
+                slot_limits.push(limit);
+                slot_limits.len() - 1
+            });
+            slot_limits[slot] = limit;
+        }
+        let mut slots = FxHashMap::default();
+        for class in reachable_class_order(egraph, root) {
+            for node in egraph.members(&class) {
+                if let Some(slot) = slots_by_op.get(&node_op(node)) {
+                    slots.insert(node.clone(), *slot);
+                }
+            }
+        }
+        Self {
+            slots,
+            limits: slot_limits,
+        }
+    }
+
+    /// The number of separate limits.
+    pub(crate) fn n_limits(&self) -> usize {
+        self.limits.len()
+    }
+
+    /// The index of the limit that covers `node`, if any.
+    pub(crate) fn slot(&self, node: &E::NodeId) -> Option<usize> {
+        self.slots.get(node).copied()
+    }
+
+    /// The number of nodes allowed for the limit at `slot`.
+    pub(crate) fn limit(&self, slot: usize) -> usize {
+        self.limits[slot]
+    }
+}
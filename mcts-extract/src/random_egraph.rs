@@ -0,0 +1,135 @@
+//! Randomly generated egraphs, for benchmarks and tests.
+
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use crate::{Assignment, Egraph, EgraphNodeCost, EgraphTotalCost, Utility};
+
+/// The odds that a node which is not needed for extraction gets a child that
+/// may point back up the egraph.
+const BACK_EDGE_ODDS: u32 = 4;
+
+/// Generate a random egraph with `n_classes` classes, rooted at class 0.
+///
+/// The egraph is a deterministic function of its arguments. Every class has
+/// between 1 and `max_members` members, and every node has at most
+/// `max_arity` children. The generated egraphs are meant to look like the
+/// ones produced by equality saturation:
+///
+/// * Every class is reachable from class 0.
+/// * Every class has at least one member whose children all come after it, so
+///   every class has an acyclic extraction.
+/// * The remaining members mostly point to classes after them as well, but one
+///   in four of them has a child anywhere in the egraph, which can introduce a
+///   cycle.
+///
+/// Each node has a random cost between 1 and 10, and the utility of an
+/// assignment is the negated sum of the costs of its nodes.
+///
+/// # Panics
+///
+/// Panics if `n_classes` or `max_members` is zero, or if `max_arity` is zero
+/// and there is more than one class.
+pub fn gen_random_egraph(
+    n_classes: usize,
+    max_members: usize,
+    max_arity: usize,
+    seed: u64,
+) -> impl EgraphNodeCost<ClassId = usize, NodeId = usize, CostCtx = ()> {
+    assert!(n_classes > 0, "egraph must have at least one class");
+    assert!(max_members > 0, "classes must have at least one member");
+    assert!(
+        max_arity > 0 || n_classes == 1,
+        "nodes must have children to connect multiple classes"
+    );
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    // Start with a random spanning tree over the classes, where every class
+    // points to classes after it. The first member of each class holds the
+    // tree edges, which makes every class reachable and extractable.
+    let mut acyclic_children = vec![Vec::new(); n_classes];
+    let mut open = vec![0];
+    for class in 1..n_classes {
+        let slot = rng.gen_range(0..open.len());
+        let parent = open[slot];
+        acyclic_children[parent].push(class);
+        if acyclic_children[parent].len() == max_arity {
+            open.swap_remove(slot);
+        }
+        open.push(class);
+    }
+
+    let mut egraph = RandomEgraph::default();
+    for (class, mut children) in acyclic_children.into_iter().enumerate() {
+        // Pad out the acyclic member with more edges pointing forward.
+        let arity = rng.gen_range(children.len()..=max_arity);
+        if class + 1 < n_classes {
+            while children.len() < arity {
+                children.push(rng.gen_range(class + 1..n_classes));
+            }
+        }
+        children.shuffle(&mut rng);
+        let mut members = vec![egraph.add_node(children, &mut rng)];
+        for _ in 1..rng.gen_range(1..=max_members) {
+            let arity = if class + 1 < n_classes {
+                rng.gen_range(0..=max_arity)
+            } else {
+                0
+            };
+            let mut children = (0..arity)
+                .map(|_| rng.gen_range(class + 1..n_classes))
+                .collect::<Vec<_>>();
+            if arity > 0 && rng.gen_ratio(1, BACK_EDGE_ODDS) {
+                children[0] = rng.gen_range(0..n_classes);
+            }
+            members.push(egraph.add_node(children, &mut rng));
+        }
+        egraph.classes.push(members);
+    }
+    egraph
+}
+
+#[derive(Default)]
+struct RandomEgraph {
+    nodes: Vec<Vec<usize>>,
+    classes: Vec<Vec<usize>>,
+    costs: Vec<f32>,
+}
+
+impl RandomEgraph {
+    fn add_node(&mut self, children: Vec<usize>, rng: &mut impl Rng) -> usize {
+        let id = self.nodes.len();
+        self.nodes.push(children);
+        self.costs.push(rng.gen_range(1.0..10.0));
+        id
+    }
+}
+
+impl Egraph for RandomEgraph {
+    type ClassId = usize;
+    type NodeId = usize;
+
+    fn children(&self, id: &Self::NodeId) -> impl Iterator<Item = &Self::ClassId> {
+        self.nodes[*id].iter()
+    }
+
+    fn members(&self, id: &Self::ClassId) -> impl Iterator<Item = &Self::NodeId> {
+        self.classes[*id].iter()
+    }
+}
+
+impl EgraphTotalCost for RandomEgraph {
+    type CostCtx = ();
+
+    fn precompute(&self, _: &[Self::ClassId]) {}
+
+    fn assignment_utility(&self, _: &(), assignment: &Assignment<Self>) -> Utility {
+        let cost: f32 = assignment.values().map(|node| self.costs[*node]).sum();
+        Utility::new(-cost).unwrap()
+    }
+}
+
+impl EgraphNodeCost for RandomEgraph {
+    fn node_cost(&self, node: &Self::NodeId) -> Utility {
+        Utility::new(self.costs[*node]).unwrap()
+    }
+}
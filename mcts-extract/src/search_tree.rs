@@ -1,19 +1,58 @@
 //! Basic monte-carlo tree search for e-graph extraction.
-use std::cmp;
+use std::{cmp, cmp::Ordering, fmt, iter, mem, num::NonZeroU32, sync::atomic};
 
-use fxhash::FxHashMap;
+use std::sync::Arc;
 
-use crate::{extraction_state::ExtractionState, Assignment, Egraph, MctsConfig, Utility};
+use fxhash::{FxHashMap, FxHashSet};
+use rand::{rngs::StdRng, SeedableRng};
 
+#[cfg(feature = "trace")]
+use crate::trace::{DecisionRecord, Trace, TraceRecorder};
+use crate::{
+    algebra::{ranks, CostAlgebra},
+    analysis::{reachable_class_order, CostBounds},
+    assignment_depth, assignment_hash,
+    choices::{ChoiceLog, Choose},
+    extraction_state::{DeadEnd, EmptyClassPenalty, ExtractionState, WatchedNode},
+    finite_utility,
+    quotas::OpQuotas,
+    selection::{ChildStat, ParentStat},
+    termination::{config_limits, Or, TerminationPolicy},
+    timing::{Clock, SearchTimings},
+    Assignment, Backup, Egraph, ExpansionStrategy, ExtractError, FinalSelection, MctsConfig,
+    NonFiniteUtilities, SearchObjective, Tiebreak, Utility,
+};
+
+/// The index of a node in a [`SearchTree`], plus one.
+///
+/// Offsetting the index leaves zero free, so that an `Option<TreeNodeId>` is
+/// no larger than a `TreeNodeId`.
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
-struct TreeNodeId(u32);
+pub(crate) struct TreeNodeId(NonZeroU32);
 
 impl TreeNodeId {
+    /// The id of the node at the start of the tree's node list.
+    const FIRST: Self = Self(NonZeroU32::MIN);
+
+    /// The most nodes a tree can have before running out of ids.
+    const LIMIT: usize = u32::MAX as usize;
+
+    fn from_index(index: usize) -> Self {
+        let id = u32::try_from(index + 1).unwrap();
+        Self(NonZeroU32::new(id).unwrap())
+    }
+
     fn index(self) -> usize {
-        self.0 as usize
+        self.0.get() as usize - 1
     }
 }
 
+/// The parts of a search tree node that are only needed while descending the
+/// tree or reorganizing it.
+///
+/// The statistics that every playout updates live separately, in
+/// [`NodeStats`], so that backpropagation only touches a compact array.
+#[derive(Clone)]
 struct TreeNode<N, C> {
     /// The class to which this TreeNode corresponds.
     ///
@@ -21,125 +60,1998 @@ struct TreeNode<N, C> {
     /// we are more confident in the correctness of the code, we can remove this
     /// field.
     class: C,
-    n_visits: u32,
-    total_utility: Utility,
+    /// The children of this node, by the decision that leads to each.
+    ///
+    /// Decisions are keyed by class as well as node: with transpositions or
+    /// a cap on nodes per class, a tree node can be shared by paths that go
+    /// on to assign different classes, and an egraph may reuse a node id in
+    /// several classes.
     // NB: look at replacing this with a SmallVec of kv pairs; the arity for
     // most languages / rulesets will be bounded and small.
-    state: FxHashMap<N, TreeNodeId>,
+    state: FxHashMap<(C, N), TreeNodeId>,
+}
+
+/// The statistics backpropagated through a search tree node.
+#[derive(Copy, Clone)]
+struct NodeStats<U> {
+    n_visits: u32,
+    /// The sum of the utilities backpropagated through this node, each
+    /// multiplied by its weight.
+    total_utility: U,
+    /// The sum of the weights of the utilities backpropagated through this
+    /// node. This is equal to `n_visits` unless weighted backpropagation or a
+    /// prior (see [`MctsConfig::prior_strength`]) is enabled.
+    total_weight: Utility,
+    /// The highest utility backpropagated through this node.
+    max_utility: U,
+    /// The sum of the squares of the utilities backpropagated through this
+    /// node, each multiplied by its weight. This is only tracked for scalar
+    /// algebras (see [`CostAlgebra::as_utility`]).
+    total_sq_utility: Utility,
+}
+
+impl<U: CostAlgebra> Default for NodeStats<U> {
+    fn default() -> Self {
+        Self {
+            n_visits: 0,
+            total_utility: U::zero(),
+            total_weight: cast_util(0),
+            max_utility: U::zero(),
+            total_sq_utility: cast_util(0),
+        }
+    }
+}
+
+impl<U: CostAlgebra> NodeStats<U> {
+    fn mean_utility(&self) -> U {
+        if self.total_weight == cast_util(0) {
+            return U::zero();
+        }
+        // Divide scalar sums directly, since scaling them by the reciprocal
+        // rounds differently.
+        self.total_utility
+            .as_utility()
+            .and_then(|total| U::from_utility(total / self.total_weight))
+            .unwrap_or_else(|| {
+                self.total_utility
+                    .scale(1.0 / self.total_weight.into_inner())
+            })
+    }
+
+    /// The half-width of a confidence interval around `mean_utility`, `z`
+    /// standard errors wide. Nodes without any weight behind them have an
+    /// infinitely wide interval. Returns `None` for algebras that are not
+    /// scalar.
+    fn confidence_radius(&self, z: f32) -> Option<f32> {
+        let weight = self.total_weight.into_inner();
+        if weight <= 0.0 {
+            return Some(f32::INFINITY);
+        }
+        let mean = self.mean_utility().as_utility()?.into_inner();
+        let variance = (self.total_sq_utility.into_inner() / weight - mean * mean).max(0.0);
+        Some(z * (variance / weight).sqrt())
+    }
+
+    /// The estimated value of this node, aggregated according to `backup`.
+    fn value(&self, backup: Backup) -> U {
+        match backup {
+            Backup::Mean => self.mean_utility(),
+            Backup::Max => self.max_utility,
+        }
+    }
+
+    /// Backpropagate `eval` through this node.
+    fn record(&mut self, eval: &Evaluation<U>) {
+        // Estimates with no weight behind them are not observations of any
+        // particular utility.
+        if let Some(max) = eval.max_utility {
+            if self.total_weight == cast_util(0) || max.compare(&self.max_utility).is_gt() {
+                self.max_utility = max;
+            }
+        }
+        self.n_visits = self.n_visits.saturating_add(eval.visits);
+        self.total_utility = self.total_utility.add(eval.total_utility);
+        self.total_weight += eval.total_weight;
+        self.total_sq_utility += eval.total_sq_utility;
+    }
+}
+
+/// One or more leaf estimates, combined so that they can be backpropagated
+/// together.
+#[derive(Copy, Clone)]
+struct Evaluation<U> {
+    visits: u32,
+    total_utility: U,
+    total_weight: Utility,
+    total_sq_utility: Utility,
+    /// The highest utility among the estimates with a positive weight.
+    max_utility: Option<U>,
+}
+
+impl<U: CostAlgebra> Default for Evaluation<U> {
+    fn default() -> Self {
+        Self {
+            visits: 0,
+            total_utility: U::zero(),
+            total_weight: cast_util(0),
+            total_sq_utility: cast_util(0),
+            max_utility: None,
+        }
+    }
+}
+
+impl<U: CostAlgebra> Evaluation<U> {
+    /// The weighted mean of the estimates, or `None` if none of them carry any
+    /// weight.
+    #[cfg(feature = "trace")]
+    fn mean_utility(&self) -> Option<U> {
+        (self.total_weight > cast_util(0)).then(|| {
+            self.total_utility
+                .scale(1.0 / self.total_weight.into_inner())
+        })
+    }
+
+    fn add(&mut self, leaf: LeafEstimate<U>) {
+        if leaf.weight > cast_util(0) {
+            self.max_utility = Some(match self.max_utility {
+                Some(max) if max.compare(&leaf.utility).is_ge() => max,
+                _ => leaf.utility,
+            });
+        }
+        self.visits = self.visits.saturating_add(1);
+        self.total_utility = self
+            .total_utility
+            .add(leaf.utility.scale(leaf.weight.into_inner()));
+        self.total_weight += leaf.weight;
+        if let Some(util) = leaf.utility.as_utility() {
+            self.total_sq_utility += util * util * leaf.weight;
+        }
+    }
+}
+
+impl<U: CostAlgebra> From<LeafEstimate<U>> for Evaluation<U> {
+    fn from(leaf: LeafEstimate<U>) -> Self {
+        let mut eval = Self::default();
+        eval.add(leaf);
+        eval
+    }
+}
+
+/// The number of decisions per reachable class after which
+/// [`SearchState::assign`] gives up on a search as non-terminating.
+const WATCHDOG_FACTOR: usize = 2;
+
+/// Convert a count to a utility.
+///
+/// Counts above 2^24 cannot be represented exactly, and are rounded. That
+/// only costs precision in visit-weighted averages, long after a single
+/// visit stops making a difference to them.
+fn cast_util(n: u32) -> Utility {
+    // Converting from an integer never produces NaN, so this never fails.
+    Utility::new(n as f32).unwrap()
+}
+
+/// Compare two utilities, treating values within `epsilon` of one another as
+/// equal. `epsilon` only applies to scalar algebras.
+pub(crate) fn compare_utility<U: CostAlgebra>(a: U, b: U, epsilon: f32) -> Ordering {
+    match (a.as_utility(), b.as_utility()) {
+        (Some(a), Some(b)) if (a.into_inner() - b.into_inner()).abs() <= epsilon => Ordering::Equal,
+        _ => a.compare(&b),
+    }
+}
+
+/// Counters collected over the course of a search.
+#[derive(Clone, Debug, Default)]
+pub struct SearchStats {
+    /// The number of children scored by the selection policy.
+    pub uct_evaluations: usize,
+    /// The number of rounds of playouts run.
+    pub rounds: usize,
+    /// The number of playouts run.
+    pub playouts: usize,
+    /// The number of leaves whose assignment was already complete, and so
+    /// were scored exactly rather than by sampling `terms_to_sample` terms.
+    pub exact_leaf_evaluations: usize,
+    /// The number of leaves scored by sampling random completions.
+    pub sampled_leaf_evaluations: usize,
+    /// The number of playouts that stopped descending the tree because they
+    /// could not beat the cost ceiling.
+    pub pruned_playouts: usize,
+    /// The number of times the search tree was thrown away (see
+    /// [`MctsConfig::restart_interval`]).
+    pub restarts: usize,
+    /// The number of extra playouts run to tell close choices apart (see
+    /// [`MctsConfig::confidence_gate`]).
+    pub disambiguating_playouts: usize,
+    /// The number of nodes in the search tree at the end of the search.
+    pub tree_nodes: usize,
+    /// The approximate size of the search tree, in bytes, at the end of the
+    /// search.
+    pub tree_memory_bytes: usize,
+    /// The cost of the extracted assignment divided by a lower bound on the
+    /// cost of any assignment.
+    ///
+    /// A ratio of 1.0 means the assignment is provably optimal. This is only
+    /// available when the search has node costs (see
+    /// [`SearchSession::prune_with_node_costs`](crate::SearchSession::prune_with_node_costs)),
+    /// an assignment was extracted, and the lower bound is positive.
+    pub optimality_ratio: Option<f32>,
+    /// The number of distinct complete assignments the search scored, as
+    /// told apart by [`assignment_hash`].
+    ///
+    /// Comparing this to `playouts` shows whether the search is exploring new
+    /// terms or revisiting the same few. This is only counted when
+    /// [`MctsConfig::count_unique_assignments`] is set.
+    pub unique_assignments: Option<usize>,
+    /// The number of playouts and random rollouts that could not be
+    /// completed, by cause.
+    pub dead_ends: DeadEnds,
+    /// The utility of the best complete assignment scored so far, if any, and
+    /// if the search's cost algebra has scalar utilities.
+    pub best_utility: Option<Utility>,
+    /// Why running the search to completion failed, if it did.
+    ///
+    /// The one-shot entry points such as
+    /// [`mcts_extract_with_stats`](crate::mcts_extract_with_stats) only
+    /// return `None` for a failed search; this tells a search that found no
+    /// term apart from one stopped by the watchdog
+    /// ([`ExtractError::NonTerminating`]).
+    pub failure: Option<ExtractError>,
+    /// How long the playouts and leaf estimates took, if the search was timing
+    /// them (see
+    /// [`SearchSession::record_timings`](crate::SearchSession::record_timings)).
+    pub timings: Option<SearchTimings>,
+}
+
+/// How far a search has got, for driving a progress bar.
+///
+/// See [`SearchSession::progress`](crate::SearchSession::progress).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SearchProgress {
+    /// The number of classes the search has assigned a node to.
+    pub committed_classes: usize,
+    /// An estimate of the number of classes left to assign: the unassigned
+    /// classes reachable from the next ones to visit, following any member.
+    /// This overestimates, but never grows as the search goes on.
+    pub remaining_classes: usize,
+}
+
+impl SearchProgress {
+    /// The fraction of the classes the search has assigned, out of those it
+    /// has assigned or may still assign.
+    ///
+    /// This starts at 0, never decreases as the search commits to nodes, and
+    /// is 1 once every class has been assigned.
+    pub fn fraction(&self) -> f32 {
+        let total = self.committed_classes + self.remaining_classes;
+        if total == 0 {
+            return 1.0;
+        }
+        self.committed_classes as f32 / total as f32
+    }
+}
+
+impl fmt::Display for SearchStats {
+    /// A one-line summary of the main counters.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} playouts, {} tree nodes ({} bytes), {}",
+            self.playouts, self.tree_nodes, self.tree_memory_bytes, self.dead_ends
+        )?;
+        if let Some(best) = self.best_utility {
+            write!(f, ", best utility {best}")?;
+        }
+        if let Some(unique) = self.unique_assignments {
+            write!(f, ", {unique} unique assignments")?;
+        }
+        if let Some(ratio) = self.optimality_ratio {
+            write!(f, ", optimality ratio {ratio:.3}")?;
+        }
+        if let Some(rate) = self.timings.and_then(|timings| timings.playouts_per_sec()) {
+            write!(f, ", {rate:.0} playouts/s")?;
+        }
+        if let Some(failure) = &self.failure {
+            write!(f, ", failed: {failure}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts of incomplete extractions, by [`DeadEnd`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeadEnds {
+    /// Extractions that reached a mandatory class with no members they could
+    /// use.
+    pub empty_class: usize,
+    /// Extractions left with nodes that depend on themselves through a
+    /// cycle.
+    pub cycle: usize,
+}
+
+impl DeadEnds {
+    pub(crate) fn add(&mut self, dead_end: DeadEnd) {
+        match dead_end {
+            DeadEnd::EmptyClass => self.empty_class += 1,
+            DeadEnd::Cycle => self.cycle += 1,
+        }
+    }
+
+    pub(crate) fn add_all(&mut self, other: &DeadEnds) {
+        self.empty_class += other.empty_class;
+        self.cycle += other.cycle;
+    }
+
+    /// The more common cause of dead ends, if there were any. Ties go to
+    /// [`DeadEnd::Cycle`].
+    pub fn most_common(&self) -> Option<DeadEnd> {
+        if self.empty_class == 0 && self.cycle == 0 {
+            None
+        } else if self.empty_class > self.cycle {
+            Some(DeadEnd::EmptyClass)
+        } else {
+            Some(DeadEnd::Cycle)
+        }
+    }
+}
+
+impl fmt::Display for DeadEnds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} dead ends at empty classes, {} at cycles",
+            self.empty_class, self.cycle
+        )
+    }
+}
+
+/// A utility estimate for a leaf of the search tree, along with how much it
+/// should count for when averaged with other estimates.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct LeafEstimate<U = Utility> {
+    pub(crate) utility: U,
+    pub(crate) weight: Utility,
+}
+
+impl<U> LeafEstimate<U> {
+    /// An estimate with unit weight.
+    pub(crate) fn new(utility: U) -> Self {
+        Self {
+            utility,
+            weight: cast_util(1),
+        }
+    }
+}
+
+/// Where a finished search keeps the assignment it settled on.
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Settled {
+    /// The best complete assignment it scored.
+    Best,
+    /// The assignment it committed to, one decision at a time.
+    Committed,
+}
+
+/// A callback for each assignment that becomes the best during a search.
+pub(crate) type NewBestCallback<E, U> = Box<dyn FnMut(&Assignment<E>, U) + Send>;
+
+/// The highest-utility complete assignment seen during a search.
+pub(crate) struct BestAssignment<E: Egraph, U = Utility> {
+    best: Option<(Assignment<E>, U)>,
+    epsilon: f32,
+    cost_ceiling: Option<Utility>,
+    /// The hashes of every assignment offered so far, if we are counting
+    /// them.
+    seen: Option<FxHashSet<u64>>,
+    /// The best distinct assignments offered so far, best first, along with
+    /// their hashes.
+    top: Vec<(u64, Assignment<E>, U)>,
+    /// How many assignments to keep in `top`.
+    keep: usize,
+    /// The first assignment offered with a utility that was not finite, in
+    /// `Debug` form.
+    non_finite: Option<String>,
+    /// Called with each assignment that becomes the best.
+    on_new_best: Option<NewBestCallback<E, U>>,
+    /// How to choose between assignments of equal utility.
+    tiebreak: Tiebreak,
+    /// The DAG depth of `best`, with [`Tiebreak::ShallowestDag`].
+    best_depth: Option<Utility>,
+}
+
+impl<E: Egraph, U: CostAlgebra> BestAssignment<E, U> {
+    /// Track the best assignment whose cost is below `cost_ceiling`, if any.
+    pub(crate) fn new(epsilon: f32, cost_ceiling: Option<Utility>) -> Self {
+        Self {
+            best: None,
+            epsilon,
+            cost_ceiling,
+            seen: None,
+            top: Vec::new(),
+            keep: 0,
+            non_finite: None,
+            on_new_best: None,
+            tiebreak: Tiebreak::FirstSeen,
+            best_depth: None,
+        }
+    }
+
+    /// Call `callback` with every assignment that becomes the best from now
+    /// on, and its utility.
+    pub(crate) fn on_new_best(&mut self, callback: NewBestCallback<E, U>) {
+        self.on_new_best = Some(callback);
+    }
+
+    /// Also keep the `k` best distinct assignments offered from now on.
+    pub(crate) fn keep_top(&mut self, k: usize) {
+        self.keep = k;
+        self.top.truncate(k);
+    }
+
+    /// The best distinct assignments offered since [`keep_top`](Self::keep_top),
+    /// best first.
+    pub(crate) fn top(&self) -> impl Iterator<Item = (&Assignment<E>, U)> {
+        self.top.iter().map(|(_, assign, util)| (assign, *util))
+    }
+
+    /// Choose between assignments of equal utility offered from now on
+    /// according to `tiebreak`.
+    pub(crate) fn set_tiebreak(&mut self, tiebreak: Tiebreak) {
+        self.tiebreak = tiebreak;
+    }
+
+    /// Count the distinct assignments offered from now on.
+    pub(crate) fn count_unique(&mut self) {
+        self.seen.get_or_insert_with(Default::default);
+    }
+
+    /// The number of distinct assignments offered, if we are counting them.
+    pub(crate) fn n_unique(&self) -> Option<usize> {
+        self.seen.as_ref().map(FxHashSet::len)
+    }
+
+    /// Record `assign` if it is better than the current best.
+    ///
+    /// Utilities within `epsilon` of the current best are treated as ties, in
+    /// which case the earlier assignment is kept, unless the tiebreak prefers
+    /// the new one.
+    ///
+    /// Scalar utilities that are not finite are clamped (see
+    /// [`finite_utility`]), and the first assignment offered with one is
+    /// remembered.
+    pub(crate) fn offer(&mut self, egraph: &E, assign: &Assignment<E>, mut util: U) {
+        if let Some(raw) = util.as_utility().filter(|util| !util.is_finite()) {
+            self.non_finite.get_or_insert_with(|| format!("{assign:?}"));
+            util = U::from_utility(finite_utility(raw)).unwrap();
+        }
+        if let Some(seen) = &mut self.seen {
+            seen.insert(assignment_hash::<E>(assign));
+        }
+        if matches!(
+            (self.cost_ceiling, util.as_utility()),
+            (Some(ceiling), Some(util)) if -util >= ceiling
+        ) {
+            return;
+        }
+        if self.keep > 0 {
+            self.offer_top(assign, util);
+        }
+        let shallowest = self.tiebreak == Tiebreak::ShallowestDag;
+        let mut depth = None;
+        if let Some((_, best)) = &self.best {
+            match compare_utility(util, *best, self.epsilon) {
+                Ordering::Less => return,
+                Ordering::Equal if !shallowest => return,
+                Ordering::Equal => {
+                    depth = dag_depth(egraph, assign);
+                    if !matches!((depth, self.best_depth), (Some(new), Some(old)) if new < old) {
+                        return;
+                    }
+                }
+                Ordering::Greater => {}
+            }
+        }
+        if shallowest {
+            self.best_depth = depth.or_else(|| dag_depth(egraph, assign));
+        }
+        self.best = Some((assign.clone(), util));
+        if let Some(callback) = &mut self.on_new_best {
+            callback(assign, util);
+        }
+    }
+
+    /// Add `assign` to the `top` assignments if it is new and good enough.
+    fn offer_top(&mut self, assign: &Assignment<E>, util: U) {
+        if self.top.len() == self.keep
+            && self
+                .top
+                .last()
+                .is_some_and(|(_, _, worst)| util.compare(worst).is_le())
+        {
+            return;
+        }
+        let hash = assignment_hash::<E>(assign);
+        if self
+            .top
+            .iter()
+            .any(|(h, other, _)| *h == hash && other == assign)
+        {
+            return;
+        }
+        // Ties keep the earlier assignment ahead.
+        let pos = self
+            .top
+            .partition_point(|(_, _, other)| other.compare(&util).is_ge());
+        self.top.insert(pos, (hash, assign.clone(), util));
+        self.top.truncate(self.keep);
+    }
+
+    pub(crate) fn assignment(&self) -> Option<&Assignment<E>> {
+        self.best.as_ref().map(|(assign, _)| assign)
+    }
+
+    /// The first assignment offered with a utility that was not finite, in
+    /// `Debug` form.
+    pub(crate) fn non_finite(&self) -> Option<&str> {
+        self.non_finite.as_deref()
+    }
+
+    pub(crate) fn utility(&self) -> Option<U> {
+        self.best.as_ref().map(|(_, util)| *util)
+    }
 }
 
-const fn cast_util(n: u32) -> Utility {
-    // SAFETY: We are always converting from a u32, which will always round to a
-    // non-NaN value.
-    unsafe { Utility::new_unchecked(n as f32) }
+/// The number of nodes along the longest path through the DAG of `assign`.
+fn dag_depth<E: Egraph>(egraph: &E, assign: &Assignment<E>) -> Option<Utility> {
+    assignment_depth(egraph, assign, |_| Utility::new(1.0).unwrap())
 }
 
-/// Compute the score of the current node given the total ruounds run under
-/// the parent node, and a constant `c` for weighting exploration.
-fn uct_score(
-    child_rounds: u32,
-    child_avg_utility: Utility,
-    total_rounds: u32,
-    c: Utility,
-) -> Utility {
-    let n_visits = cmp::max(child_rounds, 1) as f32;
-    let exploration_term =
-        c * Utility::new(((total_rounds as f32).ln() / n_visits).sqrt()).unwrap();
-    child_avg_utility + exploration_term
+/// Produces utility estimates for the leaves of the search tree.
+pub(crate) trait EstimateUtility<E: Egraph, U = Utility> {
+    /// Estimate the utility of the (possibly partial) assignment in `state`,
+    /// offering any complete assignments that get scored along the way to
+    /// `best`.
+    fn estimate(
+        &mut self,
+        state: &mut ExtractionState<E>,
+        egraph: &E,
+        best: &mut BestAssignment<E, U>,
+        choices: &mut dyn Choose<E::ClassId>,
+    ) -> LeafEstimate<U>;
+
+    /// Note that the leaves estimated from now on are children of `parent`,
+    /// or of no node at all. Estimators can use this to share work between
+    /// siblings.
+    fn set_parent(&mut self, _parent: Option<TreeNodeId>) {}
+
+    /// The number of terms the estimator has scored so far, for
+    /// [`SearchTimings::utility_calls`]. The default returns 0.
+    fn utility_calls(&self) -> usize {
+        0
+    }
 }
 
-pub(crate) struct SearchTree<E: Egraph> {
+impl<E, U, F> EstimateUtility<E, U> for F
+where
+    E: Egraph,
+    F: FnMut(&mut ExtractionState<E>, &E, &mut BestAssignment<E, U>) -> LeafEstimate<U>,
+{
+    fn estimate(
+        &mut self,
+        state: &mut ExtractionState<E>,
+        egraph: &E,
+        best: &mut BestAssignment<E, U>,
+        _: &mut dyn Choose<E::ClassId>,
+    ) -> LeafEstimate<U> {
+        self(state, egraph, best)
+    }
+}
+
+/// Search statistics for one of the nodes that could be assigned to a class.
+#[derive(Clone, Debug)]
+pub struct CandidateStats<N, U = Utility> {
+    pub node: N,
+    /// The number of playouts that assigned this node to the class.
+    pub visits: u32,
+    /// The estimated utility of assigning this node to the class.
+    pub value: U,
+}
+
+/// The result of a single step of a search: a round of playouts followed by a
+/// decision.
+pub enum StepOutcome<E: Egraph, U = Utility> {
+    /// The search assigned `node` to `class`.
+    Committed { class: E::ClassId, node: E::NodeId },
+    /// The search reached a breakpoint class and is waiting for the caller to
+    /// choose one of the candidate nodes.
+    Breakpoint {
+        class: E::ClassId,
+        candidates: Vec<CandidateStats<E::NodeId, U>>,
+    },
+    /// Every class in the term has been assigned.
+    Done,
+    /// The search could not find a node to assign to the next class, so
+    /// it cannot finish the term it committed to. The best complete
+    /// assignment it scored along the way, if any, is still a term.
+    Failed,
+    /// The search ran into one of its resource limits before finishing.
+    Stopped,
+}
+
+// NB: deriving Debug would require `E: Debug`.
+impl<E: Egraph, U: fmt::Debug> fmt::Debug for StepOutcome<E, U> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StepOutcome::Committed { class, node } => f
+                .debug_struct("Committed")
+                .field("class", class)
+                .field("node", node)
+                .finish(),
+            StepOutcome::Breakpoint { class, candidates } => f
+                .debug_struct("Breakpoint")
+                .field("class", class)
+                .field("candidates", candidates)
+                .finish(),
+            StepOutcome::Done => f.write_str("Done"),
+            StepOutcome::Failed => f.write_str("Failed"),
+            StepOutcome::Stopped => f.write_str("Stopped"),
+        }
+    }
+}
+
+pub(crate) struct SearchTree<E: Egraph, U = Utility> {
     root_class: E::ClassId,
     root_tree_node: TreeNodeId,
     nodes: Vec<TreeNode<E::NodeId, E::ClassId>>,
+    /// The statistics for each node in `nodes`, at the same index.
+    stats: Vec<NodeStats<U>>,
+    /// The approximate number of bytes allocated for the `state` maps of all
+    /// nodes in the tree, maintained incrementally as children are added.
+    child_map_bytes: usize,
+    /// When transpositions are enabled, the node reached by each `(class,
+    /// node)` decision, regardless of the path taken to get there.
+    transpositions: FxHashMap<E::ClassId, FxHashMap<E::NodeId, TreeNodeId>>,
+    /// The most nodes to create for a class before its decisions share nodes
+    /// (see [`MctsConfig::max_tree_nodes_per_class`]).
+    class_node_cap: Option<usize>,
+    /// When `class_node_cap` is set, the nodes created for each class.
+    class_nodes: FxHashMap<E::ClassId, ClassNodes<E>>,
+    /// The most nodes the tree may have. This is [`TreeNodeId::LIMIT`],
+    /// except in tests.
+    node_limit: usize,
+}
+
+/// The nodes of a [`SearchTree`] for one class.
+struct ClassNodes<E: Egraph> {
+    count: usize,
+    /// The first node created for assigning each node to the class.
+    by_decision: FxHashMap<E::NodeId, TreeNodeId>,
+}
+
+impl<E: Egraph> Default for ClassNodes<E> {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            by_decision: Default::default(),
+        }
+    }
+}
+
+impl<E: Egraph> Clone for ClassNodes<E> {
+    fn clone(&self) -> Self {
+        Self {
+            count: self.count,
+            by_decision: self.by_decision.clone(),
+        }
+    }
 }
 
-impl<E: Egraph> SearchTree<E> {
+impl<E: Egraph, U: CostAlgebra> SearchTree<E, U> {
     pub(crate) fn new(root_class: E::ClassId) -> Self {
-        let root_tree_node = TreeNodeId(0);
+        let root_tree_node = TreeNodeId::FIRST;
         Self {
             root_class: root_class.clone(),
             root_tree_node,
             nodes: vec![TreeNode {
                 class: root_class,
-                n_visits: Default::default(),
-                total_utility: Default::default(),
                 state: Default::default(),
             }],
+            stats: vec![NodeStats::default()],
+            child_map_bytes: 0,
+            transpositions: Default::default(),
+            class_node_cap: None,
+            class_nodes: Default::default(),
+            node_limit: TreeNodeId::LIMIT,
         }
     }
 
+    /// Whether the tree has run out of ids for new nodes.
+    pub(crate) fn is_full(&self) -> bool {
+        self.nodes.len() >= self.node_limit
+    }
+
+    /// Pretend that the tree runs out of ids after `limit` nodes.
+    #[cfg(test)]
+    pub(crate) fn set_node_limit(&mut self, limit: usize) {
+        assert!((2..=TreeNodeId::LIMIT).contains(&limit));
+        self.node_limit = limit;
+    }
+
+    /// An estimate of the number of bytes allocated for the tree.
+    pub(crate) fn memory_usage(&self) -> usize {
+        self.nodes.capacity() * mem::size_of::<TreeNode<E::NodeId, E::ClassId>>()
+            + self.stats.capacity() * mem::size_of::<NodeStats<U>>()
+            + self.child_map_bytes
+    }
+
+    #[cfg(test)]
     pub(crate) fn start_round<F>(
-        &mut self,
+        self,
+        config: MctsConfig,
+        estimate_util: F,
+        exploration_term: Utility,
+    ) -> SearchState<E, F, U> {
+        let assignment = ExtractionState::new(self.root_class.clone());
+        self.start_round_with(config, estimate_util, exploration_term, assignment)
+    }
+
+    /// Like [`start_round`](Self::start_round), but build assignments in
+    /// `assignment`, a fresh state for the tree's root class.
+    pub(crate) fn start_round_with<F>(
+        mut self,
+        config: MctsConfig,
         estimate_util: F,
         exploration_term: Utility,
-    ) -> SearchState<E, F> {
-        let root_class = self.root_class.clone();
+        mut assignment: ExtractionState<E>,
+    ) -> SearchState<E, F, U> {
         let start_node = self.root_tree_node;
+        let fpu_reduction =
+            Utility::new(config.fpu_reduction).expect("fpu_reduction must not be NaN");
+        if let Some(schedule) = &config.exploration_schedule {
+            assert!(
+                schedule.c_base > 0.0,
+                "exploration schedule's c_base must be positive, got {}",
+                schedule.c_base
+            );
+        }
+        let mut best = BestAssignment::new(config.epsilon, config.cost_ceiling);
+        best.set_tiebreak(config.tiebreak);
+        if config.count_unique_assignments {
+            best.count_unique();
+        }
+        let choices = ChoiceLog::new(match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        });
+        self.class_node_cap = config.max_tree_nodes_per_class;
+        assignment.set_rollout_policy(config.rollout_policy);
+        assignment.set_rollout_max_depth(config.rollout_max_depth);
+        let termination = config_limits(&config);
         SearchState {
+            best,
+            config,
             tree: self,
-            assignment: ExtractionState::new(root_class),
+            assignment,
             start_node,
             path: Default::default(),
             estimate_util,
             exploration_term,
+            fpu_reduction,
+            stats: Default::default(),
+            breakpoints: Default::default(),
+            decisions: Default::default(),
+            child_stats: Default::default(),
+            child_values: Default::default(),
+            choices,
+            rounds_since_restart: 0,
+            rounds: 0,
+            n_reachable: None,
+            forced_choice: None,
+            termination,
+            clock: None,
+            #[cfg(feature = "trace")]
+            trace: None,
+            #[cfg(feature = "trace")]
+            playout_trace: None,
         }
     }
 
-    fn fresh_node(&mut self, class: E::ClassId) -> TreeNodeId {
-        let res = TreeNodeId(u32::try_from(self.nodes.len()).unwrap());
+    /// The number of nodes in the tree.
+    pub(crate) fn n_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// The number of nodes in the tree for `class`.
+    #[cfg(test)]
+    pub(crate) fn n_class_nodes(&self, class: &E::ClassId) -> usize {
+        self.nodes
+            .iter()
+            .filter(|node| node.class == *class)
+            .count()
+    }
+
+    /// Add a node for `class`, unless the tree is full.
+    fn fresh_node(&mut self, class: E::ClassId) -> Option<TreeNodeId> {
+        if self.is_full() {
+            return None;
+        }
+        let res = TreeNodeId::from_index(self.nodes.len());
         self.nodes.push(TreeNode {
             class,
-            n_visits: Default::default(),
-            total_utility: Default::default(),
             state: Default::default(),
         });
-        res
+        self.stats.push(NodeStats::default());
+        Some(res)
+    }
+
+    /// Find the child of `parent` reached by assigning `enode` to `class`.
+    ///
+    /// If `shared` is set, this falls back to the transposition table when
+    /// `parent` does not have such a child yet.
+    fn find_child(
+        &self,
+        parent: TreeNodeId,
+        class: &E::ClassId,
+        enode: &E::NodeId,
+        shared: bool,
+    ) -> Option<TreeNodeId> {
+        let decision = (class.clone(), enode.clone());
+        if let Some(child) = self.nodes[parent.index()].state.get(&decision) {
+            return Some(*child);
+        }
+        if !shared {
+            return None;
+        }
+        self.transpositions.get(class)?.get(enode).copied()
+    }
+
+    /// Like `find_child`, but creates the child if it does not exist.
+    ///
+    /// Returns `None` if the child does not exist and the tree is full.
+    fn get_or_insert_child(
+        &mut self,
+        parent: TreeNodeId,
+        class: &E::ClassId,
+        enode: &E::NodeId,
+        shared: bool,
+    ) -> Option<TreeNodeId> {
+        let decision = (class.clone(), enode.clone());
+        if let Some(child) = self.nodes[parent.index()].state.get(&decision) {
+            return Some(*child);
+        }
+        let child = match self
+            .find_child(parent, class, enode, shared)
+            .or_else(|| self.find_past_cap(class, enode))
+        {
+            Some(child) => child,
+            None => {
+                let new = self.fresh_node(class.clone())?;
+                if shared {
+                    self.transpositions
+                        .entry(class.clone())
+                        .or_default()
+                        .insert(enode.clone(), new);
+                }
+                if self.class_node_cap.is_some() {
+                    let nodes = self.class_nodes.entry(class.clone()).or_default();
+                    nodes.count += 1;
+                    nodes.by_decision.entry(enode.clone()).or_insert(new);
+                }
+                new
+            }
+        };
+        self.add_child(parent, decision, child);
+        Some(child)
+    }
+
+    /// The node to share for assigning `enode` to `class`, if `class` already
+    /// has as many nodes as it may and one of them is for that decision.
+    fn find_past_cap(&self, class: &E::ClassId, enode: &E::NodeId) -> Option<TreeNodeId> {
+        let cap = self.class_node_cap?;
+        let nodes = self.class_nodes.get(class)?;
+        if nodes.count < cap {
+            return None;
+        }
+        nodes.by_decision.get(enode).copied()
+    }
+
+    /// Make `new_root` the root of the tree, dropping every node that is not
+    /// reachable from it and renumbering the rest.
+    ///
+    /// The statistics for the remaining nodes are unchanged. The tree then
+    /// describes searches that start from the partial assignment that led to
+    /// `new_root`.
+    fn reroot(&mut self, new_root: TreeNodeId) {
+        // Number the reachable nodes in breadth-first order. With
+        // transpositions, a node may be reachable along several paths.
+        let mut remap = vec![None; self.nodes.len()];
+        let mut order = vec![new_root];
+        remap[new_root.index()] = Some(TreeNodeId::FIRST);
+        let mut next = 0;
+        while let Some(&old) = order.get(next) {
+            next += 1;
+            for child in self.nodes[old.index()].state.values() {
+                if remap[child.index()].is_none() {
+                    remap[child.index()] = Some(TreeNodeId::from_index(order.len()));
+                    order.push(*child);
+                }
+            }
+        }
+
+        let mut old_nodes = mem::take(&mut self.nodes)
+            .into_iter()
+            .map(Some)
+            .collect::<Vec<_>>();
+        let old_stats = mem::take(&mut self.stats);
+        self.nodes = Vec::with_capacity(order.len());
+        self.stats = Vec::with_capacity(order.len());
+        self.child_map_bytes = 0;
+        for old in order {
+            let mut node = old_nodes[old.index()].take().unwrap();
+            self.stats.push(old_stats[old.index()]);
+            for child in node.state.values_mut() {
+                *child = remap[child.index()].unwrap();
+            }
+            self.child_map_bytes += node.state.capacity()
+                * (mem::size_of::<((E::ClassId, E::NodeId), TreeNodeId)>() + 1);
+            self.nodes.push(node);
+        }
+        for decisions in self.transpositions.values_mut() {
+            decisions.retain(|_, id| match remap[id.index()] {
+                Some(new) => {
+                    *id = new;
+                    true
+                }
+                None => false,
+            });
+        }
+        self.transpositions
+            .retain(|_, decisions| !decisions.is_empty());
+        if self.class_node_cap.is_some() {
+            self.class_nodes.clear();
+            for node in &self.nodes {
+                for ((class, enode), child) in &node.state {
+                    let nodes = self.class_nodes.entry(class.clone()).or_default();
+                    nodes.by_decision.entry(enode.clone()).or_insert(*child);
+                }
+            }
+            for node in &self.nodes {
+                self.class_nodes
+                    .entry(node.class.clone())
+                    .or_default()
+                    .count += 1;
+            }
+        }
+        self.root_tree_node = TreeNodeId::FIRST;
+    }
+
+    /// Make `at` the root of the tree, and drop every other node, or every
+    /// node below its children if `keep_children` is set.
+    ///
+    /// Without `keep_children`, the statistics for `at` are reset as well.
+    fn restart(&mut self, at: TreeNodeId, keep_children: bool) {
+        if keep_children {
+            let children = self.nodes[at.index()]
+                .state
+                .values()
+                .copied()
+                .filter(|child| *child != at)
+                .collect::<Vec<_>>();
+            for child in children {
+                self.nodes[child.index()].state = Default::default();
+            }
+        } else {
+            self.nodes[at.index()].state = Default::default();
+            self.stats[at.index()] = NodeStats::default();
+        }
+        self.reroot(at);
+    }
+
+    /// Add the statistics gathered below `other_at` in `other` to the
+    /// subtree below `at`.
+    ///
+    /// Both nodes must describe the same partial assignment. Nodes reached by
+    /// the same sequence of decisions from them have their statistics
+    /// combined, and decisions that only `other` has explored are added to
+    /// this tree, as long as it has room for them. As in each tree, a node's
+    /// visit count stays at least the sum of its children's.
+    fn merge(&mut self, at: TreeNodeId, other: &Self, other_at: TreeNodeId) {
+        // Trees only record transpositions if they were built with them
+        // enabled.
+        let shared = !self.transpositions.is_empty() || !other.transpositions.is_empty();
+        // With transpositions, a node may be reachable along several paths,
+        // but its statistics must only be added once.
+        let mut merged = FxHashMap::default();
+        merged.insert(other_at, at);
+        let mut to_merge = vec![(other_at, at)];
+        while let Some((from, to)) = to_merge.pop() {
+            let from_stats = &other.stats[from.index()];
+            let stats = &mut self.stats[to.index()];
+            if from_stats.total_weight > cast_util(0)
+                && (stats.total_weight == cast_util(0)
+                    || from_stats.max_utility.compare(&stats.max_utility).is_gt())
+            {
+                stats.max_utility = from_stats.max_utility;
+            }
+            stats.n_visits = stats.n_visits.saturating_add(from_stats.n_visits);
+            stats.total_utility = stats.total_utility.add(from_stats.total_utility);
+            stats.total_weight += from_stats.total_weight;
+            stats.total_sq_utility += from_stats.total_sq_utility;
+            let from = &other.nodes[from.index()];
+            for ((class, enode), other_child) in &from.state {
+                let Some(child) = self.get_or_insert_child(to, class, enode, shared) else {
+                    continue;
+                };
+                if merged.insert(*other_child, child).is_none() {
+                    to_merge.push((*other_child, child));
+                }
+            }
+        }
+    }
+
+    fn add_child(
+        &mut self,
+        parent: TreeNodeId,
+        decision: (E::ClassId, E::NodeId),
+        child: TreeNodeId,
+    ) {
+        let state = &mut self.nodes[parent.index()].state;
+        let old_capacity = state.capacity();
+        state.insert(decision, child);
+        // hashbrown stores one control byte per bucket alongside each entry.
+        self.child_map_bytes += (state.capacity() - old_capacity)
+            * (mem::size_of::<((E::ClassId, E::NodeId), TreeNodeId)>() + 1);
     }
 }
 
-pub(crate) struct SearchState<'a, E: Egraph, F> {
-    tree: &'a mut SearchTree<E>,
+impl<E: Egraph, U: CostAlgebra> Clone for SearchTree<E, U> {
+    fn clone(&self) -> Self {
+        Self {
+            root_class: self.root_class.clone(),
+            root_tree_node: self.root_tree_node,
+            nodes: self.nodes.clone(),
+            stats: self.stats.clone(),
+            child_map_bytes: self.child_map_bytes,
+            transpositions: self.transpositions.clone(),
+            class_node_cap: self.class_node_cap,
+            class_nodes: self.class_nodes.clone(),
+            node_limit: self.node_limit,
+        }
+    }
+}
+
+pub(crate) struct SearchState<E: Egraph, F, U = Utility> {
+    tree: SearchTree<E, U>,
+    config: MctsConfig,
     assignment: ExtractionState<E>,
     start_node: TreeNodeId,
     path: Vec<TreeNodeId>,
     estimate_util: F,
     exploration_term: Utility,
+    /// [`MctsConfig::fpu_reduction`], checked once up front.
+    fpu_reduction: Utility,
+    stats: SearchStats,
+    best: BestAssignment<E, U>,
+    /// Classes at which `step` hands the decision back to the caller.
+    breakpoints: FxHashSet<E::ClassId>,
+    /// The decisions committed so far, in order.
+    decisions: Vec<(E::ClassId, E::NodeId)>,
+    /// Scratch space for describing candidates to the selection policy.
+    child_stats: Vec<ChildStat>,
+    /// Scratch space for ranking candidates' values, for algebras that are
+    /// not scalar.
+    child_values: Vec<U>,
+    /// The source of every random choice the search makes.
+    choices: ChoiceLog<E::ClassId, StdRng>,
+    /// The number of rounds run since the tree was last thrown away.
+    rounds_since_restart: u32,
+    /// The number of rounds run in total.
+    rounds: usize,
+    /// The number of classes reachable from the root, if the caller supplied
+    /// it.
+    n_reachable: Option<usize>,
+    /// A node that playouts must assign to the next class, rather than
+    /// consulting the selection policy.
+    forced_choice: Option<E::NodeId>,
+    /// The policy for stopping the search early, combining the limits in
+    /// `config` with the caller's, if there are any.
+    termination: Option<Box<dyn TerminationPolicy>>,
+    /// The clock to time playouts and leaf estimates with, if timing them.
+    clock: Option<Box<dyn Clock>>,
+    /// The decisions recorded so far, if recording.
+    #[cfg(feature = "trace")]
+    trace: Option<Trace<E::ClassId, E::NodeId, U>>,
+    /// The playouts recorded so far, if recording.
+    #[cfg(feature = "trace")]
+    playout_trace: Option<TraceRecorder<U>>,
 }
 
-impl<E: Egraph, F: FnMut(&mut ExtractionState<E>, &E) -> Utility> SearchState<'_, E, F> {
-    /// Pick the next node in the assignment based on the data in the current playouts.
+impl<E: Egraph, F, U: CostAlgebra> SearchState<E, F, U> {
+    pub(crate) fn estimator(&self) -> &F {
+        &self.estimate_util
+    }
+
+    pub(crate) fn stats(&self) -> SearchStats {
+        SearchStats {
+            tree_nodes: self.tree.n_nodes(),
+            tree_memory_bytes: self.tree.memory_usage(),
+            unique_assignments: self.best.n_unique(),
+            rounds: self.rounds,
+            best_utility: self.best.utility().and_then(|util| util.as_utility()),
+            ..self.stats.clone()
+        }
+    }
+
+    /// Allow the classes in `optional` to be left unassigned.
     ///
-    /// Returns false if the current node is a leaf.
-    fn pick_node(&mut self, egraph: &E) -> Option<bool> {
-        // Look at the current start node and pick the child with the highest
-        // number of visits.
-        let Some(handle) = self.assignment.start_next_assign() else {
-            return Some(false);
-        };
+    /// This must be called before the search makes any decisions.
+    pub(crate) fn set_optional(&mut self, optional: FxHashSet<E::ClassId>) {
+        assert!(
+            self.decisions.is_empty(),
+            "optional classes must be set before the search starts"
+        );
+        self.assignment
+            .set_optional(self.tree.root_class.clone(), optional);
+    }
+
+    /// Only consider assignments that fit within `quotas`.
+    ///
+    /// This must be called before the search makes any decisions.
+    pub(crate) fn set_quotas(&mut self, quotas: Arc<OpQuotas<E>>) {
+        assert!(
+            self.decisions.is_empty(),
+            "quotas must be set before the search starts"
+        );
+        self.assignment.set_quotas(quotas);
+    }
+
+    /// Only consider assignments that assign `then` whenever they assign
+    /// `when`.
+    ///
+    /// This must be called before the search makes any decisions.
+    pub(crate) fn require_if(
+        &mut self,
+        when: (E::ClassId, E::NodeId),
+        then: (E::ClassId, E::NodeId),
+    ) {
+        assert!(
+            self.decisions.is_empty(),
+            "implications must be added before the search starts"
+        );
+        self.assignment.add_implication(when, then);
+    }
+
+    /// Let random samples leave out classes with no usable members, at the
+    /// cost `penalty` gives them.
+    pub(crate) fn on_empty_class(&mut self, penalty: EmptyClassPenalty<E>) {
+        self.assignment.set_empty_class_penalty(penalty);
+    }
+
+    /// The state the search builds assignments in, for another search to
+    /// reuse.
+    pub(crate) fn into_assignment_state(self) -> ExtractionState<E> {
+        self.assignment
+    }
+
+    /// Visit new classes in the order of the egraph's topological order hint,
+    /// `hint`.
+    ///
+    /// This must be called before the search makes any decisions.
+    pub(crate) fn set_topo_hint(&mut self, hint: Vec<E::ClassId>) {
+        assert!(
+            self.decisions.is_empty(),
+            "the visit order must be set before the search starts"
+        );
+        let order = hint.into_iter().enumerate().map(|(i, class)| (class, i));
+        self.assignment.set_visit_order(Arc::new(order.collect()));
+    }
+
+    /// Prune partial assignments that cannot beat the configured cost
+    /// ceiling, according to `bounds`.
+    ///
+    /// This must be called before the search makes any decisions.
+    pub(crate) fn set_cost_bounds(&mut self, bounds: Arc<CostBounds<E>>) {
+        assert!(
+            self.decisions.is_empty(),
+            "cost bounds must be set before the search starts"
+        );
+        self.assignment.set_cost_bounds(bounds);
+    }
+
+    pub(crate) fn cost_bounds(&self) -> Option<Arc<CostBounds<E>>> {
+        self.assignment.cost_bounds()
+    }
+
+    /// Complete partial assignments greedily by the node costs in `bounds`
+    /// when estimating them, rather than at random.
+    ///
+    /// This must be called before the search makes any decisions.
+    pub(crate) fn set_greedy_rollouts(&mut self, bounds: Arc<CostBounds<E>>) {
+        assert!(
+            self.decisions.is_empty(),
+            "the rollout policy must be set before the search starts"
+        );
+        self.assignment.set_greedy_rollouts(bounds);
+    }
+
+    /// Take the number of classes reachable from the root to be
+    /// `n_reachable`, rather than counting them.
+    ///
+    /// This must be called before the search makes any decisions.
+    pub(crate) fn set_n_reachable(&mut self, n_reachable: usize) {
+        assert!(
+            self.decisions.is_empty(),
+            "the analysis must be supplied before the search starts"
+        );
+        self.n_reachable = Some(n_reachable);
+    }
+
+    pub(crate) fn root_class(&self) -> &E::ClassId {
+        &self.tree.root_class
+    }
+
+    #[cfg(test)]
+    pub(crate) fn tree(&self) -> &SearchTree<E, U> {
+        &self.tree
+    }
+
+    pub(crate) fn choices(&self) -> &ChoiceLog<E::ClassId, StdRng> {
+        &self.choices
+    }
+
+    pub(crate) fn choices_mut(&mut self) -> &mut ChoiceLog<E::ClassId, StdRng> {
+        &mut self.choices
+    }
+
+    /// The estimator, the current partial assignment, and the source of
+    /// random choices, borrowed together so the estimator can sample from the
+    /// assignment.
+    pub(crate) fn sampling_parts(
+        &mut self,
+    ) -> (
+        &F,
+        &mut ExtractionState<E>,
+        &mut ChoiceLog<E::ClassId, StdRng>,
+    ) {
+        (&self.estimate_util, &mut self.assignment, &mut self.choices)
+    }
+
+    pub(crate) fn add_breakpoint(&mut self, class: E::ClassId) {
+        self.breakpoints.insert(class);
+    }
+
+    /// Throw away the tree below the current decision, keeping the
+    /// statistics for its choices if configured to.
+    fn restart(&mut self, egraph: &E) {
+        self.tree
+            .restart(self.start_node, self.config.restart_keeps_root_stats);
+        self.start_node = self.tree.root_tree_node;
+        self.stats.restarts += 1;
+        #[cfg(feature = "trace")]
+        if self.trace.is_some() {
+            let candidates = self.next_candidates(egraph);
+            self.trace.as_mut().unwrap().start_decision(&candidates);
+        }
+        #[cfg(not(feature = "trace"))]
+        let _ = egraph;
+    }
+
+    /// Drop the parts of the tree that are not below the current decision.
+    pub(crate) fn reroot(&mut self) {
+        self.tree.reroot(self.start_node);
+        self.start_node = self.tree.root_tree_node;
+    }
+
+    /// Add the statistics gathered by `other`, which must have made the same
+    /// decisions as this search, to this search's tree.
+    pub(crate) fn merge<G>(&mut self, other: &SearchState<E, G, U>) {
+        assert_eq!(
+            self.decisions, other.decisions,
+            "only searches that made the same decisions can be merged"
+        );
+        self.tree
+            .merge(self.start_node, &other.tree, other.start_node);
+    }
+
+    /// The next class to assign, if any.
+    pub(crate) fn next_class(&mut self) -> Option<E::ClassId> {
+        Some(self.assignment.start_next_assign()?.class().clone())
+    }
+
+    /// The statistics for each member of the next class to assign.
+    pub(crate) fn next_candidates(&mut self, egraph: &E) -> Vec<CandidateStats<E::NodeId, U>> {
+        match self.next_class() {
+            Some(class) => self.root_child_stats(&class, egraph),
+            None => Vec::new(),
+        }
+    }
+
+    /// The decisions committed so far, in the order they were made.
+    pub(crate) fn decisions(&self) -> &[(E::ClassId, E::NodeId)] {
+        &self.decisions
+    }
+
+    /// How many classes the decisions so far have assigned, and how many may
+    /// be left.
+    pub(crate) fn progress(&self, egraph: &E) -> SearchProgress {
+        SearchProgress {
+            committed_classes: self.assignment.n_assigned(),
+            remaining_classes: self.assignment.n_reachable_unassigned(egraph),
+        }
+    }
+
+    /// The committed nodes that are still waiting for some of their children.
+    pub(crate) fn pending_nodes(&self) -> Vec<WatchedNode<E::ClassId, E::NodeId>> {
+        self.assignment.pending_nodes()
+    }
+
+    /// The current assignment, if every class has been assigned.
+    pub(crate) fn complete_assignment(&self) -> Option<&Assignment<E>> {
+        self.assignment.complete_assignment()
+    }
+
+    /// The best complete assignment scored so far.
+    pub(crate) fn best_assignment(&self) -> Option<&Assignment<E>> {
+        self.best.assignment()
+    }
+
+    /// The statistics for every member of `class` as a choice for the next
+    /// decision, i.e. for the children of the current root of the search.
+    fn root_child_stats(
+        &self,
+        class: &E::ClassId,
+        egraph: &E,
+    ) -> Vec<CandidateStats<E::NodeId, U>> {
+        let shared = self.config.transposition_table;
+        egraph
+            .members(class)
+            .map(|node| {
+                let (visits, value) =
+                    match self.tree.find_child(self.start_node, class, node, shared) {
+                        Some(child) => {
+                            let child = &self.tree.stats[child.index()];
+                            (child.n_visits, child.value(self.config.backup))
+                        }
+                        None => (0, U::zero()),
+                    };
+                CandidateStats {
+                    node: node.clone(),
+                    visits,
+                    value,
+                }
+            })
+            .collect()
+    }
+
+    /// The most-visited child of the current node that assigns `class`,
+    /// among those [`MctsConfig::final_selection`] considers.
+    fn best_child(&self, class: &E::ClassId) -> Option<(E::NodeId, TreeNodeId)> {
+        // Break ties in the number of visits by picking the child with the
+        // higher value.
+        //
+        // NB: with transpositions enabled, the current node may be shared by
+        // paths that go on to assign different classes, so we only consider
+        // children for the class being assigned.
         let cur_node = &self.tree.nodes[self.start_node.index()];
-        let (next_enode, next_tree_node) = cur_node.state.iter().max_by_key(|(_, &child)| {
-            let child_node = &self.tree.nodes[child.index()];
-            child_node.n_visits
+        let backup = self.config.backup;
+        let children = || {
+            cur_node
+                .state
+                .iter()
+                .filter(|((child_class, node), _)| {
+                    child_class == class && self.assignment.allows(node)
+                })
+                .map(|((_, node), &child)| (node, child, &self.tree.stats[child.index()]))
+        };
+        let floor = match self.config.final_selection {
+            FinalSelection::MostVisits => None,
+            FinalSelection::MostVisitsNearBest { epsilon } => children()
+                .filter(|(_, _, stats)| stats.n_visits > 0)
+                .map(|(_, _, stats)| stats.value(backup))
+                .max_by(|l, r| l.compare(r))
+                .map(|best| (best, epsilon)),
+        };
+        let candidates = || {
+            children().filter(|(_, _, stats)| {
+                floor.is_none_or(|(best, epsilon)| {
+                    compare_utility(stats.value(backup), best, epsilon).is_ge()
+                })
+            })
+        };
+        let top = candidates().max_by(|(_, _, l), (_, _, r)| {
+            l.n_visits.cmp(&r.n_visits).then_with(|| {
+                compare_utility(l.value(backup), r.value(backup), self.config.epsilon)
+            })
         })?;
+        // With `Tiebreak::ShallowestDag`, the best assignment is the
+        // shallowest of the tied terms seen, so its choice goes first if its
+        // value ties the top one's.
+        let tied = match self.config.tiebreak {
+            Tiebreak::FirstSeen => None,
+            Tiebreak::ShallowestDag => {
+                let preferred = self.best.assignment().and_then(|assign| assign.get(class));
+                let top_value = top.2.value(backup);
+                candidates().find(|(node, _, stats)| {
+                    Some(*node) == preferred
+                        && stats.n_visits > 0
+                        && compare_utility(stats.value(backup), top_value, self.config.epsilon)
+                            .is_eq()
+                })
+            }
+        };
+        let (node, child, _) = tied.unwrap_or(top);
+        Some((node.clone(), child))
+    }
 
-        assert!(&self.tree.nodes[next_tree_node.index()].class == handle.class());
-        handle.assign(next_enode.clone(), egraph);
-        self.start_node = *next_tree_node;
+    /// Keep the `k` best distinct complete assignments the search scores.
+    pub(crate) fn keep_top(&mut self, k: usize) {
+        self.best.keep_top(k);
+    }
+
+    /// Call `callback` whenever the best complete assignment improves.
+    pub(crate) fn on_new_best(&mut self, callback: NewBestCallback<E, U>) {
+        self.best.on_new_best(callback);
+    }
+
+    /// Consult `policy` before each round, as well as the limits in the
+    /// config, and stop once either says to.
+    pub(crate) fn stop_when(&mut self, policy: Box<dyn TerminationPolicy>) {
+        self.termination = Some(match config_limits(&self.config) {
+            Some(limits) => Box::new(Or(limits, policy)),
+            None => policy,
+        });
+    }
+
+    /// Time every playout and leaf estimate from now on with `clock`.
+    pub(crate) fn record_timings(&mut self, clock: Box<dyn Clock>) {
+        self.clock = Some(clock);
+        self.stats.timings.get_or_insert_default();
+    }
+
+    /// The best distinct complete assignments scored since
+    /// [`keep_top`](Self::keep_top), best first.
+    pub(crate) fn top_assignments(&self) -> Vec<(Assignment<E>, U)> {
+        self.best
+            .top()
+            .map(|(assign, util)| (assign.clone(), util))
+            .collect()
+    }
+
+    /// Record every decision from now on.
+    #[cfg(feature = "trace")]
+    pub(crate) fn record_trace(&mut self, egraph: &E) {
+        let candidates = self.next_candidates(egraph);
+        self.trace = Some(Trace::new(&candidates));
+    }
+
+    /// The decisions recorded so far, leaving the recording empty.
+    #[cfg(feature = "trace")]
+    pub(crate) fn take_trace(&mut self) -> Vec<DecisionRecord<E::ClassId, E::NodeId, U>> {
+        self.trace.as_mut().map_or_else(Vec::new, Trace::take)
+    }
+
+    /// Record every playout from now on.
+    #[cfg(feature = "trace")]
+    pub(crate) fn record_playouts(&mut self) {
+        self.playout_trace.get_or_insert_with(Default::default);
+    }
+
+    /// The playouts recorded so far, leaving the recording empty.
+    #[cfg(feature = "trace")]
+    pub(crate) fn take_playout_trace(&mut self) -> TraceRecorder<U> {
+        self.playout_trace
+            .as_mut()
+            .map(mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Record `outcome` as the utility of the assignment the search ended up
+    /// extracting.
+    #[cfg(feature = "trace")]
+    pub(crate) fn finish_trace(&mut self, outcome: Option<U>) {
+        if let Some(trace) = &mut self.trace {
+            trace.finish(outcome);
+        }
+    }
+
+    /// Assign `node` to the next class, moving the search down to `child`.
+    fn commit(&mut self, node: E::NodeId, child: TreeNodeId, egraph: &E) {
+        #[cfg(feature = "trace")]
+        if self.trace.is_some() {
+            let class = self.next_class().expect("no class left to assign");
+            let candidates = self.root_child_stats(&class, egraph);
+            let trace = self.trace.as_mut().unwrap();
+            trace.record(&self.decisions, class, candidates, node.clone());
+        }
+        let handle = self
+            .assignment
+            .start_next_assign()
+            .expect("no class left to assign");
+        self.decisions.push((handle.class().clone(), node.clone()));
+        handle.assign(node, egraph);
+        self.start_node = child;
         self.assignment.push_snapshot();
-        Some(true)
+        #[cfg(feature = "trace")]
+        if self.trace.is_some() {
+            let candidates = self.next_candidates(egraph);
+            self.trace.as_mut().unwrap().start_decision(&candidates);
+        }
     }
 
-    pub(crate) fn assign(&mut self, options: &MctsConfig, egraph: &E) -> Option<Assignment<E>> {
+    /// Assign `node` to the next class, overriding the search's own choice.
+    ///
+    /// Panics if every class has been assigned or if `node` is not a member of
+    /// the next class.
+    pub(crate) fn choose(&mut self, node: E::NodeId, egraph: &E) {
+        let class = self
+            .assignment
+            .start_next_assign()
+            .expect("no class left to assign")
+            .class()
+            .clone();
+        assert!(
+            egraph.members(&class).any(|member| *member == node),
+            "{node:?} is not a member of class {class:?}"
+        );
+        let shared = self.config.transposition_table;
+        let child = match self
+            .tree
+            .get_or_insert_child(self.start_node, &class, &node, shared)
+        {
+            Some(child) => child,
+            None => {
+                // Make room by throwing away the tree below the current
+                // decision.
+                self.tree.restart(self.start_node, false);
+                self.start_node = self.tree.root_tree_node;
+                self.tree
+                    .get_or_insert_child(self.start_node, &class, &node, shared)
+                    .unwrap()
+            }
+        };
+        self.commit(node, child, egraph);
+    }
+}
+
+impl<E: Egraph, F: EstimateUtility<E, U>, U: CostAlgebra> SearchState<E, F, U> {
+    /// Pick the next node in the assignment based on the data in the current
+    /// playouts, and commit to it.
+    ///
+    /// If `stop_at_breakpoints` is set, breakpoint classes are left for the
+    /// caller to decide.
+    fn pick_node(&mut self, egraph: &E, stop_at_breakpoints: bool) -> StepOutcome<E, U> {
+        let class = loop {
+            let Some(handle) = self.assignment.start_next_assign() else {
+                return StepOutcome::Done;
+            };
+            // Optional classes with no members are always left out, so there
+            // is no decision to make.
+            if handle.is_optional() && egraph.members(handle.class()).next().is_none() {
+                handle.skip();
+                self.assignment.push_snapshot();
+                continue;
+            }
+            break handle.class().clone();
+        };
+        if stop_at_breakpoints && self.breakpoints.contains(&class) {
+            return StepOutcome::Breakpoint {
+                candidates: self.root_child_stats(&class, egraph),
+                class,
+            };
+        }
+        // Look at the current start node and pick the child with the highest
+        // number of visits.
+        let Some((node, child)) = self.best_child(&class) else {
+            return StepOutcome::Failed;
+        };
+        self.commit(node.clone(), child, egraph);
+        StepOutcome::Committed { class, node }
+    }
+
+    /// Commit to the node the statistics gathered so far favor for the next
+    /// class, without running any playouts, and drop the parts of the tree
+    /// that are not below the new decision.
+    ///
+    /// Returns `None`, committing to nothing, if every class has been
+    /// assigned or no playout has tried a node for the next class yet.
+    pub(crate) fn commit_best_root(&mut self, egraph: &E) -> Option<(E::ClassId, E::NodeId)> {
+        match self.pick_node(egraph, false) {
+            StepOutcome::Committed { class, node } => {
+                self.reroot();
+                Some((class, node))
+            }
+            _ => None,
+        }
+    }
+
+    /// Run a round of playouts from the current node.
+    ///
+    /// The round ends early if the tree runs out of ids for new nodes; the
+    /// next round then drops the nodes for decisions already made to make
+    /// room. Returns false if the search hit a resource limit, including
+    /// running out of ids even after that.
+    fn run_round(&mut self, egraph: &E) -> bool {
+        if let Some(mut policy) = self.termination.take() {
+            let stop = policy.should_stop(&self.stats());
+            self.termination = Some(policy);
+            if stop {
+                return false;
+            }
+        }
+        self.rounds += 1;
+        if self.assignment.max_assigned().is_none() {
+            let n_reachable = self.n_reachable(egraph);
+            self.assignment.set_max_assigned(n_reachable);
+        }
+        if let Some(interval) = self.config.restart_interval {
+            if self.rounds_since_restart >= interval {
+                self.restart(egraph);
+                self.rounds_since_restart = 0;
+            }
+            self.rounds_since_restart += 1;
+        }
+        if self.tree.is_full() {
+            // Make room by dropping the statistics for the decisions already
+            // made.
+            self.reroot();
+            if self.tree.is_full() {
+                return false;
+            }
+        }
+        let next_class = self
+            .assignment
+            .start_next_assign()
+            .map(|handle| handle.class().clone());
+        for _ in 0..self.config.playouts_per_round {
+            if self.cancelled() {
+                return false;
+            }
+            if self.tree.is_full() {
+                break;
+            }
+            self.playout(egraph);
+            if let (Some(threshold), Some(class)) =
+                (self.config.decisiveness_threshold, &next_class)
+            {
+                if self.is_decisive(class, threshold, egraph) {
+                    break;
+                }
+            }
+        }
+        if let (Some(gate), Some(class)) = (self.config.confidence_gate, &next_class) {
+            for _ in 0..gate.max_playouts {
+                if self.cancelled() {
+                    return false;
+                }
+                if self.tree.is_full() {
+                    break;
+                }
+                let Some(node) = self.close_call(class, gate.z) else {
+                    break;
+                };
+                self.forced_choice = Some(node);
+                self.playout(egraph);
+                self.forced_choice = None;
+                self.stats.disambiguating_playouts += 1;
+            }
+        }
+        true
+    }
+
+    /// Run a playout, counting it towards the current decision when recording.
+    fn playout(&mut self, egraph: &E) {
+        let start = self
+            .clock
+            .as_ref()
+            .map(|clock| (clock.now(), self.tree.n_nodes()));
+        self.run_playout(egraph);
+        if let (Some((start, n_nodes)), Some(clock)) = (start, &self.clock) {
+            let timings = self.stats.timings.get_or_insert_default();
+            timings.playouts += 1;
+            timings.playout_time += clock.now().saturating_sub(start);
+            timings.tree_nodes_created += self.tree.n_nodes() - n_nodes;
+        }
+        #[cfg(feature = "trace")]
+        if let Some(trace) = &mut self.trace {
+            trace.count_playout();
+        }
+    }
+
+    /// The less-visited of the two choices for `class` that the search ranks
+    /// highest, if their confidence intervals `z` standard errors wide
+    /// overlap.
+    fn close_call(&self, class: &E::ClassId, z: f32) -> Option<E::NodeId> {
+        let backup = self.config.backup;
+        let mut ranked = self.tree.nodes[self.start_node.index()]
+            .state
+            .iter()
+            .filter(|((child_class, node), _)| child_class == class && self.assignment.allows(node))
+            .map(|((_, node), child)| (node, &self.tree.stats[child.index()]))
+            .collect::<Vec<_>>();
+        ranked.sort_by(|(_, l), (_, r)| {
+            r.n_visits.cmp(&l.n_visits).then_with(|| {
+                compare_utility(r.value(backup), l.value(backup), self.config.epsilon)
+            })
+        });
+        let [(first, a), (second, b), ..] = ranked[..] else {
+            return None;
+        };
+        let gap = (a.mean_utility().as_utility()? - b.mean_utility().as_utility()?)
+            .into_inner()
+            .abs();
+        if gap > a.confidence_radius(z)? + b.confidence_radius(z)? {
+            return None;
+        }
+        Some(
+            if b.n_visits < a.n_visits {
+                second
+            } else {
+                first
+            }
+            .clone(),
+        )
+    }
+
+    /// Whether the search has run [`MctsConfig::max_rounds`] rounds.
+    fn out_of_rounds(&self) -> bool {
+        self.config.max_rounds.is_some_and(|max| self.rounds >= max)
+    }
+
+    /// Whether the search has been cancelled (see [`MctsConfig::cancel`]).
+    fn cancelled(&self) -> bool {
+        self.config
+            .cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(atomic::Ordering::Relaxed))
+    }
+
+    /// Whether the search has seen an assignment that meets
+    /// [`MctsConfig::target_utility`].
+    fn reached_target(&self) -> bool {
+        matches!(
+            (self.config.target_utility, self.best.utility()),
+            (Some(target), Some(best)) if best.as_utility().is_some_and(|best| best >= target)
+        )
+    }
+
+    /// Whether the most-visited choice for `class` has received more than
+    /// `threshold` of the visits to the current root's children.
+    ///
+    /// Decisions are never considered decisive before the children have
+    /// received at least one visit per member of `class`.
+    fn is_decisive(&self, class: &E::ClassId, threshold: f32, egraph: &E) -> bool {
+        let stats = self.root_child_stats(class, egraph);
+        let total: u64 = stats.iter().map(|c| u64::from(c.visits)).sum();
+        let top = stats.iter().map(|c| c.visits).max().unwrap_or(0);
+        total > 0
+            && total >= stats.len() as u64
+            && f64::from(top) / total as f64 > f64::from(threshold)
+    }
+
+    /// Run a round of playouts and then make the next decision.
+    pub(crate) fn step(&mut self, egraph: &E) -> StepOutcome<E, U> {
+        if !self.run_round(egraph) {
+            return StepOutcome::Stopped;
+        }
+        if self.rejected_utility().is_some() {
+            return StepOutcome::Failed;
+        }
+        self.pick_node(egraph, true)
+    }
+
+    /// The first assignment scored with a utility that was not finite, if
+    /// the config rejects them.
+    fn rejected_utility(&self) -> Option<&str> {
+        match self.config.non_finite_utilities {
+            NonFiniteUtilities::Clamp => None,
+            NonFiniteUtilities::Reject => self.best.non_finite(),
+        }
+    }
+
+    /// Run the rest of the search to completion, and clone the assignment it
+    /// settles on.
+    #[cfg(test)]
+    pub(crate) fn assign(&mut self, egraph: &E) -> Result<Assignment<E>, ExtractError> {
+        let settled = self.finish(egraph)?;
+        self.settled_assignment(settled).cloned()
+    }
+
+    /// The assignment a search that finished with `settled` settled on.
+    pub(crate) fn settled_assignment(
+        &self,
+        settled: Settled,
+    ) -> Result<&Assignment<E>, ExtractError> {
+        match settled {
+            Settled::Best => self.best.assignment(),
+            Settled::Committed => self.assignment.complete_assignment(),
+        }
+        .ok_or(ExtractError::NoAssignment)
+    }
+
+    /// The number of decisions after which a search of `egraph` is given up
+    /// on as non-terminating.
+    ///
+    /// Every decision assigns a new reachable class, so a well-formed search
+    /// never comes close to this limit.
+    pub(crate) fn decision_limit(&mut self, egraph: &E) -> usize {
+        WATCHDOG_FACTOR * self.n_reachable(egraph)
+    }
+
+    /// The number of classes reachable from the root, counting them the first
+    /// time unless the caller supplied it.
+    fn n_reachable(&mut self, egraph: &E) -> usize {
+        *self.n_reachable.get_or_insert_with(|| {
+            reachable_class_order(egraph, self.tree.root_class.clone()).len()
+        })
+    }
+
+    /// Run the rest of the search, and report where the assignment it settles
+    /// on is kept (see [`settled_assignment`](Self::settled_assignment)).
+    ///
+    /// Returns an error if the search fails, or if it makes more decisions
+    /// than the egraph could possibly need (see [`WATCHDOG_FACTOR`]).
+    pub(crate) fn finish(&mut self, egraph: &E) -> Result<Settled, ExtractError> {
+        let limit = self.decision_limit(egraph);
+        let mut iterations = 0;
         loop {
-            for _ in 0..options.playouts_per_round {
-                self.run_playout(egraph);
+            if self.out_of_rounds() {
+                return Err(ExtractError::BudgetExhausted {
+                    decisions: self.decisions.len(),
+                });
+            }
+            let running = self.run_round(egraph);
+            if let Some(assignment) = self.rejected_utility() {
+                return Err(ExtractError::NonFiniteUtility {
+                    assignment: assignment.to_owned(),
+                });
+            }
+            if !running || self.reached_target() {
+                return Ok(Settled::Best);
+            }
+            match self.pick_node(egraph, false) {
+                StepOutcome::Committed { .. } => {}
+                StepOutcome::Done => break,
+                // The decisions so far lead into a dead end, but the
+                // complete assignments scored along the way are still terms.
+                StepOutcome::Failed => return Ok(Settled::Best),
+                StepOutcome::Breakpoint { .. } | StepOutcome::Stopped => unreachable!(),
+            }
+            iterations += 1;
+            if iterations > limit {
+                return Err(ExtractError::NonTerminating {
+                    decisions: iterations,
+                });
+            }
+        }
+        Ok(if self.config.cost_ceiling.is_some() {
+            // The assignment we committed to may not beat the ceiling, but
+            // every complete assignment the search scored has been offered to
+            // `best`, which only keeps those that do.
+            Settled::Best
+        } else {
+            Settled::Committed
+        })
+    }
+
+    /// The estimate for a leaf that cannot beat the cost ceiling.
+    fn pruned_estimate(&self) -> LeafEstimate<U> {
+        match self.config.objective {
+            // This is optimistic, but still no better than anything that does
+            // beat the ceiling.
+            SearchObjective::Utility => LeafEstimate::new(
+                U::from_utility(-self.config.cost_ceiling.unwrap_or_default())
+                    .unwrap_or_else(U::zero),
+            ),
+            SearchObjective::CompleteExtraction => LeafEstimate::new(U::zero()),
+        }
+    }
+
+    /// Estimate the utility of the current assignment.
+    fn estimate_leaf(&mut self, egraph: &E) -> LeafEstimate<U> {
+        if self.assignment.complete_assignment().is_some() {
+            self.stats.exact_leaf_evaluations += 1;
+        } else {
+            self.stats.sampled_leaf_evaluations += 1;
+        }
+        let start = self
+            .clock
+            .as_ref()
+            .map(|clock| (clock.now(), self.estimate_util.utility_calls()));
+        let estimate = self.estimate_util.estimate(
+            &mut self.assignment,
+            egraph,
+            &mut self.best,
+            &mut self.choices,
+        );
+        if let (Some((start, calls)), Some(clock)) = (start, &self.clock) {
+            let timings = self.stats.timings.get_or_insert_default();
+            timings.estimates += 1;
+            timings.estimate_time += clock.now().saturating_sub(start);
+            timings.utility_calls += self.estimate_util.utility_calls() - calls;
+        }
+        estimate
+    }
+
+    /// Give `child`, which the current assignment has just reached, a prior
+    /// value if it is new: the negated lower bound on the cost of the
+    /// assignment, worth [`MctsConfig::prior_strength`] samples.
+    fn seed_prior(&mut self, child: TreeNodeId) {
+        let strength = self.config.prior_strength;
+        if strength <= 0.0 || self.config.backup != Backup::Mean {
+            return;
+        }
+        let Some(bound) = self.assignment.cost_lower_bound() else {
+            return;
+        };
+        let weight = Utility::new(strength).unwrap();
+        let Some(prior) = U::from_utility(-bound * weight) else {
+            return;
+        };
+        let node = &mut self.tree.stats[child.index()];
+        if node.n_visits == 0 && node.total_weight == cast_util(0) {
+            node.total_utility = prior;
+            node.total_weight = weight;
+            node.total_sq_utility = bound * bound * weight;
+        }
+    }
+
+    /// Add a child to `parent` for every member of the next class to assign,
+    /// up to [`MctsConfig::expand_per_visit`] new ones, and estimate the
+    /// utility of each of them.
+    ///
+    /// Each child records its own estimate; the combined estimates are
+    /// returned so they can be backpropagated through `parent` and its
+    /// ancestors. Falls back to estimating `parent` itself if the class has
+    /// no members.
+    fn expand_all(&mut self, parent: TreeNodeId, egraph: &E) -> Evaluation<U> {
+        let shared = self.config.transposition_table;
+        let class = self.assignment.start_next_assign().unwrap().class().clone();
+        let members = egraph
+            .members(&class)
+            .filter(|node| self.assignment.allows(node))
+            .cloned()
+            .collect::<Vec<_>>();
+        if members.is_empty() {
+            return self.estimate_leaf(egraph).into();
+        }
+        self.estimate_util.set_parent(Some(parent));
+        let mut total = Evaluation::default();
+        // Children found in the transposition table are not new, so they do
+        // not count against the limit.
+        let mut budget = self.config.expand_per_visit.unwrap_or(usize::MAX);
+        for node in members {
+            if budget == 0 {
+                break;
             }
-            if !self.pick_node(egraph)? {
+            let n_nodes = self.tree.n_nodes();
+            let Some(child) = self.tree.get_or_insert_child(parent, &class, &node, shared) else {
                 break;
+            };
+            if self.tree.n_nodes() > n_nodes {
+                budget -= 1;
             }
+            self.assignment.push_snapshot();
+            self.assignment
+                .start_next_assign()
+                .unwrap()
+                .assign(node, egraph);
+            self.seed_prior(child);
+            let leaf = if self.assignment.cannot_beat(self.config.cost_ceiling) {
+                self.stats.pruned_playouts += 1;
+                self.pruned_estimate()
+            } else {
+                self.estimate_leaf(egraph)
+            };
+            self.tree.stats[child.index()].record(&leaf.into());
+            total.add(leaf);
+            self.assignment.reset(egraph);
+            self.assignment.pop_snapshot();
         }
-        Some(self.assignment.complete_assignment()?.clone())
+        if total.visits == 0 {
+            // The tree had no room for any of the children.
+            return self.estimate_leaf(egraph).into();
+        }
+        total
     }
 
     /// The core of the MCTS loop: iterate through the tree, simulate a run,
@@ -148,71 +2060,187 @@ impl<E: Egraph, F: FnMut(&mut ExtractionState<E>, &E) -> Utility> SearchState<'_
         // NB: we use the `path` vector to store nodes we have visited along the
         // way instead of recursion. Terms can have a lot of nodes and we don't
         // want to blow the stack.
+        self.stats.playouts += 1;
         let mut cur_node_id = self.start_node;
         self.path.push(cur_node_id);
         let mut leaf_util = None;
         while let Some(handle) = self.assignment.start_next_assign() {
-            let cur_node = &self.tree.nodes[cur_node_id.index()];
+            let cur_node = &self.tree.stats[cur_node_id.index()];
             if cur_node.n_visits == 0 {
-                let cost = (self.estimate_util)(&mut self.assignment, egraph);
-                leaf_util = Some(cost);
+                leaf_util = Some(match self.config.expansion_strategy {
+                    ExpansionStrategy::Lazy => {
+                        let parent = self.path.len().checked_sub(2).map(|i| self.path[i]);
+                        self.estimate_util.set_parent(parent);
+                        self.estimate_leaf(egraph).into()
+                    }
+                    ExpansionStrategy::Full => self.expand_all(cur_node_id, egraph),
+                });
                 break;
             } else {
-                let total_rounds = cur_node.n_visits;
-                let next_state = egraph
-                    .members(handle.class())
-                    .map(|node| {
-                        if let Some(child) = cur_node.state.get(node) {
-                            let child_node = &self.tree.nodes[child.index()];
-                            (
-                                uct_score(
-                                    child_node.n_visits,
-                                    child_node.total_utility
-                                        / cast_util(cmp::max(child_node.n_visits, 1)),
-                                    total_rounds,
-                                    self.exploration_term,
-                                ),
-                                Some(*child),
-                                node,
-                            )
-                        } else {
-                            (
-                                uct_score(0, cast_util(0), total_rounds, self.exploration_term),
-                                None,
-                                node,
-                            )
+                let parent_value = cur_node.value(self.config.backup);
+                let scalar = parent_value.as_utility().is_some();
+                let mut parent = ParentStat {
+                    visits: cur_node.n_visits,
+                    value: parent_value.as_utility().unwrap_or_default(),
+                };
+                // First-play urgency: score unexpanded children as if they
+                // were about as good as their parent, rather than as if they
+                // had a utility of zero.
+                let fpu = parent.value - self.fpu_reduction;
+                let shared = self.config.transposition_table;
+                let exploration = match &self.config.exploration_schedule {
+                    Some(schedule) => schedule.factor(self.exploration_term, parent.visits),
+                    None => self.exploration_term,
+                };
+                let unexpanded_exploration = if self.config.expansion_boost > 0.0 {
+                    let (mut n_members, mut n_unexpanded) = (0, 0);
+                    for node in egraph
+                        .members(handle.class())
+                        .filter(|node| handle.allows(node))
+                    {
+                        n_members += 1;
+                        n_unexpanded += u32::from(
+                            self.tree
+                                .find_child(cur_node_id, handle.class(), node, shared)
+                                .is_none(),
+                        );
+                    }
+                    let unexpanded = n_unexpanded as f32 / cmp::max(n_members, 1) as f32;
+                    exploration
+                        * Utility::new(1.0 + self.config.expansion_boost * unexpanded).unwrap()
+                } else {
+                    exploration
+                };
+                let forced = match &self.forced_choice {
+                    Some(forced) if self.path.len() == 1 => {
+                        egraph.members(handle.class()).find(|node| *node == forced)
+                    }
+                    _ => None,
+                };
+                let next_state = if forced.is_some() {
+                    forced
+                } else {
+                    let mut members = egraph
+                        .members(handle.class())
+                        .filter(|node| handle.allows(node))
+                        .peekable();
+                    match members.next() {
+                        None => None,
+                        // There is nothing to choose between for single-member
+                        // classes, so skip consulting the selection policy.
+                        Some(node) if members.peek().is_none() => Some(node),
+                        Some(first) => {
+                            self.child_stats.clear();
+                            self.child_values.clear();
+                            for node in iter::once(first).chain(members) {
+                                self.stats.uct_evaluations += 1;
+                                let child =
+                                    self.tree
+                                        .find_child(cur_node_id, handle.class(), node, shared);
+                                let (stat, value) = match child {
+                                    Some(child) => {
+                                        let child_node = &self.tree.stats[child.index()];
+                                        let value = child_node.value(self.config.backup);
+                                        let stat = ChildStat {
+                                            visits: child_node.n_visits,
+                                            value: value.as_utility().unwrap_or_default(),
+                                            exploration,
+                                        };
+                                        (stat, value)
+                                    }
+                                    None => {
+                                        let stat = ChildStat {
+                                            visits: 0,
+                                            value: fpu,
+                                            exploration: unexpanded_exploration,
+                                        };
+                                        (stat, parent_value)
+                                    }
+                                };
+                                self.child_stats.push(stat);
+                                if !scalar {
+                                    self.child_values.push(value);
+                                }
+                            }
+                            if !scalar {
+                                // Selection policies can only do arithmetic
+                                // on utilities, so describe other values by
+                                // their rank among the candidates and the
+                                // parent.
+                                self.child_values.push(parent_value);
+                                let mut ranks = ranks(&self.child_values);
+                                for (stat, rank) in self.child_stats.iter_mut().zip(&mut ranks) {
+                                    stat.value = rank;
+                                }
+                                parent.value = ranks.next().unwrap();
+                            }
+                            let (selection, children) = (&self.config.selection, &self.child_stats);
+                            let seeded = self.config.seed.is_some();
+                            let choice =
+                                self.choices.decide(handle.class(), children.len(), |rng| {
+                                    let choice = if seeded {
+                                        selection.select_with_rng(&parent, children, rng)
+                                    } else {
+                                        selection.select(&parent, children)
+                                    };
+                                    choice.min(children.len() - 1)
+                                });
+                            Some(match egraph.members_slice(handle.class()) {
+                                Some(members) if !handle.has_constraints() => &members[choice],
+                                _ => egraph
+                                    .members(handle.class())
+                                    .filter(|node| handle.allows(node))
+                                    .nth(choice)
+                                    .unwrap(),
+                            })
                         }
-                    })
-                    .max_by_key(|(x, _, _)| *x);
-                let Some((_, child_tree_node, enode_id)) = next_state else {
+                    }
+                };
+                let Some(enode_id) = next_state else {
+                    if handle.is_optional() {
+                        handle.skip();
+                        continue;
+                    }
                     // There aren't any nodes in this e-class, so we can't extract.
-                    leaf_util = Some(Utility::default());
+                    self.stats.dead_ends.add(DeadEnd::EmptyClass);
+                    leaf_util = Some(LeafEstimate::new(U::zero()).into());
                     break;
                 };
-                let child = if let Some(child) = child_tree_node {
-                    child
-                } else {
-                    let new = self.tree.fresh_node(handle.class().clone());
-                    self.tree.nodes[cur_node_id.index()]
-                        .state
-                        .insert(enode_id.clone(), new);
-                    new
+                let Some(child) =
+                    self.tree
+                        .get_or_insert_child(cur_node_id, handle.class(), enode_id, shared)
+                else {
+                    // The tree is full, so estimate the current node instead.
+                    leaf_util = Some(self.estimate_leaf(egraph).into());
+                    break;
                 };
                 self.path.push(child);
                 cur_node_id = child;
                 handle.assign(enode_id.clone(), egraph);
+                self.seed_prior(child);
+                if self.assignment.cannot_beat(self.config.cost_ceiling) {
+                    self.stats.pruned_playouts += 1;
+                    leaf_util = Some(self.pruned_estimate().into());
+                    break;
+                }
             }
         }
-        let util = if let Some(util) = leaf_util {
-            util
+        let leaf = if let Some(leaf) = leaf_util {
+            leaf
         } else {
             // We got a complete assignment.
-            (self.estimate_util)(&mut self.assignment, egraph)
+            self.estimate_leaf(egraph).into()
         };
+        #[cfg(feature = "trace")]
+        if let Some(trace) = &mut self.playout_trace {
+            trace.record(
+                self.stats.playouts - 1,
+                self.path.iter().map(|id| id.index()),
+                leaf.mean_utility(),
+            );
+        }
         for node_id in self.path.drain(..).rev() {
-            let node = &mut self.tree.nodes[node_id.index()];
-            node.n_visits = node.n_visits.saturating_add(1);
-            node.total_utility += util;
+            self.tree.stats[node_id.index()].record(&leaf);
         }
         self.assignment.reset(egraph);
     }
@@ -0,0 +1,171 @@
+//! Policies for choosing which child of a search tree node a playout descends
+//! into.
+
+use std::{cmp, fmt};
+
+use rand::{Rng, RngCore};
+
+use crate::Utility;
+
+/// Statistics for the search tree node that a playout is descending from.
+#[derive(Copy, Clone, Debug)]
+pub struct ParentStat {
+    /// The number of playouts that have passed through the node.
+    pub visits: u32,
+    /// The node's value, aggregated according to
+    /// [`MctsConfig::backup`](crate::MctsConfig::backup).
+    pub value: Utility,
+}
+
+/// Statistics for one member of the class being assigned, as a child of the
+/// current search tree node.
+#[derive(Copy, Clone, Debug)]
+pub struct ChildStat {
+    /// The number of playouts that have chosen this member here. Zero if the
+    /// member has not been tried yet.
+    pub visits: u32,
+    /// The child's value, aggregated according to
+    /// [`MctsConfig::backup`](crate::MctsConfig::backup). For children that
+    /// have not been visited, this is the first-play urgency estimate (see
+    /// [`MctsConfig::fpu_reduction`](crate::MctsConfig::fpu_reduction)).
+    pub value: Utility,
+    /// The weight to give to exploring this child. This is the search's
+    /// exploration constant, adjusted for the parent's visits by
+    /// [`MctsConfig::exploration_schedule`](crate::MctsConfig::exploration_schedule)
+    /// and scaled up for unvisited children by
+    /// [`MctsConfig::expansion_boost`](crate::MctsConfig::expansion_boost).
+    pub exploration: Utility,
+}
+
+/// A strategy for the selection phase of a playout.
+///
+/// At each node of the search tree with more than one candidate, the search
+/// describes the candidates to the policy, in the order the egraph lists the
+/// class's members, and descends into the one it picks.
+///
+/// A policy is shared by every clone of the [`MctsConfig`](crate::MctsConfig)
+/// it is installed in, and by every search run with those configs, possibly
+/// on several threads at once. Policies that keep state between calls, such
+/// as posteriors for Thompson sampling, need interior mutability (a
+/// [`Mutex`](std::sync::Mutex), say) and should key that state by whatever
+/// distinguishes the searches they care about.
+pub trait SelectionPolicy: Send + Sync {
+    /// Return the index in `children` of the child to descend into.
+    ///
+    /// `children` always has at least two elements. An index past the end of
+    /// `children` is taken to mean the last child.
+    fn select(&self, parent: &ParentStat, children: &[ChildStat]) -> usize;
+
+    /// Like [`select`](Self::select), but drawing any randomness the policy
+    /// needs from `rng`.
+    ///
+    /// Searches with a [`MctsConfig::seed`](crate::MctsConfig::seed) call this
+    /// instead of `select`, with an `rng` derived from the seed, so that they
+    /// are reproducible. The default ignores `rng` and calls `select`.
+    fn select_with_rng(
+        &self,
+        parent: &ParentStat,
+        children: &[ChildStat],
+        rng: &mut dyn RngCore,
+    ) -> usize {
+        let _ = rng;
+        self.select(parent, children)
+    }
+}
+
+impl fmt::Debug for dyn SelectionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("dyn SelectionPolicy")
+    }
+}
+
+/// The UCB1 policy, which picks the child with the highest upper confidence
+/// bound on its value. This is the default.
+///
+/// Ties go to the last of the tied children, unless the search is seeded (see
+/// [`MctsConfig::seed`](crate::MctsConfig::seed)), in which case they are
+/// broken uniformly at random so that no child is favored because of where it
+/// appears in its class.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Ucb1;
+
+impl SelectionPolicy for Ucb1 {
+    fn select(&self, parent: &ParentStat, children: &[ChildStat]) -> usize {
+        children
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, child)| {
+                uct_score(child.visits, child.value, parent.visits, child.exploration)
+            })
+            .map_or(0, |(i, _)| i)
+    }
+
+    fn select_with_rng(
+        &self,
+        parent: &ParentStat,
+        children: &[ChildStat],
+        rng: &mut dyn RngCore,
+    ) -> usize {
+        let mut best = None;
+        let mut choice = 0;
+        let mut n_tied = 0;
+        for (i, child) in children.iter().enumerate() {
+            let score = uct_score(child.visits, child.value, parent.visits, child.exploration);
+            if best.is_none_or(|best| score > best) {
+                best = Some(score);
+                choice = i;
+                n_tied = 1;
+            } else if best == Some(score) {
+                // Reservoir sampling: each of the tied children ends up
+                // chosen with equal probability.
+                n_tied += 1;
+                if rng.gen_range(0..n_tied) == 0 {
+                    choice = i;
+                }
+            }
+        }
+        choice
+    }
+}
+
+/// Grow the exploration constant with the number of visits to the parent
+/// node, as in MuZero's pUCT formula.
+///
+/// The exploration constant at a node with `N` visits becomes
+/// `c * ln((N + c_base + 1) / c_base) + c_init`, where `c` is the search's
+/// base exploration constant. For `N` much smaller than `c_base`, this is
+/// about `c_init`; beyond that, it grows slowly with `N`. Because UCB1's
+/// exploration term shrinks with the number of visits to each child, a large
+/// `c_init` with a large `c_base` explores widely early on and then leans on
+/// the value estimates once they have enough visits behind them.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ExplorationSchedule {
+    /// The number of visits after which the constant starts to grow
+    /// noticeably. Must be positive; searches panic up front otherwise.
+    pub c_base: f32,
+    /// The constant for nodes with few visits.
+    pub c_init: f32,
+}
+
+impl ExplorationSchedule {
+    /// The exploration constant to use at a node with `visits` visits, given
+    /// the search's base constant `c`.
+    pub fn factor(&self, c: Utility, visits: u32) -> Utility {
+        let growth = ((visits as f32 + self.c_base + 1.0) / self.c_base).ln();
+        c * Utility::new(growth).unwrap() + Utility::new(self.c_init).unwrap()
+    }
+}
+
+/// Compute the score of the current node given the total ruounds run under
+/// the parent node, and a constant `c` for weighting exploration.
+fn uct_score(
+    child_rounds: u32,
+    child_avg_utility: Utility,
+    total_rounds: u32,
+    c: Utility,
+) -> Utility {
+    let n_visits = cmp::max(child_rounds, 1) as f32;
+    let exploration_term =
+        c * Utility::new(((total_rounds as f32).ln() / n_visits).sqrt()).unwrap();
+    child_avg_utility + exploration_term
+}
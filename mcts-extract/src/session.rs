@@ -0,0 +1,571 @@
+//! A search that can be driven one decision at a time.
+
+use std::{fmt, hash::Hash, sync::Arc};
+
+use crate::{
+    analysis::{Analysis, CostBounds},
+    estimate::RolloutEstimator,
+    extraction_state::{ExtractionState, StatePool, WatchedNode},
+    finite_utility,
+    quotas::OpQuotas,
+    search_tree::{CandidateStats, SearchState, SearchTree, StepOutcome},
+    termination::TerminationPolicy,
+    Assignment, Clock, EgraphNodeCost, EgraphTotalCost, ExtractError, MctsConfig, SearchProgress,
+    SearchStats, Utility,
+};
+#[cfg(feature = "trace")]
+use crate::{DecisionRecord, TraceRecorder};
+
+/// An in-progress extraction.
+///
+/// [`mcts_extract`](crate::mcts_extract) runs a search to completion. A
+/// `SearchSession` instead lets the caller run the search one decision at a
+/// time with [`step`](Self::step), and to override decisions along the way.
+pub struct SearchSession<'a, E: EgraphTotalCost> {
+    egraph: &'a E,
+    search: SearchState<E, RolloutEstimator<E>>,
+    optimality_ratio: Option<f32>,
+    /// Why the last run of the search to completion failed, if it did.
+    failure: Option<ExtractError>,
+}
+
+impl<E: EgraphTotalCost> fmt::Debug for SearchSession<'_, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SearchSession")
+            .field("root", self.search.root_class())
+            .field("decisions", &self.search.decisions())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a, E: EgraphTotalCost> SearchSession<'a, E> {
+    /// Start a new search for a term rooted at `root`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `root` is opaque (see
+    /// [`Egraph::is_opaque`](crate::Egraph::is_opaque)), if
+    /// [`MctsConfig::fpu_reduction`] is NaN, or if
+    /// [`MctsConfig::exploration_schedule`] has a `c_base` that is not
+    /// positive.
+    pub fn new(egraph: &'a E, root: E::ClassId, config: MctsConfig) -> Self {
+        let state = ExtractionState::new(root.clone());
+        Self::with_state(egraph, root, config, state)
+    }
+
+    /// Like [`new`](Self::new), but take the state to build assignments in
+    /// from `pool`. Hand it back with [`recycle`](Self::recycle).
+    pub(crate) fn new_in(
+        pool: &mut StatePool<E>,
+        egraph: &'a E,
+        root: E::ClassId,
+        config: MctsConfig,
+    ) -> Self {
+        let state = pool.take(root.clone());
+        Self::with_state(egraph, root, config, state)
+    }
+
+    fn with_state(
+        egraph: &'a E,
+        root: E::ClassId,
+        config: MctsConfig,
+        state: ExtractionState<E>,
+    ) -> Self {
+        assert!(
+            !egraph.is_opaque(&root),
+            "the root class {root:?} is opaque, so there is nothing to extract"
+        );
+        let estimator =
+            RolloutEstimator::new(&config, egraph.precompute(std::slice::from_ref(&root)));
+        let mut search = SearchTree::new(root).start_round_with(
+            config,
+            estimator,
+            Utility::new(2.0f32.sqrt()).unwrap(),
+            state,
+        );
+        if let Some(hint) = egraph.topo_hint() {
+            search.set_topo_hint(hint);
+        }
+        Self {
+            egraph,
+            search,
+            optimality_ratio: None,
+            failure: None,
+        }
+    }
+
+    /// Allow the search to leave the classes in `optional` unassigned.
+    ///
+    /// Optional classes that the search reaches while mandatory classes are
+    /// still pending are assigned as usual, along with every class below
+    /// them. Once every mandatory class has been assigned, the remaining
+    /// optional classes are left out, and the nodes that point to them are
+    /// treated as complete. Optional classes with no members are always left
+    /// out.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the search has already made a decision.
+    pub fn set_optional(&mut self, optional: impl IntoIterator<Item = E::ClassId>) {
+        self.search.set_optional(optional.into_iter().collect());
+    }
+
+    /// Use facts the caller already knows about the egraph instead of
+    /// computing them during the search.
+    ///
+    /// If `analysis` has cost bounds, this also prunes with them like
+    /// [`prune_with_node_costs`](Self::prune_with_node_costs) does.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the search has already made a decision.
+    pub fn use_analysis(&mut self, analysis: Analysis<E>) {
+        if let Some(bounds) = analysis.cost_bounds() {
+            self.search.set_cost_bounds(bounds);
+        }
+        self.search.set_n_reachable(analysis.n_reachable());
+    }
+
+    /// Limit how many nodes of each kind the extracted term may use.
+    ///
+    /// `node_op` gives the kind of each node, and `limits` the largest number
+    /// of nodes of each kind that the term may contain; kinds without a limit
+    /// are unrestricted. As with costs, a node used in several places in the
+    /// term only counts once. The search never picks a node that would go over
+    /// its limit, and treats classes whose members would all go over as dead
+    /// ends. Only nodes reachable from the root are looked at.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the search has already made a decision.
+    pub fn limit_ops<Op: Hash + Eq>(
+        &mut self,
+        node_op: impl Fn(&E::NodeId) -> Op,
+        limits: impl IntoIterator<Item = (Op, usize)>,
+    ) {
+        let root = self.search.root_class().clone();
+        let quotas = OpQuotas::new(self.egraph, root, node_op, limits);
+        self.search.set_quotas(Arc::new(quotas));
+    }
+
+    /// Require the term to assign `then.1` to class `then.0` whenever it
+    /// assigns `when.1` to class `when.0`.
+    ///
+    /// This holds whichever of the two classes the search reaches first: once
+    /// one side of an implication is assigned, the search never picks a node
+    /// that contradicts it. If the required class is not part of the term,
+    /// the implication holds trivially. Classes whose members all contradict
+    /// some implication are dead ends, and implications that contradict each
+    /// other can leave the egraph with no term to extract.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the search has already made a decision.
+    pub fn require_if(&mut self, when: (E::ClassId, E::NodeId), then: (E::ClassId, E::NodeId)) {
+        self.search.require_if(when, then);
+    }
+
+    /// Let the random samples that estimate utilities leave out a mandatory
+    /// class they have no usable members for, rather than fail.
+    ///
+    /// `on_empty_class` gives the cost of leaving out such a class, which is
+    /// deducted from the sample's utility, or `None` to fail the sample as
+    /// usual. A class counts as empty when it has no members, or when quotas
+    /// or implications rule them all out.
+    ///
+    /// This only softens the estimates. Samples with classes left out are not
+    /// complete assignments, so they are never kept as the best assignment or
+    /// returned. They are still scored by
+    /// [`EgraphTotalCost::assignment_utility`], which must then cope with
+    /// mandatory classes missing from the assignment.
+    ///
+    /// The search itself still treats an empty mandatory class as a dead end.
+    /// A penalty lower than the extra cost of the complete alternatives can
+    /// lead it to commit to one, and it then returns the best complete
+    /// assignment it scored instead.
+    pub fn on_empty_class(
+        &mut self,
+        on_empty_class: impl Fn(&E::ClassId) -> Option<Utility> + Send + Sync + 'static,
+    ) {
+        self.search.on_empty_class(Arc::new(on_empty_class));
+    }
+
+    /// Keep the `k` best distinct terms the search scores along the way, for
+    /// [`top_assignments`](Self::top_assignments).
+    pub fn keep_top(&mut self, k: usize) {
+        self.search.keep_top(k);
+    }
+
+    /// Call `callback` with the best complete assignment scored so far, and
+    /// its utility, every time that assignment changes.
+    ///
+    /// The callback only sees strict improvements: an assignment that ties
+    /// the current best to within [`MctsConfig::epsilon`] (unless
+    /// [`Tiebreak::ShallowestDag`](crate::Tiebreak::ShallowestDag) prefers
+    /// it), or that does not beat [`MctsConfig::cost_ceiling`], does not
+    /// replace it. Long searches can use this to save their progress as they
+    /// go.
+    pub fn on_new_best(&mut self, callback: impl FnMut(&Assignment<E>, Utility) + Send + 'static) {
+        self.search.on_new_best(Box::new(callback));
+    }
+
+    /// Stop the search early once `policy` says to, rather than only once
+    /// every class has been decided. This replaces any earlier policy, but
+    /// the limits set in the config still apply.
+    ///
+    /// See [`TerminationPolicy`] for how a stopped search behaves.
+    pub fn stop_when(&mut self, policy: impl TerminationPolicy + 'static) {
+        self.search.stop_when(Box::new(policy));
+    }
+
+    /// Time the search's playouts and leaf estimates from now on, reading
+    /// `clock`, and report the totals in [`SearchStats::timings`].
+    ///
+    /// Searches that are not timed never read a clock.
+    pub fn record_timings(&mut self, clock: impl Clock + 'static) {
+        self.search.record_timings(Box::new(clock));
+    }
+
+    /// The best distinct complete assignments scored since
+    /// [`keep_top`](Self::keep_top), with their utilities, best first.
+    ///
+    /// Two assignments are distinct if they pick a different node for some
+    /// class, or assign different classes. Assignments that do not beat
+    /// [`MctsConfig::cost_ceiling`] are left out.
+    pub fn top_assignments(&self) -> Vec<(Assignment<E>, Utility)> {
+        self.search.top_assignments()
+    }
+
+    /// Pause the search whenever it is about to assign a node to `class`.
+    ///
+    /// When `step` reaches a breakpoint class, it returns
+    /// [`StepOutcome::Breakpoint`] without committing to a node. The caller
+    /// can then pick a node with [`choose`](Self::choose). Calling `step`
+    /// again instead runs another round of playouts and returns the
+    /// breakpoint again, with updated statistics.
+    pub fn add_breakpoint(&mut self, class: E::ClassId) {
+        self.search.add_breakpoint(class);
+    }
+
+    /// Run a round of playouts, then assign a node to the next class.
+    pub fn step(&mut self) -> StepOutcome<E> {
+        let outcome = self.search.step(self.egraph);
+        if let StepOutcome::Done = outcome {
+            self.optimality_ratio = self.optimality_ratio(self.search.complete_assignment());
+            #[cfg(feature = "trace")]
+            self.search
+                .finish_trace(self.utility(self.search.complete_assignment()));
+        }
+        outcome
+    }
+
+    /// Commit to the node the search favors for the next class so far,
+    /// without running another round of playouts, and return the decision.
+    ///
+    /// This is one step of an explicit descent down the term: the search
+    /// keeps the statistics it has gathered below the new decision and drops
+    /// the rest, so that later rounds (see [`step`](Self::step)) only refine
+    /// what is left to decide. Repeated calls walk down the term for as long
+    /// as earlier playouts reached the next class.
+    ///
+    /// Returns `None`, committing to nothing, if every class has been
+    /// assigned or no playout has tried a node for the next class yet.
+    pub fn commit_best_root(&mut self) -> Option<(E::ClassId, E::NodeId)> {
+        self.search.commit_best_root(self.egraph)
+    }
+
+    /// Assign `node` to the next class instead of letting the search decide.
+    ///
+    /// # Panics
+    ///
+    /// Panics if every class has already been assigned, or if `node` is not a
+    /// member of the next class.
+    pub fn choose(&mut self, node: E::NodeId) {
+        self.search.choose(node, self.egraph);
+    }
+
+    /// Run the rest of the search to completion, ignoring breakpoints.
+    pub fn run(&mut self) -> Result<Assignment<E>, ExtractError> {
+        self.run_borrowed().cloned()
+    }
+
+    /// Like [`run`](Self::run), but write the assignment into `out` rather
+    /// than allocating a new one.
+    ///
+    /// `out` is cleared first, and left empty if the search fails.
+    pub fn run_into(&mut self, out: &mut Assignment<E>) -> Result<(), ExtractError> {
+        out.clear();
+        self.run_borrowed().map(|assign| {
+            out.extend(
+                assign
+                    .iter()
+                    .map(|(class, node)| (class.clone(), node.clone())),
+            );
+        })
+    }
+
+    /// Like [`run`](Self::run), but borrow the assignment from the session
+    /// rather than cloning it.
+    ///
+    /// This saves copying large assignments that the caller only needs to
+    /// look at. The assignment stays available from
+    /// [`best_assignment`](Self::best_assignment) or
+    /// [`complete_assignment`](Self::complete_assignment) afterwards.
+    pub fn run_borrowed(&mut self) -> Result<&Assignment<E>, ExtractError> {
+        let settled = self.search.finish(self.egraph);
+        #[cfg(feature = "trace")]
+        {
+            let assign = settled
+                .as_ref()
+                .ok()
+                .and_then(|settled| self.search.settled_assignment(*settled).ok());
+            let utility = self.utility(assign);
+            self.search.finish_trace(utility);
+        }
+        let assign = settled.and_then(|settled| self.search.settled_assignment(settled));
+        self.optimality_ratio = self.optimality_ratio(assign.as_ref().ok().copied());
+        self.failure = assign.as_ref().err().cloned();
+        assign
+    }
+
+    /// The number of decisions after which the search is given up on as
+    /// non-terminating (see [`ExtractError::NonTerminating`]).
+    #[cfg(feature = "async")]
+    pub(crate) fn decision_limit(&mut self) -> usize {
+        self.search.decision_limit(self.egraph)
+    }
+
+    /// End the session, handing the state it built assignments in back to
+    /// `pool` for another session to start from.
+    pub(crate) fn recycle(self, pool: &mut StatePool<E>) {
+        pool.put(self.search.into_assignment_state());
+    }
+
+    /// The assignment built so far, if every class has been assigned.
+    pub fn complete_assignment(&self) -> Option<&Assignment<E>> {
+        self.search.complete_assignment()
+    }
+
+    /// The best complete assignment the search has scored so far, whether or
+    /// not it has finished.
+    ///
+    /// Assignments that do not beat [`MctsConfig::cost_ceiling`] are left
+    /// out.
+    pub fn best_assignment(&self) -> Option<&Assignment<E>> {
+        self.search.best_assignment()
+    }
+
+    /// The next class the search will assign, or `None` if every class has
+    /// been assigned.
+    pub fn next_class(&mut self) -> Option<E::ClassId> {
+        self.search.next_class()
+    }
+
+    /// The search statistics for each member of the next class to assign.
+    ///
+    /// Unlike [`step`](Self::step), this does not run any playouts.
+    pub fn candidates(&mut self) -> Vec<CandidateStats<E::NodeId>> {
+        self.search.next_candidates(self.egraph)
+    }
+
+    /// Discard the search statistics for everything but the decisions still
+    /// to be made.
+    ///
+    /// The statistics gathered for the decisions the search has already
+    /// committed to, and for the alternatives it passed up, are no longer
+    /// useful once those decisions are final. Dropping them frees memory and
+    /// keeps [`MctsConfig::max_memory_bytes`] focused on the rest of the
+    /// search.
+    pub fn reroot(&mut self) {
+        self.search.reroot();
+    }
+
+    /// Record the random choices the search makes from now on.
+    ///
+    /// Every time a random rollout picks between the members of a class, the
+    /// class and the index of the chosen member are added to
+    /// [`recorded_choices`](Self::recorded_choices). Passing the recording to
+    /// [`replay_choices`](Self::replay_choices) on a fresh session for the
+    /// same egraph and configuration reproduces the search exactly, which
+    /// makes rare bad extractions debuggable.
+    pub fn record_choices(&mut self) {
+        self.search.choices_mut().record();
+    }
+
+    /// Make the choices in `choices`, recorded by
+    /// [`record_choices`](Self::record_choices), instead of random ones.
+    ///
+    /// # Panics
+    ///
+    /// The search panics if it runs out of recorded choices, or if it needs a
+    /// choice for a different class than the recording has next (i.e. the
+    /// replay has diverged from the recorded search).
+    pub fn replay_choices(&mut self, choices: Vec<(E::ClassId, usize)>) {
+        self.search.choices_mut().replay(choices);
+    }
+
+    /// The random choices recorded so far, or the ones replayed so far when
+    /// replaying.
+    pub fn recorded_choices(&self) -> &[(E::ClassId, usize)] {
+        self.search.choices().log()
+    }
+
+    /// Sample `n` random completions of the decisions made so far, and return
+    /// the utility of each, with `None` for samples that failed.
+    ///
+    /// These are the raw samples that the search averages to estimate the
+    /// utility of a partial assignment (see [`MctsConfig::terms_to_sample`]),
+    /// so their spread shows how noisy those estimates are. As in the search,
+    /// samples that cannot beat [`MctsConfig::cost_ceiling`] count as
+    /// failures. The samples draw from the session's random choices, but do
+    /// not otherwise affect the search.
+    pub fn sample_utilities(&mut self, n: usize) -> Vec<Option<Utility>> {
+        let (estimator, state, choices) = self.search.sampling_parts();
+        estimator.sample_utilities(self.egraph, state, n, choices)
+    }
+
+    /// Add the search statistics gathered by `other` to this session.
+    ///
+    /// This lets several sessions for the same problem search independently
+    /// and then pool what they learned: visit counts and utilities for the
+    /// same decisions are combined, and decisions that only `other` explored
+    /// are added. Only the tree statistics are merged; the counters in
+    /// [`stats`](Self::stats) are not.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the two sessions have not made the same
+    /// [`decisions`](Self::decisions).
+    pub fn merge(&mut self, other: &SearchSession<'_, E>) {
+        self.search.merge(&other.search);
+    }
+
+    /// The `(class, node)` decisions the search has committed to so far, in
+    /// the order it made them, including those made with
+    /// [`choose`](Self::choose).
+    ///
+    /// Passing these to `choose` in order on a fresh session for the same
+    /// egraph reproduces the same assignment.
+    pub fn decisions(&self) -> &[(E::ClassId, E::NodeId)] {
+        self.search.decisions()
+    }
+
+    /// The nodes the search has committed to that are still waiting for some
+    /// of their children to be assigned, along with the child class each one
+    /// is currently watching.
+    ///
+    /// A term is only complete once every committed node's children are
+    /// assigned. Nodes that are still listed here once there are no classes
+    /// left to assign depend on themselves through a cycle, which is why a
+    /// search that reaches that point fails.
+    pub fn pending_nodes(&self) -> Vec<WatchedNode<E::ClassId, E::NodeId>> {
+        self.search.pending_nodes()
+    }
+
+    /// Record a [`DecisionRecord`] for every decision from now on, including
+    /// those made with [`choose`](Self::choose).
+    ///
+    /// Once the search finishes, each record is filled in with the utility of
+    /// the assignment it extracted.
+    #[cfg(feature = "trace")]
+    pub fn record_trace(&mut self) {
+        self.search.record_trace(self.egraph);
+    }
+
+    /// The decisions recorded since [`record_trace`](Self::record_trace)
+    /// that have not been taken yet.
+    #[cfg(feature = "trace")]
+    pub fn take_trace(&mut self) -> Vec<DecisionRecord<E::ClassId, E::NodeId>> {
+        self.search.take_trace()
+    }
+
+    /// Record a [`PlayoutRecord`](crate::PlayoutRecord) for every playout from
+    /// now on.
+    #[cfg(feature = "trace")]
+    pub fn record_playouts(&mut self) {
+        self.search.record_playouts();
+    }
+
+    /// The playouts recorded since [`record_playouts`](Self::record_playouts)
+    /// that have not been taken yet.
+    #[cfg(feature = "trace")]
+    pub fn take_playout_trace(&mut self) -> TraceRecorder {
+        self.search.take_playout_trace()
+    }
+
+    /// How far the search has got, as the number of classes it has assigned
+    /// and an estimate of how many are left.
+    ///
+    /// Callers driving the search with [`step`](Self::step) can use
+    /// [`SearchProgress::fraction`] to show a progress bar. This walks the part
+    /// of the egraph left to extract, so it is best called once per step
+    /// rather than in a tight loop.
+    pub fn progress(&self) -> SearchProgress {
+        self.search.progress(self.egraph)
+    }
+
+    /// Counters describing the search so far.
+    pub fn stats(&self) -> SearchStats {
+        let mut stats = SearchStats {
+            optimality_ratio: self.optimality_ratio,
+            failure: self.failure.clone(),
+            ..self.search.stats()
+        };
+        stats.dead_ends.add_all(self.search.estimator().dead_ends());
+        stats
+    }
+
+    fn optimality_ratio(&self, assign: Option<&Assignment<E>>) -> Option<f32> {
+        let bound = self.search.cost_bounds()?.root_bound();
+        let cost = -self.utility(assign)?;
+        (bound > Utility::default()).then(|| (cost / bound).into_inner())
+    }
+
+    pub(crate) fn utility(&self, assign: Option<&Assignment<E>>) -> Option<Utility> {
+        let ctx = self.search.estimator().ctx();
+        Some(finite_utility(self.egraph.total_utility(ctx, assign?)))
+    }
+}
+
+impl<E: EgraphNodeCost> SearchSession<'_, E> {
+    /// Use the egraph's node costs to prune partial assignments that cannot
+    /// beat [`MctsConfig::cost_ceiling`], and to report how close the result is
+    /// to optimal in [`SearchStats::optimality_ratio`].
+    ///
+    /// Without a cost ceiling, this does not change the search itself.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the search has already made a decision.
+    pub fn prune_with_node_costs(&mut self) {
+        let bounds = CostBounds::compute(self.egraph, self.search.root_class().clone());
+        self.search.set_cost_bounds(Arc::new(bounds));
+    }
+
+    /// Estimate partial assignments by completing them greedily, always
+    /// picking the member of a class with the lowest node cost (the first of
+    /// any that tie), rather than at random.
+    ///
+    /// Greedy completions involve no randomness, so only one is taken per
+    /// estimate, whatever [`MctsConfig::terms_to_sample`] and
+    /// [`MctsConfig::rollout_policy`] say. They are often good ones, which
+    /// makes for a cheap, reproducible estimate, but a biased one: the search
+    /// cannot tell apart nodes whose cheapest completions are equally good.
+    ///
+    /// This uses the node costs from [`use_analysis`](Self::use_analysis) or
+    /// [`prune_with_node_costs`](Self::prune_with_node_costs) if the search
+    /// has them, and supplies them as the latter does otherwise.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the search has already made a decision.
+    pub fn use_greedy_rollouts(&mut self) {
+        let bounds = self.search.cost_bounds().unwrap_or_else(|| {
+            Arc::new(CostBounds::compute(
+                self.egraph,
+                self.search.root_class().clone(),
+            ))
+        });
+        self.search.set_greedy_rollouts(bounds);
+    }
+}
@@ -3,7 +3,12 @@
 //! This module does not implement congruence closure, or any other useful
 //! egraph algorithms.
 
-use crate::{Assignment, Egraph, EgraphTotalCost, Utility};
+use std::{cell::Cell, collections::BTreeMap};
+
+use crate::{
+    reachable_classes, Assignment, Egraph, EgraphAlgebraCost, EgraphNodeCost, EgraphTotalCost,
+    Utility,
+};
 
 pub(crate) struct SimpleEgraph {
     pub nodes: Vec<Vec<usize>>,
@@ -27,7 +32,218 @@ impl Egraph for SimpleEgraph {
 }
 
 impl EgraphTotalCost for SimpleEgraph {
-    fn assignment_utility(&self, assignment: &Assignment<Self>) -> Utility {
+    type CostCtx = ();
+
+    fn precompute(&self, _: &[Self::ClassId]) {}
+
+    fn assignment_utility(&self, _: &(), assignment: &Assignment<Self>) -> Utility {
         (self.score_fn)(assignment, self)
     }
 }
+
+/// A simple egraph where each node has a fixed cost, and the cost of an
+/// assignment is the sum of the costs of its nodes.
+#[derive(Default)]
+pub(crate) struct NodeCostEgraph {
+    pub nodes: Vec<Vec<usize>>,
+    pub classes: Vec<Vec<usize>>,
+    pub costs: Vec<f32>,
+}
+
+impl Egraph for NodeCostEgraph {
+    type ClassId = usize;
+    type NodeId = usize;
+
+    fn children(&self, id: &Self::NodeId) -> impl Iterator<Item = &Self::ClassId> {
+        self.nodes[*id].iter()
+    }
+
+    fn members(&self, id: &Self::ClassId) -> impl Iterator<Item = &Self::NodeId> {
+        self.classes[*id].iter()
+    }
+}
+
+impl NodeCostEgraph {
+    /// Copy the classes `0..n_classes` of `egraph`, numbering its nodes in
+    /// the order they are found.
+    pub fn copy_of(
+        egraph: &impl EgraphNodeCost<ClassId = usize, NodeId = usize>,
+        n_classes: usize,
+    ) -> Self {
+        let mut copy = Self::default();
+        for class in 0..n_classes {
+            let members = egraph
+                .members(&class)
+                .map(|node| {
+                    copy.nodes.push(egraph.children(node).copied().collect());
+                    copy.costs.push(egraph.node_cost(node).into_inner());
+                    copy.nodes.len() - 1
+                })
+                .collect();
+            copy.classes.push(members);
+        }
+        copy
+    }
+}
+
+impl EgraphTotalCost for NodeCostEgraph {
+    type CostCtx = ();
+
+    fn precompute(&self, _: &[Self::ClassId]) {}
+
+    fn assignment_utility(&self, _: &(), assignment: &Assignment<Self>) -> Utility {
+        let cost: f32 = assignment.values().map(|node| self.costs[*node]).sum();
+        Utility::new(-cost).unwrap()
+    }
+}
+
+impl EgraphNodeCost for NodeCostEgraph {
+    fn node_cost(&self, node: &Self::NodeId) -> Utility {
+        Utility::new(self.costs[*node]).unwrap()
+    }
+}
+
+/// A simple egraph where each node has a fixed pair of costs, and assignments
+/// are scored by the sums of each, compared lexicographically.
+pub(crate) struct LexCostEgraph {
+    pub nodes: Vec<Vec<usize>>,
+    pub classes: Vec<Vec<usize>>,
+    pub costs: Vec<(f32, f32)>,
+}
+
+impl Egraph for LexCostEgraph {
+    type ClassId = usize;
+    type NodeId = usize;
+
+    fn children(&self, id: &Self::NodeId) -> impl Iterator<Item = &Self::ClassId> {
+        self.nodes[*id].iter()
+    }
+
+    fn members(&self, id: &Self::ClassId) -> impl Iterator<Item = &Self::NodeId> {
+        self.classes[*id].iter()
+    }
+}
+
+impl EgraphAlgebraCost for LexCostEgraph {
+    type Value = (Utility, Utility);
+
+    fn assignment_value(&self, assignment: &Assignment<Self>) -> Self::Value {
+        let (primary, secondary) = assignment
+            .values()
+            .map(|node| self.costs[*node])
+            .fold((0.0, 0.0), |(a, b), (c, d)| (a + c, b + d));
+        (
+            Utility::new(-primary).unwrap(),
+            Utility::new(-secondary).unwrap(),
+        )
+    }
+}
+
+/// A [`NodeCostEgraph`] with the optional parts of the [`Egraph`] interface
+/// switched on as needed, which counts how often they are used.
+///
+/// Its cost context is a table of the costs of the nodes reachable from the
+/// roots, so scoring a node the search should not have seen panics.
+#[derive(Default)]
+pub(crate) struct TestEgraph {
+    pub inner: NodeCostEgraph,
+    /// The order returned by [`Egraph::topo_hint`].
+    pub hint: Option<Vec<usize>>,
+    /// The weight of each node, which makes assignments cost their
+    /// [`expected_cost`](crate::expected_cost).
+    pub weights: Option<Vec<f32>>,
+    /// A class that is opaque, and costs 1 to refer to.
+    pub opaque_class: Option<usize>,
+    /// Whether classes are also exposed through [`Egraph::members_slice`].
+    pub slices: bool,
+    pub members_calls: Cell<usize>,
+    pub opaque_members_calls: Cell<usize>,
+    pub slice_lookups: Cell<usize>,
+    pub precomputes: Cell<usize>,
+}
+
+impl TestEgraph {
+    pub fn new(inner: NodeCostEgraph) -> Self {
+        Self {
+            inner,
+            ..Default::default()
+        }
+    }
+}
+
+impl Egraph for TestEgraph {
+    type ClassId = usize;
+    type NodeId = usize;
+
+    fn children(&self, id: &Self::NodeId) -> impl Iterator<Item = &Self::ClassId> {
+        self.inner.children(id)
+    }
+
+    fn members(&self, id: &Self::ClassId) -> impl Iterator<Item = &Self::NodeId> {
+        self.members_calls.set(self.members_calls.get() + 1);
+        if self.opaque_class == Some(*id) {
+            self.opaque_members_calls
+                .set(self.opaque_members_calls.get() + 1);
+        }
+        self.inner.members(id)
+    }
+
+    fn members_slice(&self, id: &Self::ClassId) -> Option<&[Self::NodeId]> {
+        if !self.slices {
+            return None;
+        }
+        self.slice_lookups.set(self.slice_lookups.get() + 1);
+        Some(&self.inner.classes[*id])
+    }
+
+    fn topo_hint(&self) -> Option<Vec<Self::ClassId>> {
+        self.hint.clone()
+    }
+
+    fn node_weight(&self, id: &Self::NodeId) -> f32 {
+        self.weights.as_ref().map_or(1.0, |weights| weights[*id])
+    }
+
+    fn has_node_weights(&self) -> bool {
+        self.weights.is_some()
+    }
+
+    fn is_opaque(&self, id: &Self::ClassId) -> bool {
+        self.opaque_class == Some(*id)
+    }
+}
+
+impl EgraphTotalCost for TestEgraph {
+    type CostCtx = BTreeMap<usize, f32>;
+
+    fn precompute(&self, roots: &[Self::ClassId]) -> Self::CostCtx {
+        self.precomputes.set(self.precomputes.get() + 1);
+        roots
+            .iter()
+            .flat_map(|root| reachable_classes(&self.inner, *root))
+            .flat_map(|class| self.inner.classes[class].iter())
+            .map(|node| (*node, self.inner.costs[*node]))
+            .collect()
+    }
+
+    fn assignment_utility(&self, costs: &Self::CostCtx, assignment: &Assignment<Self>) -> Utility {
+        let cost = assignment
+            .values()
+            .map(|node| {
+                costs.get(node).expect("node missing from the cost context")
+                    / self.node_weight(node)
+            })
+            .sum::<f32>();
+        Utility::new(-cost).unwrap()
+    }
+
+    fn opaque_cost(&self, _: &Self::ClassId) -> Utility {
+        Utility::new(1.0).unwrap()
+    }
+}
+
+impl EgraphNodeCost for TestEgraph {
+    fn node_cost(&self, node: &Self::NodeId) -> Utility {
+        self.inner.node_cost(node)
+    }
+}
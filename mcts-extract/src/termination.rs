@@ -0,0 +1,191 @@
+//! Policies for deciding when a search should stop early.
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::{MctsConfig, SearchStats, Utility};
+
+/// A condition for stopping a search before every class has been decided.
+///
+/// The search consults its policy (see
+/// [`SearchSession::stop_when`](crate::SearchSession::stop_when)) before each
+/// round of playouts. Once the policy asks it to stop, the search behaves as
+/// if it had hit one of its resource limits: [`SearchSession::step`] returns
+/// [`StepOutcome::Stopped`], and running the search to completion returns the
+/// best complete assignment seen so far.
+///
+/// Policies combine with [`Or`] and [`And`]. The limits in [`MctsConfig`]
+/// ([`max_rounds`](MctsConfig::max_rounds),
+/// [`target_utility`](MctsConfig::target_utility) and
+/// [`max_memory_bytes`](MctsConfig::max_memory_bytes)) are built-in policies
+/// ([`MaxRounds`], [`TargetUtility`] and [`MaxMemoryBytes`]) that are always
+/// combined with whichever policy the caller installs. Without any policy, the
+/// search runs until every class has been decided.
+///
+/// [`SearchSession::step`]: crate::SearchSession::step
+/// [`StepOutcome::Stopped`]: crate::StepOutcome::Stopped
+pub trait TerminationPolicy: Send {
+    /// Whether to stop, given the search's statistics so far.
+    fn should_stop(&mut self, stats: &SearchStats) -> bool;
+}
+
+impl<P: TerminationPolicy + ?Sized> TerminationPolicy for Box<P> {
+    fn should_stop(&mut self, stats: &SearchStats) -> bool {
+        (**self).should_stop(stats)
+    }
+}
+
+impl fmt::Debug for dyn TerminationPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("dyn TerminationPolicy")
+    }
+}
+
+/// Stop once either policy would.
+///
+/// Both policies are consulted every time, so that both can keep track of the
+/// search.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Or<A, B>(pub A, pub B);
+
+impl<A: TerminationPolicy, B: TerminationPolicy> TerminationPolicy for Or<A, B> {
+    fn should_stop(&mut self, stats: &SearchStats) -> bool {
+        let a = self.0.should_stop(stats);
+        let b = self.1.should_stop(stats);
+        a || b
+    }
+}
+
+/// Stop once both policies would at the same time.
+///
+/// Both policies are consulted every time, so that both can keep track of the
+/// search.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct And<A, B>(pub A, pub B);
+
+impl<A: TerminationPolicy, B: TerminationPolicy> TerminationPolicy for And<A, B> {
+    fn should_stop(&mut self, stats: &SearchStats) -> bool {
+        let a = self.0.should_stop(stats);
+        let b = self.1.should_stop(stats);
+        a && b
+    }
+}
+
+/// Stop once the search has run this many playouts.
+#[derive(Copy, Clone, Debug)]
+pub struct MaxPlayouts(pub usize);
+
+impl TerminationPolicy for MaxPlayouts {
+    fn should_stop(&mut self, stats: &SearchStats) -> bool {
+        stats.playouts >= self.0
+    }
+}
+
+/// Stop once the search has run this many rounds (see
+/// [`MctsConfig::max_rounds`]).
+#[derive(Copy, Clone, Debug)]
+pub struct MaxRounds(pub usize);
+
+impl TerminationPolicy for MaxRounds {
+    fn should_stop(&mut self, stats: &SearchStats) -> bool {
+        stats.rounds >= self.0
+    }
+}
+
+/// Stop once the search has scored a complete assignment with at least this
+/// utility (see [`MctsConfig::target_utility`]).
+#[derive(Copy, Clone, Debug)]
+pub struct TargetUtility(pub Utility);
+
+impl TerminationPolicy for TargetUtility {
+    fn should_stop(&mut self, stats: &SearchStats) -> bool {
+        stats.best_utility.is_some_and(|best| best >= self.0)
+    }
+}
+
+/// Stop once the search tree takes up more than this many bytes (see
+/// [`MctsConfig::max_memory_bytes`]).
+///
+/// The search only checks between rounds, so the tree can outgrow the limit by
+/// up to a round of playouts.
+#[derive(Copy, Clone, Debug)]
+pub struct MaxMemoryBytes(pub usize);
+
+impl TerminationPolicy for MaxMemoryBytes {
+    fn should_stop(&mut self, stats: &SearchStats) -> bool {
+        stats.tree_memory_bytes > self.0
+    }
+}
+
+/// Stop once the wall clock passes an instant.
+///
+/// The search only checks between rounds, so it can overrun the deadline by up
+/// to a round of playouts.
+#[derive(Copy, Clone, Debug)]
+pub struct Deadline(pub Instant);
+
+impl Deadline {
+    /// A deadline `timeout` from now.
+    pub fn after(timeout: Duration) -> Self {
+        Self(Instant::now() + timeout)
+    }
+}
+
+impl TerminationPolicy for Deadline {
+    fn should_stop(&mut self, _: &SearchStats) -> bool {
+        Instant::now() >= self.0
+    }
+}
+
+/// Stop once the best utility seen has not improved for a number of rounds in
+/// a row.
+///
+/// Rounds before the search has scored any complete assignment count towards
+/// the limit too.
+#[derive(Copy, Clone, Debug)]
+pub struct NoImprovement {
+    rounds: usize,
+    best: Option<Utility>,
+    /// The number of rounds that had run when `best` was last improved on.
+    improved_at: usize,
+}
+
+impl NoImprovement {
+    /// Stop after `rounds` rounds without an improvement.
+    pub fn new(rounds: usize) -> Self {
+        Self {
+            rounds,
+            best: None,
+            improved_at: 0,
+        }
+    }
+}
+
+impl TerminationPolicy for NoImprovement {
+    fn should_stop(&mut self, stats: &SearchStats) -> bool {
+        if stats.best_utility > self.best {
+            self.best = stats.best_utility;
+            self.improved_at = stats.rounds;
+        }
+        stats.rounds.saturating_sub(self.improved_at) >= self.rounds
+    }
+}
+
+/// The built-in policies for the limits set in `config`, if it sets any.
+pub(crate) fn config_limits(config: &MctsConfig) -> Option<Box<dyn TerminationPolicy>> {
+    let limits: [Option<Box<dyn TerminationPolicy>>; 3] = [
+        config.max_rounds.map(|max| Box::new(MaxRounds(max)) as _),
+        config
+            .target_utility
+            .map(|target| Box::new(TargetUtility(target)) as _),
+        config
+            .max_memory_bytes
+            .map(|max| Box::new(MaxMemoryBytes(max)) as _),
+    ];
+    limits
+        .into_iter()
+        .flatten()
+        .reduce(|a, b| Box::new(Or(a, b)))
+}
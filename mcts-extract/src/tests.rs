@@ -1,8 +1,3321 @@
-use crate::{mcts_extract, simple_egraph::SimpleEgraph, Assignment, MctsConfig, Utility};
+use std::{
+    cell::Cell,
+    cmp, mem,
+    sync::{
+        atomic::{self, AtomicBool, AtomicU64},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+use crate::{
+    analysis::CostBounds,
+    analyze, assignment_cmp, assignment_depth, assignment_edges, assignment_hash,
+    assignment_topo_order, assignments_equal,
+    estimate::RolloutEstimator,
+    estimate_optimal, extract_any, extract_into, extract_per_root_node, extractable_classes,
+    extraction_state::{
+        random_cost_estimate, EmptyClassPenalty, ExtractionState, RolloutFailure, StatePool,
+        SHRINK_INTERVAL,
+    },
+    fold_assignment, gen_random_egraph, mcts_extract, mcts_extract_forest, mcts_extract_in_algebra,
+    mcts_extract_into, mcts_extract_multistart, mcts_extract_top_k, mcts_extract_with_analysis,
+    mcts_extract_with_stats, optimal_dag_extract, reachable_classes,
+    search_tree::{BestAssignment, EstimateUtility, LeafEstimate, SearchTree, TreeNodeId},
+    simple_egraph::{LexCostEgraph, NodeCostEgraph, SimpleEgraph, TestEgraph},
+    Analysis, And, Assignment, Backup, ChildStat, ChoiceGraph, Clock, ConfidenceGate,
+    CriticalPathCost, DeadEnd, Deadline, Egraph, EgraphEdgeCost, EgraphNodeCost, EgraphTotalCost,
+    ExpansionStrategy, ExplorationSchedule, ExtractError, FinalSelection, Materialized,
+    MaxPlayouts, MctsConfig, NoImprovement, NonFiniteUtilities, OptimalEstimate, Or, ParentStat,
+    PatternPenalties, PatternPenalty, RolloutPolicy, SampleAggregation, SearchObjective,
+    SearchSession, SearchStats, SearchTimings, SelectionPolicy, SoftPenalties, StepOutcome,
+    Tiebreak, Ucb1, Utility, WatchedNode,
+};
+
+#[test]
+fn finds_high_util() {
+    // Set up a simple egraph with a single term with positive utility.
+    let egraph = high_util_egraph();
+
+    let assign = mcts_extract(
+        &egraph,
+        0,
+        MctsConfig {
+            playouts_per_round: 4,
+            terms_to_sample: 4,
+            ..Default::default()
+        },
+    )
+    .expect("extraction should succeed");
+    assert_eq!(assign.len(), 3);
+    assert_eq!(assign[&0], 1);
+    assert_eq!(assign[&2], 4);
+    assert_eq!(assign[&3], 5);
+}
+
+#[test]
+fn extracts_into_a_reused_buffer() {
+    let egraph = high_util_egraph();
+    let config = MctsConfig {
+        playouts_per_round: 4,
+        terms_to_sample: 4,
+        seed: Some(0),
+        ..Default::default()
+    };
+    let mut out = Assignment::<SimpleEgraph>::default();
+    out.insert(7, 7);
+    assert!(mcts_extract_into(&egraph, 0, config.clone(), &mut out));
+    assert_eq!(Some(out.clone()), mcts_extract(&egraph, 0, config.clone()));
+
+    let capacity = out.capacity();
+    assert!(mcts_extract_into(&egraph, 0, config.clone(), &mut out));
+    assert_eq!(out.capacity(), capacity);
+    assert_eq!(out.len(), 3);
+
+    assert!(!mcts_extract_into(
+        &unextractable_egraph(),
+        0,
+        config,
+        &mut out
+    ));
+    assert!(out.is_empty());
+}
+
+#[test]
+fn forests_share_subterms_between_roots() {
+    // Root 0 is cheapest on its own through class 3, and root 1 is cheapest
+    // on its own as a leaf, for a total of 1 + 4 + 3.5. Sending both roots
+    // through class 2 costs 1 + 1 + 5 instead.
+    let egraph = NodeCostEgraph {
+        nodes: vec![vec![2], vec![3], vec![2], vec![], vec![], vec![]],
+        classes: vec![vec![0, 1], vec![2, 3], vec![4], vec![5]],
+        costs: vec![1.0, 1.0, 1.0, 3.5, 5.0, 4.0],
+    };
+    let config = MctsConfig {
+        seed: Some(0),
+        ..Default::default()
+    };
+    let separate = mcts_extract(&egraph, 0, config.clone()).unwrap();
+    assert_eq!(separate[&0], 1);
+    let separate = mcts_extract(&egraph, 1, config.clone()).unwrap();
+    assert_eq!(separate[&1], 3);
+
+    let forest = mcts_extract_forest(&egraph, [0, 1], config.clone()).unwrap();
+    assert_eq!(forest.len(), 3);
+    assert_eq!(forest[&0], 0);
+    assert_eq!(forest[&1], 2);
+    assert_eq!(forest[&2], 4);
+    assert!(mcts_extract_forest(&egraph, [], config).is_none());
+}
+
+#[test]
+fn tree_node_ids_have_a_niche() {
+    assert_eq!(mem::size_of::<Option<TreeNodeId>>(), 4);
+    let egraph = gen_random_egraph(30, 4, 3, 7);
+    let config = MctsConfig {
+        seed: Some(0),
+        ..Default::default()
+    };
+    let (assign, stats) = mcts_extract_with_stats(&egraph, 0, config);
+    assert!(assign.is_some());
+    assert!(stats.tree_nodes > 1);
+}
+
+#[test]
+fn op_limits_force_feasible_extractions() {
+    // Both children of the root are cheapest as multiplies, but only one
+    // multiply is allowed.
+    let egraph = NodeCostEgraph {
+        nodes: vec![vec![1, 2], vec![], vec![], vec![], vec![]],
+        classes: vec![vec![0], vec![1, 2], vec![3, 4]],
+        costs: vec![1.0, 1.0, 3.0, 1.0, 3.0],
+    };
+    let ops = ["add", "mul", "shift", "mul", "shift"];
+    let extract = |max_muls| {
+        let config = MctsConfig {
+            seed: Some(0),
+            ..Default::default()
+        };
+        let mut session = SearchSession::new(&egraph, 0, config);
+        session.limit_ops(|node| ops[*node], [("mul", max_muls)]);
+        session.run()
+    };
+
+    let unlimited = extract(2).unwrap();
+    assert_eq!(
+        egraph.assignment_utility(&(), &unlimited),
+        Utility::new(-3.0).unwrap()
+    );
+
+    let limited = extract(1).unwrap();
+    let muls = limited.values().filter(|node| ops[**node] == "mul").count();
+    assert_eq!(muls, 1);
+    assert_eq!(
+        egraph.assignment_utility(&(), &limited),
+        Utility::new(-5.0).unwrap()
+    );
+}
+
+#[test]
+fn implications_constrain_other_classes() {
+    // The cheapest term picks node 1 for class 1 and node 3 for class 2.
+    let egraph = NodeCostEgraph {
+        nodes: vec![vec![1, 2], vec![], vec![], vec![], vec![]],
+        classes: vec![vec![0], vec![1, 2], vec![3, 4]],
+        costs: vec![1.0, 1.0, 4.0, 1.0, 3.0],
+    };
+    let extract = |implications: Vec<_>| {
+        let config = MctsConfig {
+            seed: Some(0),
+            ..Default::default()
+        };
+        let mut session = SearchSession::new(&egraph, 0, config);
+        for (when, then) in implications {
+            session.require_if(when, then);
+        }
+        session.run()
+    };
+
+    let free = extract(vec![]).unwrap();
+    assert_eq!((free[&1], free[&2]), (1, 3));
+    // Class 1 is visited before class 2, so these rule out the cheapest term
+    // from either side.
+    for implication in [((1, 1), (2, 4)), ((2, 3), (1, 2))] {
+        let constrained = extract(vec![implication]).unwrap();
+        assert_eq!((constrained[&1], constrained[&2]), (1, 4));
+        assert_eq!(
+            egraph.assignment_utility(&(), &constrained),
+            Utility::new(-5.0).unwrap()
+        );
+    }
+
+    // The root cannot require two different nodes of class 1.
+    assert!(matches!(
+        extract(vec![((0, 0), (1, 1)), ((0, 0), (1, 2))]),
+        Err(ExtractError::NoAssignment)
+    ));
+}
+
+#[test]
+fn empty_class_penalties_stand_in_for_missing_members() {
+    // Node 0 is cheap, but needs class 1, which has no members.
+    let egraph = NodeCostEgraph {
+        nodes: vec![vec![1], vec![]],
+        classes: vec![vec![0, 1], vec![]],
+        costs: vec![1.0, 10.0],
+    };
+    let sample = |penalty: Option<EmptyClassPenalty<NodeCostEgraph>>| {
+        let mut state = ExtractionState::new(0);
+        if let Some(penalty) = penalty {
+            state.set_empty_class_penalty(penalty);
+        }
+        state.start_next_assign().unwrap().assign(0, &egraph);
+        let mut completed = false;
+        let util = random_cost_estimate(
+            &egraph,
+            &(),
+            &mut state,
+            &mut StdRng::seed_from_u64(0),
+            None,
+            None,
+            |_, _| completed = true,
+        );
+        assert!(!completed);
+        util
+    };
+
+    assert_eq!(
+        sample(None),
+        Err(RolloutFailure::Stuck(DeadEnd::EmptyClass))
+    );
+    assert_eq!(
+        sample(Some(Arc::new(|_| None))),
+        Err(RolloutFailure::Stuck(DeadEnd::EmptyClass))
+    );
+    assert_eq!(
+        sample(Some(Arc::new(|class| {
+            assert_eq!(*class, 1);
+            Some(Utility::new(5.0).unwrap())
+        }))),
+        Ok(Utility::new(-6.0).unwrap())
+    );
+
+    // The search still only returns complete terms, so a penalty small enough
+    // to make node 0 look best leads it into a dead end, and it falls back on
+    // the best term it scored.
+    let extract = |penalty| {
+        let config = MctsConfig {
+            seed: Some(0),
+            ..Default::default()
+        };
+        let mut session = SearchSession::new(&egraph, 0, config);
+        session.on_empty_class(move |_| Some(Utility::new(penalty).unwrap()));
+        let assign = session.run().unwrap();
+        (session.decisions()[0], assign[&0])
+    };
+    assert_eq!(extract(20.0), ((0, 1), 1));
+    assert_eq!(extract(5.0), ((0, 0), 1));
+}
+
+#[test]
+fn priors_from_node_costs_seed_new_nodes() {
+    // Node 0 leads to a class whose cheapest member costs 1, node 1 to one
+    // that costs 50.
+    let egraph = NodeCostEgraph {
+        nodes: vec![vec![1], vec![2], vec![], vec![], vec![]],
+        classes: vec![vec![0, 1], vec![2, 3], vec![4]],
+        costs: vec![1.0, 1.0, 1.0, 100.0, 50.0],
+    };
+    let candidates = |prior_strength, node_costs| {
+        let config = MctsConfig {
+            prior_strength,
+            seed: Some(0),
+            ..Default::default()
+        };
+        let mut session = SearchSession::new(&egraph, 0, config);
+        if node_costs {
+            session.prune_with_node_costs();
+        }
+        session.add_breakpoint(0);
+        match session.step() {
+            StepOutcome::Breakpoint { candidates, .. } => candidates
+                .iter()
+                .map(|c| (c.visits, c.value.into_inner()))
+                .collect::<Vec<_>>(),
+            _ => panic!("expected a breakpoint"),
+        }
+    };
+
+    // A prior worth many samples dominates the few real ones.
+    let seeded = candidates(1e5, true);
+    assert!((seeded[0].1 + 2.0).abs() < 0.01);
+    assert!((seeded[1].1 + 51.0).abs() < 0.01);
+
+    assert_eq!(candidates(0.0, true), candidates(0.0, false));
+}
+
+#[test]
+fn sibling_rollouts_share_random_choices() {
+    // Every member of the root class leads to the same chain of classes with
+    // three members each.
+    let width = 20;
+    let mut nodes = vec![vec![1]; width];
+    let mut classes = vec![(0..width).collect::<Vec<_>>()];
+    for class in 1..5 {
+        let first = nodes.len();
+        classes.push((first..first + 3).collect());
+        nodes.extend((0..3).map(|_| if class < 4 { vec![class + 1] } else { vec![] }));
+    }
+    let costs = (0..nodes.len()).map(|node| node as f32).collect();
+    let egraph = NodeCostEgraph {
+        nodes,
+        classes,
+        costs,
+    };
+    let random_choices = |share_sibling_rollouts| {
+        let config = MctsConfig {
+            expansion_strategy: ExpansionStrategy::Full,
+            share_sibling_rollouts,
+            seed: Some(0),
+            ..Default::default()
+        };
+        let mut session = SearchSession::new(&egraph, 0, config);
+        session.add_breakpoint(0);
+        session.record_choices();
+        assert!(matches!(session.step(), StepOutcome::Breakpoint { .. }));
+        session.recorded_choices().len()
+    };
+
+    let shared = random_choices(true);
+    let independent = random_choices(false);
+    assert!(
+        shared * 2 < independent,
+        "{shared} choices with sharing, {independent} without"
+    );
+}
+
+#[test]
+fn committing_best_roots_walks_down_the_term() {
+    // The cheapest term picks node 1 for class 1 and node 3 for class 2.
+    let egraph = NodeCostEgraph {
+        nodes: vec![vec![1, 2], vec![], vec![], vec![], vec![]],
+        classes: vec![vec![0], vec![1, 2], vec![3, 4]],
+        costs: vec![1.0, 1.0, 4.0, 1.0, 3.0],
+    };
+    let config = MctsConfig {
+        playouts_per_round: 64,
+        seed: Some(0),
+        ..Default::default()
+    };
+    let mut session = SearchSession::new(&egraph, 0, config);
+    session.add_breakpoint(0);
+    assert!(matches!(session.step(), StepOutcome::Breakpoint { .. }));
+
+    // A single round explores deep enough to make every decision.
+    let mut tree_nodes = session.stats().tree_nodes;
+    for expected in [(0, 0), (1, 1), (2, 3)] {
+        assert_eq!(session.commit_best_root(), Some(expected));
+        // Only the statistics below the decision are kept.
+        let remaining = session.stats().tree_nodes;
+        assert!(0 < remaining && remaining < tree_nodes);
+        tree_nodes = remaining;
+    }
+    assert_eq!(session.stats().playouts, 64);
+    assert_eq!(session.commit_best_root(), None);
+    assert_eq!(session.decisions(), [(0, 0), (1, 1), (2, 3)]);
+    assert!(matches!(session.step(), StepOutcome::Done));
+
+    // Without any playouts, there is nothing to go on.
+    let mut session = SearchSession::new(&egraph, 0, MctsConfig::default());
+    assert_eq!(session.commit_best_root(), None);
+    assert!(session.decisions().is_empty());
+}
+
+#[test]
+fn fails_unextractable() {
+    // Set up a small egraph with no valid extractions
+    let egraph = unextractable_egraph();
+
+    assert!(mcts_extract(
+        &egraph,
+        0,
+        MctsConfig {
+            playouts_per_round: 4,
+            terms_to_sample: 4,
+            ..Default::default()
+        },
+    )
+    .is_none());
+}
+
+#[test]
+fn samples_expose_failed_rollouts() {
+    let egraph = dead_end_egraph();
+    let config = MctsConfig {
+        seed: Some(0),
+        ..Default::default()
+    };
+    let mut session = SearchSession::new(&egraph, 0, config);
+    let samples = session.sample_utilities(64);
+    assert_eq!(samples.len(), 64);
+    assert!(samples.contains(&None));
+    assert!(samples.contains(&Some(Utility::new(1.0).unwrap())));
+
+    // Node 0 always extracts, with a utility of zero.
+    session.choose(0);
+    let samples = session.sample_utilities(8);
+    assert_eq!(samples, vec![Some(Utility::default()); 8]);
+}
+
+#[test]
+fn dead_ends_report_cycles() {
+    // Each class's only node needs the other class.
+    let egraph = SimpleEgraph {
+        nodes: vec![vec![1], vec![0]],
+        classes: vec![vec![0], vec![1]],
+        score_fn: Box::new(|_, _| Utility::default()),
+    };
+    let (assign, stats) = mcts_extract_with_stats(&egraph, 0, MctsConfig::default());
+    assert_eq!(assign, None);
+    assert!(stats.dead_ends.cycle > 0);
+    assert_eq!(stats.dead_ends.empty_class, 0);
+    assert_eq!(stats.dead_ends.most_common(), Some(DeadEnd::Cycle));
+}
+
+#[test]
+fn infinite_utilities_are_clamped_or_rejected() {
+    // Node 1 is infinitely bad.
+    let egraph = SimpleEgraph {
+        nodes: vec![vec![], vec![]],
+        classes: vec![vec![0, 1]],
+        score_fn: Box::new(|assign, _| {
+            Utility::new(if assign[&0] == 1 {
+                f32::NEG_INFINITY
+            } else {
+                -1.0
+            })
+            .unwrap()
+        }),
+    };
+    let config = MctsConfig {
+        seed: Some(0),
+        ..Default::default()
+    };
+    let assign = SearchSession::new(&egraph, 0, config.clone()).run();
+    assert_eq!(assign.unwrap()[&0], 0);
+
+    let config = MctsConfig {
+        non_finite_utilities: NonFiniteUtilities::Reject,
+        ..config
+    };
+    let err = SearchSession::new(&egraph, 0, config).run().unwrap_err();
+    assert_eq!(
+        err,
+        ExtractError::NonFiniteUtility {
+            assignment: "{0: 1}".to_owned()
+        }
+    );
+}
+
+#[test]
+fn resolved_rollouts_avoid_new_classes() {
+    // Node 2 needs the empty class 2 as well as classes 3 and 4; node 1 needs
+    // nothing.
+    let egraph = SimpleEgraph {
+        nodes: vec![vec![1], vec![], vec![2, 3, 4], vec![], vec![]],
+        classes: vec![vec![0], vec![1, 2], vec![], vec![3], vec![4]],
+        score_fn: Box::new(|_, _| Utility::default()),
+    };
+    let failures = |rollout_policy| {
+        let config = MctsConfig {
+            rollout_policy,
+            seed: Some(0),
+            ..Default::default()
+        };
+        let samples = SearchSession::new(&egraph, 0, config).sample_utilities(1000);
+        samples.iter().filter(|util| util.is_none()).count()
+    };
+    // Node 2 is picked half the time uniformly, and a fifth of the time when
+    // preferring resolved members.
+    assert!((400..600).contains(&failures(RolloutPolicy::Uniform)));
+    assert!((100..300).contains(&failures(RolloutPolicy::PreferResolved)));
+}
+
+#[test]
+fn sample_aggregations_pick_the_best_or_worst_sample() {
+    // The root's members cost anywhere from 1 to 100.
+    let egraph = NodeCostEgraph {
+        nodes: vec![vec![], vec![], vec![]],
+        classes: vec![vec![0, 1, 2]],
+        costs: vec![1.0, 50.0, 100.0],
+    };
+    let estimate = |sample_aggregation, weighted_backprop| {
+        let config = MctsConfig {
+            terms_to_sample: 16,
+            sample_aggregation,
+            weighted_backprop,
+            ..Default::default()
+        };
+        let mut estimator = RolloutEstimator::new(&config, ());
+        let mut state = ExtractionState::new(0);
+        let mut best = BestAssignment::new(0.0, None);
+        let mut rng = StdRng::seed_from_u64(0);
+        estimator
+            .estimate(&mut state, &egraph, &mut best, &mut rng)
+            .utility
+            .into_inner()
+    };
+    for weighted in [false, true] {
+        let mean = estimate(SampleAggregation::MeanOfSamples, weighted);
+        assert!(-100.0 < mean && mean < -1.0);
+        assert_eq!(estimate(SampleAggregation::MinOfSamples, weighted), -1.0);
+        assert_eq!(estimate(SampleAggregation::MaxOfSamples, weighted), -100.0);
+    }
+}
+
+#[test]
+fn progress_fractions_grow_to_one() {
+    let egraph = gen_random_egraph(200, 4, 3, 7);
+    let config = MctsConfig {
+        seed: Some(0),
+        ..Default::default()
+    };
+    let mut session = SearchSession::new(&egraph, 0, config);
+    let start = session.progress();
+    assert_eq!(start.committed_classes, 0);
+    assert_eq!(start.fraction(), 0.0);
+
+    let mut fractions = vec![start.fraction()];
+    loop {
+        match session.step() {
+            StepOutcome::Committed { .. } => fractions.push(session.progress().fraction()),
+            StepOutcome::Done => break,
+            outcome => panic!("unexpected outcome {outcome:?}"),
+        }
+    }
+    assert!(fractions.len() > 2);
+    assert!(fractions.windows(2).all(|pair| pair[0] <= pair[1]));
+    assert!(fractions
+        .iter()
+        .any(|&fraction| 0.0 < fraction && fraction < 1.0));
+
+    let end = session.progress();
+    assert_eq!(end.remaining_classes, 0);
+    assert_eq!(end.fraction(), 1.0);
+    assert_eq!(
+        end.committed_classes,
+        session.complete_assignment().unwrap().len()
+    );
+}
+
+#[test]
+fn termination_policies_stop_searches_early() {
+    let egraph = gen_random_egraph(200, 4, 3, 7);
+    let config = MctsConfig {
+        seed: Some(0),
+        ..Default::default()
+    };
+    let full = {
+        let mut session = SearchSession::new(&egraph, 0, config.clone());
+        session.run().unwrap();
+        session.stats()
+    };
+    assert!(full.rounds > 2);
+
+    let mut session = SearchSession::new(&egraph, 0, config.clone());
+    session.stop_when(Or(
+        MaxPlayouts(2 * config.playouts_per_round),
+        Deadline::after(Duration::from_secs(3600)),
+    ));
+    assert!(matches!(session.step(), StepOutcome::Committed { .. }));
+    assert!(matches!(session.step(), StepOutcome::Committed { .. }));
+    assert!(matches!(session.step(), StepOutcome::Stopped));
+    assert_eq!(session.stats().rounds, 2);
+
+    // The limits in the config still apply alongside the caller's policy.
+    let mut session = SearchSession::new(
+        &egraph,
+        0,
+        MctsConfig {
+            max_rounds: Some(1),
+            ..config.clone()
+        },
+    );
+    session.stop_when(MaxPlayouts(usize::MAX));
+    assert!(matches!(session.step(), StepOutcome::Committed { .. }));
+    assert!(matches!(session.step(), StepOutcome::Stopped));
+
+    // After the first round, this stops at the first round that does not
+    // improve on the best utility.
+    let mut session = SearchSession::new(&egraph, 0, config);
+    session.stop_when(And(NoImprovement::new(1), MaxPlayouts(1)));
+    let stopped = session.run();
+    let stats = session.stats();
+    assert!(stats.rounds < full.rounds);
+    assert_eq!(stopped.is_ok(), stats.best_utility.is_some());
+}
+
+#[test]
+fn cancelled_searches_return_the_best_term_so_far() {
+    let egraph = gen_random_egraph(200, 4, 3, 7);
+    let cancel = Arc::new(AtomicBool::new(false));
+    let config = MctsConfig {
+        cancel: Some(cancel.clone()),
+        seed: Some(0),
+        ..Default::default()
+    };
+    let mut session = SearchSession::new(&egraph, 0, config.clone());
+    assert!(matches!(session.step(), StepOutcome::Committed { .. }));
+    cancel.store(true, atomic::Ordering::Relaxed);
+    let assign = session.run().unwrap();
+    let stats = session.stats();
+    assert_eq!(stats.playouts, config.playouts_per_round);
+    assert_eq!(Some(&assign), session.best_assignment());
+    assert_eq!(
+        egraph.assignment_utility(&(), &assign),
+        stats.best_utility.unwrap()
+    );
+
+    // The flag stays set, so a search sharing it stops before its first
+    // playout.
+    let mut session = SearchSession::new(&egraph, 0, config.clone());
+    assert!(matches!(session.step(), StepOutcome::Stopped));
+    assert_eq!(session.stats().playouts, 0);
+    assert!(matches!(session.run(), Err(ExtractError::NoAssignment)));
+
+    // The flag is checked before every playout, so setting it also stops the
+    // search in the middle of a round.
+    cancel.store(false, atomic::Ordering::Relaxed);
+    let mut session = SearchSession::new(&egraph, 0, config.clone());
+    let flag = cancel.clone();
+    session.on_new_best(move |_, _| flag.store(true, atomic::Ordering::Relaxed));
+    let assign = session.run().unwrap();
+    assert!(session.stats().playouts < config.playouts_per_round);
+    assert_eq!(Some(&assign), session.best_assignment());
+}
+
+#[test]
+fn timings_measure_playout_throughput() {
+    /// A clock that advances by a millisecond every time it is read.
+    struct TickClock(AtomicU64);
+    impl Clock for TickClock {
+        fn now(&self) -> Duration {
+            Duration::from_millis(self.0.fetch_add(1, atomic::Ordering::Relaxed))
+        }
+    }
+
+    let egraph = gen_random_egraph(30, 4, 3, 7);
+    let config = MctsConfig {
+        seed: Some(0),
+        ..Default::default()
+    };
+    let mut session = SearchSession::new(&egraph, 0, config.clone());
+    session.run().unwrap();
+    assert_eq!(session.stats().timings, None);
+
+    let mut session = SearchSession::new(&egraph, 0, config);
+    session.record_timings(TickClock(AtomicU64::new(0)));
+    session.run().unwrap();
+    let stats = session.stats();
+    let timings = stats.timings.unwrap();
+    assert_eq!(timings.playouts, stats.playouts);
+    assert!(timings.estimates > 0);
+    // Each playout and estimate reads the clock once on either side, and the
+    // estimates happen inside the playouts.
+    let ms = |n| Duration::from_millis(n as u64);
+    assert_eq!(timings.estimate_time, ms(timings.estimates));
+    assert_eq!(
+        timings.playout_time,
+        ms(timings.playouts + 2 * timings.estimates)
+    );
+    // Estimates of partial assignments score several terms each.
+    assert!(timings.utility_calls > timings.estimates);
+    assert_eq!(
+        timings.mean_utility_call_time(),
+        Some(timings.estimate_time / timings.utility_calls as u32)
+    );
+    assert!(timings.tree_nodes_created >= stats.tree_nodes - 1);
+    let rate = timings.tree_nodes_per_sec().unwrap();
+    assert!(
+        (rate - timings.tree_nodes_created as f64 / timings.playout_time.as_secs_f64()).abs()
+            < 1e-6
+    );
+    assert!(stats.to_string().ends_with(" playouts/s"));
+    assert_eq!(SearchTimings::default().playouts_per_sec(), None);
+    assert_eq!(SearchTimings::default().mean_utility_call_time(), None);
+}
+
+#[test]
+fn new_best_callbacks_see_strict_improvements() {
+    let egraph = gen_random_egraph(200, 4, 3, 7);
+    let config = MctsConfig {
+        seed: Some(0),
+        ..Default::default()
+    };
+    let improvements = Arc::new(Mutex::new(Vec::new()));
+    let mut session = SearchSession::new(&egraph, 0, config);
+    let log = improvements.clone();
+    session.on_new_best(move |assign, util| log.lock().unwrap().push((assign.clone(), util)));
+    session.run().unwrap();
+
+    let improvements = improvements.lock().unwrap();
+    assert!(improvements.len() > 1);
+    for pair in improvements.windows(2) {
+        assert!(pair[0].1 < pair[1].1);
+    }
+    let (last, util) = improvements.last().unwrap();
+    assert_eq!(Some(last), session.best_assignment());
+    assert_eq!(session.stats().best_utility, Some(*util));
+}
+
+#[test]
+fn node_weights_steer_rollouts_and_costs() {
+    // The root is either node 0, which is cheap but unreliable, or node 1.
+    let egraph = TestEgraph {
+        weights: Some(vec![0.1, 1.0]),
+        ..TestEgraph::new(NodeCostEgraph {
+            nodes: vec![vec![], vec![]],
+            classes: vec![vec![0, 1]],
+            costs: vec![1.0, 2.0],
+        })
+    };
+    let config = MctsConfig {
+        seed: Some(0),
+        ..Default::default()
+    };
+    assert_eq!(
+        mcts_extract(&egraph.inner, 0, config.clone()).unwrap()[&0],
+        0
+    );
+    assert_eq!(mcts_extract(&egraph, 0, config.clone()).unwrap()[&0], 1);
+
+    // Rollouts pick node 0 an eleventh of the time, for an expected cost of 10.
+    let samples = SearchSession::new(&egraph, 0, config).sample_utilities(1000);
+    let unreliable = samples
+        .iter()
+        .filter(|util| **util == Some(Utility::new(-10.0).unwrap()))
+        .count();
+    assert!((50..150).contains(&unreliable));
+}
+
+#[test]
+fn extractions_lower_into_user_terms() {
+    #[derive(Clone, Debug, PartialEq)]
+    enum Expr {
+        Add(Box<Expr>, Box<Expr>),
+        Neg(Box<Expr>),
+        Num(i32),
+        Var,
+    }
+    // Node 0 adds class 1 to itself; class 1 is cheapest as the negation of
+    // a variable.
+    let egraph = NodeCostEgraph {
+        nodes: vec![vec![1, 1], vec![], vec![2], vec![]],
+        classes: vec![vec![0], vec![1, 2], vec![3]],
+        costs: vec![1.0, 5.0, 1.0, 1.0],
+    };
+    let build_calls = Cell::new(0);
+    let build = |node: &usize, children: &[Expr]| {
+        build_calls.set(build_calls.get() + 1);
+        match (node, children) {
+            (0, [a, b]) => Expr::Add(Box::new(a.clone()), Box::new(b.clone())),
+            (1, []) => Expr::Num(7),
+            (2, [a]) => Expr::Neg(Box::new(a.clone())),
+            (3, []) => Expr::Var,
+            _ => unreachable!(),
+        }
+    };
+    let config = MctsConfig {
+        seed: Some(0),
+        ..Default::default()
+    };
+    let neg_var = Expr::Neg(Box::new(Expr::Var));
+    assert_eq!(
+        extract_into(&egraph, 0, config, build),
+        Some(Expr::Add(Box::new(neg_var.clone()), Box::new(neg_var)))
+    );
+    // The shared class is only built once.
+    assert_eq!(build_calls.get(), 3);
+
+    let partial = Assignment::<NodeCostEgraph>::from_iter([(0, 0), (1, 2)]);
+    assert_eq!(fold_assignment(&egraph, &0, &partial, build), None);
+    assert_eq!(fold_assignment(&egraph, &1, &partial, build), None);
+    let cyclic = NodeCostEgraph {
+        nodes: vec![vec![0]],
+        classes: vec![vec![0]],
+        costs: vec![1.0],
+    };
+    let assign = Assignment::<NodeCostEgraph>::from_iter([(0, 0)]);
+    assert_eq!(fold_assignment(&cyclic, &0, &assign, build), None);
+}
+
+#[test]
+fn topo_orders_put_children_first() {
+    // Classes 1 and 2 both use class 3.
+    let egraph = NodeCostEgraph {
+        nodes: vec![vec![1, 2], vec![3], vec![3], vec![]],
+        classes: vec![vec![0], vec![1], vec![2], vec![3]],
+        costs: vec![1.0; 4],
+    };
+    let assign = Assignment::<NodeCostEgraph>::from_iter([(0, 0), (1, 1), (2, 2), (3, 3)]);
+    assert_eq!(
+        assignment_topo_order(&egraph, &0, &assign),
+        Some(vec![(3, 3), (2, 2), (1, 1), (0, 0)])
+    );
+    // Only the classes below the root are included.
+    assert_eq!(
+        assignment_topo_order(&egraph, &1, &assign),
+        Some(vec![(3, 3), (1, 1)])
+    );
+
+    let egraph = gen_random_egraph(200, 4, 3, 7);
+    let config = MctsConfig {
+        seed: Some(0),
+        ..Default::default()
+    };
+    let assign = mcts_extract(&egraph, 0, config).unwrap();
+    let order = assignment_topo_order(&egraph, &0, &assign).unwrap();
+    assert_eq!(order.len(), assign.len());
+    assert_eq!(order.last(), Some(&(0, assign[&0])));
+    for (i, (class, node)) in order.iter().enumerate() {
+        assert_eq!(assign[class], *node);
+        for child in egraph.children(node) {
+            assert!(order[..i].iter().any(|(done, _)| done == child));
+        }
+    }
+
+    // Classes that are left out or that depend on themselves have no order.
+    let cyclic = NodeCostEgraph {
+        nodes: vec![vec![1], vec![0]],
+        classes: vec![vec![0], vec![1]],
+        costs: vec![1.0; 2],
+    };
+    let assign = Assignment::<NodeCostEgraph>::from_iter([(0, 0), (1, 1)]);
+    assert_eq!(assignment_topo_order(&cyclic, &0, &assign), None);
+    let partial = Assignment::<NodeCostEgraph>::from_iter([(0, 0)]);
+    assert_eq!(assignment_topo_order(&cyclic, &0, &partial), None);
+}
+
+#[test]
+fn finished_assignments_can_be_borrowed() {
+    let egraph = gen_random_egraph(200, 4, 3, 7);
+    let config = MctsConfig {
+        seed: Some(0),
+        ..Default::default()
+    };
+    let owned = mcts_extract(&egraph, 0, config.clone()).unwrap();
+    let mut session = SearchSession::new(&egraph, 0, config.clone());
+    let borrowed = session.run_borrowed().unwrap();
+    assert_eq!(*borrowed, owned);
+    let borrowed = borrowed as *const _;
+    assert!(std::ptr::eq(
+        borrowed,
+        session.complete_assignment().unwrap()
+    ));
+
+    // With a cost ceiling, the result is the best assignment scored.
+    let config = MctsConfig {
+        cost_ceiling: Some(Utility::new(1e6).unwrap()),
+        ..config
+    };
+    let mut session = SearchSession::new(&egraph, 0, config);
+    let borrowed = session.run_borrowed().unwrap() as *const _;
+    assert!(std::ptr::eq(borrowed, session.best_assignment().unwrap()));
+}
+
+#[test]
+fn results_have_readable_summaries() {
+    assert_eq!(
+        ExtractError::BudgetExhausted { decisions: 3 }.to_string(),
+        "the search ran out of rounds after 3 decisions"
+    );
+    let stats = SearchStats {
+        playouts: 12,
+        tree_nodes: 5,
+        tree_memory_bytes: 640,
+        best_utility: Some(Utility::new(-4.5).unwrap()),
+        optimality_ratio: Some(1.25),
+        ..Default::default()
+    };
+    assert_eq!(
+        stats.to_string(),
+        "12 playouts, 5 tree nodes (640 bytes), 0 dead ends at empty classes, 0 at cycles, \
+         best utility -4.5, optimality ratio 1.250"
+    );
+
+    let egraph = high_util_egraph();
+    let mut session = SearchSession::new(&egraph, 0, MctsConfig::default());
+    let outcome = session.step();
+    assert!(format!("{outcome:?}").starts_with("Committed"));
+    assert!(format!("{session:?}").starts_with("SearchSession { root: 0"));
+}
+
+#[test]
+fn dead_ends_report_empty_classes() {
+    let egraph = SimpleEgraph {
+        nodes: vec![vec![1]],
+        classes: vec![vec![0], vec![]],
+        score_fn: Box::new(|_, _| Utility::default()),
+    };
+    let (assign, stats) = mcts_extract_with_stats(&egraph, 0, MctsConfig::default());
+    assert_eq!(assign, None);
+    assert!(stats.dead_ends.empty_class > 0);
+    assert_eq!(stats.dead_ends.cycle, 0);
+    assert_eq!(stats.dead_ends.most_common(), Some(DeadEnd::EmptyClass));
+
+    let (_, stats) = mcts_extract_with_stats(&high_util_egraph(), 0, MctsConfig::default());
+    assert_eq!(stats.dead_ends.most_common(), None);
+}
+
+#[test]
+fn pending_nodes_show_why_extraction_is_stuck() {
+    let egraph = unextractable_egraph();
+    let mut session = SearchSession::new(&egraph, 0, MctsConfig::default());
+    for node in [0, 3, 5, 4] {
+        session.choose(node);
+    }
+    assert_eq!(session.next_class(), None);
+    assert!(session.complete_assignment().is_none());
+
+    let mut pending = session.pending_nodes();
+    pending.sort_by_key(|pending| pending.class);
+    let watched = |class, node, watching, unresolved| WatchedNode {
+        class,
+        node,
+        watching,
+        unresolved,
+    };
+    // Every committed node waits on another of them, so none can be final.
+    assert_eq!(
+        pending,
+        vec![
+            watched(0, 0, 0, 2),
+            watched(1, 3, 0, 2),
+            watched(2, 4, 1, 2),
+            watched(3, 5, 2, 3),
+        ]
+    );
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_searches_yield_and_can_be_cancelled() {
+    use std::{
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+
+    use crate::{mcts_extract_async, CancelToken};
+
+    let egraph = gen_random_egraph(100, 3, 2, 1);
+    let config = MctsConfig {
+        seed: Some(0),
+        ..Default::default()
+    };
+    let mut cx = Context::from_waker(Waker::noop());
+
+    // Left alone, the search finds the same term as the synchronous one.
+    let cancel = CancelToken::new();
+    let mut search = pin!(mcts_extract_async(&egraph, 0, config.clone(), &cancel));
+    let mut yields = 0;
+    let assign = loop {
+        match search.as_mut().poll(&mut cx) {
+            Poll::Ready(assign) => break assign,
+            Poll::Pending => yields += 1,
+        }
+    };
+    assert!(yields > 0);
+    assert_eq!(assign, mcts_extract(&egraph, 0, config.clone()));
+
+    // Cancelled after its first round, it returns the best term so far.
+    let cancel = CancelToken::new();
+    let mut search = pin!(mcts_extract_async(&egraph, 0, config, &cancel));
+    assert!(search.as_mut().poll(&mut cx).is_pending());
+    cancel.clone().cancel();
+    let Poll::Ready(Some(assign)) = search.as_mut().poll(&mut cx) else {
+        panic!("a cancelled search should return the best term so far");
+    };
+    assert!(assign.contains_key(&0));
+
+    // Searches that never finish are stopped, as in the synchronous version.
+    let egraph = GrowingChain {
+        ids: (0..1000).collect(),
+        grown: Cell::new(false),
+    };
+    let cancel = CancelToken::new();
+    let mut search = pin!(mcts_extract_async(
+        &egraph,
+        0,
+        MctsConfig::default(),
+        &cancel
+    ));
+    let assign = (0..100)
+        .find_map(|_| match search.as_mut().poll(&mut cx) {
+            Poll::Ready(assign) => Some(assign),
+            Poll::Pending => None,
+        })
+        .expect("the watchdog should stop the search");
+    assert!(assign.is_none());
+}
+
+#[cfg(feature = "trace")]
+#[test]
+fn traces_record_every_decision() {
+    let egraph = high_util_egraph();
+    let config = MctsConfig {
+        playouts_per_round: 8,
+        seed: Some(0),
+        ..Default::default()
+    };
+    let mut session = SearchSession::new(&egraph, 0, config);
+    session.record_trace();
+    let assign = session.run().unwrap();
+    let trace = session.take_trace();
+
+    assert_eq!(trace.len(), session.decisions().len());
+    for (i, record) in trace.iter().enumerate() {
+        assert_eq!(record.prefix, session.decisions()[..i]);
+        assert_eq!(assign[&record.class], record.chosen);
+        assert_eq!(record.playouts, 8);
+        // The very first playout only estimates the root; every other one
+        // visits one of the candidates.
+        let visits: u32 = record.candidates.iter().map(|c| c.visits).sum();
+        assert_eq!(visits as usize, record.playouts - usize::from(i == 0));
+        assert_eq!(
+            record.outcome,
+            Some(egraph.assignment_utility(&(), &assign))
+        );
+    }
+}
+
+#[cfg(feature = "trace")]
+#[test]
+fn playout_traces_replay_the_search() {
+    let egraph = gen_random_egraph(30, 4, 3, 7);
+    let record = || {
+        let config = MctsConfig {
+            seed: Some(0),
+            ..Default::default()
+        };
+        let mut session = SearchSession::new(&egraph, 0, config);
+        session.record_playouts();
+        session.run().unwrap();
+        let trace = session.take_playout_trace();
+        assert_eq!(trace.playouts().len(), session.stats().playouts);
+        let mut out = Vec::new();
+        trace.write_json_lines(&mut out).unwrap();
+        (trace, String::from_utf8(out).unwrap())
+    };
+    let (trace, json) = record();
+    for (i, playout) in trace.playouts().iter().enumerate() {
+        assert_eq!(playout.playout, i);
+        assert!(!playout.path.is_empty());
+    }
+    // The first playout only scores the root.
+    let first = &trace.playouts()[0];
+    assert_eq!(
+        json.lines().next().unwrap(),
+        format!(
+            "{{\"playout\":0,\"path\":[{}],\"leaf_utility\":{}}}",
+            first.path[0],
+            first.leaf_utility.unwrap()
+        )
+    );
+    assert_eq!(json.lines().count(), trace.playouts().len());
+    // A seeded search records the same trace every time.
+    assert_eq!(record().1, json);
+}
+
+#[test]
+fn top_k_terms_for_an_inner_class() {
+    // The terms rooted at class 1 pick one of nodes 1-3 and one of nodes 4-5.
+    let egraph = NodeCostEgraph {
+        nodes: vec![vec![1], vec![2], vec![2], vec![2], vec![], vec![]],
+        classes: vec![vec![0], vec![1, 2, 3], vec![4, 5]],
+        costs: vec![0.0, 1.0, 2.0, 3.0, 10.0, 20.0],
+    };
+    let config = MctsConfig {
+        seed: Some(0),
+        ..Default::default()
+    };
+    let top = mcts_extract_top_k(&egraph, 1, 4, config);
+
+    let utils = top
+        .iter()
+        .map(|(_, util)| util.into_inner())
+        .collect::<Vec<_>>();
+    assert_eq!(utils, vec![-11.0, -12.0, -13.0, -21.0]);
+    for (i, (assign, util)) in top.iter().enumerate() {
+        assert!(!assign.contains_key(&0));
+        assert_eq!(egraph.assignment_utility(&(), assign), *util);
+        assert!(top[..i].iter().all(|(other, _)| other != assign));
+    }
+}
+
+#[test]
+fn multistart_extractions_beat_single_runs_on_average() {
+    // With so few playouts, single runs vary a lot with the seed. The four
+    // restarts split four times the budget, so each is as cheap as a single
+    // run.
+    let config = MctsConfig {
+        playouts_per_round: 16,
+        ..Default::default()
+    };
+    let mean = |utils: &[f32]| utils.iter().sum::<f32>() / utils.len() as f32;
+    for egraph_seed in [4, 7, 10] {
+        let egraph = gen_random_egraph(30, 4, 3, egraph_seed);
+        let (mut single, mut multi) = (vec![], vec![]);
+        for seed in 0..6 {
+            let single_config = MctsConfig {
+                playouts_per_round: 4,
+                seed: Some(seed),
+                ..config.clone()
+            };
+            let assign =
+                mcts_extract(&egraph, 0, single_config).expect("single runs should succeed");
+            single.push(egraph.assignment_utility(&(), &assign).into_inner());
+            let (assign, util) =
+                mcts_extract_multistart(&egraph, 0, config.clone(), 4, seed).unwrap();
+            assert_eq!(egraph.assignment_utility(&(), &assign), util);
+            multi.push(util.into_inner());
+        }
+        assert!(mean(&multi) >= mean(&single));
+    }
+
+    // The restarts are seeded deterministically.
+    let egraph = gen_random_egraph(30, 4, 3, 11);
+    assert_eq!(
+        mcts_extract_multistart(&egraph, 0, config.clone(), 4, 1),
+        mcts_extract_multistart(&egraph, 0, config.clone(), 4, 1),
+    );
+    assert!(mcts_extract_multistart(&egraph, 0, config, 0, 1).is_none());
+}
+
+#[test]
+fn optimal_estimates_summarize_independent_runs() {
+    let egraph = gen_random_egraph(30, 4, 3, 5);
+    let config = MctsConfig {
+        playouts_per_round: 2,
+        seed: Some(3),
+        ..Default::default()
+    };
+    let estimate = estimate_optimal(&egraph, 0, config.clone(), 8);
+    assert_eq!(estimate.utilities.len() + estimate.failed_runs, 8);
+    assert!(estimate.utilities.len() > 1);
+    let best = estimate.best().unwrap();
+    assert!(estimate.utilities.iter().all(|util| *util <= best));
+    let mean = estimate.mean().unwrap();
+    assert!(mean <= f64::from(best.into_inner()));
+    assert!(estimate.variance().unwrap() >= 0.0);
+    // The runs are seeded the same way as restarts, which split their budget.
+    assert_eq!(estimate, estimate_optimal(&egraph, 0, config.clone(), 8));
+    let multistart_config = MctsConfig {
+        playouts_per_round: 8 * config.playouts_per_round,
+        ..config.clone()
+    };
+    assert_eq!(
+        mcts_extract_multistart(&egraph, 0, multistart_config, 8, 3).map(|(_, util)| util),
+        Some(best)
+    );
+
+    let single = estimate_optimal(&egraph, 0, config, 1);
+    assert_eq!(single.variance(), None);
+    assert_eq!(OptimalEstimate::default().best(), None);
+}
+
+#[test]
+fn single_member_classes_skip_scoring() {
+    // The root class has two members, both of which point at a long chain of
+    // single-member classes.
+    const CHAIN_LEN: usize = 10;
+    let mut nodes = vec![vec![1], vec![1]];
+    let mut classes = vec![vec![0, 1]];
+    for class in 1..=CHAIN_LEN {
+        let node = nodes.len();
+        nodes.push(if class == CHAIN_LEN {
+            vec![]
+        } else {
+            vec![class + 1]
+        });
+        classes.push(vec![node]);
+    }
+
+    let egraph = SimpleEgraph {
+        nodes,
+        classes,
+        score_fn: Box::new(|assign, _| {
+            if assign.get(&0) == Some(&1) {
+                Utility::new(1.0).unwrap()
+            } else {
+                Utility::new(0.0).unwrap()
+            }
+        }),
+    };
+
+    let playouts_per_round = 4;
+    let (assign, stats) = mcts_extract_with_stats(
+        &egraph,
+        0,
+        MctsConfig {
+            playouts_per_round,
+            terms_to_sample: 4,
+            ..Default::default()
+        },
+    );
+    let assign = assign.expect("extraction should succeed");
+    assert_eq!(assign.len(), CHAIN_LEN + 1);
+    assert_eq!(assign[&0], 1);
+    for class in 1..=CHAIN_LEN {
+        assert_eq!(assign[&class], class + 1);
+    }
+    // Only the root class has a choice to make, and it is committed after the
+    // first round.
+    assert!(stats.uct_evaluations <= 2 * playouts_per_round);
+}
+
+#[test]
+fn memory_limit_returns_best_so_far() {
+    let egraph = high_util_egraph();
+    let config = MctsConfig {
+        playouts_per_round: 4,
+        terms_to_sample: 4,
+        ..Default::default()
+    };
+    let (_, unlimited) = mcts_extract_with_stats(&egraph, 0, config.clone());
+    assert!(unlimited.tree_memory_bytes > 0);
+
+    let (assign, limited) = mcts_extract_with_stats(
+        &egraph,
+        0,
+        MctsConfig {
+            max_memory_bytes: Some(unlimited.tree_memory_bytes / 2),
+            ..config
+        },
+    );
+    assert!(limited.playouts < unlimited.playouts);
+    // Every random term in this egraph is extractable, so the search will have
+    // seen at least one complete assignment before stopping.
+    let assign = assign.expect("should return the best assignment so far");
+    assert_valid_assignment(&egraph, 0, &assign);
+}
+
+#[test]
+fn complete_extraction_objective_avoids_dead_ends() {
+    let egraph = dead_end_egraph();
+    let config = MctsConfig {
+        playouts_per_round: 16,
+        terms_to_sample: 8,
+        ..Default::default()
+    };
+
+    let assign = mcts_extract(&egraph, 0, config.clone()).expect("extraction should succeed");
+    assert_eq!(assign[&0], 1);
+
+    let assign = mcts_extract(
+        &egraph,
+        0,
+        MctsConfig {
+            objective: SearchObjective::CompleteExtraction,
+            ..config
+        },
+    )
+    .expect("extraction should succeed");
+    assert_eq!(assign[&0], 0);
+}
+
+#[test]
+fn analyze_counts_reachable_classes() {
+    let stats = analyze(&high_util_egraph(), 0);
+    assert_eq!(stats.reachable_classes, 4);
+    assert_eq!(stats.reachable_nodes, 6);
+    assert_eq!(stats.class_sizes.get(&1), Some(&2));
+    assert_eq!(stats.class_sizes.get(&2), Some(&2));
+    assert!((stats.log10_search_space - 4f64.log10()).abs() < 1e-9);
+
+    // Classes that are unreachable from the root are not counted.
+    let stats = analyze(&high_util_egraph(), 2);
+    assert_eq!(stats.reachable_classes, 2);
+}
+
+#[test]
+fn finds_extractable_classes() {
+    let egraph = SimpleEgraph {
+        nodes: vec![vec![1], vec![2], vec![1], vec![]],
+        classes: vec![vec![0, 1], vec![2], vec![3]],
+        score_fn: Box::new(score_fn),
+    };
+    let extractable = extractable_classes(&egraph, 0);
+    assert_eq!(extractable, [0, 2].into_iter().collect());
+    assert!(extractable_classes(&unextractable_egraph(), 0).is_empty());
+}
+
+#[test]
+fn finds_reachable_classes_through_cycles() {
+    // Classes 0 and 1 reach each other, and class 3 is unreachable from
+    // either.
+    let egraph = SimpleEgraph {
+        nodes: vec![vec![1], vec![0], vec![2], vec![], vec![2]],
+        classes: vec![vec![0], vec![1, 2], vec![3], vec![4]],
+        score_fn: Box::new(score_fn),
+    };
+    assert_eq!(
+        reachable_classes(&egraph, 0),
+        [0, 1, 2].into_iter().collect()
+    );
+    assert_eq!(reachable_classes(&egraph, 2), [2].into_iter().collect());
+    assert_eq!(reachable_classes(&egraph, 3), [3, 2].into_iter().collect());
+}
+
+#[test]
+fn near_equal_utilities_are_tied() {
+    let mut first = Assignment::<SimpleEgraph>::default();
+    first.insert(0, 0);
+    let mut second = Assignment::<SimpleEgraph>::default();
+    second.insert(0, 1);
+    let util = Utility::new(1.0).unwrap();
+    let nudged = Utility::new(1.0 + 1e-7).unwrap();
+    assert!(nudged > util);
+
+    let egraph = SimpleEgraph {
+        nodes: vec![vec![], vec![]],
+        classes: vec![vec![0, 1]],
+        score_fn: Box::new(|_, _| Utility::default()),
+    };
+
+    let mut exact = BestAssignment::new(0.0, None);
+    exact.offer(&egraph, &first, util);
+    exact.offer(&egraph, &second, nudged);
+    assert_eq!(exact.assignment(), Some(&second));
+
+    let mut tolerant = BestAssignment::new(1e-6, None);
+    tolerant.offer(&egraph, &first, util);
+    tolerant.offer(&egraph, &second, nudged);
+    assert_eq!(tolerant.assignment(), Some(&first));
+}
+
+#[test]
+fn shallow_dags_win_ties() {
+    // Node 0 and the chain through nodes 1 and 2 cost the same.
+    let egraph = NodeCostEgraph {
+        nodes: vec![vec![], vec![1], vec![]],
+        classes: vec![vec![0, 1], vec![2]],
+        costs: vec![2.0, 1.0, 1.0],
+    };
+    let extract = |tiebreak| {
+        let config = MctsConfig {
+            tiebreak,
+            seed: Some(0),
+            ..Default::default()
+        };
+        let mut session = SearchSession::new(&egraph, 0, config);
+        let assign = session.run().unwrap();
+        assert_eq!(
+            egraph.assignment_utility(&(), &assign),
+            Utility::new(-2.0).unwrap()
+        );
+        (assign[&0], session.best_assignment().unwrap()[&0])
+    };
+    // By default, the search happens to settle on the deeper term.
+    assert_eq!(extract(Tiebreak::FirstSeen), (1, 1));
+    assert_eq!(extract(Tiebreak::ShallowestDag), (0, 0));
+}
+
+#[test]
+fn cloned_search_state_is_independent() {
+    let egraph = high_util_egraph();
+
+    let mut state = ExtractionState::<SimpleEgraph>::new(0);
+    let mut fork = state.clone();
+    fork.start_next_assign().unwrap().assign(1, &egraph);
+    assert_eq!(fork.start_next_assign().unwrap().class(), &2);
+    assert_eq!(state.start_next_assign().unwrap().class(), &0);
+    state.start_next_assign().unwrap().assign(0, &egraph);
+    assert_eq!(fork.start_next_assign().unwrap().class(), &2);
+
+    let tree = SearchTree::<SimpleEgraph>::new(0);
+    let mut search = tree.clone().start_round(
+        MctsConfig {
+            playouts_per_round: 4,
+            ..Default::default()
+        },
+        |_: &mut ExtractionState<SimpleEgraph>,
+         _: &SimpleEgraph,
+         _: &mut BestAssignment<SimpleEgraph>| LeafEstimate::new(Utility::default()),
+        Utility::new(2.0f32.sqrt()).unwrap(),
+    );
+    search.assign(&egraph).unwrap();
+    assert!(search.stats().tree_nodes > 1);
+    assert_eq!(tree.n_nodes(), 1);
+}
+
+#[test]
+fn long_dependency_chains_resolve_without_recursion() {
+    // Class i holds a single node pointing at class i + 1. Every node waits on
+    // its child, so assigning the last class resolves the whole chain at once.
+    const DEPTH: usize = 50_000;
+    let mut nodes = (1..DEPTH).map(|child| vec![child]).collect::<Vec<_>>();
+    nodes.push(vec![]);
+    let egraph = SimpleEgraph {
+        nodes,
+        classes: (0..DEPTH).map(|class| vec![class]).collect(),
+        score_fn: Box::new(score_fn),
+    };
+    let mut state = ExtractionState::<SimpleEgraph>::new(0);
+    for class in 0..DEPTH {
+        assert!(state.complete_assignment().is_none());
+        let handle = state.start_next_assign().unwrap();
+        assert_eq!(handle.class(), &class);
+        handle.assign(class, &egraph);
+    }
+    let assign = state.complete_assignment().unwrap();
+    assert_eq!(assign.len(), DEPTH);
+    // Children are resolved before their parents.
+    assert!(assign.keys().copied().eq((0..DEPTH).rev()));
+}
+
+#[test]
+fn greedy_rollouts_pick_the_cheapest_members() {
+    // A random number generator for code that must not make random choices.
+    struct NoRng;
+    impl RngCore for NoRng {
+        fn next_u32(&mut self) -> u32 {
+            panic!("greedy rollouts made a random choice")
+        }
+        fn next_u64(&mut self) -> u64 {
+            panic!("greedy rollouts made a random choice")
+        }
+        fn fill_bytes(&mut self, _: &mut [u8]) {
+            panic!("greedy rollouts made a random choice")
+        }
+        fn try_fill_bytes(&mut self, _: &mut [u8]) -> Result<(), rand::Error> {
+            panic!("greedy rollouts made a random choice")
+        }
+    }
+
+    // Node 1 is cheaper than node 0, and nodes 4 and 5 tie.
+    let egraph = NodeCostEgraph {
+        nodes: vec![vec![1], vec![2], vec![], vec![], vec![], vec![]],
+        classes: vec![vec![0, 1], vec![2, 3], vec![4, 5]],
+        costs: vec![5.0, 2.0, 3.0, 1.0, 1.0, 1.0],
+    };
+    let mut state = ExtractionState::new(0);
+    state.set_greedy_rollouts(Arc::new(CostBounds::compute(&egraph, 0)));
+    let mut sampled = None;
+    let util = random_cost_estimate(
+        &egraph,
+        &(),
+        &mut state,
+        &mut NoRng,
+        None,
+        None,
+        |assign, _| sampled = Some(assign.clone()),
+    );
+    assert_eq!(util, Ok(Utility::new(-3.0).unwrap()));
+    assert_eq!(
+        sampled,
+        Some(Assignment::<NodeCostEgraph>::from_iter([(0, 1), (2, 4)]))
+    );
+
+    // Every sample comes out the same, whatever the seed.
+    for seed in 0..4 {
+        let config = MctsConfig {
+            seed: Some(seed),
+            ..Default::default()
+        };
+        let mut session = SearchSession::new(&egraph, 0, config);
+        session.use_greedy_rollouts();
+        assert_eq!(
+            session.sample_utilities(3),
+            [Some(Utility::new(-3.0).unwrap()); 3]
+        );
+        let assign = session.run().unwrap();
+        assert_eq!(egraph.assignment_utility(&(), &assign), -3.0);
+    }
+}
+
+#[test]
+fn rollouts_use_member_slices() {
+    // The egraph of `dead_end_egraph`, where node 1 is the better root.
+    let egraph = || NodeCostEgraph {
+        nodes: vec![vec![1], vec![2], vec![], vec![0], vec![2], vec![]],
+        classes: vec![vec![0, 1], vec![2], vec![3, 4, 5]],
+        costs: vec![1.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+    };
+    let sliced = TestEgraph {
+        slices: true,
+        ..TestEgraph::new(egraph())
+    };
+    let plain = TestEgraph::new(egraph());
+    let costs = plain.precompute(&[0]);
+    for seed in 0..32 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut from_slices = None;
+        let util = random_cost_estimate(
+            &sliced,
+            &costs,
+            &mut ExtractionState::new(0),
+            &mut rng,
+            None,
+            None,
+            |assign, _| from_slices = Some(assign.clone()),
+        );
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut from_iters = None;
+        let expected = random_cost_estimate(
+            &plain,
+            &costs,
+            &mut ExtractionState::new(0),
+            &mut rng,
+            None,
+            None,
+            |assign, _| from_iters = Some(assign.clone()),
+        );
+        assert_eq!(util, expected);
+        assert_eq!(from_slices, from_iters);
+    }
+    assert!(sliced.slice_lookups.get() > 0);
+}
+
+#[test]
+fn extract_any_finds_valid_term() {
+    let egraph = high_util_egraph();
+    for _ in 0..16 {
+        let assign = extract_any(&egraph, 0).expect("extraction should succeed");
+        assert_valid_assignment(&egraph, 0, &assign);
+    }
+}
+
+#[test]
+fn extract_any_fails_unextractable() {
+    assert!(extract_any(&unextractable_egraph(), 0).is_none());
+}
+
+#[test]
+fn weighted_backprop_ignores_failed_samples() {
+    // Node 1 in the root class has a higher utility than node 0, but most
+    // rollouts through it fail.
+    let egraph = dead_end_egraph();
+    let assign = mcts_extract(
+        &egraph,
+        0,
+        MctsConfig {
+            playouts_per_round: 16,
+            terms_to_sample: 8,
+            weighted_backprop: true,
+            ..Default::default()
+        },
+    )
+    .expect("extraction should succeed");
+    assert_valid_assignment(&egraph, 0, &assign);
+    assert_eq!(assign[&0], 1);
+}
+
+#[test]
+fn transpositions_share_decisions() {
+    let egraph = high_util_egraph();
+    let config = MctsConfig {
+        playouts_per_round: 16,
+        terms_to_sample: 4,
+        transposition_table: true,
+        ..Default::default()
+    };
+    let (assign, stats) = mcts_extract_with_stats(&egraph, 0, config);
+    let assign = assign.expect("extraction should succeed");
+    assert_eq!(assign[&0], 1);
+    assert_eq!(assign[&2], 4);
+    assert_eq!(assign[&3], 5);
+    // There is at most one tree node per (class, node) pair, plus the root.
+    let n_decisions: usize = egraph.classes.iter().map(Vec::len).sum();
+    assert!(stats.tree_nodes <= n_decisions + 1);
+}
+
+#[test]
+fn lexicographic_costs_prefer_the_primary_key() {
+    // Costs are (errors, size) pairs. Only node 1 and node 2 below it make a
+    // term without errors, and it is by far the largest, so it only wins if
+    // errors come first.
+    let egraph = LexCostEgraph {
+        nodes: vec![vec![], vec![1], vec![], vec![]],
+        classes: vec![vec![0, 1], vec![2, 3]],
+        costs: vec![(1.0, 1.0), (0.0, 10.0), (0.0, 40.0), (1.0, 0.0)],
+    };
+    for seed in 0..4 {
+        let config = MctsConfig {
+            playouts_per_round: 16,
+            seed: Some(seed),
+            ..Default::default()
+        };
+        let (assign, value) = mcts_extract_in_algebra(&egraph, 0, config).unwrap();
+        assert_eq!(
+            assign,
+            Assignment::<LexCostEgraph>::from_iter([(0, 1), (1, 2)])
+        );
+        assert_eq!(value, (Utility::default(), Utility::new(-50.0).unwrap()));
+    }
+}
+
+#[test]
+fn value_floors_skip_heavily_visited_mediocre_choices() {
+    // Node 0 looks great for its first 24 playouts, which wins it most of the
+    // visits, and terrible after that. Node 1 is consistently good.
+    let egraph = NodeCostEgraph {
+        nodes: vec![vec![], vec![]],
+        classes: vec![vec![0, 1]],
+        costs: vec![1.0; 2],
+    };
+    let search = |final_selection| {
+        let config = MctsConfig {
+            playouts_per_round: 48,
+            final_selection,
+            seed: Some(0),
+            ..Default::default()
+        };
+        // The number of playouts through each node, and their total utility.
+        let playouts = Cell::new([0; 2]);
+        let totals = Cell::new([0.0; 2]);
+        let mut search = SearchTree::new(0).start_round(
+            config,
+            |state: &mut ExtractionState<NodeCostEgraph>,
+             _: &NodeCostEgraph,
+             _: &mut BestAssignment<NodeCostEgraph>| {
+                let Some(&node) = state.complete_assignment().map(|assign| &assign[&0]) else {
+                    return LeafEstimate::new(Utility::default());
+                };
+                let mut n = playouts.get();
+                n[node] += 1;
+                playouts.set(n);
+                let util = match node {
+                    0 if n[0] <= 24 => 1.0,
+                    0 => -10.0,
+                    _ => 0.5,
+                };
+                let mut total = totals.get();
+                total[node] += util;
+                totals.set(total);
+                LeafEstimate::new(Utility::new(util).unwrap())
+            },
+            Utility::new(2.0f32.sqrt()).unwrap(),
+        );
+        let StepOutcome::Committed { node, .. } = search.step(&egraph) else {
+            panic!("search should commit to a node");
+        };
+        drop(search);
+        let (n, total) = (playouts.get(), totals.get());
+        (node, n, [total[0] / n[0] as f32, total[1] / n[1] as f32])
+    };
+
+    let (node, playouts, means) = search(FinalSelection::MostVisits);
+    assert!(playouts[0] > playouts[1]);
+    assert!(means[0] < means[1] - 0.1);
+    assert_eq!(node, 0);
+    let (node, ..) = search(FinalSelection::MostVisitsNearBest { epsilon: 0.1 });
+    assert_eq!(node, 1);
+}
+
+#[test]
+fn class_node_cap_bounds_nodes_per_class() {
+    // Class 3 can be reached along 27 different sequences of decisions.
+    let egraph = NodeCostEgraph {
+        nodes: (0..11)
+            .map(|node| if node < 9 { vec![node / 3 + 1] } else { vec![] })
+            .collect(),
+        classes: vec![vec![0, 1, 2], vec![3, 4, 5], vec![6, 7, 8], vec![9, 10]],
+        costs: vec![1.0; 11],
+    };
+    let class_nodes = |max_tree_nodes_per_class| {
+        let config = MctsConfig {
+            playouts_per_round: 256,
+            max_tree_nodes_per_class,
+            seed: Some(0),
+            ..Default::default()
+        };
+        let mut search = SearchTree::new(0).start_round(
+            config,
+            |_: &mut ExtractionState<NodeCostEgraph>,
+             _: &NodeCostEgraph,
+             _: &mut BestAssignment<NodeCostEgraph>| {
+                LeafEstimate::new(Utility::default())
+            },
+            Utility::new(2.0f32.sqrt()).unwrap(),
+        );
+        search.step(&egraph);
+        search.tree().n_class_nodes(&3)
+    };
+
+    assert!(class_nodes(None) > 6);
+    // At most the cap plus one node per member.
+    assert!(class_nodes(Some(4)) <= 6);
+}
+
+#[test]
+fn expand_per_visit_limits_new_children_per_playout() {
+    // A single class with 20 leaf members.
+    let egraph = NodeCostEgraph {
+        nodes: vec![vec![]; 20],
+        classes: vec![(0..20).collect()],
+        costs: vec![1.0; 20],
+    };
+    let new_nodes = |expand_per_visit, playouts_per_round| {
+        let config = MctsConfig {
+            expansion_strategy: ExpansionStrategy::Full,
+            expand_per_visit,
+            playouts_per_round,
+            seed: Some(0),
+            ..Default::default()
+        };
+        let mut search = SearchTree::new(0).start_round(
+            config,
+            |_: &mut ExtractionState<NodeCostEgraph>,
+             _: &NodeCostEgraph,
+             _: &mut BestAssignment<NodeCostEgraph>| {
+                LeafEstimate::new(Utility::default())
+            },
+            Utility::new(2.0f32.sqrt()).unwrap(),
+        );
+        let before = search.tree().n_nodes();
+        search.step(&egraph);
+        search.tree().n_nodes() - before
+    };
+
+    assert_eq!(new_nodes(None, 1), 20);
+    for playouts in 1..=8 {
+        assert_eq!(new_nodes(Some(1), playouts), playouts);
+    }
+    assert_eq!(new_nodes(Some(3), 1), 3);
+}
+
+#[test]
+fn full_trees_make_room_instead_of_panicking() {
+    let egraph = high_util_egraph();
+    let search = |node_limit, expansion_strategy| {
+        let mut tree = SearchTree::new(0);
+        tree.set_node_limit(node_limit);
+        let config = MctsConfig {
+            expansion_strategy,
+            seed: Some(0),
+            ..Default::default()
+        };
+        tree.start_round(
+            config,
+            |state: &mut ExtractionState<SimpleEgraph>,
+             egraph: &SimpleEgraph,
+             best: &mut BestAssignment<SimpleEgraph>| {
+                let util = match state.complete_assignment() {
+                    Some(assign) => {
+                        let util = egraph.total_utility(&(), assign);
+                        best.offer(egraph, assign, util);
+                        util
+                    }
+                    None => Utility::default(),
+                };
+                LeafEstimate::new(util)
+            },
+            Utility::new(2.0f32.sqrt()).unwrap(),
+        )
+    };
+    for strategy in [ExpansionStrategy::Lazy, ExpansionStrategy::Full] {
+        // Dropping the nodes for past decisions leaves enough room to finish.
+        let mut roomy = search(4, strategy);
+        let assign = roomy.assign(&egraph).unwrap();
+        assert_valid_assignment(&egraph, 0, &assign);
+        assert!(roomy.stats().tree_nodes <= 4);
+
+        // With room for a single child, each round only explores one choice.
+        let mut cramped = search(2, strategy);
+        let assign = cramped.assign(&egraph).unwrap();
+        assert_valid_assignment(&egraph, 0, &assign);
+        assert!(cramped.stats().tree_nodes <= 2);
+    }
+}
+
+#[test]
+fn restarts_keep_the_best_assignment() {
+    let egraph = high_util_egraph();
+    for restart_keeps_root_stats in [false, true] {
+        let config = MctsConfig {
+            restart_interval: Some(1),
+            restart_keeps_root_stats,
+            seed: Some(0),
+            ..Default::default()
+        };
+        let (assign, stats) = mcts_extract_with_stats(&egraph, 0, config);
+        let assign = assign.expect("extraction should succeed");
+        assert_eq!(assign[&0], 1);
+        assert_eq!(assign[&2], 4);
+        assert_eq!(assign[&3], 5);
+        assert!(stats.restarts > 0);
+    }
+
+    let (_, stats) = mcts_extract_with_stats(&egraph, 0, MctsConfig::default());
+    assert_eq!(stats.restarts, 0);
+}
+
+#[test]
+fn confidence_gate_extends_close_decisions() {
+    // The root class picks between two leaves, which tie unless `tied` is
+    // false, in which case node 0 is clearly better.
+    let run = |tied: bool, confidence_gate| {
+        let egraph = SimpleEgraph {
+            nodes: vec![vec![], vec![]],
+            classes: vec![vec![0, 1]],
+            score_fn: Box::new(move |assign, _| {
+                let util = if tied || assign[&0] == 0 { 1.0 } else { 0.0 };
+                Utility::new(util).unwrap()
+            }),
+        };
+        let config = MctsConfig {
+            playouts_per_round: 8,
+            confidence_gate,
+            seed: Some(0),
+            ..Default::default()
+        };
+        let (assign, stats) = mcts_extract_with_stats(&egraph, 0, config);
+        assert_valid_assignment(&egraph, 0, &assign.unwrap());
+        stats.disambiguating_playouts
+    };
+    let gate = Some(ConfidenceGate {
+        z: 1.96,
+        max_playouts: 16,
+    });
+    assert_eq!(run(true, gate), 16);
+    assert_eq!(run(false, gate), 0);
+    assert_eq!(run(true, None), 0);
+}
+
+#[test]
+fn max_backup_finds_rare_optimum() {
+    // The root class can pick node 0, which leads to a wide class where only
+    // one member has a high utility, or node 1, which has a middling utility.
+    // Utilities are negated costs.
+    const WIDTH: usize = 10;
+    const OPTIMUM: usize = 2 + WIDTH / 2;
+    let mut nodes = vec![vec![1], vec![]];
+    nodes.extend((0..WIDTH).map(|_| vec![]));
+    let classes = vec![vec![0, 1], (2..WIDTH + 2).collect()];
+    let egraph = SimpleEgraph {
+        nodes,
+        classes,
+        score_fn: Box::new(|assign, _| {
+            let util = match (assign[&0], assign.get(&1)) {
+                (1, _) => -0.7,
+                (0, Some(&OPTIMUM)) => 0.0,
+                _ => -1.0,
+            };
+            Utility::new(util).unwrap()
+        }),
+    };
+    // Seed each trial, so that ties between the unexplored members of the
+    // wide class are broken at random rather than always in the same order.
+    let n_optimal = |backup, playouts_per_round| {
+        (0..TRIALS as u64)
+            .filter(|&seed| {
+                let assign = mcts_extract(
+                    &egraph,
+                    0,
+                    MctsConfig {
+                        playouts_per_round,
+                        terms_to_sample: 2,
+                        backup,
+                        seed: Some(seed),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+                assign[&0] == 0 && assign[&1] == OPTIMUM
+            })
+            .count()
+    };
+    const TRIALS: usize = 20;
+    // With a small budget, averaging buries the optimum under its poor
+    // siblings, but `Max` backup commits to it right away.
+    assert!(n_optimal(Backup::Max, 24) > n_optimal(Backup::Mean, 24));
+    // `Mean` gets there too, eventually.
+    assert!(n_optimal(Backup::Mean, 96) > TRIALS / 2);
+}
+
+#[test]
+fn fpu_reduction_limits_exploration() {
+    // A root with a single child class containing many equally good leaves.
+    const WIDTH: usize = 10;
+    let mut nodes = vec![vec![1]];
+    nodes.extend((0..WIDTH).map(|_| vec![]));
+    let egraph = SimpleEgraph {
+        nodes,
+        classes: vec![vec![0], (1..WIDTH + 1).collect()],
+        score_fn: Box::new(|_, _| Utility::new(-1.0).unwrap()),
+    };
+    let n_explored = |fpu_reduction| {
+        let mut session = SearchSession::new(
+            &egraph,
+            0,
+            MctsConfig {
+                playouts_per_round: 32,
+                fpu_reduction,
+                ..Default::default()
+            },
+        );
+        session.add_breakpoint(1);
+        assert!(matches!(session.step(), StepOutcome::Committed { .. }));
+        let StepOutcome::Breakpoint { candidates, .. } = session.step() else {
+            panic!("expected to stop at class 1");
+        };
+        candidates.iter().filter(|c| c.visits > 0).count()
+    };
+    // Unvisited leaves look as good as their parent, so they all get tried.
+    assert_eq!(n_explored(0.0), WIDTH);
+    // Unvisited leaves look far worse than the one that was tried first.
+    assert_eq!(n_explored(100.0), 1);
+}
+
+#[test]
+fn expansion_boost_tries_every_member_first() {
+    // A wide root class whose leaves differ in utility.
+    const WIDTH: usize = 12;
+    let egraph = SimpleEgraph {
+        nodes: vec![vec![]; WIDTH],
+        classes: vec![(0..WIDTH).collect()],
+        score_fn: Box::new(|assign, _| Utility::new(-(assign[&0] as f32)).unwrap()),
+    };
+    let visits = |expansion_boost| {
+        let mut session = SearchSession::new(
+            &egraph,
+            0,
+            MctsConfig {
+                // The first playout only scores the root.
+                playouts_per_round: WIDTH + 1,
+                expansion_boost,
+                ..Default::default()
+            },
+        );
+        session.add_breakpoint(0);
+        let StepOutcome::Breakpoint { candidates, .. } = session.step() else {
+            panic!("expected to stop at class 0");
+        };
+        candidates.iter().map(|c| c.visits).collect::<Vec<_>>()
+    };
+    assert!(visits(0.0).contains(&0));
+    assert_eq!(visits(100.0), vec![1; WIDTH]);
+}
+
+#[test]
+fn custom_selection_policy_drives_playouts() {
+    struct FirstMember;
+    impl SelectionPolicy for FirstMember {
+        fn select(&self, parent: &ParentStat, children: &[ChildStat]) -> usize {
+            assert!(parent.visits > 0);
+            assert_eq!(children.len(), 2);
+            0
+        }
+    }
+    let egraph = high_util_egraph();
+    let mut session = SearchSession::new(
+        &egraph,
+        0,
+        MctsConfig {
+            selection: Arc::new(FirstMember),
+            ..Default::default()
+        },
+    );
+    session.add_breakpoint(0);
+    let StepOutcome::Breakpoint { candidates, .. } = session.step() else {
+        panic!("expected to stop at class 0");
+    };
+    assert!(candidates[0].visits > 0);
+    assert_eq!(candidates[1].visits, 0);
+}
+
+#[test]
+fn out_of_range_selections_pick_the_last_child() {
+    struct PastTheEnd;
+    impl SelectionPolicy for PastTheEnd {
+        fn select(&self, _: &ParentStat, children: &[ChildStat]) -> usize {
+            children.len() + 3
+        }
+    }
+    let egraph = high_util_egraph();
+    let mut session = SearchSession::new(
+        &egraph,
+        0,
+        MctsConfig {
+            selection: Arc::new(PastTheEnd),
+            ..Default::default()
+        },
+    );
+    session.add_breakpoint(0);
+    let StepOutcome::Breakpoint { candidates, .. } = session.step() else {
+        panic!("expected to stop at class 0");
+    };
+    assert_eq!(candidates[0].visits, 0);
+    assert!(candidates[1].visits > 0);
+}
+
+#[test]
+fn exploration_schedule_grows_with_parent_visits() {
+    let schedule = ExplorationSchedule {
+        c_base: 10.0,
+        c_init: 1.0,
+    };
+    let c = Utility::new(2.0).unwrap();
+    let mut last = Utility::new(f32::NEG_INFINITY).unwrap();
+    for visits in [0, 1, 10, 100, 1000] {
+        let factor = schedule.factor(c, visits);
+        let expected = 2.0 * ((visits as f32 + 11.0) / 10.0).ln() + 1.0;
+        assert!((factor.into_inner() - expected).abs() < 1e-5);
+        assert!(factor > last);
+        last = factor;
+    }
+
+    // The search hands the scheduled constant to the selection policy.
+    struct Recorder(Mutex<Vec<(u32, Utility)>>);
+    impl SelectionPolicy for Recorder {
+        fn select(&self, parent: &ParentStat, children: &[ChildStat]) -> usize {
+            self.0
+                .lock()
+                .unwrap()
+                .push((parent.visits, children[0].exploration));
+            Ucb1.select(parent, children)
+        }
+    }
+    let recorder = Arc::new(Recorder(Mutex::new(Vec::new())));
+    let egraph = high_util_egraph();
+    let config = MctsConfig {
+        exploration_schedule: Some(schedule),
+        selection: recorder.clone(),
+        seed: Some(0),
+        ..Default::default()
+    };
+    mcts_extract(&egraph, 0, config).expect("extraction should succeed");
+    let seen = recorder.0.lock().unwrap();
+    assert!(!seen.is_empty());
+    for &(visits, exploration) in seen.iter() {
+        assert_eq!(
+            exploration,
+            schedule.factor(Utility::new(2.0f32.sqrt()).unwrap(), visits)
+        );
+    }
+}
+
+#[test]
+fn full_expansion_evaluates_every_member() {
+    let egraph = high_util_egraph();
+    let config = |expansion_strategy| MctsConfig {
+        playouts_per_round: 1,
+        expansion_strategy,
+        ..Default::default()
+    };
+    let first_visits = |expansion_strategy| {
+        let mut session = SearchSession::new(&egraph, 0, config(expansion_strategy));
+        session.add_breakpoint(0);
+        assert!(matches!(session.step(), StepOutcome::Breakpoint { .. }));
+        session
+            .candidates()
+            .iter()
+            .map(|c| c.visits)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(first_visits(ExpansionStrategy::Lazy), vec![0, 0]);
+    assert_eq!(first_visits(ExpansionStrategy::Full), vec![1, 1]);
+
+    let assign = mcts_extract(
+        &egraph,
+        0,
+        MctsConfig {
+            expansion_strategy: ExpansionStrategy::Full,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        egraph.assignment_utility(&(), &assign),
+        Utility::new(1.0).unwrap()
+    );
+}
+
+#[test]
+fn target_utility_stops_the_search() {
+    let egraph = high_util_egraph();
+    let (assign, stats) = mcts_extract_with_stats(
+        &egraph,
+        0,
+        MctsConfig {
+            target_utility: Some(Utility::new(1.0).unwrap()),
+            seed: Some(0),
+            ..Default::default()
+        },
+    );
+    assert_eq!(
+        egraph.assignment_utility(&(), &assign.unwrap()),
+        Utility::new(1.0).unwrap()
+    );
+    let (_, full) = mcts_extract_with_stats(
+        &egraph,
+        0,
+        MctsConfig {
+            seed: Some(0),
+            ..Default::default()
+        },
+    );
+    assert!(stats.playouts < full.playouts);
+}
+
+#[test]
+fn counts_unique_assignments() {
+    let egraph = high_util_egraph();
+    let (_, stats) = mcts_extract_with_stats(
+        &egraph,
+        0,
+        MctsConfig {
+            count_unique_assignments: true,
+            ..Default::default()
+        },
+    );
+    // The egraph only has three terms.
+    let unique = stats.unique_assignments.unwrap();
+    assert!((1..=3).contains(&unique));
+    assert!(unique < stats.playouts);
+    let (_, stats) = mcts_extract_with_stats(&egraph, 0, MctsConfig::default());
+    assert_eq!(stats.unique_assignments, None);
+
+    let forward = [(0, 1), (2, 4)]
+        .into_iter()
+        .collect::<Assignment<SimpleEgraph>>();
+    let backward = [(2, 4), (0, 1)]
+        .into_iter()
+        .collect::<Assignment<SimpleEgraph>>();
+    let different = [(0, 1), (2, 5)]
+        .into_iter()
+        .collect::<Assignment<SimpleEgraph>>();
+    assert_eq!(
+        assignment_hash::<SimpleEgraph>(&forward),
+        assignment_hash::<SimpleEgraph>(&backward)
+    );
+    assert_ne!(
+        assignment_hash::<SimpleEgraph>(&forward),
+        assignment_hash::<SimpleEgraph>(&different)
+    );
+}
+
+#[test]
+fn assignment_comparisons_ignore_insertion_order() {
+    let assign =
+        |pairs: &[(usize, usize)]| -> Assignment<SimpleEgraph> { pairs.iter().copied().collect() };
+    let forward = assign(&[(0, 1), (1, 2), (2, 0)]);
+    let backward = assign(&[(2, 0), (1, 2), (0, 1)]);
+    assert_eq!(forward, backward);
+    assert!(assignments_equal::<SimpleEgraph>(&forward, &backward));
+    assert_eq!(
+        assignment_cmp::<SimpleEgraph>(&forward, &backward),
+        cmp::Ordering::Equal
+    );
+
+    let other = assign(&[(2, 0), (1, 3), (0, 1)]);
+    assert_ne!(forward, other);
+    assert!(!assignments_equal::<SimpleEgraph>(&forward, &other));
+    assert_eq!(
+        assignment_cmp::<SimpleEgraph>(&forward, &other),
+        cmp::Ordering::Less
+    );
+    let fewer = assign(&[(0, 1), (1, 2)]);
+    assert_ne!(forward, fewer);
+    assert_ne!(fewer, forward);
+    assert_eq!(
+        assignment_cmp::<SimpleEgraph>(&fewer, &forward),
+        cmp::Ordering::Less
+    );
+}
+
+#[test]
+fn ties_are_broken_fairly() {
+    // A root class whose members are all equally good.
+    const WIDTH: usize = 4;
+    let egraph = SimpleEgraph {
+        nodes: vec![vec![]; WIDTH],
+        classes: vec![(0..WIDTH).collect()],
+        score_fn: Box::new(|_, _| Utility::new(-1.0).unwrap()),
+    };
+    let mut totals = [0; WIDTH];
+    for seed in 0..100 {
+        let mut session = SearchSession::new(
+            &egraph,
+            0,
+            MctsConfig {
+                playouts_per_round: 9,
+                seed: Some(seed),
+                ..Default::default()
+            },
+        );
+        session.add_breakpoint(0);
+        let StepOutcome::Breakpoint { candidates, .. } = session.step() else {
+            panic!("expected to stop at class 0");
+        };
+        for (total, candidate) in totals.iter_mut().zip(&candidates) {
+            *total += candidate.visits;
+        }
+    }
+    // Each member gets about 200 of the 800 visits.
+    assert!(
+        totals.iter().all(|total| (150..250).contains(total)),
+        "{totals:?}"
+    );
+}
+
+#[test]
+fn session_pauses_at_breakpoints() {
+    let egraph = high_util_egraph();
+    let mut session = SearchSession::new(
+        &egraph,
+        0,
+        MctsConfig {
+            playouts_per_round: 4,
+            ..Default::default()
+        },
+    );
+    session.add_breakpoint(0);
+
+    let StepOutcome::Breakpoint { class, candidates } = session.step() else {
+        panic!("expected to stop at the root class");
+    };
+    assert_eq!(class, 0);
+    let mut nodes = candidates.iter().map(|c| c.node).collect::<Vec<_>>();
+    nodes.sort();
+    assert_eq!(nodes, vec![0, 1]);
+    // Stepping again just refines the statistics.
+    assert!(matches!(
+        session.step(),
+        StepOutcome::Breakpoint { class: 0, .. }
+    ));
+
+    // Override the search's preference for node 1.
+    session.choose(0);
+    loop {
+        match session.step() {
+            StepOutcome::Committed { .. } => {}
+            StepOutcome::Done => break,
+            _ => panic!("unexpected step outcome"),
+        }
+    }
+    let assign = session.complete_assignment().unwrap();
+    assert_eq!(assign[&0], 0);
+    assert_valid_assignment(&egraph, 0, assign);
+}
+
+#[test]
+fn decisive_rounds_end_early() {
+    let egraph = high_util_egraph();
+    let run = |decisiveness_threshold| {
+        mcts_extract_with_stats(
+            &egraph,
+            0,
+            MctsConfig {
+                playouts_per_round: 64,
+                decisiveness_threshold,
+                ..Default::default()
+            },
+        )
+    };
+    let (full, full_stats) = run(None);
+    let (early, early_stats) = run(Some(0.75));
+    assert_valid_assignment(&egraph, 0, &full.unwrap());
+    assert_valid_assignment(&egraph, 0, &early.unwrap());
+    // Classes 2 and 3 have a single member, so their decisions are settled
+    // after a single visit.
+    assert!(early_stats.playouts < full_stats.playouts);
+}
+
+#[test]
+fn counts_exact_leaf_evaluations() {
+    // Every term in this egraph is at most three classes deep, so playouts
+    // quickly reach complete assignments.
+    let egraph = high_util_egraph();
+    let (assign, stats) = mcts_extract_with_stats(
+        &egraph,
+        0,
+        MctsConfig {
+            playouts_per_round: 8,
+            ..Default::default()
+        },
+    );
+    assert_valid_assignment(&egraph, 0, &assign.unwrap());
+    assert!(stats.exact_leaf_evaluations > 0);
+    assert!(stats.sampled_leaf_evaluations > 0);
+    assert!(stats.exact_leaf_evaluations + stats.sampled_leaf_evaluations <= stats.playouts);
+}
+
+#[test]
+fn optional_subtrees_are_left_out() {
+    // Class 2 can only be extracted through a cycle, and class 3 is empty.
+    let egraph = optional_egraph(vec![1, 2, 3]);
+    assert!(mcts_extract(&egraph, 0, MctsConfig::default()).is_none());
+
+    let mut session = SearchSession::new(&egraph, 0, MctsConfig::default());
+    session.set_optional([2, 3]);
+    let assign = session.run().unwrap();
+    assert_eq!(assign.len(), 2);
+    assert_eq!(assign[&0], 0);
+    assert_eq!(assign[&1], 1);
+}
+
+#[test]
+fn realized_optional_classes_must_be_complete() {
+    // Class 2 is visited before the mandatory class 1, so it gets assigned,
+    // which pulls in class 4 and, through it, a cycle back to class 2.
+    let egraph = optional_egraph(vec![2, 1]);
+    let mut state = ExtractionState::<SimpleEgraph>::with_optional(0, [2].into_iter().collect());
+    state.start_next_assign().unwrap().assign(0, &egraph);
+    let handle = state.start_next_assign().unwrap();
+    assert_eq!(handle.class(), &2);
+    assert!(handle.is_optional());
+    handle.assign(2, &egraph);
+    while let Some(handle) = state.start_next_assign() {
+        let node = egraph.classes[*handle.class()][0];
+        handle.assign(node, &egraph);
+    }
+    assert!(state.complete_assignment().is_none());
+
+    // Skipping class 2 instead leaves nothing else to assign.
+    state.reset(&egraph);
+    state.start_next_assign().unwrap().assign(0, &egraph);
+    state.start_next_assign().unwrap().skip();
+    state.start_next_assign().unwrap().assign(1, &egraph);
+    assert!(state.start_next_assign().is_none());
+    let assign = state.complete_assignment().unwrap();
+    assert_eq!(assign.keys().copied().collect::<Vec<_>>(), vec![1, 0]);
+}
 
 #[test]
-fn finds_high_util() {
-    // Set up a simple egraph with a single term with positive utility.
+fn deep_rollouts_do_not_retain_their_memory() {
+    // A single long chain of classes, so that one rollout assigns all of them.
+    const CHAIN_LEN: usize = 10_000;
+    let egraph = SimpleEgraph {
+        nodes: (0..CHAIN_LEN)
+            .map(|class| {
+                if class + 1 < CHAIN_LEN {
+                    vec![class + 1]
+                } else {
+                    vec![]
+                }
+            })
+            .collect(),
+        classes: (0..CHAIN_LEN).map(|class| vec![class]).collect(),
+        score_fn: Box::new(|_, _| Utility::default()),
+    };
+    let mut state = ExtractionState::new(0);
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut completed = 0;
+    random_cost_estimate(
+        &egraph,
+        &(),
+        &mut state,
+        &mut rng,
+        None,
+        None,
+        |assign, _| {
+            completed = assign.len();
+        },
+    )
+    .expect("the chain is extractable");
+    assert_eq!(completed, CHAIN_LEN);
+    // Spare capacity is only released every so often, after backtracking.
+    for _ in 0..SHRINK_INTERVAL {
+        state.push_snapshot();
+        state.reset(&egraph);
+        state.pop_snapshot();
+    }
+    assert!(state.capacity() < 1_000, "retained {}", state.capacity());
+}
+
+#[test]
+fn optimal_dag_extraction_minimizes_tree_cost() {
+    // Node 0 uses class 1 twice; class 1 has a cheap node that loops back to
+    // the root, and a leaf.
+    let egraph = NodeCostEgraph {
+        nodes: vec![vec![1, 1], vec![], vec![0], vec![]],
+        classes: vec![vec![0, 1], vec![2, 3]],
+        costs: vec![1.0, 10.0, 2.0, 3.0],
+    };
+    let assign = optimal_dag_extract(&egraph, 0).unwrap();
+    assert_eq!(assign.len(), 2);
+    assert_eq!((assign[&0], assign[&1]), (0, 3));
+
+    // A root that only loops back on itself cannot be extracted.
+    let egraph = NodeCostEgraph {
+        nodes: vec![vec![0]],
+        classes: vec![vec![0]],
+        costs: vec![1.0],
+    };
+    assert_eq!(optimal_dag_extract(&egraph, 0), None);
+}
+
+#[test]
+fn supplied_analyses_drive_pruning() {
+    // Node 0 costs 1 and needs class 1, whose only node costs 1; node 1 is a
+    // leaf that costs 5.
+    let egraph = NodeCostEgraph {
+        nodes: vec![vec![1], vec![], vec![]],
+        classes: vec![vec![0, 1], vec![2]],
+        costs: vec![1.0, 5.0, 1.0],
+    };
+    let cost = |cost: f32| Utility::new(cost).unwrap();
+    let config = MctsConfig {
+        cost_ceiling: Some(cost(10.0)),
+        seed: Some(0),
+        ..Default::default()
+    };
+    let computed = Analysis::compute_with_costs(&egraph, 0);
+    assert_eq!(computed.n_reachable(), 2);
+    let assign = mcts_extract_with_analysis(&egraph, 0, config.clone(), computed).unwrap();
+    assert_eq!(assign[&0], 0);
+
+    // A sentinel bound on class 1 rules out node 0, which the search could
+    // only notice by consulting the supplied analysis.
+    let supplied = Analysis::new(2).with_cost_bounds(
+        (0..3).map(|node| (node, cost(egraph.costs[node]))),
+        [(0, cost(1.0)), (1, cost(100.0))],
+        cost(1.0),
+    );
+    let assign = mcts_extract_with_analysis(&egraph, 0, config, supplied).unwrap();
+    assert_eq!(assign[&0], 1);
+}
+
+#[test]
+fn deep_rollouts_are_truncated() {
+    // A chain of ten classes, so that every sample assigns all of them.
+    let egraph = SimpleEgraph {
+        nodes: (0..10)
+            .map(|class| if class < 9 { vec![class + 1] } else { vec![] })
+            .collect(),
+        classes: (0..10).map(|class| vec![class]).collect(),
+        score_fn: Box::new(|_, _| Utility::new(1.0).unwrap()),
+    };
+    let samples = |rollout_max_depth| {
+        let config = MctsConfig {
+            rollout_max_depth,
+            ..Default::default()
+        };
+        SearchSession::new(&egraph, 0, config).sample_utilities(4)
+    };
+    assert_eq!(samples(Some(9)), [None; 4]);
+    assert_eq!(samples(Some(10)), [Some(Utility::new(1.0).unwrap()); 4]);
+}
+
+#[test]
+fn rollouts_stop_at_the_reachable_class_count() {
+    let config = MctsConfig {
+        seed: Some(0),
+        ..Default::default()
+    };
+    // A chain of 1,000 classes, of which the analysis only admits to ten.
+    let egraph = SimpleEgraph {
+        nodes: (0..1_000)
+            .map(|class| if class < 999 { vec![class + 1] } else { vec![] })
+            .collect(),
+        classes: (0..1_000).map(|class| vec![class]).collect(),
+        score_fn: Box::new(|_, _| Utility::new(1.0).unwrap()),
+    };
+    let mut session = SearchSession::new(&egraph, 0, config.clone());
+    session.use_analysis(Analysis::new(10));
+    assert!(matches!(
+        session.run(),
+        Err(ExtractError::NonTerminating { .. })
+    ));
+    assert_eq!(session.stats().best_utility, None);
+
+    // Well-formed egraphs never reach the cap, so raising it changes nothing.
+    for seed in [0, 2, 3] {
+        let egraph = gen_random_egraph(200, 4, 3, seed);
+        let capped = SearchSession::new(&egraph, 0, config.clone()).run().ok();
+        let mut session = SearchSession::new(&egraph, 0, config.clone());
+        session.use_analysis(Analysis::new(1_000));
+        assert!(capped.is_some());
+        assert_eq!(capped, session.run().ok());
+    }
+}
+
+#[test]
+fn topo_hints_order_the_classes_to_visit() {
+    // Node 0 needs classes 1 and 2, and node 1 needs class 3.
+    let hinted = |hint| TestEgraph {
+        hint: Some(hint),
+        ..TestEgraph::new(NodeCostEgraph {
+            nodes: vec![vec![1, 2], vec![3], vec![], vec![]],
+            classes: vec![vec![0], vec![1], vec![2], vec![3]],
+            costs: vec![0.0; 4],
+        })
+    };
+    let visited = |hint| {
+        let egraph = hinted(hint);
+        let mut session = SearchSession::new(&egraph, 0, MctsConfig::default());
+        let assign = session.run().unwrap();
+        assert_eq!(assign.len(), 4);
+        session
+            .decisions()
+            .iter()
+            .map(|(class, _)| *class)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(visited(vec![3, 2, 1, 0]), [0, 2, 1, 3]);
+    // Wrong or partial hints still extract a valid term.
+    assert_eq!(visited(vec![0, 1, 2, 3]), [0, 1, 2, 3]);
+    assert_eq!(visited(vec![2]), [0, 2, 1, 3]);
+}
+
+#[test]
+fn node_ids_shared_between_classes_do_not_collide() {
+    // Both root nodes need class 3 first, and then node 0 needs class 1 and
+    // node 1 needs class 2. Node 2 is a member of both classes 1 and 2, so
+    // with transpositions the tree node for class 3 goes on to assign node 2
+    // to either class. Both root nodes are worth exploring, but only node 1
+    // leads to the best assignment, which needs the tree node for assigning
+    // node 2 to class 2 rather than the one for class 1.
+    let egraph = SimpleEgraph {
+        nodes: vec![vec![3, 1], vec![3, 2], vec![], vec![], vec![], vec![]],
+        classes: vec![vec![0, 1], vec![2, 4], vec![2, 5], vec![3]],
+        score_fn: Box::new(|assign, _| {
+            let value = |(class, node)| match (class, node) {
+                (0, 1) => 0.25,
+                (1 | 2, 2) => 0.5,
+                _ => 0.0,
+            };
+            Utility::new(assign.iter().map(|(c, n)| value((*c, *n))).sum()).unwrap()
+        }),
+    };
+    for transposition_table in [false, true] {
+        let config = MctsConfig {
+            transposition_table,
+            playouts_per_round: 256,
+            seed: Some(0),
+            ..Default::default()
+        };
+        let assign = mcts_extract(&egraph, 0, config).unwrap();
+        assert_eq!(assign[&0], 1);
+        assert_eq!(assign[&2], 2);
+        assert_eq!(egraph.assignment_utility(&(), &assign), 0.75);
+    }
+}
+
+#[test]
+fn round_limits_stop_runaway_extractions() {
+    // A deep chain of classes with two members each, one round per class.
+    const CHAIN_LEN: usize = 1_000;
+    let egraph = SimpleEgraph {
+        nodes: (0..2 * CHAIN_LEN)
+            .map(|node| {
+                let class = node / 2;
+                if class + 1 < CHAIN_LEN {
+                    vec![class + 1]
+                } else {
+                    vec![]
+                }
+            })
+            .collect(),
+        classes: (0..CHAIN_LEN)
+            .map(|class| vec![2 * class, 2 * class + 1])
+            .collect(),
+        score_fn: Box::new(|_, _| Utility::default()),
+    };
+    let config = MctsConfig {
+        playouts_per_round: 2,
+        terms_to_sample: 1,
+        max_rounds: Some(10),
+        seed: Some(0),
+        ..Default::default()
+    };
+    let mut session = SearchSession::new(&egraph, 0, config);
+    assert_eq!(
+        session.run(),
+        Err(ExtractError::BudgetExhausted { decisions: 10 })
+    );
+    assert_eq!(session.decisions().len(), 10);
+    assert!(matches!(session.step(), StepOutcome::Stopped));
+}
+
+#[test]
+fn cleared_states_roll_out_like_fresh_ones() {
+    let egraph = gen_random_egraph(50, 3, 2, 8);
+    let rollout = |state: &mut ExtractionState<_>| {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut completed = Vec::new();
+        random_cost_estimate(&egraph, &(), state, &mut rng, None, None, |assign, _| {
+            completed = assign.iter().map(|(c, n)| (*c, *n)).collect()
+        })
+        .ok()
+        .map(|_| completed)
+    };
+    let mut reused = ExtractionState::new(1);
+    rollout(&mut reused);
+    reused.clear_and_seed(0);
+    let fresh = rollout(&mut ExtractionState::new(0));
+    assert!(fresh.is_some());
+    assert_eq!(rollout(&mut reused), fresh);
+}
+
+#[test]
+fn pooled_states_are_reused_across_roots() {
+    let egraph = gen_random_egraph(30, 4, 3, 7);
+    let config = MctsConfig {
+        seed: Some(0),
+        ..Default::default()
+    };
+    let mut pool = StatePool::default();
+    for root in 0..20 {
+        let mut session = SearchSession::new_in(&mut pool, &egraph, root, config.clone());
+        // Settings from one search must not carry over to the next.
+        if root % 2 == 0 {
+            session.prune_with_node_costs();
+        }
+        let pooled = session.run();
+        session.recycle(&mut pool);
+        let mut fresh = SearchSession::new(&egraph, root, config.clone());
+        if root % 2 == 0 {
+            fresh.prune_with_node_costs();
+        }
+        assert_eq!(pooled, fresh.run(), "root {root}");
+    }
+    assert_eq!(pool.allocated(), 1);
+}
+
+#[test]
+fn random_egraphs_are_reproducible_and_extractable() {
+    let structure = |seed| {
+        let egraph = gen_random_egraph(200, 4, 3, seed);
+        (0..200)
+            .flat_map(|class| egraph.members(&class).copied().collect::<Vec<_>>())
+            .map(|node| (node, egraph.children(&node).copied().collect::<Vec<_>>()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(structure(7), structure(7));
+    assert_ne!(structure(7), structure(8));
+
+    let egraph = gen_random_egraph(200, 4, 3, 7);
+    assert_eq!(analyze(&egraph, 0).reachable_classes, 200);
+    assert!(extract_any(&egraph, 0).is_some());
+    let egraph = gen_random_egraph(30, 4, 3, 7);
+    assert!(mcts_extract(&egraph, 0, MctsConfig::default()).is_some());
+}
+
+#[test]
+fn rollouts_stop_once_they_cannot_beat_the_best() {
+    let egraph = TestEgraph::new(NodeCostEgraph::copy_of(
+        &gen_random_egraph(100, 4, 3, 8),
+        100,
+    ));
+    let work = |prune_rollouts_by_best| {
+        egraph.members_calls.set(0);
+        let mut session = SearchSession::new(
+            &egraph,
+            0,
+            MctsConfig {
+                prune_rollouts_by_best,
+                seed: Some(0),
+                ..Default::default()
+            },
+        );
+        session.prune_with_node_costs();
+        session.run().expect("extraction should succeed");
+        egraph.members_calls.get()
+    };
+    let full = work(false);
+    let pruned = work(true);
+    assert!(pruned < full, "{pruned} >= {full}");
+}
+
+#[test]
+fn abandoned_rollouts_do_not_outrank_completed_ones() {
+    /// A [`NodeCostEgraph`] that charges an extra 10 for node 3, which its
+    /// node costs do not show.
+    struct HiddenCost(NodeCostEgraph);
+    impl Egraph for HiddenCost {
+        type ClassId = usize;
+        type NodeId = usize;
+        fn children(&self, id: &usize) -> impl Iterator<Item = &usize> {
+            self.0.children(id)
+        }
+        fn members(&self, id: &usize) -> impl Iterator<Item = &usize> {
+            self.0.members(id)
+        }
+    }
+    impl EgraphTotalCost for HiddenCost {
+        type CostCtx = ();
+        fn precompute(&self, _: &[usize]) {}
+        fn assignment_utility(&self, _: &(), assignment: &Assignment<Self>) -> Utility {
+            let extra = if assignment.values().any(|node| *node == 3) {
+                10.0
+            } else {
+                0.0
+            };
+            self.0.assignment_utility(&(), assignment) - extra
+        }
+    }
+    impl EgraphNodeCost for HiddenCost {
+        fn node_cost(&self, node: &usize) -> Utility {
+            self.0.node_cost(node)
+        }
+    }
+
+    // Node 0 leads to a leaf costing 5, which can never beat the best
+    // assignment's cost of 4, and node 1 to one costing 1, plus 10 hidden.
+    let egraph = HiddenCost(NodeCostEgraph {
+        nodes: vec![vec![1], vec![2], vec![], vec![]],
+        classes: vec![vec![0, 1], vec![2], vec![3]],
+        costs: vec![0.0, 0.0, 5.0, 1.0],
+    });
+    let config = MctsConfig {
+        cost_ceiling: Some(Utility::new(20.0).unwrap()),
+        prune_rollouts_by_best: true,
+        ..Default::default()
+    };
+    let estimate = |node| {
+        let mut estimator = RolloutEstimator::new(&config, ());
+        let mut best = BestAssignment::new(0.0, config.cost_ceiling);
+        best.offer(
+            &egraph,
+            &Assignment::<HiddenCost>::from_iter([(0, 0), (1, 2)]),
+            Utility::new(-4.0).unwrap(),
+        );
+        let mut state = ExtractionState::new(0);
+        state.set_cost_bounds(Arc::new(CostBounds::compute(&egraph, 0)));
+        state.start_next_assign().unwrap().assign(node, &egraph);
+        let mut rng = StdRng::seed_from_u64(0);
+        estimator
+            .estimate(&mut state, &egraph, &mut best, &mut rng)
+            .utility
+    };
+    let abandoned = estimate(0);
+    let completed = estimate(1);
+    assert_eq!(completed, Utility::new(-11.0).unwrap());
+    assert!(abandoned < completed, "{abandoned} >= {completed}");
+}
+
+#[test]
+fn cost_ceiling_excludes_worse_assignments() {
+    let egraph = gen_random_egraph(30, 4, 3, 11);
+    let config = MctsConfig {
+        playouts_per_round: 8,
+        seed: Some(0),
+        ..Default::default()
+    };
+    let session = |cost_ceiling| {
+        let mut session = SearchSession::new(
+            &egraph,
+            0,
+            MctsConfig {
+                cost_ceiling: Some(cost_ceiling),
+                ..config.clone()
+            },
+        );
+        session.prune_with_node_costs();
+        session
+    };
+
+    // The first member of every class only points forward, so picking it
+    // everywhere gives a valid, if poor, baseline.
+    fn first_members<E: Egraph>(egraph: &E, root: E::ClassId) -> Assignment<E> {
+        let mut assign = Assignment::<E>::default();
+        let mut to_visit = vec![root];
+        while let Some(class) = to_visit.pop() {
+            if assign.contains_key(&class) {
+                continue;
+            }
+            let node = egraph.members(&class).next().unwrap().clone();
+            to_visit.extend(egraph.children(&node).cloned());
+            assign.insert(class, node);
+        }
+        assign
+    }
+    let baseline = first_members(&egraph, 0);
+    let baseline_cost = -egraph.assignment_utility(&(), &baseline);
+    let assign = session(baseline_cost).run().unwrap();
+    assert!(-egraph.assignment_utility(&(), &assign) < baseline_cost);
+
+    // No assignment costs less than nothing, so every descent is pruned as
+    // soon as it assigns a node.
+    let mut nothing = session(Utility::default());
+    assert!(matches!(nothing.run(), Err(ExtractError::NoAssignment)));
+    assert!(nothing.stats().pruned_playouts > 0);
+
+    // Without node costs, the ceiling still applies to the result.
+    let (assign, _) = mcts_extract_with_stats(
+        &egraph,
+        0,
+        MctsConfig {
+            cost_ceiling: Some(Utility::default()),
+            ..config
+        },
+    );
+    assert!(assign.is_none());
+}
+
+#[test]
+fn soft_penalties_flip_ties() {
+    let egraph = NodeCostEgraph {
+        nodes: vec![vec![], vec![]],
+        classes: vec![vec![0, 1]],
+        costs: vec![1.0, 1.0],
+    };
+    for (penalized, expected) in [(0, 1), (1, 0)] {
+        let penalties = [(penalized, Utility::new(0.5).unwrap())]
+            .into_iter()
+            .collect();
+        let penalized = SoftPenalties::new(&egraph, penalties);
+        let assign = mcts_extract(&penalized, 0, MctsConfig::default()).unwrap();
+        assert_eq!(assign[&0], expected);
+    }
+}
+
+#[test]
+fn extracts_once_per_pinned_root_node() {
+    let egraph = high_util_egraph();
+    let res = extract_per_root_node(&egraph, 0, 5, MctsConfig::default());
+    let mut pinned = res.iter().map(|(node, _, _)| *node).collect::<Vec<_>>();
+    pinned.sort();
+    assert_eq!(pinned, vec![0, 1]);
+    for (node, assign, _) in &res {
+        let assign = assign.as_ref().expect("extraction should succeed");
+        assert_eq!(assign[&0], *node);
+        assert_valid_assignment(&egraph, 0, assign);
+    }
+    assert_eq!(
+        extract_per_root_node(&egraph, 0, 1, MctsConfig::default()).len(),
+        1
+    );
+}
+
+#[test]
+fn pattern_penalties_steer_away_from_patterns() {
+    let egraph = NodeCostEgraph {
+        nodes: vec![vec![1], vec![1], vec![]],
+        classes: vec![vec![0, 1], vec![2]],
+        costs: vec![1.0, 1.0, 1.0],
+    };
+    let ops = ["add", "mul", "x"];
+    for (penalized, expected) in [("add", 1), ("mul", 0)] {
+        let patterns = [PatternPenalty {
+            parent_op: penalized,
+            child_op: "x",
+            penalty: Utility::new(0.5).unwrap(),
+        }];
+        let penalized = PatternPenalties::new(&egraph, |node: &usize| ops[*node], patterns);
+        let assign = mcts_extract(&penalized, 0, MctsConfig::default()).unwrap();
+        assert_eq!(assign[&0], expected);
+        assert_eq!(
+            penalized.assignment_utility(&(), &assign),
+            Utility::new(-2.0).unwrap()
+        );
+    }
+}
+
+#[test]
+fn choice_graphs_pick_the_cheapest_lowering() {
+    let cost = |cost: f32| Utility::new(cost).unwrap();
+    let mut graph = ChoiceGraph::new();
+    let x = graph.add_position();
+    graph.add_choice(x, "x", cost(0.0), []);
+    let two = graph.add_position();
+    graph.add_choice(two, "2", cost(0.0), []);
+    let root = graph.add_position();
+    let mul = graph.add_choice(root, "x * 2", cost(3.0), [x, two]);
+    let add = graph.add_choice(root, "x + x", cost(1.0), [x, x]);
+
+    let assign = mcts_extract(&graph, root, MctsConfig::default()).unwrap();
+    assert_eq!(assign[&root], add);
+    assert!(!assign.contains_key(&two));
+    assert_eq!(graph.value(mul), &"x * 2");
+    assert_eq!(graph.total_utility(&(), &assign), cost(-1.0));
+}
+
+#[test]
+fn extraction_keeps_children_in_order() {
+    let cost = |cost: f32| Utility::new(cost).unwrap();
+    let mut graph = ChoiceGraph::new();
+    let x = graph.add_position();
+    graph.add_choice(x, "x", cost(0.0), []);
+    let y = graph.add_position();
+    graph.add_choice(y, "y", cost(0.0), []);
+    let root = graph.add_position();
+    let sub = graph.add_choice(root, "y - x", cost(1.0), [y, x]);
+
+    let assign = mcts_extract(&graph, root, MctsConfig::default()).unwrap();
+    assert_eq!(assign[&root], sub);
+    assert_eq!(graph.ordered_children(&sub), Some(&[y, x][..]));
+    let edges = assignment_edges(&graph, &assign)
+        .filter(|(parent, _)| **parent == sub)
+        .map(|(_, child)| *child)
+        .collect::<Vec<_>>();
+    assert_eq!(edges, [y, x]);
+}
+
+#[test]
+fn materialized_classes_are_free_to_reuse() {
+    // Root 0 needs class 2. Root 1 can refer to class 2 too, or use a leaf
+    // that is cheaper than building class 2 from scratch.
+    let egraph = NodeCostEgraph {
+        nodes: vec![vec![2], vec![2], vec![], vec![]],
+        classes: vec![vec![0], vec![1, 2], vec![3]],
+        costs: vec![1.0, 1.0, 3.0, 5.0],
+    };
+    let mut built = Materialized::new(&egraph, Default::default());
+    let alone = mcts_extract(&built, 1, MctsConfig::default()).unwrap();
+    assert_eq!(alone[&1], 2);
+
+    let first = mcts_extract(&built, 0, MctsConfig::default()).unwrap();
+    built.materialize(&first);
+    let second = mcts_extract(&built, 1, MctsConfig::default()).unwrap();
+    assert_eq!(second[&1], 1);
+    assert!(!second.contains_key(&2));
+    assert_eq!(
+        built.total_utility(&(), &second),
+        Utility::new(-1.0).unwrap()
+    );
+}
+
+#[test]
+fn reports_optimality_ratio() {
+    // The root can pick a cheap node over a chain of two cheap classes, or an
+    // expensive leaf. The chain is optimal, and costs exactly as much as the
+    // most expensive path through it.
+    let egraph = NodeCostEgraph {
+        nodes: vec![vec![1], vec![], vec![2], vec![]],
+        classes: vec![vec![0, 1], vec![2], vec![3]],
+        costs: vec![1.0, 5.0, 1.0, 1.0],
+    };
+    let mut session = SearchSession::new(&egraph, 0, MctsConfig::default());
+    assert_eq!(session.stats().optimality_ratio, None);
+    session.prune_with_node_costs();
+    let assign = session.run().unwrap();
+    assert_eq!(assign[&0], 0);
+    assert_eq!(session.stats().optimality_ratio, Some(1.0));
+
+    for seed in 0..3 {
+        let egraph = gen_random_egraph(30, 4, 3, seed);
+        let mut session = SearchSession::new(&egraph, 0, MctsConfig::default());
+        session.prune_with_node_costs();
+        if session.run().is_ok() {
+            assert!(session.stats().optimality_ratio.unwrap() >= 1.0);
+        }
+    }
+}
+
+#[test]
+fn decisions_replay_the_search() {
+    let egraph = high_util_egraph();
+    let mut session = SearchSession::new(&egraph, 0, MctsConfig::default());
+    let assign = session.run().unwrap();
+    let decisions = session.decisions().to_vec();
+    assert_eq!(decisions[0].0, 0);
+    assert_eq!(decisions.len(), assign.len());
+    for (class, node) in &decisions {
+        assert_eq!(assign[class], *node);
+    }
+
+    let mut replay = SearchSession::new(&egraph, 0, MctsConfig::default());
+    for (_, node) in &decisions {
+        replay.choose(*node);
+    }
+    assert_eq!(replay.complete_assignment(), Some(&assign));
+    assert_eq!(replay.decisions(), decisions);
+}
+
+#[test]
+fn replaying_choices_reproduces_the_search() {
+    let egraph = gen_random_egraph(40, 3, 3, 7);
+    let mut recorded = SearchSession::new(&egraph, 0, MctsConfig::default());
+    recorded.record_choices();
+    let expected = recorded.run();
+    let choices = recorded.recorded_choices().to_vec();
+    assert!(!choices.is_empty());
+
+    let mut replayed = SearchSession::new(&egraph, 0, MctsConfig::default());
+    replayed.replay_choices(choices.clone());
+    assert_eq!(replayed.run(), expected);
+    assert_eq!(replayed.decisions(), recorded.decisions());
+    assert_eq!(replayed.recorded_choices(), choices);
+}
+
+#[test]
+fn edges_are_counted_once_per_dag_edge() {
+    struct Spills(SimpleEgraph);
+    impl Egraph for Spills {
+        type ClassId = usize;
+        type NodeId = usize;
+        fn children(&self, id: &usize) -> impl Iterator<Item = &usize> {
+            self.0.children(id)
+        }
+        fn members(&self, id: &usize) -> impl Iterator<Item = &usize> {
+            self.0.members(id)
+        }
+    }
+    impl EgraphEdgeCost for Spills {
+        fn edge_cost(&self, parent: &usize, child: &usize) -> Utility {
+            Utility::new((10 * parent + child) as f32).unwrap()
+        }
+    }
+
+    let egraph = Spills(high_util_egraph());
+    // Class 2 is shared by nodes 0 and 2, and its node refers to class 3
+    // twice; it still only contributes a single edge.
+    let assign = [(0, 0), (1, 2), (2, 4), (3, 5)]
+        .into_iter()
+        .collect::<Assignment<Spills>>();
+    let mut edges = assignment_edges(&egraph, &assign)
+        .map(|(parent, child)| (*parent, *child))
+        .collect::<Vec<_>>();
+    edges.sort();
+    assert_eq!(edges, vec![(0, 1), (0, 2), (2, 2), (2, 3), (4, 3)]);
+    assert_eq!(
+        egraph.assignment_edge_cost(&assign),
+        Utility::new((1 + 2 + 22 + 23 + 43) as f32).unwrap()
+    );
+}
+
+#[test]
+fn reroot_keeps_statistics_below_the_new_root() {
+    let egraph = high_util_egraph();
+    for transposition_table in [false, true] {
+        let config = MctsConfig {
+            transposition_table,
+            ..Default::default()
+        };
+        check_reroot(&egraph, config);
+    }
+}
+
+fn check_reroot(egraph: &SimpleEgraph, config: MctsConfig) {
+    let mut session = SearchSession::new(egraph, 0, config);
+    let StepOutcome::Committed {
+        node: root_node, ..
+    } = session.step()
+    else {
+        panic!("expected to commit to a node for the root class");
+    };
+    let summarize = |session: &mut SearchSession<SimpleEgraph>| {
+        let candidates = session
+            .candidates()
+            .into_iter()
+            .map(|c| (c.node, c.visits, c.value))
+            .collect::<Vec<_>>();
+        (session.next_class(), candidates)
+    };
+    let before = summarize(&mut session);
+    let stats_before = session.stats();
+
+    session.reroot();
+    assert_eq!(summarize(&mut session), before);
+    let stats_after = session.stats();
+    assert!(stats_after.tree_nodes < stats_before.tree_nodes);
+    assert!(stats_after.tree_memory_bytes < stats_before.tree_memory_bytes);
+
+    let assign = session.run().unwrap();
+    assert_eq!(assign[&0], root_node);
+    assert_valid_assignment(egraph, 0, &assign);
+}
+
+#[test]
+fn watchdog_stops_non_terminating_searches() {
+    // The egraph looks like a single class when the search sizes it up, but
+    // turns out to be a long chain.
+    let egraph = GrowingChain {
+        ids: (0..1000).collect(),
+        grown: Cell::new(false),
+    };
+    let mut session = SearchSession::new(&egraph, 0, MctsConfig::default());
+    assert!(matches!(
+        session.run(),
+        Err(ExtractError::NonTerminating { decisions: 3 })
+    ));
+
+    // The one-shot entry points report why they failed in their stats.
+    egraph.grown.set(false);
+    let (assign, stats) = mcts_extract_with_stats(&egraph, 0, MctsConfig::default());
+    assert!(assign.is_none());
+    assert_eq!(
+        stats.failure,
+        Some(ExtractError::NonTerminating { decisions: 3 })
+    );
+}
+
+#[test]
+fn cost_context_is_precomputed_once() {
+    let egraph = TestEgraph::new(NodeCostEgraph {
+        nodes: vec![vec![1], vec![1], vec![], vec![]],
+        classes: vec![vec![0, 1], vec![2, 3]],
+        costs: vec![1.0, 2.0, 3.0, 1.0],
+    });
+    let config = MctsConfig {
+        seed: Some(0),
+        ..Default::default()
+    };
+    let mut session = SearchSession::new(&egraph, 0, config);
+    assert_eq!(egraph.precomputes.get(), 1);
+    let assign = session.run().unwrap();
+    assert_eq!(egraph.precomputes.get(), 1);
+    assert_eq!(assign[&0], 0);
+    assert_eq!(assign[&1], 3);
+}
+
+#[test]
+fn forest_cost_contexts_cover_every_root() {
+    // Two unrelated roots, each with a cheap and an expensive leaf, so that
+    // the context for either root alone knows nothing about the other.
+    let egraph = TestEgraph::new(NodeCostEgraph {
+        nodes: vec![vec![]; 4],
+        classes: vec![vec![0, 1], vec![2, 3]],
+        costs: vec![2.0, 1.0, 1.0, 2.0],
+    });
+    assert_ne!(egraph.precompute(&[0]), egraph.precompute(&[1]));
+    egraph.precomputes.set(0);
+    let config = MctsConfig {
+        seed: Some(0),
+        ..Default::default()
+    };
+    let forest = mcts_extract_forest(&egraph, [0, 1], config).unwrap();
+    assert_eq!(egraph.precomputes.get(), 1);
+    assert_eq!(forest[&0], 1);
+    assert_eq!(forest[&1], 2);
+}
+
+#[test]
+fn opaque_classes_are_not_extracted() {
+    let egraph = TestEgraph {
+        opaque_class: Some(1),
+        ..TestEgraph::new(NodeCostEgraph {
+            nodes: vec![vec![1], vec![2], vec![3], vec![], vec![]],
+            classes: vec![vec![0, 1], vec![2], vec![3], vec![4]],
+            costs: vec![1.0, 1.0, 1.0, 2.0, 1.0],
+        })
+    };
+    let mut session = SearchSession::new(&egraph, 0, MctsConfig::default());
+    session.prune_with_node_costs();
+    let assign = session.run().unwrap();
+    // Referring to the opaque class costs 1 + 1, which beats the 1 + 2 for
+    // the alternative.
+    assert_eq!(assign.len(), 1);
+    assert_eq!(assign[&0], 0);
+    assert_eq!(
+        egraph.total_utility(&egraph.precompute(&[0]), &assign),
+        Utility::new(-2.0).unwrap()
+    );
+    assert_eq!(session.stats().optimality_ratio, Some(1.0));
+    assert_eq!(egraph.opaque_members_calls.get(), 0);
+    let any = extract_any(&egraph, 0).unwrap();
+    assert!(!any.contains_key(&1) && !any.contains_key(&3));
+}
+
+/// A chain of single-member classes that hides everything below the root
+/// the first time it is asked for children. Node `i` is the only member of
+/// class `i`.
+struct GrowingChain {
+    ids: Vec<usize>,
+    grown: Cell<bool>,
+}
+
+impl Egraph for GrowingChain {
+    type ClassId = usize;
+    type NodeId = usize;
+
+    fn children(&self, id: &usize) -> impl Iterator<Item = &usize> {
+        let end = if self.grown.replace(true) {
+            id + 2
+        } else {
+            id + 1
+        };
+        self.ids.get(id + 1..end).unwrap_or_default().iter()
+    }
+
+    fn members(&self, id: &usize) -> impl Iterator<Item = &usize> {
+        self.ids[*id..=*id].iter()
+    }
+}
+
+impl EgraphTotalCost for GrowingChain {
+    type CostCtx = ();
+
+    fn precompute(&self, _: &[Self::ClassId]) {}
+
+    fn assignment_utility(&self, _: &(), _: &Assignment<Self>) -> Utility {
+        Utility::default()
+    }
+}
+
+#[test]
+fn merged_sessions_pool_their_statistics() {
+    let egraph = high_util_egraph();
+    check_merge(&egraph, MctsConfig::default());
+    check_merge(
+        &egraph,
+        MctsConfig {
+            transposition_table: true,
+            ..Default::default()
+        },
+    );
+}
+
+#[test]
+fn critical_path_costs_prefer_wide_terms() {
+    // The root is either a chain of three nodes (size 3, depth 3) or a node
+    // over three leaves (size 4, depth 2).
+    let egraph = NodeCostEgraph {
+        nodes: vec![
+            vec![1],
+            vec![2],
+            vec![2, 3, 4],
+            vec![],
+            vec![],
+            vec![],
+            vec![],
+        ],
+        classes: vec![vec![0, 2], vec![1], vec![3], vec![4], vec![5], vec![6]],
+        costs: vec![1.0; 7],
+    };
+    let config = MctsConfig {
+        seed: Some(0),
+        ..Default::default()
+    };
+    let unit = |_: &usize| Utility::new(1.0).unwrap();
+
+    let smallest = mcts_extract(&egraph, 0, config.clone()).unwrap();
+    assert_eq!(smallest[&0], 0);
+    assert_eq!(smallest.len(), 3);
+    assert_eq!(
+        assignment_depth(&egraph, &smallest, unit),
+        Some(Utility::new(3.0).unwrap())
+    );
+
+    let shallowest = mcts_extract(&CriticalPathCost::new(&egraph, unit), 0, config).unwrap();
+    assert_eq!(shallowest[&0], 2);
+    assert_eq!(shallowest.len(), 4);
+    assert_eq!(
+        assignment_depth(&egraph, &shallowest, unit),
+        Some(Utility::new(2.0).unwrap())
+    );
+
+    // A class that depends on itself has no depth.
+    let cyclic = NodeCostEgraph {
+        nodes: vec![vec![0]],
+        classes: vec![vec![0]],
+        costs: vec![1.0],
+    };
+    let assign = Assignment::<NodeCostEgraph>::from_iter([(0, 0)]);
+    assert_eq!(assignment_depth(&cyclic, &assign, unit), None);
+}
+
+#[test]
+fn critical_paths_follow_the_longest_path_through_shared_subterms() {
+    // Classes 1 and 2 both use class 3, so the assignment is a diamond.
+    let egraph = NodeCostEgraph {
+        nodes: vec![vec![1, 2], vec![3], vec![3], vec![]],
+        classes: vec![vec![0], vec![1], vec![2], vec![3]],
+        costs: vec![1.0; 4],
+    };
+    let latency = |node: &usize| Utility::new([1.0, 2.0, 5.0, 10.0][*node]).unwrap();
+    let assign = Assignment::<NodeCostEgraph>::from_iter([(0, 0), (1, 1), (2, 2), (3, 3)]);
+
+    // Through node 2 rather than node 1, counting node 3 once.
+    assert_eq!(
+        assignment_depth(&egraph, &assign, latency),
+        Some(Utility::new(16.0).unwrap())
+    );
+    let cost = CriticalPathCost::new(&egraph, latency);
+    assert_eq!(cost.assignment_utility(&(), &assign), -16.0);
+}
+
+fn check_merge(egraph: &SimpleEgraph, config: MctsConfig) {
+    let grow = || {
+        let mut session = SearchSession::new(egraph, 0, config.clone());
+        session.add_breakpoint(0);
+        assert!(matches!(session.step(), StepOutcome::Breakpoint { .. }));
+        session
+    };
+    let (mut merged, mut other) = (grow(), grow());
+    let visits = |session: &mut SearchSession<SimpleEgraph>| {
+        session
+            .candidates()
+            .into_iter()
+            .map(|c| (c.node, c.visits))
+            .collect::<Vec<_>>()
+    };
+    let (before, from_other) = (visits(&mut merged), visits(&mut other));
+    let nodes_before = merged.stats().tree_nodes;
+
+    merged.merge(&other);
+    let expected = before
+        .iter()
+        .zip(&from_other)
+        .map(|((node, a), (_, b))| (*node, a + b))
+        .collect::<Vec<_>>();
+    assert_eq!(visits(&mut merged), expected);
+    assert!(merged.stats().tree_nodes >= nodes_before.max(other.stats().tree_nodes));
+
+    let assign = merged.run().unwrap();
+    assert_valid_assignment(egraph, 0, &assign);
+}
+
+/// Check that `assign` is a complete, acyclic assignment rooted at `root`.
+fn assert_valid_assignment(egraph: &SimpleEgraph, root: usize, assign: &Assignment<SimpleEgraph>) {
+    assert!(assign.contains_key(&root));
+    for (class, node) in assign {
+        assert!(egraph.classes[*class].contains(node));
+        for child in &egraph.nodes[*node] {
+            assert!(assign.contains_key(child), "class {child} is unassigned");
+        }
+    }
+    // Every class must be reachable from the root without revisiting a class
+    // already on the current path.
+    let mut stack = vec![(root, vec![root])];
+    while let Some((class, path)) = stack.pop() {
+        for child in &egraph.nodes[assign[&class]] {
+            assert!(!path.contains(child), "cycle through class {child}");
+            let mut path = path.clone();
+            path.push(*child);
+            stack.push((*child, path));
+        }
+    }
+}
+
+/// The egraph used in `finds_high_util`.
+fn high_util_egraph() -> SimpleEgraph {
     let nodes = vec![
         vec![2, 1],
         vec![2, 2],
@@ -14,30 +3327,34 @@ fn finds_high_util() {
 
     let classes = vec![vec![0, 1], vec![2, 3], vec![4], vec![5]];
 
-    let egraph = SimpleEgraph {
+    SimpleEgraph {
         nodes,
         classes,
         score_fn: Box::new(score_fn),
-    };
+    }
+}
 
-    let assign = mcts_extract(
-        &egraph,
-        0,
-        MctsConfig {
-            playouts_per_round: 4,
-            terms_to_sample: 4,
-        },
-    )
-    .expect("extraction should succeed");
-    assert_eq!(assign.len(), 3);
-    assert_eq!(assign[&0], 1);
-    assert_eq!(assign[&2], 4);
-    assert_eq!(assign[&3], 5);
+/// An egraph whose root class can either pick node 0, which always extracts,
+/// or node 1, which has a higher utility but leads to a class where most
+/// members form a cycle.
+fn dead_end_egraph() -> SimpleEgraph {
+    let nodes = vec![vec![1], vec![2], vec![], vec![0], vec![2], vec![]];
+    let classes = vec![vec![0, 1], vec![2], vec![3, 4, 5]];
+    SimpleEgraph {
+        nodes,
+        classes,
+        score_fn: Box::new(|assign, _| {
+            if assign.get(&0) == Some(&1) {
+                Utility::new(1.0).unwrap()
+            } else {
+                Utility::new(0.0).unwrap()
+            }
+        }),
+    }
 }
 
-#[test]
-fn fails_unextractable() {
-    // Set up a small egraph with no valid extractions
+/// The egraph used in `fails_unextractable`.
+fn unextractable_egraph() -> SimpleEgraph {
     let nodes = vec![
         vec![0, 1],
         vec![2, 2],
@@ -49,21 +3366,11 @@ fn fails_unextractable() {
 
     let classes = vec![vec![0, 1], vec![2, 3], vec![4], vec![5]];
 
-    let egraph = SimpleEgraph {
+    SimpleEgraph {
         nodes,
         classes,
         score_fn: Box::new(score_fn),
-    };
-
-    assert!(mcts_extract(
-        &egraph,
-        0,
-        MctsConfig {
-            playouts_per_round: 4,
-            terms_to_sample: 4,
-        },
-    )
-    .is_none());
+    }
 }
 
 /// Simple score function in used in some tests.
@@ -77,3 +3384,16 @@ fn score_fn(assignment: &Assignment<SimpleEgraph>, _: &SimpleEgraph) -> Utility
         Utility::new(0.0).unwrap()
     }
 }
+
+/// An egraph whose root node has the given children. Class 1 is a leaf, class
+/// 2 (and class 4 below it) can only be extracted through a cycle, and class 3
+/// is empty.
+fn optional_egraph(root_children: Vec<usize>) -> SimpleEgraph {
+    let nodes = vec![root_children, vec![], vec![4], vec![2]];
+    let classes = vec![vec![0], vec![1], vec![2], vec![], vec![3]];
+    SimpleEgraph {
+        nodes,
+        classes,
+        score_fn: Box::new(score_fn),
+    }
+}
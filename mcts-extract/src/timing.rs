@@ -0,0 +1,95 @@
+//! Throughput measurements for tuning the search's performance.
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// A monotonic source of time for [`SearchTimings`].
+///
+/// Timing is opt-in (see
+/// [`SearchSession::record_timings`](crate::SearchSession::record_timings)), and
+/// the clock is only read around playouts and leaf estimates. [`StdClock`]
+/// uses the standard library; other clocks, such as a cycle counter, can be
+/// plugged in instead.
+pub trait Clock: Send {
+    /// The time elapsed since some fixed point, such as when the clock was
+    /// created. Only differences between readings are used.
+    fn now(&self) -> Duration;
+}
+
+impl fmt::Debug for dyn Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("dyn Clock")
+    }
+}
+
+/// A [`Clock`] that reads [`Instant::now`].
+#[derive(Copy, Clone, Debug)]
+pub struct StdClock(Instant);
+
+impl Default for StdClock {
+    fn default() -> Self {
+        Self(Instant::now())
+    }
+}
+
+impl Clock for StdClock {
+    fn now(&self) -> Duration {
+        self.0.elapsed()
+    }
+}
+
+/// Time spent on the hot loops of a search, for working out its throughput.
+///
+/// These only cover the time spent inside playouts: committing to decisions
+/// and the bookkeeping between rounds are not included.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SearchTimings {
+    /// The number of playouts timed.
+    pub playouts: usize,
+    /// The total time spent in those playouts, including their leaf
+    /// estimates.
+    pub playout_time: Duration,
+    /// The number of search tree nodes those playouts created.
+    pub tree_nodes_created: usize,
+    /// The number of leaf estimates timed. Each one scores the leaf's
+    /// assignment exactly, or scores the random terms sampled to complete it.
+    pub estimates: usize,
+    /// The total time spent in those estimates.
+    pub estimate_time: Duration,
+    /// The number of terms those estimates scored, which is the number of
+    /// times they called
+    /// [`EgraphTotalCost::assignment_utility`](crate::EgraphTotalCost::assignment_utility).
+    pub utility_calls: usize,
+}
+
+impl SearchTimings {
+    /// The number of playouts run per second of playout time, or `None`
+    /// before any time has been measured.
+    pub fn playouts_per_sec(&self) -> Option<f64> {
+        per_sec(self.playouts, self.playout_time)
+    }
+
+    /// The number of tree nodes created per second of playout time, or `None`
+    /// before any time has been measured.
+    pub fn tree_nodes_per_sec(&self) -> Option<f64> {
+        per_sec(self.tree_nodes_created, self.playout_time)
+    }
+
+    /// The average time spent in leaf estimates per call to
+    /// [`EgraphTotalCost::assignment_utility`](crate::EgraphTotalCost::assignment_utility),
+    /// or `None` if there were none.
+    ///
+    /// This includes the time taken to sample the terms that were scored, and
+    /// to sample those that failed before they could be.
+    pub fn mean_utility_call_time(&self) -> Option<Duration> {
+        let calls = u32::try_from(self.utility_calls).ok().filter(|&n| n > 0)?;
+        Some(self.estimate_time / calls)
+    }
+}
+
+fn per_sec(count: usize, time: Duration) -> Option<f64> {
+    let secs = time.as_secs_f64();
+    (secs > 0.0).then(|| count as f64 / secs)
+}
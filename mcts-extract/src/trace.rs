@@ -0,0 +1,183 @@
+//! Recording the decisions a search makes, e.g. to use as training data for
+//! learned value and policy estimates.
+
+use std::{io, mem};
+
+use crate::{search_tree::CandidateStats, Utility};
+
+/// A decision made by a search, along with the statistics it was based on.
+#[derive(Clone, Debug)]
+pub struct DecisionRecord<C, N, U = Utility> {
+    /// The decisions committed before this one, in order.
+    pub prefix: Vec<(C, N)>,
+    /// The class the decision assigned.
+    pub class: C,
+    /// The search statistics for each member of `class`. The visit counts
+    /// only include the playouts run while making this decision, since the
+    /// last restart (see
+    /// [`MctsConfig::restart_interval`](crate::MctsConfig::restart_interval))
+    /// if any.
+    pub candidates: Vec<CandidateStats<N, U>>,
+    /// The node assigned to `class`.
+    pub chosen: N,
+    /// The number of playouts run while making this decision, since the last
+    /// restart if any.
+    pub playouts: usize,
+    /// The utility of the assignment the search ended up extracting, once the
+    /// search has finished. `None` if it has not, or if it failed.
+    pub outcome: Option<U>,
+}
+
+/// One playout, as recorded by a [`TraceRecorder`].
+#[derive(Clone, Debug)]
+pub struct PlayoutRecord<U = Utility> {
+    /// The index of the playout among all the playouts the search has run,
+    /// starting from zero.
+    pub playout: usize,
+    /// The search tree nodes the playout passed through, from the node for the
+    /// last committed decision down to the leaf it scored, by their index in
+    /// the tree.
+    ///
+    /// Indices only identify nodes until the tree is reorganized, which
+    /// happens when the search restarts (see
+    /// [`MctsConfig::restart_interval`](crate::MctsConfig::restart_interval))
+    /// or runs out of node ids.
+    pub path: Vec<usize>,
+    /// The utility backpropagated along `path`, or `None` if the leaf could
+    /// not be scored at all.
+    pub leaf_utility: Option<U>,
+}
+
+/// A log of every playout a search runs, for analyzing or animating how the
+/// search tree grew.
+///
+/// This is much heavier than [`DecisionRecord`]s, since it keeps a record per
+/// playout rather than per decision. A search with a fixed
+/// [`MctsConfig::seed`](crate::MctsConfig::seed) runs the same playouts every
+/// time, so the log can be reproduced by running the search again.
+#[derive(Clone, Debug)]
+pub struct TraceRecorder<U = Utility> {
+    playouts: Vec<PlayoutRecord<U>>,
+}
+
+impl<U> Default for TraceRecorder<U> {
+    fn default() -> Self {
+        Self {
+            playouts: Vec::new(),
+        }
+    }
+}
+
+impl<U> TraceRecorder<U> {
+    /// The playouts recorded, in the order they ran.
+    pub fn playouts(&self) -> &[PlayoutRecord<U>] {
+        &self.playouts
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        playout: usize,
+        path: impl IntoIterator<Item = usize>,
+        leaf_utility: Option<U>,
+    ) {
+        self.playouts.push(PlayoutRecord {
+            playout,
+            path: path.into_iter().collect(),
+            leaf_utility,
+        });
+    }
+}
+
+impl TraceRecorder {
+    /// Write the trace to `out` as JSON Lines: one object per playout, with
+    /// `playout`, `path` and `leaf_utility` fields.
+    pub fn write_json_lines(&self, mut out: impl io::Write) -> io::Result<()> {
+        for record in &self.playouts {
+            write!(out, "{{\"playout\":{},\"path\":[", record.playout)?;
+            for (i, node) in record.path.iter().enumerate() {
+                if i > 0 {
+                    write!(out, ",")?;
+                }
+                write!(out, "{node}")?;
+            }
+            match record.leaf_utility {
+                Some(util) => writeln!(out, "],\"leaf_utility\":{util}}}")?,
+                None => writeln!(out, "],\"leaf_utility\":null}}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The decisions recorded so far, and the statistics for the one in progress.
+pub(crate) struct Trace<C, N, U> {
+    records: Vec<DecisionRecord<C, N, U>>,
+    /// The visit counts of the candidates for the next decision when the
+    /// search started making it.
+    baseline: Vec<u32>,
+    /// The number of playouts run since the last decision.
+    playouts: usize,
+}
+
+impl<C: Clone, N: Clone, U: Copy> Trace<C, N, U> {
+    /// Start recording, with `candidates` describing the next decision.
+    pub(crate) fn new(candidates: &[CandidateStats<N, U>]) -> Self {
+        let mut trace = Self {
+            records: Vec::new(),
+            baseline: Vec::new(),
+            playouts: 0,
+        };
+        trace.start_decision(candidates);
+        trace
+    }
+
+    /// Note that the search ran another playout.
+    pub(crate) fn count_playout(&mut self) {
+        self.playouts += 1;
+    }
+
+    /// Record that the search assigned `chosen` to `class`, after the
+    /// decisions in `prefix`.
+    pub(crate) fn record(
+        &mut self,
+        prefix: &[(C, N)],
+        class: C,
+        mut candidates: Vec<CandidateStats<N, U>>,
+        chosen: N,
+    ) {
+        // The candidates are listed in the same order every time, so they
+        // line up with the baseline.
+        for (candidate, before) in candidates.iter_mut().zip(&self.baseline) {
+            candidate.visits -= before;
+        }
+        self.records.push(DecisionRecord {
+            prefix: prefix.to_vec(),
+            class,
+            candidates,
+            chosen,
+            playouts: self.playouts,
+            outcome: None,
+        });
+    }
+
+    /// Start counting for the next decision, whose candidates currently have
+    /// the statistics in `candidates`.
+    pub(crate) fn start_decision(&mut self, candidates: &[CandidateStats<N, U>]) {
+        self.baseline.clear();
+        self.baseline.extend(candidates.iter().map(|c| c.visits));
+        self.playouts = 0;
+    }
+
+    /// Fill in the outcome of the search for every decision that does not
+    /// have one yet.
+    pub(crate) fn finish(&mut self, outcome: Option<U>) {
+        for record in &mut self.records {
+            record.outcome = record.outcome.or(outcome);
+        }
+    }
+
+    /// Hand out the records made so far.
+    pub(crate) fn take(&mut self) -> Vec<DecisionRecord<C, N, U>> {
+        mem::take(&mut self.records)
+    }
+}